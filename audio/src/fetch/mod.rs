@@ -3,12 +3,13 @@ mod receive;
 use std::cmp::{max, min};
 use std::fs;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::sync::atomic::{self, AtomicUsize};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 
 use byteorder::{BigEndian, ByteOrder};
 use futures_util::{future, StreamExt, TryFutureExt, TryStreamExt};
+use librespot_core::cache::PinnedFile;
 use librespot_core::channel::{ChannelData, ChannelError, ChannelHeaders};
 use librespot_core::session::Session;
 use librespot_core::spotify_id::FileId;
@@ -83,8 +84,21 @@ const MAX_PREFETCH_REQUESTS: usize = 4;
 /// The time we will wait to obtain status updates on downloading.
 const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// When a request for a range of the file fails, only that range is requested again after
+/// this delay. The delay doubles with every further failure of the same range, up to
+/// `MAXIMUM_CHUNK_RETRY_DELAY`.
+const INITIAL_CHUNK_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The upper bound for the delay between two requests for the same range.
+const MAXIMUM_CHUNK_RETRY_DELAY: Duration = Duration::from_secs(4);
+
+/// The number of times a failed range is requested again before the download of the whole
+/// file is given up. Reads from the file return an error after that.
+const MAX_CHUNK_RETRIES: u32 = 8;
+
 pub enum AudioFile {
-    Cached(fs::File),
+    // The pin keeps the file in the cache while it is played.
+    Cached(fs::File, Option<PinnedFile>),
     Streaming(AudioFileStreaming),
 }
 
@@ -131,6 +145,36 @@ impl StreamLoaderController {
         })
     }
 
+    pub fn is_cached(&self) -> bool {
+        self.stream_shared.is_none()
+    }
+
+    /// Returns how much is downloaded from where the file is read onwards, or `None` if it
+    /// is read from the cache.
+    pub fn buffered_bytes(&self) -> Option<usize> {
+        self.stream_shared.as_ref().map(|shared| {
+            let read_position = shared.read_position.load(atomic::Ordering::Relaxed);
+            let download_status = shared.download_status.lock().unwrap();
+            download_status
+                .downloaded
+                .contained_length_from_value(read_position)
+        })
+    }
+
+    /// Returns the number of bytes received from the network since the last call.
+    pub fn take_bytes_fetched(&self) -> usize {
+        self.stream_shared.as_ref().map_or(0, |shared| {
+            shared.bytes_fetched.swap(0, atomic::Ordering::Relaxed)
+        })
+    }
+
+    /// Returns the number of failed requests that were retried since the last call.
+    pub fn take_chunk_retries(&self) -> usize {
+        self.stream_shared.as_ref().map_or(0, |shared| {
+            shared.chunk_retries.swap(0, atomic::Ordering::Relaxed)
+        })
+    }
+
     pub fn ping_time(&self) -> Duration {
         Duration::from_millis(self.stream_shared.as_ref().map_or(0, |shared| {
             shared.ping_time_ms.load(atomic::Ordering::Relaxed) as u64
@@ -149,7 +193,18 @@ impl StreamLoaderController {
         self.send_stream_loader_command(StreamLoaderCommand::Fetch(range));
     }
 
-    pub fn fetch_blocking(&self, mut range: Range) {
+    pub fn fetch_blocking(&self, range: Range) {
+        self.fetch_blocking_unless(range, &AtomicBool::new(false), None);
+    }
+
+    // Like `fetch_blocking`, but stops waiting and loading the file once `cancelled` is set,
+    // or at the `deadline`. Returns whether the range is available.
+    fn fetch_blocking_unless(
+        &self,
+        mut range: Range,
+        cancelled: &AtomicBool,
+        deadline: Option<Instant>,
+    ) -> bool {
         // signal the stream loader to tech a range of the file and block until it is loaded.
 
         // ensure the range is within the file's bounds.
@@ -168,11 +223,19 @@ impl StreamLoaderController {
                     .downloaded
                     .contained_length_from_value(range.start)
             {
+                if shared.failed.load(atomic::Ordering::Relaxed) {
+                    break;
+                }
                 download_status = shared
                     .cond
                     .wait_timeout(download_status, DOWNLOAD_TIMEOUT)
                     .unwrap()
                     .0;
+                let timed_out = matches!(deadline, Some(deadline) if Instant::now() >= deadline);
+                if cancelled.load(atomic::Ordering::Relaxed) || timed_out {
+                    self.close();
+                    return false;
+                }
                 if range.length
                     > (download_status
                         .downloaded
@@ -185,6 +248,8 @@ impl StreamLoaderController {
                 }
             }
         }
+
+        true
     }
 
     pub fn fetch_next(&self, length: usize) {
@@ -207,6 +272,26 @@ impl StreamLoaderController {
         }
     }
 
+    /// Like `fetch_next_blocking`, but stops waiting and loading the file once `cancelled` is
+    /// set, or once `timeout` has passed. Returns whether the data is available.
+    pub fn fetch_next_blocking_unless(
+        &self,
+        length: usize,
+        cancelled: &AtomicBool,
+        timeout: Duration,
+    ) -> bool {
+        match self.stream_shared {
+            Some(ref shared) => {
+                let range = Range {
+                    start: shared.read_position.load(atomic::Ordering::Relaxed),
+                    length,
+                };
+                self.fetch_blocking_unless(range, cancelled, Some(Instant::now() + timeout))
+            }
+            None => true,
+        }
+    }
+
     pub fn set_random_access_mode(&self) {
         // optimise download strategy for random access
         self.send_stream_loader_command(StreamLoaderCommand::RandomAccessMode());
@@ -251,6 +336,9 @@ struct AudioFileShared {
     number_of_open_requests: AtomicUsize,
     ping_time_ms: AtomicUsize,
     read_position: AtomicUsize,
+    bytes_fetched: AtomicUsize,
+    chunk_retries: AtomicUsize,
+    failed: AtomicBool,
 }
 
 impl AudioFile {
@@ -260,9 +348,12 @@ impl AudioFile {
         bytes_per_second: usize,
         play_from_beginning: bool,
     ) -> Result<AudioFile, ChannelError> {
-        if let Some(file) = session.cache().and_then(|cache| cache.file(file_id)) {
-            debug!("File {} already in cache", file_id);
-            return Ok(AudioFile::Cached(file));
+        if let Some(cache) = session.cache() {
+            let pin = cache.pin_file(file_id);
+            if let Some(file) = cache.file(file_id) {
+                debug!("File {} already in cache", file_id);
+                return Ok(AudioFile::Cached(file, pin));
+            }
         }
 
         debug!("Downloading file {}", file_id);
@@ -315,7 +406,7 @@ impl AudioFile {
                 stream_shared: Some(stream.shared.clone()),
                 file_size: stream.shared.file_size,
             },
-            AudioFile::Cached(ref file) => StreamLoaderController {
+            AudioFile::Cached(ref file, _) => StreamLoaderController {
                 channel_tx: None,
                 stream_shared: None,
                 file_size: file.metadata().unwrap().len() as usize,
@@ -360,6 +451,9 @@ impl AudioFileStreaming {
             number_of_open_requests: AtomicUsize::new(0),
             ping_time_ms: AtomicUsize::new(0),
             read_position: AtomicUsize::new(0),
+            bytes_fetched: AtomicUsize::new(0),
+            chunk_retries: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
         });
 
         let mut write_file = NamedTempFile::new().unwrap();
@@ -400,6 +494,13 @@ impl Read for AudioFileStreaming {
             return Ok(0);
         }
 
+        if self.shared.failed.load(atomic::Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "download of the file failed",
+            ));
+        }
+
         let length = min(output.len(), self.shared.file_size - offset);
 
         let length_to_request = match *(self.shared.download_strategy.lock().unwrap()) {
@@ -431,9 +532,10 @@ impl Read for AudioFileStreaming {
         ranges_to_request.subtract_range_set(&download_status.requested);
 
         for &range in ranges_to_request.iter() {
-            self.stream_loader_command_tx
-                .send(StreamLoaderCommand::Fetch(range))
-                .unwrap();
+            // ignore the error in case the download was given up in the meantime.
+            let _ = self
+                .stream_loader_command_tx
+                .send(StreamLoaderCommand::Fetch(range));
         }
 
         if length == 0 {
@@ -442,6 +544,12 @@ impl Read for AudioFileStreaming {
 
         let mut download_message_printed = false;
         while !download_status.downloaded.contains(offset) {
+            if self.shared.failed.load(atomic::Ordering::Relaxed) {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "download of the file failed",
+                ));
+            }
             if let DownloadStrategy::Streaming() = *self.shared.download_strategy.lock().unwrap() {
                 if !download_message_printed {
                     debug!("Stream waiting for download of file position {}. Downloaded ranges: {}. Pending ranges: {}", offset, download_status.downloaded, download_status.requested.minus(&download_status.downloaded));
@@ -497,7 +605,7 @@ impl Seek for AudioFileStreaming {
 impl Read for AudioFile {
     fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
         match *self {
-            AudioFile::Cached(ref mut file) => file.read(output),
+            AudioFile::Cached(ref mut file, _) => file.read(output),
             AudioFile::Streaming(ref mut file) => file.read(output),
         }
     }
@@ -506,7 +614,7 @@ impl Read for AudioFile {
 impl Seek for AudioFile {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         match *self {
-            AudioFile::Cached(ref mut file) => file.seek(pos),
+            AudioFile::Cached(ref mut file, _) => file.seek(pos),
             AudioFile::Streaming(ref mut file) => file.seek(pos),
         }
     }
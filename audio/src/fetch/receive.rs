@@ -6,19 +6,21 @@ use std::time::{Duration, Instant};
 use atomic::Ordering;
 use byteorder::{BigEndian, WriteBytesExt};
 use bytes::Bytes;
-use futures_util::StreamExt;
-use librespot_core::channel::{Channel, ChannelData};
+use futures_util::{Stream, StreamExt};
+use librespot_core::channel::{Channel, ChannelData, ChannelError};
 use librespot_core::session::Session;
 use librespot_core::spotify_id::FileId;
 use tempfile::NamedTempFile;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time;
 
 use crate::range_set::{Range, RangeSet};
 
 use super::{AudioFileShared, DownloadStrategy, StreamLoaderCommand};
 use super::{
-    FAST_PREFETCH_THRESHOLD_FACTOR, MAXIMUM_ASSUMED_PING_TIME, MAX_PREFETCH_REQUESTS,
-    MINIMUM_DOWNLOAD_SIZE, PREFETCH_THRESHOLD_FACTOR,
+    FAST_PREFETCH_THRESHOLD_FACTOR, INITIAL_CHUNK_RETRY_DELAY, MAXIMUM_ASSUMED_PING_TIME,
+    MAXIMUM_CHUNK_RETRY_DELAY, MAX_CHUNK_RETRIES, MAX_PREFETCH_REQUESTS, MINIMUM_DOWNLOAD_SIZE,
+    PREFETCH_THRESHOLD_FACTOR,
 };
 
 pub fn request_range(session: &Session, file: FileId, offset: usize, length: usize) -> Channel {
@@ -60,16 +62,19 @@ struct PartialFileData {
 enum ReceivedData {
     ResponseTime(Duration),
     Data(PartialFileData),
+    Failed(Range),
 }
 
-async fn receive_data(
+async fn receive_data<S>(
     shared: Arc<AudioFileShared>,
     file_data_tx: mpsc::UnboundedSender<ReceivedData>,
-    mut data_rx: ChannelData,
+    mut data_rx: S,
     initial_data_offset: usize,
     initial_request_length: usize,
     request_sent_time: Instant,
-) {
+) where
+    S: Stream<Item = Result<Bytes, ChannelError>> + Unpin,
+{
     let mut data_offset = initial_data_offset;
     let mut request_length = initial_request_length;
 
@@ -118,9 +123,16 @@ async fn receive_data(
     if request_length > 0 {
         let missing_range = Range::new(data_offset, request_length);
 
-        let mut download_status = shared.download_status.lock().unwrap();
-        download_status.requested.subtract_range(&missing_range);
-        shared.cond.notify_all();
+        // The range stays requested until the fetch task requests it again. Only if that
+        // task is gone, it is released for whoever reads the file next.
+        if file_data_tx
+            .send(ReceivedData::Failed(missing_range))
+            .is_err()
+        {
+            let mut download_status = shared.download_status.lock().unwrap();
+            download_status.requested.subtract_range(&missing_range);
+            shared.cond.notify_all();
+        }
     }
 
     shared
@@ -140,6 +152,116 @@ async fn receive_data(
     }
 }
 
+/// Keeps track of how often ranges of the file failed to download.
+#[derive(Default)]
+struct ChunkRetries {
+    attempts: Vec<(Range, u32)>,
+}
+
+impl ChunkRetries {
+    /// Records a failed request for `range`. Returns how long to wait before requesting it
+    /// again, or `None` if it failed too often.
+    fn on_failure(&mut self, range: Range) -> Option<Duration> {
+        let overlapping = self
+            .attempts
+            .iter()
+            .position(|(failed, _)| failed.start < range.end() && range.start < failed.end());
+
+        let attempts = match overlapping {
+            Some(i) => {
+                self.attempts[i] = (range, self.attempts[i].1 + 1);
+                self.attempts[i].1
+            }
+            None => {
+                self.attempts.push((range, 1));
+                1
+            }
+        };
+
+        if attempts > MAX_CHUNK_RETRIES {
+            return None;
+        }
+
+        Some(min(
+            INITIAL_CHUNK_RETRY_DELAY * 2u32.pow(attempts - 1),
+            MAXIMUM_CHUNK_RETRY_DELAY,
+        ))
+    }
+
+    /// Forgets the failures of ranges that have been downloaded since.
+    fn on_downloaded(&mut self, downloaded: &RangeSet) {
+        self.attempts.retain(|(range, _)| {
+            downloaded.contained_length_from_value(range.start) < range.length
+        });
+    }
+}
+
+/// The ranges whose requests failed, and when each of them is requested again.
+#[derive(Default)]
+struct FailedRanges {
+    retries: ChunkRetries,
+    pending: Vec<(Instant, Range)>,
+}
+
+impl FailedRanges {
+    /// Schedules `range` to be requested again after a backoff, during which it stays
+    /// requested. Gives up the download of the file if the range failed too often.
+    fn on_failure(&mut self, shared: &AudioFileShared, range: Range, now: Instant) -> ControlFlow {
+        match self.retries.on_failure(range) {
+            Some(delay) => {
+                warn!(
+                    "Requesting range {} (+{}) again in {} ms.",
+                    range.start,
+                    range.length,
+                    delay.as_millis()
+                );
+                shared.chunk_retries.fetch_add(1, Ordering::Relaxed);
+                self.pending.push((now + delay, range));
+                ControlFlow::Continue
+            }
+            None => {
+                error!(
+                    "Giving up download of file {} after range {} (+{}) failed {} times.",
+                    shared.file_id,
+                    range.start,
+                    range.length,
+                    MAX_CHUNK_RETRIES + 1
+                );
+                shared.failed.store(true, Ordering::Relaxed);
+                let _lock = shared.download_status.lock().unwrap();
+                shared.cond.notify_all();
+                ControlFlow::Break
+            }
+        }
+    }
+
+    fn on_downloaded(&mut self, downloaded: &RangeSet) {
+        if !self.retries.attempts.is_empty() {
+            self.retries.on_downloaded(downloaded);
+        }
+    }
+
+    fn next_retry(&self) -> Option<Instant> {
+        self.pending.iter().map(|(time, _)| *time).min()
+    }
+
+    /// Returns the ranges that are due to be requested again at `now`, which are no longer
+    /// marked as requested.
+    fn take_due(&mut self, shared: &AudioFileShared, now: Instant) -> Vec<Range> {
+        let (due, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|(time, _)| *time <= now);
+        self.pending = pending;
+
+        let mut download_status = shared.download_status.lock().unwrap();
+        due.into_iter()
+            .map(|(_, range)| {
+                download_status.requested.subtract_range(&range);
+                range
+            })
+            .collect()
+    }
+}
+
 struct AudioFileFetch {
     session: Session,
     shared: Arc<AudioFileShared>,
@@ -148,6 +270,8 @@ struct AudioFileFetch {
     file_data_tx: mpsc::UnboundedSender<ReceivedData>,
     complete_tx: Option<oneshot::Sender<NamedTempFile>>,
     network_response_times: Vec<Duration>,
+
+    failed_ranges: FailedRanges,
 }
 
 // Might be replaced by enum from std once stable
@@ -306,6 +430,10 @@ impl AudioFileFetch {
                     .write_all(data.data.as_ref())
                     .unwrap();
 
+                self.shared
+                    .bytes_fetched
+                    .fetch_add(data.data.len(), Ordering::Relaxed);
+
                 let mut download_status = self.shared.download_status.lock().unwrap();
 
                 let received_range = Range::new(data.offset, data.data.len());
@@ -315,6 +443,9 @@ impl AudioFileFetch {
                 let full = download_status.downloaded.contained_length_from_value(0)
                     >= self.shared.file_size;
 
+                self.failed_ranges
+                    .on_downloaded(&download_status.downloaded);
+
                 drop(download_status);
 
                 if full {
@@ -322,10 +453,21 @@ impl AudioFileFetch {
                     return ControlFlow::Break;
                 }
             }
+            ReceivedData::Failed(range) => {
+                return self
+                    .failed_ranges
+                    .on_failure(&self.shared, range, Instant::now());
+            }
         }
         ControlFlow::Continue
     }
 
+    fn retry_due_ranges(&mut self) {
+        for range in self.failed_ranges.take_due(&self.shared, Instant::now()) {
+            self.download_range(range.start, range.length);
+        }
+    }
+
     fn handle_stream_loader_command(&mut self, cmd: StreamLoaderCommand) -> ControlFlow {
         match cmd {
             StreamLoaderCommand::Fetch(request) => {
@@ -387,10 +529,23 @@ pub(super) async fn audio_file_fetch(
         file_data_tx,
         complete_tx: Some(complete_tx),
         network_response_times: Vec::with_capacity(3),
+
+        failed_ranges: FailedRanges::default(),
     };
 
+    // When prefetching is held back by the download rate limit, when to try again.
+    let mut throttled_until: Option<Instant> = None;
+
     loop {
+        let next_retry = fetch.failed_ranges.next_retry();
+
         tokio::select! {
+            _ = async { time::sleep_until(throttled_until.unwrap().into()).await }, if throttled_until.is_some() => {
+                throttled_until = None;
+            },
+            _ = async { time::sleep_until(next_retry.unwrap().into()).await }, if next_retry.is_some() => {
+                fetch.retry_due_ranges();
+            },
             cmd = stream_loader_command_rx.recv() => {
                 if cmd.map_or(true, |cmd| fetch.handle_stream_loader_command(cmd) == ControlFlow::Break) {
                     break;
@@ -431,12 +586,211 @@ pub(super) async fn audio_file_fetch(
                 );
 
                 if bytes_pending < desired_pending_bytes {
-                    fetch.pre_fetch_more_data(
-                        desired_pending_bytes - bytes_pending,
-                        max_requests_to_send,
-                    );
+                    let mut bytes_to_fetch = desired_pending_bytes - bytes_pending;
+
+                    // Requested data that is still on its way will use up the allowance too.
+                    let channel = fetch.session.channel();
+                    if let (Some(allowance), Some(limit)) = (
+                        channel.get_download_allowance(),
+                        channel.get_download_rate_limit(),
+                    ) {
+                        let allowance = allowance - bytes_pending as f64;
+                        if allowance >= MINIMUM_DOWNLOAD_SIZE as f64 {
+                            bytes_to_fetch = min(bytes_to_fetch, allowance as usize);
+                        } else {
+                            let missing = MINIMUM_DOWNLOAD_SIZE as f64 - allowance;
+                            let wait = Duration::from_secs_f64(missing / max(limit, 1) as f64);
+                            throttled_until = Some(Instant::now() + wait);
+                            bytes_to_fetch = 0;
+                        }
+                    }
+
+                    if bytes_to_fetch > 0 {
+                        fetch.pre_fetch_more_data(bytes_to_fetch, max_requests_to_send);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fetch::{AudioFileDownloadStatus, StreamLoaderController};
+    use futures_util::stream;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::{Condvar, Mutex};
+
+    fn shared(file_size: usize) -> Arc<AudioFileShared> {
+        Arc::new(AudioFileShared {
+            file_id: FileId([0; 20]),
+            file_size,
+            stream_data_rate: 0,
+            cond: Condvar::new(),
+            download_status: Mutex::new(AudioFileDownloadStatus {
+                requested: RangeSet::new(),
+                downloaded: RangeSet::new(),
+            }),
+            download_strategy: Mutex::new(DownloadStrategy::Streaming()),
+            number_of_open_requests: AtomicUsize::new(0),
+            ping_time_ms: AtomicUsize::new(0),
+            read_position: AtomicUsize::new(0),
+            bytes_fetched: AtomicUsize::new(0),
+            chunk_retries: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    fn is_requested(shared: &AudioFileShared, range: Range) -> bool {
+        let download_status = shared.download_status.lock().unwrap();
+        download_status
+            .requested
+            .contained_length_from_value(range.start)
+            >= range.length
+    }
+
+    #[test]
+    fn failed_chunks_are_requested_again_until_the_download_is_given_up() {
+        let shared = shared(4 * MINIMUM_DOWNLOAD_SIZE);
+        let controller = StreamLoaderController {
+            channel_tx: None,
+            stream_shared: Some(shared.clone()),
+            file_size: shared.file_size,
+        };
+        let (file_data_tx, mut file_data_rx) = mpsc::unbounded_channel();
+        let mut failed_ranges = FailedRanges::default();
+
+        let mut range = Range::new(0, MINIMUM_DOWNLOAD_SIZE);
+        shared
+            .download_status
+            .lock()
+            .unwrap()
+            .requested
+            .add_range(&range);
+        let mut now = Instant::now();
+
+        for attempt in 0..=MAX_CHUNK_RETRIES {
+            // The server sends the first kilobyte of the range, then the channel fails.
+            let data = stream::iter(vec![Ok(Bytes::from(vec![0; 1024])), Err(ChannelError)]);
+            futures_executor::block_on(receive_data(
+                shared.clone(),
+                file_data_tx.clone(),
+                data,
+                range.start,
+                range.length,
+                now,
+            ));
+
+            let mut missing = None;
+            while let Ok(data) = file_data_rx.try_recv() {
+                if let ReceivedData::Failed(range) = data {
+                    missing = Some(range);
                 }
             }
+            let missing = missing.unwrap();
+            assert_eq!(
+                (missing.start, missing.length),
+                (range.start + 1024, range.length - 1024)
+            );
+            assert!(is_requested(&shared, missing));
+
+            let control = failed_ranges.on_failure(&shared, missing, now);
+            if attempt == MAX_CHUNK_RETRIES {
+                assert!(control == ControlFlow::Break);
+                break;
+            }
+            assert!(control == ControlFlow::Continue);
+
+            // Only the missing part is requested again, once its backoff has passed.
+            let due = failed_ranges.next_retry().unwrap();
+            assert_eq!(
+                due - now,
+                min(
+                    INITIAL_CHUNK_RETRY_DELAY * 2u32.pow(attempt),
+                    MAXIMUM_CHUNK_RETRY_DELAY
+                )
+            );
+            assert!(failed_ranges
+                .take_due(&shared, due - Duration::from_millis(1))
+                .is_empty());
+            assert!(is_requested(&shared, missing));
+            let due_ranges = failed_ranges.take_due(&shared, due);
+            assert_eq!(due_ranges.len(), 1);
+            assert_eq!(due_ranges[0].start, missing.start);
+            assert!(!is_requested(&shared, missing));
+            assert_eq!(
+                controller.take_chunk_retries(),
+                1,
+                "retry {} is reported",
+                attempt
+            );
+
+            shared
+                .download_status
+                .lock()
+                .unwrap()
+                .requested
+                .add_range(&missing);
+            range = missing;
+            now = due;
+        }
+
+        // Readers that wait for the range are let go.
+        assert!(shared.failed.load(Ordering::Relaxed));
+        assert!(failed_ranges.next_retry().is_none());
+        controller.fetch_blocking(range);
+        assert!(!controller.range_available(range));
+    }
+
+    #[test]
+    fn chunk_retries_back_off_until_exhausted() {
+        let mut retries = ChunkRetries::default();
+        let range = Range::new(0, MINIMUM_DOWNLOAD_SIZE);
+
+        let mut delays = Vec::new();
+        while let Some(delay) = retries.on_failure(range) {
+            delays.push(delay);
         }
+
+        assert_eq!(delays.len(), MAX_CHUNK_RETRIES as usize);
+        assert_eq!(delays[0], INITIAL_CHUNK_RETRY_DELAY);
+        assert_eq!(delays[1], INITIAL_CHUNK_RETRY_DELAY * 2);
+        assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*delays.last().unwrap(), MAXIMUM_CHUNK_RETRY_DELAY);
+    }
+
+    #[test]
+    fn chunk_retries_are_counted_per_range() {
+        let mut retries = ChunkRetries::default();
+        let first = Range::new(0, MINIMUM_DOWNLOAD_SIZE);
+        let second = Range::new(MINIMUM_DOWNLOAD_SIZE, MINIMUM_DOWNLOAD_SIZE);
+
+        retries.on_failure(first);
+        retries.on_failure(first);
+        assert_eq!(retries.on_failure(second), Some(INITIAL_CHUNK_RETRY_DELAY));
+
+        // A partial response leaves a smaller range, which counts as the same one.
+        let rest = Range::new(1024, MINIMUM_DOWNLOAD_SIZE - 1024);
+        assert_eq!(
+            retries.on_failure(rest),
+            Some(INITIAL_CHUNK_RETRY_DELAY * 4)
+        );
+    }
+
+    #[test]
+    fn downloaded_chunks_reset_their_retries() {
+        let mut retries = ChunkRetries::default();
+        let range = Range::new(0, MINIMUM_DOWNLOAD_SIZE);
+
+        for _ in 0..MAX_CHUNK_RETRIES {
+            assert!(retries.on_failure(range).is_some());
+        }
+
+        let mut downloaded = RangeSet::new();
+        downloaded.add_range(&range);
+        retries.on_downloaded(&downloaded);
+
+        assert_eq!(retries.on_failure(range), Some(INITIAL_CHUNK_RETRY_DELAY));
     }
 }
@@ -1,6 +1,9 @@
 use crate::core::spotify_id::SpotifyId;
+use crate::playback::player::Restrictions;
+use crate::protocol::context_player_state::ContextPlayerState;
 use crate::protocol::spirc::TrackRef;
 
+use protobuf::Message;
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -9,6 +12,11 @@ pub struct StationContext {
     pub next_page_url: String,
     #[serde(deserialize_with = "deserialize_protobuf_TrackRef")]
     pub tracks: Vec<TrackRef>,
+    // Only the first page of a station says what it restricts.
+    #[serde(default)]
+    pub restrictions: Option<ContextRestrictions>,
+    #[serde(default)]
+    pub title: Option<String>,
     // Not required for core functionality
     // pub seeds: Vec<String>,
     // #[serde(rename = "imageUri")]
@@ -17,7 +25,6 @@ pub struct StationContext {
     // pub subtitles: Vec<String>,
     // #[serde(rename = "subtitleUri")]
     // pub subtitle_uri: Option<String>,
-    // pub title: String,
     // #[serde(rename = "titleUri")]
     // pub title_uri: String,
     // pub related_artists: Vec<ArtistContext>,
@@ -34,6 +41,124 @@ pub struct PageContext {
     // // pub restrictions:
 }
 
+// A context as resolved by `hm://context-resolve`, for `Spirc::load_context`.
+#[derive(Deserialize, Debug, Default)]
+pub struct ResolvedContext {
+    #[serde(default)]
+    pub pages: Vec<ResolvedContextPage>,
+    #[serde(default)]
+    pub restrictions: ContextRestrictions,
+    #[serde(default)]
+    pub metadata: ResolvedContextMetadata,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ResolvedContextMetadata {
+    // The name of the context, as the controllers show it.
+    pub context_description: Option<String>,
+}
+
+// The reasons a context gives for not allowing an action, if it doesn't. Any reason at all
+// means the action is not allowed.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ContextRestrictions {
+    pub disallow_skipping_next_reasons: Vec<String>,
+    pub disallow_skipping_prev_reasons: Vec<String>,
+    pub disallow_seeking_reasons: Vec<String>,
+    pub disallow_toggling_repeat_context_reasons: Vec<String>,
+    pub disallow_toggling_repeat_track_reasons: Vec<String>,
+    pub disallow_toggling_shuffle_reasons: Vec<String>,
+}
+
+impl ContextRestrictions {
+    pub fn to_restrictions(&self) -> Restrictions {
+        Restrictions {
+            can_skip_next: self.disallow_skipping_next_reasons.is_empty(),
+            can_skip_prev: self.disallow_skipping_prev_reasons.is_empty(),
+            can_seek: self.disallow_seeking_reasons.is_empty(),
+            can_repeat: self.disallow_toggling_repeat_context_reasons.is_empty()
+                && self.disallow_toggling_repeat_track_reasons.is_empty(),
+            can_shuffle: self.disallow_toggling_shuffle_reasons.is_empty(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolvedContextPage {
+    // Later pages of long contexts may only come with a `page_url`, and are left out.
+    #[serde(default)]
+    pub tracks: Vec<ResolvedContextTrack>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolvedContextTrack {
+    #[serde(default)]
+    pub uri: String,
+}
+
+impl ResolvedContext {
+    // A track or episode played on its own, which needs no resolving.
+    pub fn single(uri: &str) -> Self {
+        Self {
+            pages: vec![ResolvedContextPage {
+                tracks: vec![ResolvedContextTrack {
+                    uri: uri.to_owned(),
+                }],
+            }],
+            ..Self::default()
+        }
+    }
+
+    pub fn track_refs(&self) -> Vec<TrackRef> {
+        self.pages
+            .iter()
+            .flat_map(|page| page.tracks.iter())
+            .filter(|track| !track.uri.is_empty())
+            .map(|track| track_ref_for_uri(&track.uri))
+            .collect()
+    }
+}
+
+// What the context of a load allows, from the `context_player_state` of its frame. Loads
+// that don't say, or can't be read, allow everything.
+pub fn load_restrictions(context_player_state: &[u8]) -> Restrictions {
+    let restrictions = match ContextPlayerState::parse_from_bytes(context_player_state) {
+        Ok(mut state) if state.has_context_restrictions() => state.take_context_restrictions(),
+        Ok(_) => return Restrictions::default(),
+        Err(e) => {
+            warn!("Invalid context player state: {}", e);
+            return Restrictions::default();
+        }
+    };
+
+    Restrictions {
+        can_skip_next: restrictions.get_disallow_skipping_next_reasons().is_empty(),
+        can_skip_prev: restrictions.get_disallow_skipping_prev_reasons().is_empty(),
+        can_seek: restrictions.get_disallow_seeking_reasons().is_empty(),
+        can_repeat: restrictions
+            .get_disallow_toggling_repeat_context_reasons()
+            .is_empty()
+            && restrictions
+                .get_disallow_toggling_repeat_track_reasons()
+                .is_empty(),
+        can_shuffle: restrictions
+            .get_disallow_toggling_shuffle_reasons()
+            .is_empty(),
+    }
+}
+
+// Local files and the like have no id, and are played by uri, if at all.
+pub fn track_ref_for_uri(uri: &str) -> TrackRef {
+    let mut t = TrackRef::new();
+    if let Ok(id) = SpotifyId::from_uri(uri) {
+        t.set_gid(id.to_raw().to_vec());
+    }
+    t.set_uri(uri.to_owned());
+    t
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TrackContext {
     #[serde(rename = "original_gid")]
@@ -0,0 +1,163 @@
+use std::convert::TryFrom;
+
+use crate::core::config::DeviceType;
+use crate::protocol::spirc::{CapabilityType, DeviceState, Frame, MessageType, State};
+
+/// Another Connect device on the account, as last seen in its messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectDevice {
+    pub ident: String,
+    pub name: String,
+    /// What kind of device it is, if it says and the type is known.
+    pub device_type: Option<DeviceType>,
+    pub is_active: bool,
+    pub volume: u16,
+}
+
+// The other devices on the account. Devices send their state along with every message, and
+// answer the hello that is sent at startup, so all of them are known after that.
+#[derive(Default)]
+pub(crate) struct Devices {
+    devices: Vec<ConnectDevice>,
+    // The active device and its state, from the last message it sent with one.
+    active_state: Option<(String, State)>,
+}
+
+impl Devices {
+    pub(crate) fn update(&mut self, frame: &Frame) {
+        let ident = frame.get_ident();
+
+        if frame.get_typ() == MessageType::kMessageTypeGoodbye {
+            self.devices.retain(|device| device.ident != ident);
+            self.forget_active_state(ident);
+            return;
+        }
+
+        if !frame.has_device_state() {
+            return;
+        }
+
+        let device_state = frame.get_device_state();
+        let device = ConnectDevice {
+            ident: ident.to_owned(),
+            name: device_state.get_name().to_owned(),
+            device_type: device_type(device_state),
+            is_active: device_state.get_is_active(),
+            volume: device_state.get_volume() as u16,
+        };
+
+        if device.is_active {
+            // Only one device is active at a time, and the others may not have said yet
+            // that they aren't any more.
+            self.deactivate_all();
+            if frame.has_state() {
+                self.active_state = Some((device.ident.clone(), frame.get_state().clone()));
+            }
+        } else {
+            self.forget_active_state(ident);
+        }
+
+        match self.devices.iter_mut().find(|d| d.ident == device.ident) {
+            Some(known) => *known = device,
+            None => self.devices.push(device),
+        }
+    }
+
+    // This device became active, so none of the others are.
+    pub(crate) fn deactivate_all(&mut self) {
+        for device in &mut self.devices {
+            device.is_active = false;
+        }
+        self.active_state = None;
+    }
+
+    fn forget_active_state(&mut self, ident: &str) {
+        if matches!(self.active_state, Some((ref active, _)) if active == ident) {
+            self.active_state = None;
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<ConnectDevice> {
+        self.devices.clone()
+    }
+
+    pub(crate) fn get(&self, ident: &str) -> Option<&ConnectDevice> {
+        self.devices.iter().find(|device| device.ident == ident)
+    }
+
+    pub(crate) fn active_state(&self) -> Option<&State> {
+        self.active_state.as_ref().map(|(_, state)| state)
+    }
+}
+
+fn device_type(device_state: &DeviceState) -> Option<DeviceType> {
+    device_state
+        .get_capabilities()
+        .iter()
+        .find(|capability| capability.get_typ() == CapabilityType::kDeviceType)
+        .and_then(|capability| capability.get_intValue().first())
+        .and_then(|&id| DeviceType::try_from(id).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::spirc::PlayStatus;
+
+    fn frame(typ: MessageType, ident: &str, is_active: bool) -> Frame {
+        let mut frame = Frame::new();
+        frame.set_typ(typ);
+        frame.set_ident(ident.to_owned());
+        frame.mut_device_state().set_name(ident.to_uppercase());
+        frame.mut_device_state().set_is_active(is_active);
+        let capability = frame.mut_device_state().mut_capabilities().push_default();
+        capability.set_typ(CapabilityType::kDeviceType);
+        capability.mut_intValue().push(DeviceType::Tv as i64);
+        if is_active {
+            frame.mut_state().set_status(PlayStatus::kPlayStatusPlay);
+        }
+        frame
+    }
+
+    fn idents(devices: &Devices) -> Vec<(String, bool)> {
+        devices
+            .list()
+            .into_iter()
+            .map(|device| (device.ident, device.is_active))
+            .collect()
+    }
+
+    #[test]
+    fn devices_are_updated_from_frames() {
+        let mut devices = Devices::default();
+        devices.update(&frame(MessageType::kMessageTypeNotify, "a", false));
+        devices.update(&frame(MessageType::kMessageTypeNotify, "b", true));
+        assert_eq!(
+            idents(&devices),
+            [("a".to_owned(), false), ("b".to_owned(), true)]
+        );
+        assert_eq!(devices.get("a").unwrap().name, "A");
+        assert_eq!(devices.get("a").unwrap().device_type, Some(DeviceType::Tv));
+        assert!(devices.active_state().is_some());
+
+        devices.update(&frame(MessageType::kMessageTypeLoad, "a", true));
+        assert_eq!(
+            idents(&devices),
+            [("a".to_owned(), true), ("b".to_owned(), false)]
+        );
+        assert!(devices.active_state().is_some());
+
+        devices.update(&frame(MessageType::kMessageTypeNotify, "a", false));
+        assert!(devices.active_state().is_none());
+    }
+
+    #[test]
+    fn goodbye_removes_device() {
+        let mut devices = Devices::default();
+        devices.update(&frame(MessageType::kMessageTypeNotify, "a", true));
+        devices.update(&frame(MessageType::kMessageTypeGoodbye, "a", false));
+        assert!(devices.list().is_empty());
+        assert!(devices.get("a").is_none());
+        assert!(devices.active_state().is_none());
+    }
+}
@@ -0,0 +1,111 @@
+//! A passive listener for debugging the Connect protocol. It receives what the server sends to
+//! the devices of the user and logs it, without announcing a device of its own or playing
+//! anything, so that it can run next to a normal instance.
+
+use std::fmt::Write;
+
+use futures_util::StreamExt;
+use protobuf::Message;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::core::mercury::{MercuryError, MercuryResponse, PushMessage};
+use crate::core::session::Session;
+use crate::protocol::spirc::Frame;
+
+use super::spirc::url_encode;
+
+/// Logs every message the session receives, at debug level, until the session is lost. The
+/// frames of the Connect devices of the user are decoded, other messages are logged as JSON
+/// or text where they are, and as hex otherwise.
+pub async fn monitor(session: Session) -> Result<(), MercuryError> {
+    let remote = format!("hm://remote/user/{}/", url_encode(session.username()));
+    info!(
+        "Monitoring {} and the other messages of the session",
+        remote
+    );
+
+    let mut frames =
+        UnboundedReceiverStream::new(session.mercury().subscribe(remote.clone()).await?);
+    let mut pushes = session.subscribe_pushes("hm://");
+
+    loop {
+        tokio::select! {
+            response = frames.next() => match response {
+                Some(response) => log_frame(&response),
+                None => break,
+            },
+            Some(message) = pushes.recv() => {
+                // The frames are logged above, decoded.
+                if !message.uri.starts_with(&remote) {
+                    log_push(&message);
+                }
+            }
+        }
+    }
+
+    info!(
+        "Session[{}] is gone, stopped monitoring",
+        session.session_id()
+    );
+    Ok(())
+}
+
+fn log_frame(response: &MercuryResponse) {
+    for part in &response.payload {
+        match Frame::parse_from_bytes(part) {
+            Ok(frame) => debug!("{} {:#?}", response.uri, frame),
+            Err(e) => debug!(
+                "{} undecodable frame ({}): {}",
+                response.uri,
+                e,
+                format_payload(part)
+            ),
+        }
+    }
+}
+
+fn log_push(message: &PushMessage) {
+    let content_type = message.content_type.as_deref().unwrap_or("no content type");
+    if message.payload.is_empty() {
+        debug!("{} ({}) without payload", message.uri, content_type);
+    }
+    for part in &message.payload {
+        debug!(
+            "{} ({}) {}",
+            message.uri,
+            content_type,
+            format_payload(part)
+        );
+    }
+}
+
+fn format_payload(part: &[u8]) -> String {
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(part) {
+        if let Ok(pretty) = serde_json::to_string_pretty(&json) {
+            return pretty;
+        }
+    }
+
+    match std::str::from_utf8(part) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => text.to_string(),
+        _ => {
+            let mut hex = String::with_capacity(part.len() * 2);
+            for byte in part {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            hex
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn payloads_are_formatted_by_content() {
+        assert_eq!(format_payload(br#"{"a":1}"#), "{\n  \"a\": 1\n}");
+        assert_eq!(format_payload(b"plain text"), "plain text");
+        assert_eq!(format_payload(&[0x08, 0x01, 0xff]), "0801ff");
+    }
+}
@@ -1,16 +1,32 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::future::Future;
+use std::ops::Range;
 use std::pin::Pin;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use crate::context::StationContext;
-use crate::core::config::ConnectConfig;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::context::{load_restrictions, track_ref_for_uri, ResolvedContext, StationContext};
+use crate::core::config::{
+    AutoplayMode, ConnectConfig, ContextKind, DeviceType, EndOfContextAction, RepeatMode,
+    VolumeStepSpacing,
+};
 use crate::core::mercury::{MercuryError, MercurySender};
+use crate::core::rate_limit::RequestPriority;
 use crate::core::session::Session;
 use crate::core::spotify_id::{SpotifyAudioType, SpotifyId, SpotifyIdError};
 use crate::core::util::SeqGenerator;
 use crate::core::version;
+use crate::devices::{ConnectDevice, Devices};
+use crate::metadata::{Metadata, MetadataError, Track};
+use crate::playback::config::{VolumeCtrl, MAX_PRELOAD_DEPTH};
+use crate::playback::mixer::mappings::MappedCtrl;
 use crate::playback::mixer::Mixer;
-use crate::playback::player::{Player, PlayerEvent, PlayerEventChannel};
+use crate::playback::player::{
+    db_to_ratio, ratio_to_db, Player, PlayerEvent, PlayerEventChannel, Restrictions,
+    TrackFilterReason, VolumeSource,
+};
 use crate::protocol;
 use crate::protocol::spirc::{DeviceState, Frame, MessageType, PlayStatus, State, TrackRef};
 
@@ -19,7 +35,10 @@ use futures_util::stream::FusedStream;
 use futures_util::{FutureExt, StreamExt};
 use protobuf::{self, Message};
 use rand::seq::SliceRandom;
-use tokio::sync::mpsc;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, MissedTickBehavior};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 enum SpircPlayStatus {
@@ -40,6 +59,70 @@ enum SpircPlayStatus {
     },
 }
 
+/// The volume `steps` away from `volume`, with `volume_steps` steps from 0 to the maximum
+/// spaced as `spacing` says. Steps that are equal in dB follow the range of the volume curve,
+/// and so sound like equal changes whatever the curve is. The steps are counted from the
+/// nearest one, so that the volume always ends up on a step, and stop at either end rather
+/// than wrapping around.
+pub fn stepped_volume(
+    volume_ctrl: VolumeCtrl,
+    volume_steps: u16,
+    spacing: VolumeStepSpacing,
+    volume: u16,
+    steps: i32,
+) -> u16 {
+    let volume_steps = volume_steps.max(1) as i32;
+    let db_range = volume_ctrl.db_range();
+    // Without a range there is no curve to follow, e.g. for fixed volume.
+    if spacing == VolumeStepSpacing::Linear || db_range <= 0.0 {
+        let step_size = VolumeCtrl::MAX_VOLUME as f64 / volume_steps as f64;
+        let current = (volume as f64 / step_size).round() as i32;
+        let step = (current + steps).clamp(0, volume_steps);
+        return (step as f64 * step_size).round() as u16;
+    }
+
+    let mapped = volume_ctrl.to_mapped(volume);
+    let position = if mapped > 0.0 {
+        (ratio_to_db(mapped) / db_range + 1.0).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let current = (position * volume_steps as f64).round() as i32;
+    let step = (current + steps).clamp(0, volume_steps);
+
+    let mapped = if step > 0 {
+        db_to_ratio(db_range * (step as f64 / volume_steps as f64 - 1.0))
+    } else {
+        0.0
+    };
+    volume_ctrl.to_unmapped(mapped)
+}
+
+/// The step nearest to `volume`, see `stepped_volume`.
+pub fn quantized_volume(
+    volume_ctrl: VolumeCtrl,
+    volume_steps: u16,
+    spacing: VolumeStepSpacing,
+    volume: u16,
+) -> u16 {
+    stepped_volume(volume_ctrl, volume_steps, spacing, volume, 0)
+}
+
+// The position of a track that started playing at `nominal_start_time` and advances `speed`
+// times as fast as the clock. Clock and position corrections can put `now_ms` before that,
+// which must not wrap around to a huge position.
+fn position_since(nominal_start_time: i64, now_ms: i64, speed: f32) -> u32 {
+    ((now_ms - nominal_start_time) as f64 * speed as f64).clamp(0.0, u32::MAX as f64) as u32
+}
+
+// Positions before the start of the track are the start. The player does the same for those
+// past its end, and moves on to what comes next.
+fn offset_position(position_ms: u32, delta_ms: i64) -> u32 {
+    (position_ms as i64)
+        .saturating_add(delta_ms)
+        .clamp(0, u32::MAX as i64) as u32
+}
+
 type BoxedFuture<T> = Pin<Box<dyn FusedFuture<Output = T> + Send>>;
 type BoxedStream<T> = Pin<Box<dyn FusedStream<Item = T> + Send>>;
 
@@ -52,6 +135,8 @@ struct SpircTask {
 
     ident: String,
     device: DeviceState,
+    // The type the device is shown as, as it is in the capabilities of `device`.
+    device_type: DeviceType,
     state: State,
     play_request_id: Option<u64>,
     play_status: SpircPlayStatus,
@@ -59,13 +144,231 @@ struct SpircTask {
     subscription: BoxedStream<Frame>,
     sender: MercurySender,
     commands: Option<mpsc::UnboundedReceiver<SpircCommand>>,
+    shared: Arc<SpircShared>,
     player_events: Option<PlayerEventChannel>,
+    // Volume changes made outside of librespot, from mixers that can observe them.
+    external_volume: Option<mpsc::UnboundedReceiver<u16>>,
+    user_attributes: mpsc::UnboundedReceiver<()>,
 
     shutdown: bool,
     session: Session,
     context_fut: BoxedFuture<Result<serde_json::Value, MercuryError>>,
     autoplay_fut: BoxedFuture<Result<String, MercuryError>>,
+    context_metadata_fut: BoxedFuture<HashMap<SpotifyId, Result<Track, MetadataError>>>,
     context: Option<StationContext>,
+    // A `Spirc::load_context` waiting for its context to resolve.
+    pending_load: Option<LoadContext>,
+    load_context_fut: BoxedFuture<Result<ResolvedContext, SpircError>>,
+    playback_speed: f32,
+    // The player paused because of a sink error, and resumes on its own once the sink is back.
+    paused_by_sink_error: bool,
+    // Mirrors the player's stop after current track setting, from its events.
+    stop_after_current: bool,
+    // Whether the first load from a controller is done paused, see `autoplay_on_connect`.
+    awaiting_play: bool,
+    // The order of the tracks before they were shuffled here, to go back to when shuffle
+    // is turned off.
+    unshuffled_tracks: Option<Vec<TrackRef>>,
+    // Repeat is on for the current track rather than the context, see `RepeatMode`.
+    repeat_track: bool,
+    // Overrides whether `EndOfContextAction::Autoplay` is used, see `Spirc::set_autoplay`.
+    autoplay: Option<bool>,
+    // Decides which autoplay tracks may play, see `Spirc::set_autoplay_veto`.
+    autoplay_veto: Option<AutoplayVeto>,
+    // The tracks that autoplay added, and those of them that were vetoed.
+    autoplay_tracks: HashSet<SpotifyId>,
+    vetoed_tracks: HashSet<SpotifyId>,
+    // Skip explicit tracks, see `ConnectConfig::filter_explicit_content`.
+    filter_explicit_content: bool,
+    // The account has explicit content filtered, as it said last.
+    account_filters_explicit: bool,
+    // The tracks that are known to be explicit, from their metadata.
+    explicit_tracks: HashSet<SpotifyId>,
+    // What the context that is loaded allows, as far as it said.
+    restrictions: Restrictions,
+    // What the track that was loaded last is played from, as it was reported.
+    playing_context: Option<PlayingContext>,
+    devices: Devices,
+    // A `Spirc::transfer_playback_to` waiting for the device to take over.
+    pending_transfer: Option<(String, oneshot::Sender<Result<(), SpircError>>)>,
+    transfer_timeout: BoxedFuture<()>,
+    // The volume was stepped up or down, and is announced once the steps settle.
+    stepped_volume: bool,
+    volume_step_timeout: BoxedFuture<()>,
+}
+
+#[derive(Debug, Error)]
+pub enum SpircError {
+    #[error("Spirc has shut down")]
+    Shutdown,
+    #[error("Nothing is loaded on this device")]
+    NothingLoaded,
+    #[error("{0} is not a playlist, album, artist, show, track or episode uri")]
+    InvalidUri(String),
+    #[error("{0} could not be resolved or has nothing to play")]
+    Unavailable(String),
+    #[error("The context has no track at index {0}")]
+    InvalidStartIndex(usize),
+    #[error("A later request of the same kind took the place of this one")]
+    Superseded,
+    #[error("{0} is not a track or episode uri")]
+    NotAnItem(String),
+    #[error("There is no queued track at index {0}")]
+    InvalidQueueIndex(usize),
+    #[error("Nothing is playing on any device")]
+    NothingPlaying,
+    #[error("There is no device {0}")]
+    UnknownDevice(String),
+    #[error("Device {0} did not take over playback")]
+    TransferTimedOut(String),
+    #[error("The context that is playing does not allow {0}")]
+    Restricted(RestrictedAction),
+    #[error(
+        "{0:?} is empty, longer than {} characters or has control characters",
+        MAX_DEVICE_NAME_LENGTH
+    )]
+    InvalidDeviceName(String),
+}
+
+/// The longest device name other devices show in full.
+pub const MAX_DEVICE_NAME_LENGTH: usize = 64;
+
+// The name with the whitespace around it trimmed, if it is one other devices can show.
+fn valid_device_name(name: &str) -> Result<String, SpircError> {
+    let trimmed = name.trim();
+    if trimmed.is_empty()
+        || trimmed.chars().count() > MAX_DEVICE_NAME_LENGTH
+        || trimmed.chars().any(char::is_control)
+    {
+        return Err(SpircError::InvalidDeviceName(name.to_owned()));
+    }
+
+    Ok(trimmed.to_owned())
+}
+
+/// An action that a context can disallow, see `Restrictions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestrictedAction {
+    SkipNext,
+    SkipPrev,
+    Seek,
+    Repeat,
+    Shuffle,
+}
+
+impl RestrictedAction {
+    fn is_allowed(self, restrictions: &Restrictions) -> bool {
+        match self {
+            Self::SkipNext => restrictions.can_skip_next,
+            Self::SkipPrev => restrictions.can_skip_prev,
+            Self::Seek => restrictions.can_seek,
+            Self::Repeat => restrictions.can_repeat,
+            Self::Shuffle => restrictions.can_shuffle,
+        }
+    }
+}
+
+impl fmt::Display for RestrictedAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::SkipNext => "skipping to the next track",
+            Self::SkipPrev => "skipping to the previous track",
+            Self::Seek => "seeking",
+            Self::Repeat => "changing repeat",
+            Self::Shuffle => "changing shuffle",
+        })
+    }
+}
+
+// The kinds of context `Spirc::load_context` can play.
+const LOADABLE_CONTEXTS: [&str; 6] = ["playlist", "album", "artist", "show", "track", "episode"];
+
+// The kind of context `uri` is, if it can be loaded. Playlists may also have the older
+// `spotify:user:<name>:playlist:<id>` form.
+fn loadable_context_kind(uri: &str) -> Option<&str> {
+    let parts: Vec<&str> = uri.split(':').collect();
+    let (kind, id) = match parts.as_slice() {
+        ["spotify", "user", _, "playlist", id] => ("playlist", *id),
+        ["spotify", kind, id] => (*kind, *id),
+        _ => return None,
+    };
+
+    let valid_id = id.len() == 22 && SpotifyId::from_base62(id).is_ok();
+    if valid_id && LOADABLE_CONTEXTS.contains(&kind) {
+        Some(kind)
+    } else {
+        None
+    }
+}
+
+/// Whether `uri` is a playlist, album, artist, show, track or episode that
+/// `Spirc::load_context` can play, to check a uri before there is a `Spirc` to load it with.
+pub fn is_loadable_context(uri: &str) -> bool {
+    loadable_context_kind(uri).is_some()
+}
+
+// What `Spirc` needs to know about the task to reject commands up front, and what the task
+// leaves behind for `Spirc::snapshot`.
+#[derive(Default)]
+struct SpircShared {
+    is_active: AtomicBool,
+    has_tracks: AtomicBool,
+    // Whether another device is active, with something loaded.
+    remote_has_tracks: AtomicBool,
+    queue_len: AtomicUsize,
+    restrictions: Mutex<Restrictions>,
+    // What the task left behind when it ended without being shut down.
+    snapshot: Mutex<Option<SpircSnapshot>>,
+}
+
+/// The state of a `Spirc` that ended because its session was lost, to carry it over into the
+/// one that replaces it with `Spirc::restore`.
+#[derive(Debug, Clone)]
+pub struct SpircSnapshot {
+    state: State,
+    repeat: RepeatMode,
+    volume: u16,
+    was_active: bool,
+    restrictions: Restrictions,
+}
+
+/// Decides whether a track that autoplay picked may play. Those it returns `false` for are
+/// skipped, and reported with `TrackFilterReason::AutoplayVeto`.
+pub type AutoplayVeto = Box<dyn Fn(&Track) -> bool + Send>;
+
+/// What the track that is playing is played from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayingContext {
+    /// The context that was loaded, or the station that autoplay went on with.
+    pub uri: String,
+    /// The name of the context, if it is known.
+    pub name: Option<String>,
+    pub kind: ContextKind,
+}
+
+/// The context and queue of a `Spirc`, all as they were at one moment, from
+/// `Spirc::dump_context`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContextDump {
+    /// While another device is active, the rest is what it last reported.
+    pub active: bool,
+    pub context_uri: String,
+    pub context_name: Option<String>,
+    /// Every track that is loaded, in the order they play in, queued ones included.
+    pub tracks: Vec<String>,
+    /// Where the playing track is in `tracks`.
+    pub index: u32,
+    pub position_ms: u32,
+    pub shuffle: bool,
+    pub repeat: String,
+    /// The queued tracks, which play after the current one.
+    pub queue: Vec<String>,
+}
+
+impl ContextDump {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("ContextDump is always valid JSON")
+    }
 }
 
 pub enum SpircCommand {
@@ -74,24 +377,88 @@ pub enum SpircCommand {
     Pause,
     Prev,
     Next,
+    Seek(u32),
+    SeekRelative(i64),
+    SetVolume(u16),
+    SetVolumeStep(u16),
     VolumeUp,
     VolumeDown,
     Shutdown,
     Shuffle,
+    SetPlaybackSpeed(f32),
+    SetNormalisationBypass(bool),
+    SetStopAfterCurrent(bool),
+    SetSleepTimer(Duration),
+    ExtendSleepTimer(Duration),
+    CancelSleepTimer,
+    SetVolumeCtrl(VolumeCtrl),
+    SetMute(bool),
+    ToggleMute,
+    LoadContext(LoadContext),
+    AddToQueue(String),
+    RemoveFromQueue(usize),
+    ClearQueue,
+    SetShuffle(bool),
+    SetRepeat(RepeatMode),
+    SetAutoplay(bool),
+    SetFilterExplicitContent(bool),
+    SetDeviceName(String),
+    SetDeviceType(DeviceType),
+    SetAutoplayVeto(Option<AutoplayVeto>),
+    ListDevices(oneshot::Sender<Vec<ConnectDevice>>),
+    GetPlayingContext(oneshot::Sender<Option<PlayingContext>>),
+    DumpContext(oneshot::Sender<ContextDump>),
+    TransferHere(oneshot::Sender<Result<(), SpircError>>),
+    TransferTo(String, oneshot::Sender<Result<(), SpircError>>),
+    Restore(Box<SpircSnapshot>),
+}
+
+pub struct LoadContext {
+    uri: String,
+    start_index: Option<usize>,
+    start_position_ms: u32,
+    start_playing: bool,
+    shuffle: Option<bool>,
+    reply: oneshot::Sender<Result<(), SpircError>>,
 }
 
 struct SpircTaskConfig {
-    autoplay: bool,
+    end_of_context: EndOfContextAction,
+    resume_episodes: bool,
+    volume_steps: u16,
+    volume_step_spacing: VolumeStepSpacing,
+    quantize_volume: bool,
+    hidden: bool,
+    allowed_controllers: Vec<String>,
+    filter_current_track: bool,
+    autoplay: AutoplayMode,
 }
 
 const CONTEXT_TRACKS_HISTORY: usize = 10;
 const CONTEXT_FETCH_THRESHOLD: u32 = 5;
 
-const VOLUME_STEPS: i64 = 64;
-const VOLUME_STEP_SIZE: u16 = 1024; // (u16::MAX + 1) / VOLUME_STEPS
+// Past this many, the tracks that are remembered to be added by autoplay, vetoed or explicit
+// are forgotten unless they are still in the tracks or the station, so that autoplay that
+// goes on for long doesn't pile them up.
+const MAX_REMEMBERED_TRACKS: usize = 1000;
+
+// How often the position of a playing episode is sent, so that other devices can resume it.
+const EPISODE_PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+// Other devices advance the position at normal speed, so it is corrected more often
+// while playing faster or slower.
+const PLAYBACK_SPEED_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long another device has to take over in `Spirc::transfer_playback_to`.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long volume up and down have to stop coming in before the volume they left is
+// announced, so that a run of presses only gives a single event.
+const VOLUME_STEP_SETTLE_TIME: Duration = Duration::from_millis(500);
 
 pub struct Spirc {
     commands: mpsc::UnboundedSender<SpircCommand>,
+    shared: Arc<SpircShared>,
 }
 
 fn initial_state() -> State {
@@ -165,7 +532,7 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 {
                     let repeated = msg.mut_intValue();
                     if config.has_volume_ctrl {
-                        repeated.push(VOLUME_STEPS)
+                        repeated.push(config.volume_steps.max(1) as i64)
                     } else {
                         repeated.push(0)
                     }
@@ -215,7 +582,77 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
     }
 }
 
-fn url_encode(bytes: impl AsRef<[u8]>) -> String {
+// Puts `shuffled` back in the `original` order. The current track and the `queue` after
+// it stay in place, so playback goes on with the queue and then with what followed the
+// current track in the original order. Returns the tracks and the new current index.
+// Queued tracks in `original` are left out, the queue is the one that was edited since.
+fn unshuffle(
+    mut original: Vec<TrackRef>,
+    shuffled: &[TrackRef],
+    current_index: usize,
+    queue: Range<usize>,
+) -> Option<(Vec<TrackRef>, usize)> {
+    let same_track =
+        |a: &TrackRef, b: &TrackRef| a.get_gid() == b.get_gid() && a.get_uri() == b.get_uri();
+
+    let current = shuffled.get(current_index)?.clone();
+    original.retain(|track_ref| !track_ref.get_queued());
+
+    // A queued track that is playing goes after the track that played before it.
+    let anchor = shuffled[..=current_index]
+        .iter()
+        .rev()
+        .find(|track_ref| !track_ref.get_queued());
+    let index = match anchor.and_then(|anchor| original.iter().position(|t| same_track(t, anchor)))
+    {
+        Some(index) if current.get_queued() => {
+            original.insert(index + 1, current);
+            index + 1
+        }
+        Some(index) => index,
+        None if current.get_queued() => {
+            original.insert(0, current);
+            0
+        }
+        None => return None,
+    };
+
+    let after_current = index + 1;
+    original.splice(
+        after_current..after_current,
+        shuffled[queue].iter().cloned(),
+    );
+    Some((original, index))
+}
+
+// The queued tracks, which follow the current one.
+fn queue_range(tracks: &[TrackRef], current_index: usize) -> Range<usize> {
+    let start = (current_index + 1).min(tracks.len());
+    let len = tracks[start..]
+        .iter()
+        .take_while(|track_ref| track_ref.get_queued())
+        .count();
+    start..start + len
+}
+
+// Puts the current track first, followed by the queue, and shuffles the rest.
+fn shuffle_tracks(tracks: &mut Vec<TrackRef>, current_index: usize) {
+    let queued: Vec<TrackRef> = tracks.drain(queue_range(tracks, current_index)).collect();
+    tracks.swap(0, current_index);
+    if let Some((_, rest)) = tracks.split_first_mut() {
+        let mut rng = rand::thread_rng();
+        rest.shuffle(&mut rng);
+    }
+    let after_current = 1.min(tracks.len());
+    tracks.splice(after_current..after_current, queued);
+}
+
+// Stations come with tracks of their own to go on with, instead of autoplay.
+fn is_station_uri(uri: &str) -> bool {
+    uri.starts_with("spotify:station:") || uri.starts_with("spotify:dailymix:")
+}
+
+pub(crate) fn url_encode(bytes: impl AsRef<[u8]>) -> String {
     form_urlencoded::byte_serialize(bytes.as_ref()).collect()
 }
 
@@ -250,9 +687,21 @@ impl Spirc {
         let sender = session.mercury().sender(uri);
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(SpircShared::default());
 
         let initial_volume = config.initial_volume;
+        let awaiting_play = !config.autoplay_on_connect;
+        let filter_explicit_content = config.filter_explicit_content;
+        let device_type = config.device_type;
         let task_config = SpircTaskConfig {
+            end_of_context: config.end_of_context,
+            resume_episodes: config.resume_episodes,
+            volume_steps: config.volume_steps.max(1),
+            volume_step_spacing: config.volume_step_spacing,
+            quantize_volume: config.quantize_volume,
+            hidden: config.hidden,
+            allowed_controllers: config.allowed_controllers.clone(),
+            filter_current_track: config.filter_current_track,
             autoplay: config.autoplay,
         };
 
@@ -260,6 +709,11 @@ impl Spirc {
 
         let player_events = player.get_player_event_channel();
 
+        let (volume_tx, volume_rx) = mpsc::unbounded_channel();
+        mixer.set_volume_change_callback(Box::new(move |volume| {
+            let _ = volume_tx.send(volume);
+        }));
+
         let mut task = SpircTask {
             player,
             mixer,
@@ -270,6 +724,7 @@ impl Spirc {
             ident,
 
             device,
+            device_type,
             state: initial_state(),
             play_request_id: None,
             play_status: SpircPlayStatus::Stopped,
@@ -277,14 +732,40 @@ impl Spirc {
             subscription,
             sender,
             commands: Some(cmd_rx),
+            shared: shared.clone(),
             player_events: Some(player_events),
+            external_volume: Some(volume_rx),
+            user_attributes: session.subscribe_user_attributes(),
 
             shutdown: false,
             session,
 
             context_fut: Box::pin(future::pending()),
             autoplay_fut: Box::pin(future::pending()),
+            context_metadata_fut: Box::pin(future::pending()),
             context: None,
+            pending_load: None,
+            load_context_fut: Box::pin(future::pending()),
+            playback_speed: 1.0,
+            paused_by_sink_error: false,
+            stop_after_current: false,
+            awaiting_play,
+            unshuffled_tracks: None,
+            repeat_track: false,
+            autoplay: None,
+            autoplay_veto: None,
+            autoplay_tracks: HashSet::new(),
+            vetoed_tracks: HashSet::new(),
+            filter_explicit_content,
+            account_filters_explicit: false,
+            explicit_tracks: HashSet::new(),
+            devices: Devices::default(),
+            restrictions: Restrictions::default(),
+            playing_context: None,
+            pending_transfer: None,
+            transfer_timeout: Box::pin(future::pending()),
+            stepped_volume: false,
+            volume_step_timeout: Box::pin(future::pending()),
         };
 
         if let Some(volume) = initial_volume {
@@ -294,27 +775,116 @@ impl Spirc {
             task.set_volume(current_volume);
         }
 
-        let spirc = Spirc { commands: cmd_tx };
+        let spirc = Spirc {
+            commands: cmd_tx,
+            shared,
+        };
 
         task.hello();
 
         (spirc, task.run())
     }
 
-    pub fn play(&self) {
-        let _ = self.commands.send(SpircCommand::Play);
+    fn send_command(&self, command: SpircCommand) -> Result<(), SpircError> {
+        self.commands
+            .send(command)
+            .map_err(|_| SpircError::Shutdown)
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> SpircCommand,
+    ) -> Result<T, SpircError> {
+        let (reply, result) = oneshot::channel();
+        self.send_command(command(reply))?;
+        result.await.map_err(|_| SpircError::Shutdown)
+    }
+
+    // Commands that act on what is loaded, here while this device is active, or on the
+    // active device otherwise, where they are sent.
+    fn send_playback_command(&self, command: SpircCommand) -> Result<(), SpircError> {
+        let has_tracks = if self.shared.is_active.load(Ordering::Relaxed) {
+            &self.shared.has_tracks
+        } else {
+            &self.shared.remote_has_tracks
+        };
+        if !has_tracks.load(Ordering::Relaxed) {
+            return Err(SpircError::NothingLoaded);
+        }
+
+        self.send_command(command)
+    }
+
+    // What the context allows is only known for what this device plays, and other devices
+    // are left to decide for themselves.
+    fn check_allowed(&self, action: RestrictedAction) -> Result<(), SpircError> {
+        if self.shared.is_active.load(Ordering::Relaxed)
+            && !action.is_allowed(&self.shared.restrictions.lock().unwrap())
+        {
+            return Err(SpircError::Restricted(action));
+        }
+
+        Ok(())
+    }
+
+    // The queue is kept by the active device, and other devices can't be asked to change it.
+    fn check_queue(&self) -> Result<(), SpircError> {
+        if !self.shared.is_active.load(Ordering::Relaxed)
+            || !self.shared.has_tracks.load(Ordering::Relaxed)
+        {
+            return Err(SpircError::NothingLoaded);
+        }
+
+        Ok(())
+    }
+
+    /// Resumes playback. While another device is active, it is asked to resume. Fails with
+    /// `SpircError::NothingLoaded` if nothing is loaded on the active device, or if no device
+    /// is active.
+    /// All of these update the state that other devices see.
+    pub fn play(&self) -> Result<(), SpircError> {
+        self.send_playback_command(SpircCommand::Play)
+    }
+    /// Toggles between playing and paused, like `play`.
+    pub fn play_pause(&self) -> Result<(), SpircError> {
+        self.send_playback_command(SpircCommand::PlayPause)
+    }
+    /// Pauses playback, like `play`.
+    pub fn pause(&self) -> Result<(), SpircError> {
+        self.send_playback_command(SpircCommand::Pause)
+    }
+    /// Goes back to the start of the track or the previous track, like `play`. Fails with
+    /// `SpircError::Restricted` if this device is active and the context doesn't allow it.
+    pub fn prev(&self) -> Result<(), SpircError> {
+        self.check_allowed(RestrictedAction::SkipPrev)?;
+        self.send_playback_command(SpircCommand::Prev)
     }
-    pub fn play_pause(&self) {
-        let _ = self.commands.send(SpircCommand::PlayPause);
+    /// Skips to the next track, like `prev`.
+    pub fn next(&self) -> Result<(), SpircError> {
+        self.check_allowed(RestrictedAction::SkipNext)?;
+        self.send_playback_command(SpircCommand::Next)
     }
-    pub fn pause(&self) {
-        let _ = self.commands.send(SpircCommand::Pause);
+    /// Seeks in the current track, like `prev`.
+    pub fn seek(&self, position_ms: u32) -> Result<(), SpircError> {
+        self.check_allowed(RestrictedAction::Seek)?;
+        self.send_playback_command(SpircCommand::Seek(position_ms))
     }
-    pub fn prev(&self) {
-        let _ = self.commands.send(SpircCommand::Prev);
+    /// Seeks `delta_ms` forwards, or backwards when it is negative, from the position that
+    /// is playing at the moment the command is handled. Seeking before the start goes to
+    /// the start, and seeking past the end moves on as if the track had ended. The player
+    /// reports the position it went to with `PlayerEvent::Seeked`.
+    pub fn seek_relative(&self, delta_ms: i64) -> Result<(), SpircError> {
+        self.check_allowed(RestrictedAction::Seek)?;
+        self.send_playback_command(SpircCommand::SeekRelative(delta_ms))
     }
-    pub fn next(&self) {
-        let _ = self.commands.send(SpircCommand::Next);
+    /// Sets the volume of this device, which works whether or not anything is loaded.
+    pub fn set_volume(&self, volume: u16) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::SetVolume(volume))
+    }
+    /// Sets the volume to the given step of `ConnectConfig::volume_steps`, where 0 is silent
+    /// and `volume_steps` is the maximum, like for a remote with a button per step.
+    pub fn set_volume_step(&self, step: u16) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::SetVolumeStep(step))
     }
     pub fn volume_up(&self) {
         let _ = self.commands.send(SpircCommand::VolumeUp);
@@ -322,19 +892,244 @@ impl Spirc {
     pub fn volume_down(&self) {
         let _ = self.commands.send(SpircCommand::VolumeDown);
     }
-    pub fn shutdown(&self) {
-        let _ = self.commands.send(SpircCommand::Shutdown);
+    /// Says goodbye to the other devices, stops playback and ends the task. Fails with
+    /// `SpircError::Shutdown` if the task has ended already.
+    pub fn shutdown(&self) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::Shutdown)
+    }
+    /// What was loaded, the volume and shuffle and repeat when the task ended, if it ended
+    /// because the session was lost rather than by `Spirc::shutdown`.
+    pub fn snapshot(&self) -> Option<SpircSnapshot> {
+        self.shared.snapshot.lock().unwrap().take()
+    }
+    /// Picks up where the `Spirc` the snapshot was taken from left off. What was loaded is
+    /// loaded again paused at the position it was at, if that device was the active one.
+    pub fn restore(&self, snapshot: SpircSnapshot) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::Restore(Box::new(snapshot)))
     }
     pub fn shuffle(&self) {
         let _ = self.commands.send(SpircCommand::Shuffle);
     }
+    pub fn set_playback_speed(&self, speed: f32) {
+        let _ = self.commands.send(SpircCommand::SetPlaybackSpeed(speed));
+    }
+    /// Plays at unity gain instead of normalising, or normalises again, see
+    /// `Player::set_normalisation_bypass`.
+    pub fn set_normalisation_bypass(&self, bypass: bool) {
+        let _ = self
+            .commands
+            .send(SpircCommand::SetNormalisationBypass(bypass));
+    }
+    /// Once the current track ends, pause at the start of the next one instead of playing it.
+    /// This takes precedence over repeat, and disarms itself when it kicks in.
+    pub fn set_stop_after_current(&self, armed: bool) {
+        let _ = self.commands.send(SpircCommand::SetStopAfterCurrent(armed));
+    }
+    /// Fades out and pauses playback once `duration` has passed.
+    pub fn set_sleep_timer(&self, duration: Duration) {
+        let _ = self.commands.send(SpircCommand::SetSleepTimer(duration));
+    }
+    pub fn extend_sleep_timer(&self, duration: Duration) {
+        let _ = self.commands.send(SpircCommand::ExtendSleepTimer(duration));
+    }
+    pub fn cancel_sleep_timer(&self) {
+        let _ = self.commands.send(SpircCommand::CancelSleepTimer);
+    }
+    /// Switches the mixer to another volume control curve. The loudness stays the same, so
+    /// the volume is re-mapped to where it is on the new curve.
+    pub fn set_volume_ctrl(&self, volume_ctrl: VolumeCtrl) {
+        let _ = self.commands.send(SpircCommand::SetVolumeCtrl(volume_ctrl));
+    }
+    /// Mutes or unmutes the mixer, see `Mixer::set_mute`. Other devices are shown a volume
+    /// of 0 while muted, and the volume from before once unmuted. Setting the volume also
+    /// unmutes.
+    pub fn set_mute(&self, mute: bool) {
+        let _ = self.commands.send(SpircCommand::SetMute(mute));
+    }
+    pub fn mute(&self) {
+        self.set_mute(true);
+    }
+    pub fn unmute(&self) {
+        self.set_mute(false);
+    }
+    pub fn toggle_mute(&self) {
+        let _ = self.commands.send(SpircCommand::ToggleMute);
+    }
+    /// Replaces what is playing with the playlist, album, artist, show, track or episode at
+    /// `uri`, and makes this the active device. Playback starts at `start_index`, or at the
+    /// start of the context, and is paused unless `start_playing`. Without `shuffle` the
+    /// current shuffle setting is kept, and a shuffled context without a `start_index`
+    /// starts at a random track.
+    ///
+    /// Completes once the context is resolved and loaded. If it can't be, nothing changes.
+    pub async fn load_context(
+        &self,
+        uri: &str,
+        start_index: Option<usize>,
+        start_playing: bool,
+        shuffle: Option<bool>,
+    ) -> Result<(), SpircError> {
+        self.load_context_at(uri, start_index, 0, start_playing, shuffle)
+            .await
+    }
+
+    /// Like `load_context`, but starts `start_position_ms` into the first track. The load is
+    /// asked for right away, so the future doesn't need to borrow the `Spirc`.
+    pub fn load_context_at(
+        &self,
+        uri: &str,
+        start_index: Option<usize>,
+        start_position_ms: u32,
+        start_playing: bool,
+        shuffle: Option<bool>,
+    ) -> impl Future<Output = Result<(), SpircError>> + Send + 'static {
+        let result = if loadable_context_kind(uri).is_none() {
+            Err(SpircError::InvalidUri(uri.to_owned()))
+        } else {
+            let (reply, result) = oneshot::channel();
+            self.send_command(SpircCommand::LoadContext(LoadContext {
+                uri: uri.to_owned(),
+                start_index,
+                start_position_ms,
+                start_playing,
+                shuffle,
+                reply,
+            }))
+            .map(|_| result)
+        };
+
+        async move { result?.await.map_err(|_| SpircError::Shutdown)? }
+    }
+    /// Adds the track or episode at `uri` to the queue, after what is queued already.
+    /// Queued tracks play next, before the rest of the context and before autoplay.
+    /// Fails with `SpircError::NotAnItem` for other uris, and with
+    /// `SpircError::NothingLoaded` unless this device is active and has something loaded.
+    pub fn add_to_queue(&self, uri: &str) -> Result<(), SpircError> {
+        match SpotifyId::from_uri(uri) {
+            Ok(id) if id.audio_type != SpotifyAudioType::NonPlayable => (),
+            _ => return Err(SpircError::NotAnItem(uri.to_owned())),
+        }
+
+        self.check_queue()?;
+        self.send_command(SpircCommand::AddToQueue(uri.to_owned()))
+    }
+    /// Removes the queued track at `index`, where 0 is the one that plays next, like
+    /// `add_to_queue`.
+    pub fn remove_from_queue(&self, index: usize) -> Result<(), SpircError> {
+        self.check_queue()?;
+        if index >= self.shared.queue_len.load(Ordering::Relaxed) {
+            return Err(SpircError::InvalidQueueIndex(index));
+        }
+
+        self.send_command(SpircCommand::RemoveFromQueue(index))
+    }
+    /// Removes all queued tracks, like `add_to_queue`.
+    pub fn clear_queue(&self) -> Result<(), SpircError> {
+        self.check_queue()?;
+        self.send_command(SpircCommand::ClearQueue)
+    }
+    /// Turns shuffle on or off. Turning it off puts the tracks that were shuffled here back
+    /// in their original order, with the current track playing on. While another device is
+    /// active, it is asked to do it. Fails with `SpircError::Restricted` if this device is
+    /// active and the context doesn't allow it.
+    pub fn set_shuffle(&self, shuffle: bool) -> Result<(), SpircError> {
+        self.check_allowed(RestrictedAction::Shuffle)?;
+        self.send_command(SpircCommand::SetShuffle(shuffle))
+    }
+    /// Sets what plays again once it has ended, like `set_shuffle`. Other devices can only
+    /// be asked to repeat the context.
+    pub fn set_repeat(&self, repeat: RepeatMode) -> Result<(), SpircError> {
+        self.check_allowed(RestrictedAction::Repeat)?;
+        self.send_command(SpircCommand::SetRepeat(repeat))
+    }
+    /// Turns playing similar tracks at the end of a context on or off, overriding the
+    /// `EndOfContextAction` this device was started with. Off stops at the end instead of
+    /// autoplaying.
+    pub fn set_autoplay(&self, autoplay: bool) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::SetAutoplay(autoplay))
+    }
+    /// Turns skipping explicit tracks on or off. Explicit tracks are skipped anyway if the
+    /// account has explicit content filtered. Turning it on while an explicit track is
+    /// playing skips that one too if `ConnectConfig::filter_current_track` is set.
+    pub fn set_filter_explicit_content(&self, filter: bool) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::SetFilterExplicitContent(filter))
+    }
+    /// Changes the name other devices show for this one. Fails with
+    /// `SpircError::InvalidDeviceName` if it is empty, longer than `MAX_DEVICE_NAME_LENGTH`
+    /// characters or has control characters in it. It is reported with
+    /// `PlayerEvent::DeviceInfoChanged`, for discovery to advertise the device under the new
+    /// name too.
+    pub fn set_device_name(&self, name: impl AsRef<str>) -> Result<(), SpircError> {
+        let name = valid_device_name(name.as_ref())?;
+        self.send_command(SpircCommand::SetDeviceName(name))
+    }
+    /// Sets what decides which of the tracks that autoplay picks may play, or lets all of
+    /// them play again with `None`. It is asked about each track once its metadata is
+    /// fetched, which happens when autoplay resolves the tracks to go on with.
+    pub fn set_autoplay_veto(&self, veto: Option<AutoplayVeto>) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::SetAutoplayVeto(veto))
+    }
+    /// Changes the type other devices show this one as, which picks its icon. It is reported
+    /// with `PlayerEvent::DeviceInfoChanged` like a new name.
+    pub fn set_device_type(&self, device_type: DeviceType) -> Result<(), SpircError> {
+        self.send_command(SpircCommand::SetDeviceType(device_type))
+    }
+    /// The other devices on the account, as far as they have been seen since startup.
+    pub async fn list_devices(&self) -> Result<Vec<ConnectDevice>, SpircError> {
+        self.request(SpircCommand::ListDevices).await
+    }
+    /// What is playing is played from, on this device or the one that is active. Only this
+    /// device knows when a track comes from the queue or autoplay.
+    pub async fn playing_context(&self) -> Result<Option<PlayingContext>, SpircError> {
+        self.request(SpircCommand::GetPlayingContext).await
+    }
+    /// The context that is loaded, its tracks and the queue, as they are when the call gets
+    /// to the `Spirc`. Nothing changes in between, so they always agree with each other.
+    pub async fn dump_context(&self) -> Result<ContextDump, SpircError> {
+        self.request(SpircCommand::DumpContext).await
+    }
+    /// Takes playback over from the active device, at the position it is at. Fails with
+    /// `SpircError::NothingPlaying` if no other device is active.
+    pub async fn transfer_playback_here(&self) -> Result<(), SpircError> {
+        self.request(SpircCommand::TransferHere).await?
+    }
+    /// Hands playback over to the device `ident` from `list_devices`, from this device or
+    /// from whichever is active. Completes once the device has taken over, and fails with
+    /// `SpircError::TransferTimedOut` if it doesn't.
+    pub async fn transfer_playback_to(&self, ident: &str) -> Result<(), SpircError> {
+        self.request(|reply| SpircCommand::TransferTo(ident.to_owned(), reply))
+            .await?
+    }
 }
 
 impl SpircTask {
     async fn run(mut self) {
+        let mut episode_progress = time::interval(EPISODE_PROGRESS_INTERVAL);
+        episode_progress.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut speed_progress = time::interval(PLAYBACK_SPEED_PROGRESS_INTERVAL);
+        speed_progress.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         while !self.session.is_invalid() && !self.shutdown {
+            self.shared
+                .is_active
+                .store(self.device.get_is_active(), Ordering::Relaxed);
+            self.shared
+                .has_tracks
+                .store(!self.state.get_track().is_empty(), Ordering::Relaxed);
+            self.shared.remote_has_tracks.store(
+                matches!(self.devices.active_state(), Some(state) if !state.get_track().is_empty()),
+                Ordering::Relaxed,
+            );
+            self.shared
+                .queue_len
+                .store(self.queue_range().len(), Ordering::Relaxed);
+
+            let playing_episode = self.is_playing_episode();
+            let playing_at_speed = self.playback_speed != 1.0
+                && matches!(self.play_status, SpircPlayStatus::Playing { .. });
             let commands = self.commands.as_mut();
             let player_events = self.player_events.as_mut();
+            let external_volume = self.external_volume.as_mut();
             tokio::select! {
                 frame = self.subscription.next() => match frame {
                     Some(frame) => self.handle_frame(frame),
@@ -349,10 +1144,26 @@ impl SpircTask {
                 event = async { player_events.unwrap().recv().await }, if player_events.is_some() => if let Some(event) = event {
                     self.handle_player_event(event)
                 },
+                volume = async { external_volume.unwrap().recv().await }, if external_volume.is_some() => match volume {
+                    Some(volume) => self.handle_mixer_volume(volume, VolumeSource::External),
+                    // The mixer can't observe external changes.
+                    None => self.external_volume = None,
+                },
+                Some(()) = self.user_attributes.recv() => self.update_account_filter(),
                 result = self.sender.flush(), if !self.sender.is_flushed() => if result.is_err() {
                     error!("Cannot flush spirc event sender.");
                     break;
                 },
+                _ = episode_progress.tick(), if playing_episode => {
+                    let position_ms = self.position();
+                    self.update_state_position(position_ms);
+                    self.notify(None, true);
+                },
+                _ = speed_progress.tick(), if playing_at_speed => {
+                    let position_ms = self.position();
+                    self.update_state_position(position_ms);
+                    self.notify(None, true);
+                },
                 context = &mut self.context_fut, if !self.context_fut.is_terminated() => {
                     match context {
                         Ok(value) => {
@@ -364,6 +1175,11 @@ impl SpircTask {
                                         context.tracks.len(),
                                         self.state.get_context_uri(),
                                     );
+                                    self.context_metadata_fut =
+                                        self.resolve_tracks_metadata(&context.tracks);
+                                    if let Some(ref restrictions) = context.restrictions {
+                                        self.set_restrictions(restrictions.to_restrictions());
+                                    }
                                     Some(context)
                                 }
                                 Err(e) => {
@@ -382,6 +1198,26 @@ impl SpircTask {
                         }
                     }
                 },
+                _ = &mut self.transfer_timeout, if !self.transfer_timeout.is_terminated() => {
+                    if let Some((ident, reply)) = self.pending_transfer.take() {
+                        warn!("Device {} did not take over playback", ident);
+                        let _ = reply.send(Err(SpircError::TransferTimedOut(ident)));
+                    }
+                },
+                _ = &mut self.volume_step_timeout, if !self.volume_step_timeout.is_terminated() => {
+                    self.handle_volume_step_settled();
+                },
+                tracks = &mut self.load_context_fut, if !self.load_context_fut.is_terminated() => {
+                    if let Some(load) = self.pending_load.take() {
+                        let result = tracks.and_then(|context| self.handle_context_resolved(&load, context));
+                        let _ = load.reply.send(result);
+                    }
+                },
+                metadata = &mut self.context_metadata_fut, if !self.context_metadata_fut.is_terminated() => {
+                    self.mark_unplayable_tracks(&metadata);
+                    self.note_vetoed_tracks(&metadata);
+                    self.note_explicit_tracks(&metadata);
+                },
                 autoplay = &mut self.autoplay_fut, if !self.autoplay_fut.is_terminated() => {
                     match autoplay {
                         Ok(autoplay_station_uri) => {
@@ -397,11 +1233,50 @@ impl SpircTask {
             }
         }
 
+        if !self.shutdown {
+            let snapshot = self.snapshot();
+            *self.shared.snapshot.lock().unwrap() = Some(snapshot);
+        }
+
         if self.sender.flush().await.is_err() {
             warn!("Cannot flush spirc event sender.");
         }
     }
 
+    fn snapshot(&mut self) -> SpircSnapshot {
+        let position_ms = self.position();
+        self.update_state_position(position_ms);
+        SpircSnapshot {
+            state: self.state.clone(),
+            repeat: self.repeat_mode(),
+            volume: self.device.get_volume() as u16,
+            was_active: self.device.get_is_active(),
+            restrictions: self.restrictions,
+        }
+    }
+
+    fn handle_restore(&mut self, snapshot: SpircSnapshot) {
+        self.set_volume(snapshot.volume);
+        self.set_repeat_mode(snapshot.repeat);
+
+        if !snapshot.was_active || snapshot.state.get_track().is_empty() {
+            self.set_shuffle_state(snapshot.state.get_shuffle());
+            return;
+        }
+
+        info!(
+            "Loading <{}> again at {} ms, from before the connection was lost",
+            snapshot.state.get_context_uri(),
+            snapshot.state.get_position_ms()
+        );
+        let mut frame = Frame::new();
+        frame.set_state(snapshot.state);
+        frame.mut_state().set_status(PlayStatus::kPlayStatusPause);
+        self.set_shuffle_state(frame.get_state().get_shuffle());
+        self.awaiting_play = false;
+        self.handle_load(&frame, snapshot.restrictions);
+    }
+
     fn now_ms(&mut self) -> i64 {
         let dur = match SystemTime::now().duration_since(UNIX_EPOCH) {
             Ok(dur) => dur,
@@ -411,6 +1286,11 @@ impl SpircTask {
         dur.as_millis() as i64 + 1000 * self.session.time_delta()
     }
 
+    // The time playback would have started at to be at `position_ms` now.
+    fn nominal_start_time(&mut self, position_ms: u32) -> i64 {
+        self.now_ms() - (position_ms as f64 / self.playback_speed as f64) as i64
+    }
+
     fn update_state_position(&mut self, position_ms: u32) {
         let now = self.now_ms();
         self.state.set_position_measured_at(now as u64);
@@ -460,6 +1340,47 @@ impl SpircTask {
                     CommandSender::new(self, MessageType::kMessageTypeNext).send();
                 }
             }
+            SpircCommand::Seek(position_ms) => {
+                if active {
+                    self.handle_seek(position_ms);
+                    self.notify(None, true);
+                } else {
+                    CommandSender::new(self, MessageType::kMessageTypeSeek)
+                        .position(position_ms)
+                        .send();
+                }
+            }
+            SpircCommand::SeekRelative(delta_ms) => {
+                if active {
+                    let position_ms = offset_position(self.position(), delta_ms);
+                    self.handle_seek(position_ms);
+                    self.notify(None, true);
+                } else if let Some(state) = self.remote_state() {
+                    let position_ms = offset_position(state.get_position_ms(), delta_ms);
+                    CommandSender::new(self, MessageType::kMessageTypeSeek)
+                        .position(position_ms)
+                        .send();
+                }
+            }
+            SpircCommand::SetVolume(volume) => {
+                self.set_volume(volume);
+                if active {
+                    self.notify(None, true);
+                }
+            }
+            SpircCommand::SetVolumeStep(step) => {
+                let volume = stepped_volume(
+                    self.mixer.volume_ctrl(),
+                    self.config.volume_steps,
+                    self.config.volume_step_spacing,
+                    0,
+                    step as i32,
+                );
+                self.set_volume(volume);
+                if active {
+                    self.notify(None, true);
+                }
+            }
             SpircCommand::VolumeUp => {
                 if active {
                     self.handle_volume_up();
@@ -477,7 +1398,12 @@ impl SpircTask {
                 }
             }
             SpircCommand::Shutdown => {
+                self.handle_volume_step_settled();
                 CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
+                if self.device.get_is_active() {
+                    self.device.set_is_active(false);
+                    self.player.emit_became_inactive_event();
+                }
                 self.player.stop();
                 self.shutdown = true;
                 if let Some(rx) = self.commands.as_mut() {
@@ -487,60 +1413,479 @@ impl SpircTask {
             SpircCommand::Shuffle => {
                 CommandSender::new(self, MessageType::kMessageTypeShuffle).send();
             }
+            SpircCommand::Restore(snapshot) => self.handle_restore(*snapshot),
+            SpircCommand::SetPlaybackSpeed(speed) => self.player.set_playback_speed(speed),
+            SpircCommand::SetNormalisationBypass(bypass) => {
+                self.player.set_normalisation_bypass(bypass)
+            }
+            SpircCommand::SetStopAfterCurrent(armed) => self.player.set_stop_after_current(armed),
+            SpircCommand::SetSleepTimer(duration) => self.player.set_sleep_timer(duration),
+            SpircCommand::ExtendSleepTimer(duration) => self.player.extend_sleep_timer(duration),
+            SpircCommand::CancelSleepTimer => self.player.cancel_sleep_timer(),
+            SpircCommand::SetVolumeCtrl(volume_ctrl) => self.handle_set_volume_ctrl(volume_ctrl),
+            SpircCommand::SetMute(mute) => self.handle_set_mute(mute),
+            SpircCommand::ToggleMute => self.handle_set_mute(!self.mixer.is_muted()),
+            SpircCommand::LoadContext(load) => self.handle_load_context(load),
+            SpircCommand::AddToQueue(uri) => self.handle_add_to_queue(uri),
+            SpircCommand::RemoveFromQueue(index) => self.handle_remove_from_queue(index),
+            SpircCommand::ClearQueue => self.handle_clear_queue(),
+            SpircCommand::SetShuffle(shuffle) => {
+                if active {
+                    self.handle_shuffle(shuffle);
+                    self.notify(None, true);
+                } else {
+                    let mut state = initial_state();
+                    state.set_shuffle(shuffle);
+                    CommandSender::new(self, MessageType::kMessageTypeShuffle)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::SetRepeat(repeat) => {
+                if active {
+                    self.set_repeat_mode(repeat);
+                    self.notify(None, true);
+                } else {
+                    let mut state = initial_state();
+                    state.set_repeat(repeat != RepeatMode::Off);
+                    CommandSender::new(self, MessageType::kMessageTypeRepeat)
+                        .state(state)
+                        .send();
+                }
+            }
+            SpircCommand::SetAutoplay(autoplay) => self.handle_set_autoplay(autoplay),
+            SpircCommand::SetFilterExplicitContent(filter) => {
+                self.handle_set_filter_explicit_content(filter)
+            }
+            SpircCommand::SetDeviceName(name) => {
+                if self.device.get_name() != name {
+                    info!("Renaming the device to {:?}", name);
+                    self.device.set_name(name);
+                    self.device_info_changed();
+                }
+            }
+            SpircCommand::SetAutoplayVeto(veto) => {
+                // What the previous veto rejected is no longer rejected by this one.
+                if !self.vetoed_tracks.is_empty() {
+                    self.vetoed_tracks.clear();
+                    self.update_preloaded_tracks();
+                }
+                self.autoplay_veto = veto;
+            }
+            SpircCommand::SetDeviceType(device_type) => {
+                if self.device_type != device_type {
+                    info!("Changing the device type to {}", device_type);
+                    self.device_type = device_type;
+                    for capability in self.device.mut_capabilities().iter_mut() {
+                        if capability.get_typ() == protocol::spirc::CapabilityType::kDeviceType {
+                            capability.set_intValue(vec![device_type as i64]);
+                        }
+                    }
+                    self.device_info_changed();
+                }
+            }
+            SpircCommand::ListDevices(reply) => {
+                let _ = reply.send(self.devices.list());
+            }
+            SpircCommand::GetPlayingContext(reply) => {
+                let _ = reply.send(self.current_playing_context());
+            }
+            SpircCommand::DumpContext(reply) => {
+                let _ = reply.send(self.dump_context());
+            }
+            SpircCommand::TransferHere(reply) => {
+                let _ = reply.send(self.handle_transfer_here());
+            }
+            SpircCommand::TransferTo(ident, reply) => self.handle_transfer_to(ident, reply),
         }
     }
 
-    fn handle_player_event(&mut self, event: PlayerEvent) {
-        // we only process events if the play_request_id matches. If it doesn't, it is
-        // an event that belongs to a previous track and only arrives now due to a race
-        // condition. In this case we have updated the state already and don't want to
-        // mess with it.
-        if let Some(play_request_id) = event.get_play_request_id() {
-            if Some(play_request_id) == self.play_request_id {
-                match event {
-                    PlayerEvent::EndOfTrack { .. } => self.handle_end_of_track(),
-                    PlayerEvent::Loading { .. } => self.notify(None, false),
-                    PlayerEvent::Playing { position_ms, .. } => {
-                        let new_nominal_start_time = self.now_ms() - position_ms as i64;
-                        match self.play_status {
-                            SpircPlayStatus::Playing {
-                                ref mut nominal_start_time,
-                                ..
-                            } => {
-                                if (*nominal_start_time - new_nominal_start_time).abs() > 100 {
-                                    *nominal_start_time = new_nominal_start_time;
-                                    self.update_state_position(position_ms);
-                                    self.notify(None, true);
-                                }
-                            }
-                            SpircPlayStatus::LoadingPlay { .. }
-                            | SpircPlayStatus::LoadingPause { .. } => {
-                                self.state.set_status(PlayStatus::kPlayStatusPlay);
-                                self.update_state_position(position_ms);
-                                self.notify(None, true);
-                                self.play_status = SpircPlayStatus::Playing {
-                                    nominal_start_time: new_nominal_start_time,
-                                    preloading_of_next_track_triggered: false,
-                                };
-                            }
-                            _ => (),
-                        };
-                        trace!("==> kPlayStatusPlay");
-                    }
-                    PlayerEvent::Paused {
-                        position_ms: new_position_ms,
-                        ..
-                    } => {
-                        match self.play_status {
-                            SpircPlayStatus::Paused {
-                                ref mut position_ms,
-                                ..
-                            } => {
-                                if *position_ms != new_position_ms {
-                                    *position_ms = new_position_ms;
-                                    self.update_state_position(new_position_ms);
-                                    self.notify(None, true);
-                                }
+    fn set_restrictions(&mut self, restrictions: Restrictions) {
+        if self.restrictions != restrictions {
+            debug!("The context allows {:?}", restrictions);
+        }
+        self.restrictions = restrictions;
+        *self.shared.restrictions.lock().unwrap() = restrictions;
+        self.player.set_restrictions(restrictions);
+    }
+
+    fn set_shuffle_state(&mut self, shuffle: bool) {
+        if self.state.get_shuffle() != shuffle {
+            self.state.set_shuffle(shuffle);
+            self.player.emit_shuffle_changed_event(shuffle);
+        }
+    }
+
+    fn handle_shuffle(&mut self, shuffle: bool) {
+        self.set_shuffle_state(shuffle);
+        if shuffle {
+            let current_index = self.state.get_playing_track_index() as usize;
+            let mut tracks = self.state.take_track().into_vec();
+            if current_index < tracks.len() {
+                // The queue is kept apart from the original order, so that editing it while
+                // shuffled doesn't have to change that as well.
+                if self.unshuffled_tracks.is_none() {
+                    let unqueued = tracks.iter().filter(|track_ref| !track_ref.get_queued());
+                    self.unshuffled_tracks = Some(unqueued.cloned().collect());
+                }
+                shuffle_tracks(&mut tracks, current_index);
+                self.state.set_playing_track_index(0);
+            }
+            self.state
+                .set_track(protobuf::RepeatedField::from_vec(tracks));
+        } else if let Some(tracks) = self.unshuffled_tracks.take() {
+            self.restore_track_order(tracks);
+        } else {
+            debug!(
+                "No order to go back to for <{}>",
+                self.state.get_context_uri()
+            );
+        }
+        self.update_preloaded_tracks();
+    }
+
+    fn restore_track_order(&mut self, tracks: Vec<TrackRef>) {
+        let current_index = self.state.get_playing_track_index() as usize;
+        match unshuffle(
+            tracks,
+            self.state.get_track(),
+            current_index,
+            self.queue_range(),
+        ) {
+            Some((tracks, index)) => {
+                self.state
+                    .set_track(protobuf::RepeatedField::from_vec(tracks));
+                self.state.set_playing_track_index(index as u32);
+            }
+            None => warn!("Current track is missing from the original order"),
+        }
+    }
+
+    fn repeat_mode(&self) -> RepeatMode {
+        match (self.state.get_repeat(), self.repeat_track) {
+            (false, _) => RepeatMode::Off,
+            (true, false) => RepeatMode::Context,
+            (true, true) => RepeatMode::Track,
+        }
+    }
+
+    fn set_repeat_mode(&mut self, repeat: RepeatMode) {
+        if self.repeat_mode() != repeat {
+            self.state.set_repeat(repeat != RepeatMode::Off);
+            self.repeat_track = repeat == RepeatMode::Track;
+            self.player.emit_repeat_changed_event(repeat);
+        }
+    }
+
+    fn end_of_context(&self) -> EndOfContextAction {
+        let autoplay = self.autoplay.or(match self.config.autoplay {
+            AutoplayMode::ForceOn => Some(true),
+            AutoplayMode::ForceOff => Some(false),
+            AutoplayMode::AccountDefault => {
+                match self.session.user_attribute("autoplay").as_deref() {
+                    Some("0") => Some(false),
+                    _ => None,
+                }
+            }
+        });
+        match (autoplay, self.config.end_of_context) {
+            (Some(true), _) => EndOfContextAction::Autoplay,
+            (Some(false), EndOfContextAction::Autoplay) => EndOfContextAction::Stop,
+            (_, action) => action,
+        }
+    }
+
+    fn handle_set_autoplay(&mut self, autoplay: bool) {
+        let was_autoplay = self.end_of_context() == EndOfContextAction::Autoplay;
+        self.autoplay = Some(autoplay);
+        if was_autoplay == autoplay {
+            return;
+        }
+
+        let context_uri = self.state.get_context_uri().to_owned();
+        if autoplay && !context_uri.is_empty() && !is_station_uri(&context_uri) {
+            info!("Fetching autoplay context uri");
+            self.autoplay_fut = self.resolve_autoplay_uri(&context_uri);
+        }
+        self.player.emit_autoplay_changed_event(autoplay);
+    }
+
+    // Loads what the frame says, which the context allows as `restrictions` says.
+    fn handle_load(&mut self, frame: &Frame, restrictions: Restrictions) {
+        self.update_account_filter();
+        if !self.device.get_is_active() {
+            let now = self.now_ms();
+            self.device.set_is_active(true);
+            self.device.set_became_active_at(now);
+            self.devices.deactivate_all();
+            self.player.emit_became_active_event();
+        }
+
+        self.update_tracks(frame);
+        self.set_restrictions(restrictions);
+
+        if !self.state.get_track().is_empty() {
+            let mut start_playing = frame.get_state().get_status() == PlayStatus::kPlayStatusPlay;
+            if start_playing && self.awaiting_play {
+                info!("Loading paused, waiting for a play command");
+                start_playing = false;
+            }
+            self.awaiting_play = false;
+            self.load_track(start_playing, frame.get_state().get_position_ms());
+        } else {
+            info!("No more tracks left in queue");
+            self.state.set_status(PlayStatus::kPlayStatusStop);
+            self.player.stop();
+            self.play_status = SpircPlayStatus::Stopped;
+        }
+
+        self.notify(None, true);
+    }
+
+    fn handle_load_context(&mut self, load: LoadContext) {
+        if let Some(superseded) = self.pending_load.take() {
+            let _ = superseded.reply.send(Err(SpircError::Superseded));
+        }
+
+        info!("Resolving context <{}>", load.uri);
+        self.load_context_fut = match loadable_context_kind(&load.uri) {
+            Some("track") | Some("episode") => {
+                Box::pin(future::ready(Ok(ResolvedContext::single(&load.uri))))
+            }
+            _ => self.resolve_context(&load.uri),
+        };
+        self.pending_load = Some(load);
+    }
+
+    // Loads a resolved context like a load from a controller, which asks this device to
+    // start playing this context as is.
+    fn handle_context_resolved(
+        &mut self,
+        load: &LoadContext,
+        context: ResolvedContext,
+    ) -> Result<(), SpircError> {
+        let mut tracks = context.track_refs();
+        if let Some(start_index) = load.start_index {
+            if start_index >= tracks.len() {
+                return Err(SpircError::InvalidStartIndex(start_index));
+            }
+        }
+
+        let shuffle = load.shuffle.unwrap_or_else(|| self.state.get_shuffle());
+        let mut index = load.start_index.unwrap_or(0);
+        let unshuffled_tracks = shuffle.then(|| tracks.clone());
+        if shuffle {
+            let mut rng = rand::thread_rng();
+            match load.start_index {
+                Some(start_index) => {
+                    tracks.swap(0, start_index);
+                    tracks[1..].shuffle(&mut rng);
+                }
+                None => tracks.shuffle(&mut rng),
+            }
+            index = 0;
+        }
+
+        info!("Loading {} tracks from <{}>", tracks.len(), load.uri);
+
+        let mut frame = Frame::new();
+        {
+            let state = frame.mut_state();
+            state.set_context_uri(load.uri.clone());
+            if let Some(ref name) = context.metadata.context_description {
+                state.set_context_description(name.clone());
+            }
+            state.set_track(protobuf::RepeatedField::from_vec(tracks));
+            state.set_playing_track_index(index as u32);
+            state.set_position_ms(load.start_position_ms);
+            state.set_status(if load.start_playing {
+                PlayStatus::kPlayStatusPlay
+            } else {
+                PlayStatus::kPlayStatusPause
+            });
+        }
+
+        self.set_shuffle_state(shuffle);
+        self.awaiting_play = false;
+        self.handle_load(&frame, context.restrictions.to_restrictions());
+        self.unshuffled_tracks = unshuffled_tracks;
+
+        Ok(())
+    }
+
+    // The state of what is playing elsewhere, at the position it is at now.
+    fn remote_state(&mut self) -> Option<State> {
+        let mut state = self.devices.active_state()?.clone();
+        if state.get_track().is_empty() {
+            return None;
+        }
+
+        if state.get_status() == PlayStatus::kPlayStatusPlay {
+            let elapsed = self.now_ms() - state.get_position_measured_at() as i64;
+            let position_ms = state.get_position_ms() as i64 + elapsed.max(0);
+            state.set_position_ms(position_ms.min(u32::MAX as i64) as u32);
+        }
+
+        Some(state)
+    }
+
+    fn handle_transfer_here(&mut self) -> Result<(), SpircError> {
+        if self.device.get_is_active() {
+            return Ok(());
+        }
+
+        let state = self.remote_state().ok_or(SpircError::NothingPlaying)?;
+        info!("Taking over playback");
+
+        let mut frame = Frame::new();
+        frame.set_state(state);
+        self.awaiting_play = false;
+        self.handle_load(&frame, Restrictions::default());
+
+        Ok(())
+    }
+
+    fn handle_transfer_to(
+        &mut self,
+        ident: String,
+        reply: oneshot::Sender<Result<(), SpircError>>,
+    ) {
+        let is_active = match self.devices.get(&ident) {
+            Some(device) => device.is_active,
+            None => {
+                let _ = reply.send(Err(SpircError::UnknownDevice(ident)));
+                return;
+            }
+        };
+        if is_active {
+            let _ = reply.send(Ok(()));
+            return;
+        }
+
+        let state = if self.device.get_is_active() && !self.state.get_track().is_empty() {
+            let position_ms = self.position();
+            self.update_state_position(position_ms);
+            Some(self.state.clone())
+        } else {
+            self.remote_state()
+        };
+        let state = match state {
+            Some(state) => state,
+            None => {
+                let _ = reply.send(Err(SpircError::NothingPlaying));
+                return;
+            }
+        };
+
+        info!("Handing playback over to {}", ident);
+        CommandSender::new(self, MessageType::kMessageTypeLoad)
+            .recipient(&ident)
+            .state(state)
+            .send();
+
+        if let Some((_, superseded)) = self.pending_transfer.replace((ident, reply)) {
+            let _ = superseded.send(Err(SpircError::Superseded));
+        }
+        self.transfer_timeout = Box::pin(time::sleep(TRANSFER_TIMEOUT).fuse());
+    }
+
+    fn check_pending_transfer(&mut self) {
+        let taken_over = match self.pending_transfer {
+            Some((ref ident, _)) => {
+                matches!(self.devices.get(ident), Some(device) if device.is_active)
+            }
+            None => false,
+        };
+
+        if taken_over {
+            if let Some((_, reply)) = self.pending_transfer.take() {
+                let _ = reply.send(Ok(()));
+            }
+            self.transfer_timeout = Box::pin(future::pending());
+        }
+    }
+
+    fn handle_player_event(&mut self, event: PlayerEvent) {
+        // we only process events if the play_request_id matches. If it doesn't, it is
+        // an event that belongs to a previous track and only arrives now due to a race
+        // condition. In this case we have updated the state already and don't want to
+        // mess with it.
+        if let Some(play_request_id) = event.get_play_request_id() {
+            if Some(play_request_id) == self.play_request_id {
+                match event {
+                    PlayerEvent::EndOfTrack { .. } => self.handle_end_of_track(),
+                    PlayerEvent::TrackSkipped { .. } => {
+                        self.handle_next();
+                        self.notify(None, true);
+                    }
+                    PlayerEvent::Loading { .. } => self.notify(None, false),
+                    PlayerEvent::Playing { position_ms, .. } => {
+                        let new_nominal_start_time = self.nominal_start_time(position_ms);
+                        match self.play_status {
+                            SpircPlayStatus::Playing {
+                                ref mut nominal_start_time,
+                                ..
+                            } => {
+                                if (*nominal_start_time - new_nominal_start_time).abs() > 100 {
+                                    *nominal_start_time = new_nominal_start_time;
+                                    self.update_state_position(position_ms);
+                                    self.notify(None, true);
+                                }
+                            }
+                            SpircPlayStatus::LoadingPlay { .. }
+                            | SpircPlayStatus::LoadingPause { .. } => {
+                                self.state.set_status(PlayStatus::kPlayStatusPlay);
+                                self.update_state_position(position_ms);
+                                self.notify(None, true);
+                                self.play_status = SpircPlayStatus::Playing {
+                                    nominal_start_time: new_nominal_start_time,
+                                    preloading_of_next_track_triggered: false,
+                                };
+                            }
+                            SpircPlayStatus::Paused {
+                                preloading_of_next_track_triggered,
+                                ..
+                            } if self.paused_by_sink_error => {
+                                self.paused_by_sink_error = false;
+                                self.state.set_status(PlayStatus::kPlayStatusPlay);
+                                self.update_state_position(position_ms);
+                                self.notify(None, true);
+                                self.play_status = SpircPlayStatus::Playing {
+                                    nominal_start_time: new_nominal_start_time,
+                                    preloading_of_next_track_triggered,
+                                };
+                            }
+                            _ => (),
+                        };
+                        trace!("==> kPlayStatusPlay");
+                    }
+                    PlayerEvent::Paused {
+                        position_ms: new_position_ms,
+                        ..
+                    } => {
+                        match self.play_status {
+                            SpircPlayStatus::Paused {
+                                ref mut position_ms,
+                                ..
+                            } => {
+                                if *position_ms != new_position_ms {
+                                    *position_ms = new_position_ms;
+                                    self.update_state_position(new_position_ms);
+                                    self.notify(None, true);
+                                }
+                            }
+                            SpircPlayStatus::Playing {
+                                preloading_of_next_track_triggered,
+                                ..
+                            } => {
+                                // The player paused on its own, e.g. for the sleep timer.
+                                self.state.set_status(PlayStatus::kPlayStatusPause);
+                                self.update_state_position(new_position_ms);
+                                self.notify(None, true);
+                                self.play_status = SpircPlayStatus::Paused {
+                                    position_ms: new_position_ms,
+                                    preloading_of_next_track_triggered,
+                                };
                             }
                             SpircPlayStatus::LoadingPlay { .. }
                             | SpircPlayStatus::LoadingPause { .. } => {
@@ -565,11 +1910,57 @@ impl SpircTask {
                             self.play_status = SpircPlayStatus::Stopped;
                         }
                     },
+                    PlayerEvent::Seeked {
+                        position_ms,
+                        requested_position_ms: Some(_),
+                        ..
+                    } => {
+                        // The player clamped the position we asked for.
+                        self.set_position(position_ms);
+                        self.notify(None, true);
+                    }
+                    PlayerEvent::PositionCorrection { position_ms, .. } => {
+                        // The seek landed somewhere else than we asked for.
+                        self.set_position(position_ms);
+                        self.notify(None, true);
+                    }
+                    PlayerEvent::SinkError { position_ms, .. } => {
+                        let preloading_of_next_track_triggered = match self.play_status {
+                            SpircPlayStatus::Playing {
+                                preloading_of_next_track_triggered,
+                                ..
+                            }
+                            | SpircPlayStatus::Paused {
+                                preloading_of_next_track_triggered,
+                                ..
+                            } => preloading_of_next_track_triggered,
+                            _ => false,
+                        };
+                        self.paused_by_sink_error = true;
+                        self.state.set_status(PlayStatus::kPlayStatusPause);
+                        self.update_state_position(position_ms);
+                        self.notify(None, true);
+                        self.play_status = SpircPlayStatus::Paused {
+                            position_ms,
+                            preloading_of_next_track_triggered,
+                        };
+                    }
                     PlayerEvent::TimeToPreloadNextTrack { .. } => self.handle_preload_next_track(),
                     PlayerEvent::Unavailable { track_id, .. } => self.handle_unavailable(track_id),
                     _ => (),
                 }
             }
+        } else {
+            match event {
+                PlayerEvent::PlaybackSpeedChanged { speed } => {
+                    let position_ms = self.position();
+                    self.playback_speed = speed;
+                    self.set_position(position_ms);
+                    self.notify(None, true);
+                }
+                PlayerEvent::StopAfterCurrentChanged { armed } => self.stop_after_current = armed,
+                _ => (),
+            }
         }
     }
 
@@ -591,38 +1982,37 @@ impl SpircTask {
             state_string,
         );
 
-        if frame.get_ident() == self.ident
-            || (!frame.get_recipient().is_empty() && !frame.get_recipient().contains(&self.ident))
-        {
+        if frame.get_ident() == self.ident {
             return;
         }
 
+        self.devices.update(&frame);
+        self.check_pending_transfer();
+
+        if !frame.get_recipient().is_empty() && !frame.get_recipient().contains(&self.ident) {
+            return;
+        }
+
+        if !self.may_control(frame.get_ident()) {
+            match frame.get_typ() {
+                // Answering a hello is what makes the device show up on the other one.
+                MessageType::kMessageTypeHello if self.config.hidden => return,
+                MessageType::kMessageTypeHello
+                | MessageType::kMessageTypeGoodbye
+                | MessageType::kMessageTypeProbe
+                | MessageType::kMessageTypeNotify => (),
+                typ => return self.handle_blocked_control(&frame, typ),
+            }
+        }
+
         match frame.get_typ() {
             MessageType::kMessageTypeHello => {
                 self.notify(Some(frame.get_ident()), true);
             }
 
             MessageType::kMessageTypeLoad => {
-                if !self.device.get_is_active() {
-                    let now = self.now_ms();
-                    self.device.set_is_active(true);
-                    self.device.set_became_active_at(now);
-                }
-
-                self.update_tracks(&frame);
-
-                if !self.state.get_track().is_empty() {
-                    let start_playing =
-                        frame.get_state().get_status() == PlayStatus::kPlayStatusPlay;
-                    self.load_track(start_playing, frame.get_state().get_position_ms());
-                } else {
-                    info!("No more tracks left in queue");
-                    self.state.set_status(PlayStatus::kPlayStatusStop);
-                    self.player.stop();
-                    self.play_status = SpircPlayStatus::Stopped;
-                }
-
-                self.notify(None, true);
+                let restrictions = load_restrictions(frame.get_context_player_state());
+                self.handle_load(&frame, restrictions)
             }
 
             MessageType::kMessageTypePlay => {
@@ -661,28 +2051,18 @@ impl SpircTask {
             }
 
             MessageType::kMessageTypeRepeat => {
-                self.state.set_repeat(frame.get_state().get_repeat());
+                self.set_repeat_mode(if frame.get_state().get_repeat() {
+                    RepeatMode::Context
+                } else {
+                    RepeatMode::Off
+                });
                 self.notify(None, true);
             }
 
             MessageType::kMessageTypeShuffle => {
-                self.state.set_shuffle(frame.get_state().get_shuffle());
-                if self.state.get_shuffle() {
-                    let current_index = self.state.get_playing_track_index();
-                    let tracks = self.state.mut_track();
-                    if !tracks.is_empty() {
-                        tracks.swap(0, current_index as usize);
-                        if let Some((_, rest)) = tracks.split_first_mut() {
-                            let mut rng = rand::thread_rng();
-                            rest.shuffle(&mut rng);
-                        }
-                        self.state.set_playing_track_index(0);
-                    }
-                } else {
-                    let context = self.state.get_context_uri();
-                    debug!("{:?}", context);
-                }
+                self.handle_shuffle(frame.get_state().get_shuffle());
                 self.notify(None, true);
+                self.update_preloaded_tracks();
             }
 
             MessageType::kMessageTypeSeek => {
@@ -693,23 +2073,7 @@ impl SpircTask {
             MessageType::kMessageTypeReplace => {
                 self.update_tracks(&frame);
                 self.notify(None, true);
-
-                if let SpircPlayStatus::Playing {
-                    preloading_of_next_track_triggered,
-                    ..
-                }
-                | SpircPlayStatus::Paused {
-                    preloading_of_next_track_triggered,
-                    ..
-                } = self.play_status
-                {
-                    if preloading_of_next_track_triggered {
-                        // Get the next track_id in the playlist
-                        if let Some(track_id) = self.preview_next_track() {
-                            self.player.preload(track_id);
-                        }
-                    }
-                }
+                self.update_preloaded_tracks();
             }
 
             MessageType::kMessageTypeVolume => {
@@ -723,10 +2087,7 @@ impl SpircTask {
                     && self.device.get_became_active_at()
                         <= frame.get_device_state().get_became_active_at()
                 {
-                    self.device.set_is_active(false);
-                    self.state.set_status(PlayStatus::kPlayStatusStop);
-                    self.player.stop();
-                    self.play_status = SpircPlayStatus::Stopped;
+                    self.handle_taken_over(&frame);
                 }
             }
 
@@ -734,12 +2095,59 @@ impl SpircTask {
         }
     }
 
+    // Another device became active after this one, so it plays instead. Stopping releases
+    // the sink right away, rather than after `PlayerConfig::sink_close_delay`.
+    fn handle_taken_over(&mut self, frame: &Frame) {
+        let device = self.devices.get(frame.get_ident());
+        let device_name = device.map(|device| device.name.clone());
+        let device_type = device.and_then(|device| device.device_type);
+        info!(
+            "Playback was taken over by {}",
+            device_name.as_deref().unwrap_or(frame.get_ident())
+        );
+
+        self.device.set_is_active(false);
+        self.state.set_status(PlayStatus::kPlayStatusStop);
+        self.player.stop();
+        self.play_status = SpircPlayStatus::Stopped;
+        self.playing_context = None;
+        self.player
+            .emit_playback_taken_over_event(device_name, device_type);
+        self.player.emit_became_inactive_event();
+    }
+
+    fn may_control(&self, ident: &str) -> bool {
+        may_control(self.config.hidden, &self.config.allowed_controllers, ident)
+    }
+
+    fn handle_blocked_control(&mut self, frame: &Frame, typ: MessageType) {
+        let ident = frame.get_ident().to_owned();
+        let name = frame.get_device_state().get_name().to_owned();
+        let command = format!("{:?}", typ)
+            .trim_start_matches("kMessageType")
+            .to_lowercase();
+        warn!(
+            "Ignoring {} from {} ({}), it is not allowed to control this device",
+            command, name, ident
+        );
+
+        // Show the device that nothing changed, rather than leaving it waiting on the command.
+        // A hidden device doesn't answer at all, which would give it away.
+        if !self.config.hidden {
+            self.notify(Some(&ident), false);
+        }
+        self.player.emit_control_blocked_event(ident, name, command);
+    }
+
     fn handle_play(&mut self) {
+        self.awaiting_play = false;
         match self.play_status {
             SpircPlayStatus::Paused {
                 position_ms,
                 preloading_of_next_track_triggered,
             } => {
+                self.paused_by_sink_error = false;
+
                 // Synchronize the volume from the mixer. This is useful on
                 // systems that can switch sources from and back to librespot.
                 let current_volume = self.mixer.volume();
@@ -749,7 +2157,7 @@ impl SpircTask {
                 self.state.set_status(PlayStatus::kPlayStatusPlay);
                 self.update_state_position(position_ms);
                 self.play_status = SpircPlayStatus::Playing {
-                    nominal_start_time: self.now_ms() as i64 - position_ms as i64,
+                    nominal_start_time: self.nominal_start_time(position_ms),
                     preloading_of_next_track_triggered,
                 };
             }
@@ -781,7 +2189,8 @@ impl SpircTask {
             } => {
                 self.player.pause();
                 self.state.set_status(PlayStatus::kPlayStatusPause);
-                let position_ms = (self.now_ms() - nominal_start_time) as u32;
+                let position_ms =
+                    position_since(nominal_start_time, self.now_ms(), self.playback_speed);
                 self.update_state_position(position_ms);
                 self.play_status = SpircPlayStatus::Paused {
                     position_ms,
@@ -797,9 +2206,13 @@ impl SpircTask {
     }
 
     fn handle_seek(&mut self, position_ms: u32) {
-        self.update_state_position(position_ms);
         self.player.seek(position_ms);
-        let now = self.now_ms();
+        self.set_position(position_ms);
+    }
+
+    fn set_position(&mut self, position_ms: u32) {
+        self.update_state_position(position_ms);
+        let new_nominal_start_time = self.nominal_start_time(position_ms);
         match self.play_status {
             SpircPlayStatus::Stopped => (),
             SpircPlayStatus::LoadingPause {
@@ -815,7 +2228,7 @@ impl SpircTask {
             SpircPlayStatus::Playing {
                 ref mut nominal_start_time,
                 ..
-            } => *nominal_start_time = now - position_ms as i64,
+            } => *nominal_start_time = new_nominal_start_time,
         };
     }
 
@@ -833,9 +2246,86 @@ impl SpircTask {
         }
     }
 
-    fn preview_next_track(&mut self) -> Option<SpotifyId> {
-        self.get_track_id_to_play_from_playlist(self.state.get_playing_track_index() + 1)
-            .map(|(track_id, _)| track_id)
+    // The next track, followed by the tracks up to the end of the playlist that the player
+    // may fetch ahead.
+    fn preview_next_tracks(&mut self) -> Vec<SpotifyId> {
+        let mut track_ids = Vec::new();
+        let mut index = self.state.get_playing_track_index() + 1;
+        while track_ids.len() < MAX_PRELOAD_DEPTH {
+            match self.get_track_id_to_play_from_playlist(index) {
+                Some((track_id, next_index)) if track_ids.is_empty() || next_index >= index => {
+                    track_ids.push(track_id);
+                    index = next_index + 1;
+                }
+                _ => break,
+            }
+        }
+        track_ids
+    }
+
+    // Preloads the upcoming tracks again if they were preloaded before the tracks changed.
+    // The player drops what it fetched for tracks that no longer come up.
+    fn update_preloaded_tracks(&mut self) {
+        if let SpircPlayStatus::Playing {
+            preloading_of_next_track_triggered: true,
+            ..
+        }
+        | SpircPlayStatus::Paused {
+            preloading_of_next_track_triggered: true,
+            ..
+        } = self.play_status
+        {
+            let track_ids = self.preview_next_tracks();
+            self.player.preload_upcoming(track_ids);
+        }
+    }
+
+    fn queue_range(&self) -> Range<usize> {
+        queue_range(
+            self.state.get_track(),
+            self.state.get_playing_track_index() as usize,
+        )
+    }
+
+    fn handle_add_to_queue(&mut self, uri: String) {
+        let mut track_ref = track_ref_for_uri(&uri);
+        track_ref.set_queued(true);
+
+        let index = self.queue_range().end;
+        let queued = self.resolve_tracks_metadata(std::slice::from_ref(&track_ref));
+        self.resolve_more_tracks_metadata(queued);
+        self.state.mut_track().insert(index, track_ref);
+        self.handle_queue_changed();
+    }
+
+    fn handle_remove_from_queue(&mut self, index: usize) {
+        let queue = self.queue_range();
+        if index < queue.len() {
+            self.state.mut_track().remove(queue.start + index);
+            self.handle_queue_changed();
+        } else {
+            warn!("There is no queued track at index {}", index);
+        }
+    }
+
+    fn handle_clear_queue(&mut self) {
+        let queue = self.queue_range();
+        let mut tracks = self.state.take_track().into_vec();
+        tracks.drain(queue);
+        self.state
+            .set_track(protobuf::RepeatedField::from_vec(tracks));
+        self.handle_queue_changed();
+    }
+
+    fn handle_queue_changed(&mut self) {
+        self.notify(None, true);
+        self.update_preloaded_tracks();
+
+        let uris = self.state.get_track()[self.queue_range()]
+            .iter()
+            .map(|track_ref| track_ref.get_uri().to_owned())
+            .collect();
+        self.player.emit_queue_changed_event(uris);
     }
 
     fn handle_preload_next_track(&mut self) {
@@ -850,8 +2340,9 @@ impl SpircTask {
                 ..
             } => {
                 *preloading_of_next_track_triggered = true;
-                if let Some(track_id) = self.preview_next_track() {
-                    self.player.preload(track_id);
+                let track_ids = self.preview_next_tracks();
+                if !track_ids.is_empty() {
+                    self.player.preload_upcoming(track_ids);
                 }
             }
             SpircPlayStatus::LoadingPause { .. }
@@ -901,21 +2392,37 @@ impl SpircTask {
             && ((self.state.get_track().len() as u32) - new_index) < CONTEXT_FETCH_THRESHOLD
         {
             self.context_fut = self.resolve_station(&context_uri);
-            self.update_tracks_from_context();
+            self.update_tracks_from_context(false);
         }
         if new_index >= tracks_len {
-            if self.config.autoplay {
-                // Extend the playlist
-                debug!("Extending playlist <{}>", context_uri);
-                self.update_tracks_from_context();
-                self.player.set_auto_normalise_as_album(false);
-            } else {
+            if self.state.get_repeat() {
+                debug!("Looping around back to start, repeat is on");
                 new_index = 0;
-                continue_playing = self.state.get_repeat();
+            } else {
+                let action = self.end_of_context();
                 debug!(
-                    "Looping around back to start, repeat is {}",
-                    continue_playing
+                    "Reached the end of <{}>, going on with {}",
+                    context_uri, action
                 );
+                match action {
+                    EndOfContextAction::Autoplay => {
+                        // Extend the playlist
+                        self.update_tracks_from_context(!is_station_uri(&context_uri));
+                        self.player.set_auto_normalise_as_album(false);
+                    }
+                    EndOfContextAction::RestartContext => {
+                        new_index = 0;
+                        if self.state.get_shuffle() {
+                            let mut rng = rand::thread_rng();
+                            self.state.mut_track().shuffle(&mut rng);
+                        }
+                    }
+                    EndOfContextAction::Stop => {
+                        new_index = 0;
+                        continue_playing = false;
+                    }
+                }
+                self.player.emit_context_ended_event(action);
             }
         }
 
@@ -971,17 +2478,129 @@ impl SpircTask {
     }
 
     fn handle_volume_up(&mut self) {
-        let volume = (self.device.get_volume() as u16).saturating_add(VOLUME_STEP_SIZE);
-        self.set_volume(volume);
+        self.step_volume(1);
     }
 
     fn handle_volume_down(&mut self) {
-        let volume = (self.device.get_volume() as u16).saturating_sub(VOLUME_STEP_SIZE);
-        self.set_volume(volume);
+        self.step_volume(-1);
+    }
+
+    fn step_volume(&mut self, steps: i32) {
+        let current = self.device.get_volume() as u16;
+        let volume = stepped_volume(
+            self.mixer.volume_ctrl(),
+            self.config.volume_steps,
+            self.config.volume_step_spacing,
+            current,
+            steps,
+        );
+        if volume == current {
+            trace!("Volume is already at {}, not stepping it further", volume);
+            return;
+        }
+
+        self.device.set_volume(volume as u32);
+        self.set_mixer_volume(volume);
+        self.stepped_volume = true;
+        self.volume_step_timeout = Box::pin(time::sleep(VOLUME_STEP_SETTLE_TIME).fuse());
+    }
+
+    fn handle_volume_step_settled(&mut self) {
+        self.volume_step_timeout = Box::pin(future::pending());
+        if !self.stepped_volume {
+            return;
+        }
+
+        self.stepped_volume = false;
+        let volume = self.device.get_volume() as u16;
+        if let Some(cache) = self.session.cache() {
+            cache.save_volume(volume)
+        }
+        self.player
+            .emit_volume_set_event(volume, VolumeSource::Connect, self.mixer.volume_ctrl());
+    }
+
+    // The mixer is at a different volume than Connect thinks, without it being set from here.
+    fn handle_mixer_volume(&mut self, volume: u16, source: VolumeSource) {
+        if volume as u32 == self.device.get_volume() {
+            return;
+        }
+
+        self.device.set_volume(volume as u32);
+        if let Some(cache) = self.session.cache() {
+            cache.save_volume(volume)
+        }
+        self.player
+            .emit_volume_set_event(volume, source, self.mixer.volume_ctrl());
+
+        if self.device.get_is_active() {
+            self.notify(None, true);
+        }
+    }
+
+    fn handle_set_volume_ctrl(&mut self, volume_ctrl: VolumeCtrl) {
+        // Whether the volume can be changed at all is announced to controllers at startup.
+        if matches!(volume_ctrl, VolumeCtrl::Fixed)
+            || matches!(self.mixer.volume_ctrl(), VolumeCtrl::Fixed)
+        {
+            warn!("Fixed volume can only be set at startup");
+            return;
+        }
+
+        self.mixer.set_volume_ctrl(volume_ctrl);
+        let volume = self.mixer.volume();
+        if volume as u32 == self.device.get_volume() {
+            self.player.emit_volume_set_event(
+                volume,
+                VolumeSource::Remapped,
+                self.mixer.volume_ctrl(),
+            );
+        } else {
+            self.handle_mixer_volume(volume, VolumeSource::Remapped);
+        }
+    }
+
+    fn handle_set_mute(&mut self, mute: bool) {
+        if self.mixer.is_muted() == mute {
+            return;
+        }
+
+        self.mixer.set_mute(mute);
+        // Other devices are shown the volume that is heard. The one to go back to is kept by
+        // the mixer, so it isn't cached either.
+        let volume = self.mixer.volume();
+        self.device.set_volume(volume as u32);
+        self.player.emit_mute_changed_event(mute);
+        self.player
+            .emit_volume_set_event(volume, VolumeSource::Mute, self.mixer.volume_ctrl());
+
+        if self.device.get_is_active() {
+            self.notify(None, true);
+        }
+    }
+
+    // Setting the volume unmutes the mixer.
+    fn set_mixer_volume(&mut self, volume: u16) {
+        let was_muted = self.mixer.is_muted();
+        self.mixer.set_volume(volume);
+        if was_muted {
+            self.player.emit_mute_changed_event(false);
+        }
     }
 
     fn handle_end_of_track(&mut self) {
-        self.handle_next();
+        if self.repeat_track {
+            debug!("Playing the track again, repeat is on for it");
+            self.load_track(true, 0);
+        } else {
+            self.handle_next();
+        }
+        if self.stop_after_current {
+            // The player disarms it right after this event, so it only applies once.
+            debug!("Stopping after the track that just ended");
+            self.stop_after_current = false;
+            self.handle_pause();
+        }
         self.notify(None, true);
     }
 
@@ -993,72 +2612,349 @@ impl SpircTask {
             | SpircPlayStatus::Paused { position_ms, .. } => position_ms,
             SpircPlayStatus::Playing {
                 nominal_start_time, ..
-            } => (self.now_ms() - nominal_start_time) as u32,
+            } => position_since(nominal_start_time, self.now_ms(), self.playback_speed),
         }
     }
 
-    fn resolve_station(&self, uri: &str) -> BoxedFuture<Result<serde_json::Value, MercuryError>> {
-        let radio_uri = format!("hm://radio-apollo/v3/stations/{}", uri);
+    fn resolve_station(&self, uri: &str) -> BoxedFuture<Result<serde_json::Value, MercuryError>> {
+        let radio_uri = format!("hm://radio-apollo/v3/stations/{}", uri);
+
+        self.resolve_uri(&radio_uri)
+    }
+
+    fn resolve_autoplay_uri(&self, uri: &str) -> BoxedFuture<Result<String, MercuryError>> {
+        let query_uri = format!("hm://autoplay-enabled/query?uri={}", uri);
+        let request = self
+            .session
+            .mercury()
+            .get_with_priority(query_uri, RequestPriority::Essential);
+        Box::pin(
+            async {
+                let response = request.await?;
+
+                if response.status_code == 200 {
+                    let data = response
+                        .payload
+                        .first()
+                        .expect("Empty autoplay uri")
+                        .to_vec();
+                    let autoplay_uri = String::from_utf8(data).unwrap();
+                    Ok(autoplay_uri)
+                } else {
+                    warn!("No autoplay_uri found");
+                    Err(MercuryError::Failed)
+                }
+            }
+            .fuse(),
+        )
+    }
+
+    fn resolve_uri(&self, uri: &str) -> BoxedFuture<Result<serde_json::Value, MercuryError>> {
+        let request = self
+            .session
+            .mercury()
+            .get_with_priority(uri, RequestPriority::Essential);
+
+        Box::pin(
+            async move {
+                let response = request.await?;
+
+                let data = response
+                    .payload
+                    .first()
+                    .expect("Empty payload on context uri");
+                let response: serde_json::Value = serde_json::from_slice(data).unwrap();
+
+                Ok(response)
+            }
+            .fuse(),
+        )
+    }
+
+    fn resolve_context(&self, uri: &str) -> BoxedFuture<Result<ResolvedContext, SpircError>> {
+        let request = self.session.mercury().get_with_priority(
+            format!("hm://context-resolve/v1/{}", uri),
+            RequestPriority::Essential,
+        );
+        let uri = uri.to_owned();
+
+        Box::pin(
+            async move {
+                let unavailable = || SpircError::Unavailable(uri.clone());
+
+                let response = request.await.map_err(|_| unavailable())?;
+                let data = match response.payload.first() {
+                    Some(data) if response.status_code == 200 => data,
+                    _ => return Err(unavailable()),
+                };
+                let context: ResolvedContext = serde_json::from_slice(data).map_err(|e| {
+                    warn!("Unable to parse context <{}>: {}", uri, e);
+                    unavailable()
+                })?;
+
+                if context.track_refs().is_empty() {
+                    return Err(unavailable());
+                }
+
+                Ok(context)
+            }
+            .fuse(),
+        )
+    }
+
+    fn resolve_tracks_metadata(
+        &self,
+        tracks: &[TrackRef],
+    ) -> BoxedFuture<HashMap<SpotifyId, Result<Track, MetadataError>>> {
+        let ids: Vec<SpotifyId> = tracks
+            .iter()
+            .filter_map(|track_ref| self.get_spotify_id_for_track(track_ref).ok())
+            .filter(|id| id.audio_type == SpotifyAudioType::Track)
+            .collect();
+        let session = self.session.clone();
+
+        Box::pin(async move { Track::get_multiple(&session, &ids).await }.fuse())
+    }
+
+    // Adds to the metadata that is being fetched, rather than dropping what was asked for
+    // before.
+    fn resolve_more_tracks_metadata(
+        &mut self,
+        more: BoxedFuture<HashMap<SpotifyId, Result<Track, MetadataError>>>,
+    ) {
+        let pending =
+            std::mem::replace(&mut self.context_metadata_fut, Box::pin(future::pending()));
+        self.context_metadata_fut = if pending.is_terminated() {
+            more
+        } else {
+            Box::pin(
+                future::join(pending, more)
+                    .map(|(mut metadata, more)| {
+                        metadata.extend(more);
+                        metadata
+                    })
+                    .fuse(),
+            )
+        };
+    }
+
+    // Flag tracks that can't be played in this country and have nothing to relink to,
+    // so they are skipped without first being handed to the player.
+    fn mark_unplayable_tracks(
+        &mut self,
+        metadata: &HashMap<SpotifyId, Result<Track, MetadataError>>,
+    ) {
+        let is_unplayable = |track_ref: &TrackRef| match SpotifyId::from_raw(track_ref.get_gid()) {
+            Ok(id) => matches!(
+                metadata.get(&id),
+                Some(Ok(track)) if !track.available && track.alternatives.is_empty()
+            ),
+            Err(_) => false,
+        };
+
+        let context_tracks = self
+            .context
+            .iter_mut()
+            .flat_map(|context| context.tracks.iter_mut());
+        let unshuffled_tracks = self.unshuffled_tracks.iter_mut().flatten();
+        for track_ref in self
+            .state
+            .mut_track()
+            .iter_mut()
+            .chain(context_tracks)
+            .chain(unshuffled_tracks)
+        {
+            if track_ref.get_context() != "NonPlayable" && is_unplayable(track_ref) {
+                debug!("Marked <{:?}> as NonPlayable", track_ref.get_uri());
+                track_ref.set_context(String::from("NonPlayable"));
+            }
+        }
+    }
+
+    fn note_explicit_tracks(
+        &mut self,
+        metadata: &HashMap<SpotifyId, Result<Track, MetadataError>>,
+    ) {
+        let explicit = metadata
+            .values()
+            .filter_map(|track| track.as_ref().ok())
+            .filter(|track| track.explicit)
+            .map(|track| track.id);
+        self.explicit_tracks.extend(explicit);
+        let known = self.known_tracks();
+        forget_unknown_tracks(&mut self.explicit_tracks, &known);
+        self.update_account_filter();
+
+        if self.explicit_filter().is_some() || !self.vetoed_tracks.is_empty() {
+            self.skip_current_if_filtered();
+            self.update_preloaded_tracks();
+        }
+    }
+
+    // The tracks of a station could be what autoplay goes on with, so the veto is asked
+    // about all of them. Only those that autoplay adds are skipped.
+    fn note_vetoed_tracks(&mut self, metadata: &HashMap<SpotifyId, Result<Track, MetadataError>>) {
+        let veto = match self.autoplay_veto {
+            Some(ref veto) => veto,
+            None => return,
+        };
+
+        let vetoed = metadata
+            .values()
+            .filter_map(|track| track.as_ref().ok())
+            .filter(|track| !veto(track))
+            .map(|track| track.id);
+        self.vetoed_tracks.extend(vetoed);
+        let known = self.known_tracks();
+        forget_unknown_tracks(&mut self.vetoed_tracks, &known);
+    }
+
+    // The tracks that are to play or may, the ones worth remembering anything about.
+    fn known_tracks(&self) -> HashSet<SpotifyId> {
+        let context_tracks = self
+            .context
+            .iter()
+            .flat_map(|context| context.tracks.iter());
+        let unshuffled_tracks = self.unshuffled_tracks.iter().flatten();
+        self.state
+            .get_track()
+            .iter()
+            .chain(context_tracks)
+            .chain(unshuffled_tracks)
+            .filter_map(|track_ref| spotify_id_for_track(track_ref).ok())
+            .collect()
+    }
+
+    // Why explicit tracks are skipped, if they are.
+    fn explicit_filter(&self) -> Option<TrackFilterReason> {
+        if self.filter_explicit_content {
+            Some(TrackFilterReason::Explicit)
+        } else if self.account_filters_explicit {
+            Some(TrackFilterReason::AccountExplicit)
+        } else {
+            None
+        }
+    }
+
+    // Why the track is skipped, if it is.
+    fn filter_reason(&self, track_id: &SpotifyId) -> Option<TrackFilterReason> {
+        match self.explicit_filter() {
+            Some(reason) if self.explicit_tracks.contains(track_id) => Some(reason),
+            _ if self.autoplay_tracks.contains(track_id)
+                && self.vetoed_tracks.contains(track_id) =>
+            {
+                Some(TrackFilterReason::AutoplayVeto)
+            }
+            _ => None,
+        }
+    }
+
+    // Other devices pick up the new name and type from the next state that is sent.
+    fn device_info_changed(&mut self) {
+        self.notify(None, true);
+        self.player
+            .emit_device_info_changed_event(self.device.get_name().to_owned(), self.device_type);
+    }
+
+    // The account says whether it filters explicit content when the session starts, and again
+    // when that changes.
+    fn update_account_filter(&mut self) {
+        let filters = self
+            .session
+            .user_attribute("filter-explicit-content")
+            .as_deref()
+            == Some("1");
+        if self.account_filters_explicit == filters {
+            return;
+        }
 
-        self.resolve_uri(&radio_uri)
+        info!(
+            "The account has explicit content {}",
+            if filters { "filtered" } else { "allowed" }
+        );
+        let was_filtering = self.explicit_filter().is_some();
+        self.account_filters_explicit = filters;
+        self.explicit_filter_changed(was_filtering);
     }
 
-    fn resolve_autoplay_uri(&self, uri: &str) -> BoxedFuture<Result<String, MercuryError>> {
-        let query_uri = format!("hm://autoplay-enabled/query?uri={}", uri);
-        let request = self.session.mercury().get(query_uri);
-        Box::pin(
-            async {
-                let response = request.await?;
+    // The current track may have started loading before it was known to be filtered.
+    fn skip_current_if_filtered(&mut self) {
+        let index = self.state.get_playing_track_index() as usize;
+        let current = self
+            .state
+            .get_track()
+            .get(index)
+            .and_then(|track_ref| self.get_spotify_id_for_track(track_ref).ok());
+
+        let filtered = matches!(current, Some(track_id) if self.filter_reason(&track_id).is_some());
+        if !filtered {
+            return;
+        }
 
-                if response.status_code == 200 {
-                    let data = response
-                        .payload
-                        .first()
-                        .expect("Empty autoplay uri")
-                        .to_vec();
-                    let autoplay_uri = String::from_utf8(data).unwrap();
-                    Ok(autoplay_uri)
-                } else {
-                    warn!("No autoplay_uri found");
-                    Err(MercuryError)
-                }
-            }
-            .fuse(),
-        )
+        let start_playing = match self.play_status {
+            SpircPlayStatus::Stopped => return,
+            SpircPlayStatus::Playing { .. } | SpircPlayStatus::LoadingPlay { .. } => true,
+            SpircPlayStatus::Paused { .. } | SpircPlayStatus::LoadingPause { .. } => false,
+        };
+        self.load_track(start_playing, 0);
+        self.notify(None, true);
     }
 
-    fn resolve_uri(&self, uri: &str) -> BoxedFuture<Result<serde_json::Value, MercuryError>> {
-        let request = self.session.mercury().get(uri);
+    fn handle_set_filter_explicit_content(&mut self, filter: bool) {
+        if self.filter_explicit_content == filter {
+            return;
+        }
 
-        Box::pin(
-            async move {
-                let response = request.await?;
+        let was_filtering = self.explicit_filter().is_some();
+        self.filter_explicit_content = filter;
+        self.explicit_filter_changed(was_filtering);
+    }
 
-                let data = response
-                    .payload
-                    .first()
-                    .expect("Empty payload on context uri");
-                let response: serde_json::Value = serde_json::from_slice(data).unwrap();
+    // The track that is playing is left to finish, unless `ConnectConfig::filter_current_track`
+    // says to skip it as well.
+    fn explicit_filter_changed(&mut self, was_filtering: bool) {
+        let filtering = self.explicit_filter().is_some();
+        if filtering == was_filtering {
+            return;
+        }
 
-                Ok(response)
-            }
-            .fuse(),
-        )
+        self.player
+            .emit_filter_explicit_content_changed_event(filtering);
+        if filtering && self.config.filter_current_track {
+            self.skip_current_if_filtered();
+        }
+        self.update_preloaded_tracks();
     }
 
-    fn update_tracks_from_context(&mut self) {
+    // Adds the tracks of the station that was resolved last. With `from_autoplay` they go on
+    // after the context that was loaded, and are marked with the station they come from.
+    fn update_tracks_from_context(&mut self, from_autoplay: bool) {
         if let Some(ref context) = self.context {
             self.context_fut = self.resolve_uri(&context.next_page_url);
 
-            let new_tracks = &context.tracks;
+            let mut new_tracks = context.tracks.clone();
+            if from_autoplay {
+                let station_uri = context.uri.clone().unwrap_or_default();
+                for track in &mut new_tracks {
+                    track.set_context(station_uri.clone());
+                }
+                let ids = new_tracks
+                    .iter()
+                    .filter_map(|track_ref| SpotifyId::from_raw(track_ref.get_gid()).ok());
+                self.autoplay_tracks.extend(ids);
+            }
             debug!("Adding {:?} tracks from context to frame", new_tracks.len());
+            // The added tracks have no order from before shuffling to go back to.
+            self.unshuffled_tracks = None;
             let mut track_vec = self.state.take_track().into_vec();
             if let Some(head) = track_vec.len().checked_sub(CONTEXT_TRACKS_HISTORY) {
                 track_vec.drain(0..head);
             }
-            track_vec.extend_from_slice(new_tracks);
+            track_vec.extend(new_tracks);
             self.state
                 .set_track(protobuf::RepeatedField::from_vec(track_vec));
+            let known = self.known_tracks();
+            forget_unknown_tracks(&mut self.autoplay_tracks, &known);
 
             // Update playing index
             if let Some(new_index) = self
@@ -1079,11 +2975,9 @@ impl SpircTask {
         let context_uri = frame.get_state().get_context_uri().to_owned();
         let tracks = frame.get_state().get_track();
         debug!("Frame has {:?} tracks", tracks.len());
-        if context_uri.starts_with("spotify:station:")
-            || context_uri.starts_with("spotify:dailymix:")
-        {
+        if is_station_uri(&context_uri) {
             self.context_fut = self.resolve_station(&context_uri);
-        } else if self.config.autoplay {
+        } else if self.end_of_context() == EndOfContextAction::Autoplay {
             info!("Fetching autoplay context uri");
             // Get autoplay_station_uri for regular playlists
             self.autoplay_fut = self.resolve_autoplay_uri(&context_uri);
@@ -1092,28 +2986,32 @@ impl SpircTask {
         self.player
             .set_auto_normalise_as_album(context_uri.starts_with("spotify:album:"));
 
+        self.context_metadata_fut = self.resolve_tracks_metadata(tracks);
+        // The tracks the controller sent take the place of any that autoplay added.
+        self.autoplay_tracks.clear();
+
         self.state.set_playing_track_index(index);
         self.state.set_track(tracks.iter().cloned().collect());
         self.state.set_context_uri(context_uri);
+        self.state
+            .set_context_description(frame.get_state().get_context_description().to_owned());
         // has_shuffle/repeat seem to always be true in these replace msgs,
         // but to replicate the behaviour of the Android client we have to
         // ignore false values.
         let state = frame.get_state();
-        if state.get_repeat() {
-            self.state.set_repeat(true);
+        if state.get_repeat() && self.repeat_mode() == RepeatMode::Off {
+            self.set_repeat_mode(RepeatMode::Context);
         }
         if state.get_shuffle() {
-            self.state.set_shuffle(true);
+            self.set_shuffle_state(true);
         }
+        // The controller's order is the one to keep now.
+        self.unshuffled_tracks = None;
     }
 
     // should this be a method of SpotifyId directly?
     fn get_spotify_id_for_track(&self, track_ref: &TrackRef) -> Result<SpotifyId, SpotifyIdError> {
-        SpotifyId::from_raw(track_ref.get_gid()).or_else(|_| {
-            let uri = track_ref.get_uri();
-            debug!("Malformed or no gid, attempting to parse URI <{}>", uri);
-            SpotifyId::from_uri(uri)
-        })
+        spotify_id_for_track(track_ref)
     }
 
     // Helper to find corresponding index(s) for track_id
@@ -1133,6 +3031,10 @@ impl SpircTask {
         index
     }
 
+    fn track_ref_is_local_file(&self, track_ref: &TrackRef) -> bool {
+        is_local_file(track_ref)
+    }
+
     // Broken out here so we can refactor this later when we move to SpotifyObjectID or similar
     fn track_ref_is_unavailable(&self, track_ref: &TrackRef) -> bool {
         track_ref.get_context() == "NonPlayable"
@@ -1164,13 +3066,18 @@ impl SpircTask {
         let mut track_ref = self.state.get_track()[new_playlist_index].clone();
         let mut track_id = self.get_spotify_id_for_track(&track_ref);
         while self.track_ref_is_unavailable(&track_ref)
+            || self.track_ref_is_local_file(&track_ref)
             || track_id.is_err()
             || track_id.unwrap().audio_type == SpotifyAudioType::NonPlayable
+            || self.filter_reason(&track_id.unwrap()).is_some()
         {
-            warn!(
-                "Skipping track <{:?}> at position [{}] of {}",
-                track_ref, new_playlist_index, tracks_len
-            );
+            // Local files are expected, and reported with the skipped filtered tracks.
+            if !self.track_ref_is_local_file(&track_ref) {
+                warn!(
+                    "Skipping track <{:?}> at position [{}] of {}",
+                    track_ref, new_playlist_index, tracks_len
+                );
+            }
 
             new_playlist_index += 1;
             if new_playlist_index >= tracks_len {
@@ -1191,13 +3098,41 @@ impl SpircTask {
         }
     }
 
+    fn is_playing_episode(&self) -> bool {
+        let index = self.state.get_playing_track_index() as usize;
+        let track_id = self
+            .state
+            .get_track()
+            .get(index)
+            .and_then(|track_ref| self.get_spotify_id_for_track(track_ref).ok());
+
+        matches!(self.play_status, SpircPlayStatus::Playing { .. })
+            && matches!(track_id, Some(id) if id.audio_type == SpotifyAudioType::Podcast)
+    }
+
     fn load_track(&mut self, start_playing: bool, position_ms: u32) {
         let index = self.state.get_playing_track_index();
+        self.paused_by_sink_error = false;
 
-        match self.get_track_id_to_play_from_playlist(index) {
-            Some((track, index)) => {
+        let next = self.get_track_id_to_play_from_playlist(index);
+        self.report_skipped_filtered(index, next.map(|(_, next_index)| next_index));
+
+        match next {
+            Some((track, next_index)) => {
+                // A track that was skipped over starts from the beginning.
+                let position_ms = if next_index == index { position_ms } else { 0 };
+                let index = next_index;
                 self.state.set_playing_track_index(index);
 
+                let position_ms = if track.audio_type == SpotifyAudioType::Podcast
+                    && !self.config.resume_episodes
+                {
+                    0
+                } else {
+                    position_ms
+                };
+
+                self.report_playing_context();
                 self.play_request_id = Some(self.player.load(track, start_playing, position_ms));
 
                 self.update_state_position(position_ms);
@@ -1217,6 +3152,142 @@ impl SpircTask {
         }
     }
 
+    // What the track at the playing index is played from.
+    fn playing_context_here(&self) -> Option<PlayingContext> {
+        let track = self
+            .state
+            .get_track()
+            .get(self.state.get_playing_track_index() as usize)?;
+        let context_uri = self.state.get_context_uri();
+        let station_uri = track.get_context();
+
+        if !station_uri.is_empty() && station_uri != context_uri && is_station_uri(station_uri) {
+            let name = self
+                .context
+                .as_ref()
+                .and_then(|context| context.title.clone());
+            return Some(PlayingContext {
+                uri: station_uri.to_owned(),
+                name,
+                kind: ContextKind::Autoplay,
+            });
+        }
+
+        let name = self.state.get_context_description();
+        Some(PlayingContext {
+            uri: context_uri.to_owned(),
+            name: (!name.is_empty()).then(|| name.to_owned()),
+            kind: if track.get_queued() {
+                ContextKind::Queue
+            } else {
+                ContextKind::Context
+            },
+        })
+    }
+
+    fn dump_context(&mut self) -> ContextDump {
+        let active = self.device.get_is_active();
+        let position_ms = if active {
+            self.position()
+        } else {
+            self.state.get_position_ms()
+        };
+
+        let track_uri = |track_ref: &TrackRef| {
+            if !track_ref.get_uri().is_empty() {
+                return track_ref.get_uri().to_owned();
+            }
+            self.get_spotify_id_for_track(track_ref)
+                .ok()
+                .and_then(|id| id.to_uri().ok())
+                .unwrap_or_default()
+        };
+
+        let tracks = self.state.get_track();
+        let context_name = self.state.get_context_description();
+        ContextDump {
+            active,
+            context_uri: self.state.get_context_uri().to_owned(),
+            context_name: (!context_name.is_empty()).then(|| context_name.to_owned()),
+            tracks: tracks.iter().map(track_uri).collect(),
+            index: self.state.get_playing_track_index(),
+            position_ms,
+            shuffle: self.state.get_shuffle(),
+            repeat: self.repeat_mode().to_string(),
+            queue: tracks[self.queue_range()].iter().map(track_uri).collect(),
+        }
+    }
+
+    fn current_playing_context(&self) -> Option<PlayingContext> {
+        if self.device.get_is_active() {
+            return self.playing_context.clone();
+        }
+
+        let state = self.devices.active_state()?;
+        let name = state.get_context_description();
+        Some(PlayingContext {
+            uri: state.get_context_uri().to_owned(),
+            name: (!name.is_empty()).then(|| name.to_owned()),
+            kind: ContextKind::Context,
+        })
+    }
+
+    // Reports what the track about to be loaded is played from, if that changed.
+    fn report_playing_context(&mut self) {
+        let playing_context = self.playing_context_here();
+        if playing_context == self.playing_context {
+            return;
+        }
+
+        if let Some(ref context) = playing_context {
+            debug!("Playing from <{}> ({})", context.uri, context.kind);
+            self.player.emit_context_changed_event(
+                context.uri.clone(),
+                context.name.clone(),
+                context.kind,
+            );
+        }
+        self.playing_context = playing_context;
+    }
+
+    // Sends the filtered tracks that loading from `from` skipped to get to `to`, or says that
+    // playback stops because of explicit ones if there is nothing else to play.
+    fn report_skipped_filtered(&self, from: u32, to: Option<u32>) {
+        let tracks = self.state.get_track();
+        if tracks.is_empty() {
+            return;
+        }
+
+        let skipped_indexes = skipped_indexes(tracks.len(), from, to);
+
+        for track_ref in skipped_local_files(tracks, skipped_indexes.clone()) {
+            info!("Skipping local file <{}>", track_ref.get_uri());
+            self.player
+                .emit_skipped_local_file_event(track_ref.get_uri().to_owned());
+        }
+
+        let left = skipped_indexes.clone().count();
+        let skipped: Vec<(SpotifyId, TrackFilterReason)> = skipped_indexes
+            .filter_map(|index| self.get_spotify_id_for_track(&tracks[index]).ok())
+            .filter_map(|track_id| Some((track_id, self.filter_reason(&track_id)?)))
+            .collect();
+
+        for &(track_id, reason) in &skipped {
+            match reason {
+                TrackFilterReason::AutoplayVeto => {
+                    info!("Skipping <{:?}>, autoplay was vetoed for it", track_id)
+                }
+                _ => info!("Skipping <{:?}>, it is explicit", track_id),
+            }
+            self.player.emit_track_filtered_event(track_id, reason);
+        }
+
+        if to.is_none() && stopped_for_explicit(left, &skipped) {
+            warn!("Stopping, every track that is left to play is explicit");
+            self.player.emit_explicit_content_stopped_event();
+        }
+    }
+
     fn hello(&mut self) {
         CommandSender::new(self, MessageType::kMessageTypeHello).send();
     }
@@ -1240,12 +3311,28 @@ impl SpircTask {
     }
 
     fn set_volume(&mut self, volume: u16) {
+        // The volume that is set takes the place of any steps that weren't announced yet.
+        self.stepped_volume = false;
+        self.volume_step_timeout = Box::pin(future::pending());
+
+        let volume = if self.config.quantize_volume {
+            quantized_volume(
+                self.mixer.volume_ctrl(),
+                self.config.volume_steps,
+                self.config.volume_step_spacing,
+                volume,
+            )
+        } else {
+            volume
+        };
+
         self.device.set_volume(volume as u32);
-        self.mixer.set_volume(volume);
+        self.set_mixer_volume(volume);
         if let Some(cache) = self.session.cache() {
             cache.save_volume(volume)
         }
-        self.player.emit_volume_set_event(volume);
+        self.player
+            .emit_volume_set_event(volume, VolumeSource::Connect, self.mixer.volume_ctrl());
     }
 }
 
@@ -1278,7 +3365,11 @@ impl<'a> CommandSender<'a> {
         self
     }
 
-    #[allow(dead_code)]
+    fn position(mut self, position_ms: u32) -> CommandSender<'a> {
+        self.frame.set_position(position_ms);
+        self
+    }
+
     fn state(mut self, state: protocol::spirc::State) -> CommandSender<'a> {
         self.frame.set_state(state);
         self
@@ -1289,6 +3380,640 @@ impl<'a> CommandSender<'a> {
             self.frame.set_state(self.spirc.state.clone());
         }
 
+        if self.spirc.config.hidden {
+            match hidden_recipients(
+                self.frame.get_recipient(),
+                &self.spirc.config.allowed_controllers,
+            ) {
+                Some(recipients) => self.frame.set_recipient(recipients.into()),
+                None => {
+                    trace!(
+                        "Not sending {:?}, the device is hidden",
+                        self.frame.get_typ()
+                    );
+                    return;
+                }
+            }
+        }
+
         self.spirc.sender.send(self.frame.write_to_bytes().unwrap());
     }
 }
+
+// Any device may control one that is neither hidden nor limited to some controllers.
+fn may_control(hidden: bool, allowed_controllers: &[String], ident: &str) -> bool {
+    let restricted = hidden || !allowed_controllers.is_empty();
+    !restricted || allowed_controllers.iter().any(|allowed| allowed == ident)
+}
+
+// A hidden device only shows itself to the devices that may control it, instead of sending to
+// all of them, or to one that asked. Nothing is sent if none of them would get it.
+fn hidden_recipients(recipients: &[String], allowed_controllers: &[String]) -> Option<Vec<String>> {
+    let recipients: Vec<String> = if recipients.is_empty() {
+        allowed_controllers.to_vec()
+    } else {
+        recipients
+            .iter()
+            .filter(|&recipient| allowed_controllers.contains(recipient))
+            .cloned()
+            .collect()
+    };
+
+    if recipients.is_empty() {
+        None
+    } else {
+        Some(recipients)
+    }
+}
+
+// Local files are on the devices of the user, and can't be streamed. They are skipped rather
+// than played, which their ids that don't parse would make happen anyway. Telling that apart
+// from an invalid track is all that this adds, so there is no option to turn it off.
+fn is_local_file(track_ref: &TrackRef) -> bool {
+    track_ref.get_uri().starts_with("spotify:local:")
+}
+
+// The indexes of the `len` tracks that loading from `from` skips to get to `to`, all of them
+// if it gets to none.
+fn skipped_indexes(len: usize, from: u32, to: Option<u32>) -> impl Iterator<Item = usize> + Clone {
+    let from = from as usize % len;
+    (0..len)
+        .map(move |offset| (from + offset) % len)
+        .take_while(move |&index| Some(index as u32) != to)
+}
+
+fn skipped_local_files(
+    tracks: &[TrackRef],
+    skipped_indexes: impl Iterator<Item = usize>,
+) -> impl Iterator<Item = &TrackRef> {
+    skipped_indexes
+        .map(move |index| &tracks[index])
+        .filter(|track_ref| is_local_file(track_ref))
+}
+
+// Playback stops because of explicit content only if every one of the `left` tracks was
+// skipped for being explicit. Tracks that are unavailable for other reasons don't count.
+fn stopped_for_explicit(left: usize, skipped: &[(SpotifyId, TrackFilterReason)]) -> bool {
+    left > 0
+        && skipped.len() == left
+        && skipped
+            .iter()
+            .all(|&(_, reason)| reason != TrackFilterReason::AutoplayVeto)
+}
+
+// Only past the cap, so that a track that drops out of the tracks is known if it comes back.
+fn forget_unknown_tracks(tracks: &mut HashSet<SpotifyId>, known: &HashSet<SpotifyId>) {
+    if tracks.len() > MAX_REMEMBERED_TRACKS {
+        tracks.retain(|track_id| known.contains(track_id));
+    }
+}
+
+// The gid says nothing about the audio type, which is taken from the uri, like for episodes.
+fn spotify_id_for_track(track_ref: &TrackRef) -> Result<SpotifyId, SpotifyIdError> {
+    let uri = track_ref.get_uri();
+    match SpotifyId::from_raw(track_ref.get_gid()) {
+        Ok(mut id) => {
+            if let Ok(uri_id) = SpotifyId::from_uri(uri) {
+                id.audio_type = uri_id.audio_type;
+            }
+            Ok(id)
+        }
+        Err(_) => {
+            debug!("Malformed or no gid, attempting to parse URI <{}>", uri);
+            SpotifyId::from_uri(uri)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        forget_unknown_tracks, hidden_recipients, is_loadable_context, loadable_context_kind,
+        may_control, offset_position, position_since, quantized_volume, queue_range, ratio_to_db,
+        shuffle_tracks, skipped_indexes, skipped_local_files, spotify_id_for_track, stepped_volume,
+        stopped_for_explicit, unshuffle, valid_device_name, Spirc, SpircCommand, SpircError,
+        SpircShared, MAX_REMEMBERED_TRACKS,
+    };
+    use crate::context::{load_restrictions, track_ref_for_uri, ResolvedContext};
+    use crate::core::config::VolumeStepSpacing;
+    use crate::core::spotify_id::{SpotifyAudioType, SpotifyId};
+    use crate::playback::config::VolumeCtrl;
+    use crate::playback::mixer::mappings::MappedCtrl;
+    use crate::playback::player::{Restrictions, TrackFilterReason};
+    use crate::protocol::spirc::TrackRef;
+    use futures_util::FutureExt;
+    use std::collections::HashSet;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    fn tracks(uris: &[&str]) -> Vec<TrackRef> {
+        uris.iter()
+            .map(|uri| {
+                let mut track_ref = TrackRef::new();
+                track_ref.set_uri(uri.trim_end_matches('*').to_string());
+                track_ref.set_queued(uri.ends_with('*'));
+                track_ref
+            })
+            .collect()
+    }
+
+    fn uris(tracks: &[TrackRef]) -> Vec<&str> {
+        tracks.iter().map(|track_ref| track_ref.get_uri()).collect()
+    }
+
+    // A `Spirc` without a task, and what it sends the task.
+    fn spirc() -> (
+        Spirc,
+        Arc<SpircShared>,
+        mpsc::UnboundedReceiver<SpircCommand>,
+    ) {
+        let (commands, received) = mpsc::unbounded_channel();
+        let shared = Arc::new(SpircShared::default());
+        let spirc = Spirc {
+            commands,
+            shared: shared.clone(),
+        };
+        (spirc, shared, received)
+    }
+
+    #[test]
+    fn playback_commands_need_something_loaded_on_the_active_device() {
+        let (spirc, shared, mut received) = spirc();
+
+        // No device is active.
+        assert!(matches!(spirc.play(), Err(SpircError::NothingLoaded)));
+        assert!(matches!(spirc.seek(1000), Err(SpircError::NothingLoaded)));
+        assert!(spirc.set_volume(1000).is_ok());
+        assert!(matches!(
+            received.try_recv(),
+            Ok(SpircCommand::SetVolume(1000))
+        ));
+
+        shared.remote_has_tracks.store(true, Ordering::Relaxed);
+        assert!(spirc.pause().is_ok());
+        assert!(matches!(received.try_recv(), Ok(SpircCommand::Pause)));
+
+        // What another device has loaded doesn't count once this one is active.
+        shared.is_active.store(true, Ordering::Relaxed);
+        assert!(matches!(spirc.next(), Err(SpircError::NothingLoaded)));
+        shared.has_tracks.store(true, Ordering::Relaxed);
+        assert!(spirc.next().is_ok());
+        assert!(matches!(received.try_recv(), Ok(SpircCommand::Next)));
+    }
+
+    #[test]
+    fn skipped_local_files_are_reported() {
+        let local = "spotify:local:Artist:Album:Title:180";
+        let other_local = "spotify:local:Artist:Album:Other:200";
+        let tracks = tracks(&[
+            "spotify:track:4uLU6hMCjMI75M1A2tKUQC",
+            local,
+            "spotify:track:0nrRP2bk19rLc0orkWPQk2",
+            other_local,
+        ]);
+
+        let skipped = |from, to| {
+            skipped_local_files(&tracks, skipped_indexes(tracks.len(), from, to))
+                .map(|track_ref| track_ref.get_uri())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(skipped(1, Some(2)), [local]);
+        assert!(skipped(0, Some(0)).is_empty());
+        // From the last track around to the one that plays.
+        assert_eq!(skipped(3, Some(2)), [other_local, local]);
+        // Nothing is left to play.
+        assert_eq!(skipped(1, None), [local, other_local]);
+    }
+
+    #[test]
+    fn only_tracks_that_are_known_are_remembered_past_the_limit() {
+        let track = |n: u128| SpotifyId::from_raw(&n.to_be_bytes()).unwrap();
+        let known = (0..10).map(track).collect::<HashSet<_>>();
+
+        let mut tracks = (0..MAX_REMEMBERED_TRACKS as u128)
+            .map(track)
+            .collect::<HashSet<_>>();
+        forget_unknown_tracks(&mut tracks, &known);
+        assert_eq!(tracks.len(), MAX_REMEMBERED_TRACKS);
+
+        tracks.insert(track(u128::MAX));
+        forget_unknown_tracks(&mut tracks, &known);
+        assert_eq!(tracks, known);
+    }
+
+    #[test]
+    fn only_allowed_controllers_may_control_a_restricted_device() {
+        let allowed = vec!["allowed".to_string()];
+        assert!(may_control(false, &[], "anyone"));
+        assert!(may_control(false, &allowed, "allowed"));
+        assert!(!may_control(false, &allowed, "anyone"));
+        assert!(!may_control(true, &[], "anyone"));
+        assert!(may_control(true, &allowed, "allowed"));
+        assert!(!may_control(true, &allowed, "anyone"));
+    }
+
+    #[test]
+    fn hidden_device_only_sends_to_allowed_controllers() {
+        let allowed = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(hidden_recipients(&[], &allowed), Some(allowed.clone()));
+        assert_eq!(hidden_recipients(&[], &[]), None);
+
+        let recipients = vec!["b".to_string(), "blocked".to_string()];
+        assert_eq!(
+            hidden_recipients(&recipients, &allowed),
+            Some(vec!["b".to_string()])
+        );
+        assert_eq!(hidden_recipients(&["blocked".to_string()], &allowed), None);
+    }
+
+    #[test]
+    fn playback_stops_for_explicit_tracks_only_if_all_that_is_left_is_explicit() {
+        let track = |n: u128| SpotifyId::from_raw(&n.to_be_bytes()).unwrap();
+        let explicit = [
+            (track(1), TrackFilterReason::Explicit),
+            (track(2), TrackFilterReason::AccountExplicit),
+        ];
+        assert!(stopped_for_explicit(2, &explicit));
+
+        // The third track is unavailable, or a local file.
+        assert!(!stopped_for_explicit(3, &explicit));
+        assert!(!stopped_for_explicit(0, &[]));
+
+        let vetoed = [
+            (track(1), TrackFilterReason::Explicit),
+            (track(2), TrackFilterReason::AutoplayVeto),
+        ];
+        assert!(!stopped_for_explicit(2, &vetoed));
+    }
+
+    #[test]
+    fn audio_type_comes_from_the_uri() {
+        let episode = track_ref_for_uri("spotify:episode:4rOoJ6Egrf8K2IrywzwOMk");
+        let id = spotify_id_for_track(&episode).unwrap();
+        assert_eq!(id.audio_type, SpotifyAudioType::Podcast);
+        assert_eq!(id.to_raw(), episode.get_gid());
+
+        let track = track_ref_for_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC");
+        let id = spotify_id_for_track(&track).unwrap();
+        assert_eq!(id.audio_type, SpotifyAudioType::Track);
+
+        // Connect frames may leave out the uri.
+        let mut gid_only = TrackRef::new();
+        gid_only.set_gid(track.get_gid().to_vec());
+        let id = spotify_id_for_track(&gid_only).unwrap();
+        assert_eq!(id.audio_type, SpotifyAudioType::Track);
+    }
+
+    #[test]
+    fn position_since_start() {
+        assert_eq!(position_since(1_000, 1_000, 1.0), 0);
+        assert_eq!(position_since(1_000, 181_000, 1.0), 180_000);
+    }
+
+    #[test]
+    fn relative_positions() {
+        assert_eq!(offset_position(60_000, -15_000), 45_000);
+        assert_eq!(offset_position(10_000, -15_000), 0);
+        assert_eq!(offset_position(60_000, 30_000), 90_000);
+        assert_eq!(offset_position(u32::MAX - 1, i64::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn position_before_start() {
+        assert_eq!(position_since(1_000, 999, 1.0), 0);
+        assert_eq!(position_since(i64::MAX, 0, 1.0), 0);
+    }
+
+    #[test]
+    fn position_overflow() {
+        assert_eq!(position_since(0, i64::from(u32::MAX) + 1, 1.0), u32::MAX);
+    }
+
+    #[test]
+    fn position_at_speed() {
+        assert_eq!(position_since(1_000, 61_000, 2.0), 120_000);
+        assert_eq!(position_since(1_000, 61_000, 0.5), 30_000);
+    }
+
+    #[test]
+    fn loadable_contexts() {
+        let id = "4uLU6hMCjMI75M1A2tKUQC";
+        for kind in ["playlist", "album", "artist", "show", "track", "episode"] {
+            let uri = format!("spotify:{}:{}", kind, id);
+            assert_eq!(loadable_context_kind(&uri), Some(kind));
+        }
+        let uri = format!("spotify:user:someone:playlist:{}", id);
+        assert_eq!(loadable_context_kind(&uri), Some("playlist"));
+    }
+
+    #[test]
+    fn loadable_contexts_are_checked_by_uri() {
+        assert!(is_loadable_context("spotify:album:4uLU6hMCjMI75M1A2tKUQC"));
+        assert!(is_loadable_context(
+            "spotify:user:someone:playlist:4uLU6hMCjMI75M1A2tKUQC"
+        ));
+        assert!(!is_loadable_context(
+            "spotify:station:4uLU6hMCjMI75M1A2tKUQC"
+        ));
+        assert!(!is_loadable_context(
+            "https://open.spotify.com/album/4uLU6hMCjMI75M1A2tKUQC"
+        ));
+    }
+
+    #[test]
+    fn context_loads_are_sent_to_the_task_and_answered_by_it() {
+        let (spirc, _, mut received) = spirc();
+
+        let invalid = spirc.load_context_at("spotify:station:x", None, 0, true, None);
+        assert!(matches!(
+            invalid.now_or_never(),
+            Some(Err(SpircError::InvalidUri(uri))) if uri == "spotify:station:x"
+        ));
+        assert!(received.try_recv().is_err());
+
+        let uri = "spotify:album:4uLU6hMCjMI75M1A2tKUQC";
+        let loaded = spirc.load_context_at(uri, Some(3), 45_000, false, Some(true));
+        let load = match received.try_recv() {
+            Ok(SpircCommand::LoadContext(load)) => load,
+            _ => panic!("no load was sent"),
+        };
+        assert_eq!(load.uri, uri);
+        assert_eq!(load.start_index, Some(3));
+        assert_eq!(load.start_position_ms, 45_000);
+        assert!(!load.start_playing);
+        assert_eq!(load.shuffle, Some(true));
+        load.reply.send(Ok(())).unwrap();
+        assert!(matches!(loaded.now_or_never(), Some(Ok(()))));
+
+        // A task that goes away without answering has shut down.
+        let unanswered = spirc.load_context_at(uri, None, 0, true, None);
+        drop(received);
+        assert!(matches!(
+            unanswered.now_or_never(),
+            Some(Err(SpircError::Shutdown))
+        ));
+        let after_shutdown = spirc.load_context_at(uri, None, 0, true, None);
+        assert!(matches!(
+            after_shutdown.now_or_never(),
+            Some(Err(SpircError::Shutdown))
+        ));
+    }
+
+    #[test]
+    fn unloadable_contexts() {
+        assert_eq!(
+            loadable_context_kind("spotify:station:4uLU6hMCjMI75M1A2tKUQC"),
+            None
+        );
+        assert_eq!(
+            loadable_context_kind("spotify:album:4uLU6hMCjMI75M1A2tKUQ"),
+            None
+        );
+        assert_eq!(
+            loadable_context_kind("spotify:album:4uLU6hMCjMI75M1A2tKUQ!"),
+            None
+        );
+        assert_eq!(loadable_context_kind("spotify:album"), None);
+        assert_eq!(loadable_context_kind("album:4uLU6hMCjMI75M1A2tKUQC"), None);
+        assert_eq!(loadable_context_kind(""), None);
+    }
+
+    #[test]
+    fn unshuffle_keeps_current_track() {
+        let original = tracks(&["a", "b", "c", "d"]);
+        let shuffled = tracks(&["c", "a", "d", "b"]);
+        let (restored, index) = unshuffle(original, &shuffled, 2, 3..3).unwrap();
+        assert_eq!(uris(&restored), ["a", "b", "c", "d"]);
+        assert_eq!(index, 3);
+    }
+
+    #[test]
+    fn unshuffle_keeps_queue_after_current_track() {
+        let original = tracks(&["a", "b", "c", "d"]);
+        let shuffled = tracks(&["c", "a", "x*", "y*", "d", "b"]);
+        let (restored, index) = unshuffle(original.clone(), &shuffled, 1, 2..4).unwrap();
+        assert_eq!(uris(&restored), ["a", "x", "y", "b", "c", "d"]);
+        assert_eq!(index, 0);
+
+        // Playing a queued track, which goes after the track before it.
+        let (restored, index) = unshuffle(original, &shuffled, 2, 3..4).unwrap();
+        assert_eq!(uris(&restored), ["a", "x", "y", "b", "c", "d"]);
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn queue_follows_current_track() {
+        let tracks = tracks(&["a", "x*", "y*", "b", "z*"]);
+        assert_eq!(queue_range(&tracks, 0), 1..3);
+        assert_eq!(queue_range(&tracks, 1), 2..3);
+        assert_eq!(queue_range(&tracks, 2), 3..3);
+        assert_eq!(queue_range(&tracks, 3), 4..5);
+        assert_eq!(queue_range(&tracks, 4), 5..5);
+    }
+
+    #[test]
+    fn shuffle_keeps_queue_after_current_track() {
+        let mut shuffled = tracks(&["a", "b", "c", "x*", "y*", "d", "e"]);
+        shuffle_tracks(&mut shuffled, 2);
+        assert_eq!(uris(&shuffled[..3]), ["c", "x", "y"]);
+        assert_eq!(queue_range(&shuffled, 0), 1..3);
+
+        let mut rest = uris(&shuffled[3..]);
+        rest.sort_unstable();
+        assert_eq!(rest, ["a", "b", "d", "e"]);
+    }
+
+    #[test]
+    fn unshuffle_keeps_queue_edits() {
+        let original = tracks(&["a", "b", "c", "d"]);
+
+        // "x" was removed from the queue and "z" was added while shuffled.
+        let shuffled = tracks(&["c", "y*", "z*", "a", "d", "b"]);
+        let (restored, index) = unshuffle(original, &shuffled, 0, 1..3).unwrap();
+        assert_eq!(uris(&restored), ["a", "b", "c", "y", "z", "d"]);
+        assert_eq!(index, 2);
+    }
+
+    #[test]
+    fn unshuffle_without_current_track() {
+        let original = tracks(&["a", "b"]);
+        let shuffled = tracks(&["b", "z"]);
+        assert!(unshuffle(original.clone(), &shuffled, 1, 2..2).is_none());
+        assert!(unshuffle(original, &shuffled, 2, 2..2).is_none());
+    }
+
+    #[test]
+    fn volume_steps_are_equal_in_db() {
+        for volume_ctrl in [
+            VolumeCtrl::Linear,
+            VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE),
+            VolumeCtrl::Cubic(VolumeCtrl::DEFAULT_DB_RANGE),
+        ] {
+            let db_range = volume_ctrl.db_range();
+            let mut volume = 0;
+            for step in 1..=10 {
+                volume = stepped_volume(volume_ctrl, 10, VolumeStepSpacing::Db, volume, 1);
+                let db = ratio_to_db(volume_ctrl.to_mapped(volume));
+                let expected = db_range * (step as f64 / 10.0 - 1.0);
+                assert!(
+                    (db - expected).abs() < 0.1,
+                    "{:?} step {} is at {:.2} dB instead of {:.2} dB",
+                    volume_ctrl,
+                    step,
+                    db,
+                    expected
+                );
+            }
+            assert_eq!(volume, VolumeCtrl::MAX_VOLUME);
+        }
+    }
+
+    #[test]
+    fn volume_steps_follow_the_curve() {
+        // The log curve is already linear in dB, more so than the raw volume.
+        let log = VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE);
+        let first = stepped_volume(log, 4, VolumeStepSpacing::Db, 0, 1);
+        let second = stepped_volume(log, 4, VolumeStepSpacing::Db, first, 1);
+        assert!((second as i32 - 2 * first as i32).abs() <= 2);
+
+        let linear = VolumeCtrl::Linear;
+        let first = stepped_volume(linear, 4, VolumeStepSpacing::Db, 0, 1);
+        let second = stepped_volume(linear, 4, VolumeStepSpacing::Db, first, 1);
+        assert!(second > 4 * first);
+    }
+
+    #[test]
+    fn volume_steps_clamp_at_the_ends() {
+        let volume_ctrl = VolumeCtrl::Linear;
+        let max = VolumeCtrl::MAX_VOLUME;
+        assert_eq!(
+            stepped_volume(volume_ctrl, 64, VolumeStepSpacing::Db, max, 1),
+            max
+        );
+        assert_eq!(
+            stepped_volume(volume_ctrl, 64, VolumeStepSpacing::Db, max - 10, 1),
+            max
+        );
+        assert_eq!(
+            stepped_volume(volume_ctrl, 64, VolumeStepSpacing::Db, 0, -1),
+            0
+        );
+        assert_eq!(
+            stepped_volume(volume_ctrl, 64, VolumeStepSpacing::Db, 10, -3),
+            0
+        );
+        assert_eq!(
+            stepped_volume(volume_ctrl, 1, VolumeStepSpacing::Db, 0, 1),
+            max
+        );
+    }
+
+    #[test]
+    fn volume_steps_start_from_the_nearest_step() {
+        let volume_ctrl = VolumeCtrl::Linear;
+        let step = stepped_volume(volume_ctrl, 4, VolumeStepSpacing::Db, 0, 1);
+        assert_eq!(
+            stepped_volume(volume_ctrl, 4, VolumeStepSpacing::Db, step + 100, 1),
+            stepped_volume(volume_ctrl, 4, VolumeStepSpacing::Db, 0, 2)
+        );
+        assert_eq!(
+            stepped_volume(volume_ctrl, 4, VolumeStepSpacing::Db, step + 100, -1),
+            0
+        );
+    }
+
+    #[test]
+    fn linear_volume_steps_are_even_and_snap() {
+        let volume_ctrl = VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE);
+        let linear = VolumeStepSpacing::Linear;
+        let mut volume = 0;
+        for expected in [16384, 32768, 49151, VolumeCtrl::MAX_VOLUME] {
+            volume = stepped_volume(volume_ctrl, 4, linear, volume, 1);
+            assert_eq!(volume, expected);
+        }
+        assert_eq!(quantized_volume(volume_ctrl, 4, linear, 17000), 16384);
+        assert_eq!(quantized_volume(volume_ctrl, 4, linear, 100), 0);
+        assert_eq!(stepped_volume(volume_ctrl, 4, linear, 17000, 1), 32768);
+    }
+
+    #[test]
+    fn restrictions_come_from_the_resolved_context() {
+        let context: ResolvedContext =
+            serde_json::from_str(r#"{"pages": [{"tracks": [{"uri": "spotify:track:a"}]}]}"#)
+                .unwrap();
+        assert_eq!(
+            context.restrictions.to_restrictions(),
+            Restrictions::default()
+        );
+
+        let context: ResolvedContext = serde_json::from_str(
+            r#"{"pages": [], "restrictions": {
+                "disallow_skipping_prev_reasons": ["radio"],
+                "disallow_toggling_repeat_track_reasons": ["radio"],
+                "disallow_seeking_reasons": []
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            context.restrictions.to_restrictions(),
+            Restrictions {
+                can_skip_prev: false,
+                can_repeat: false,
+                ..Restrictions::default()
+            }
+        );
+    }
+
+    #[test]
+    fn restrictions_come_from_the_load_frame() {
+        use crate::protocol::context_player_state::{ContextPlayerState, ContextRestrictions};
+        use protobuf::Message;
+
+        assert_eq!(load_restrictions(&[]), Restrictions::default());
+        assert_eq!(load_restrictions(&[0xff]), Restrictions::default());
+
+        let mut restrictions = ContextRestrictions::new();
+        restrictions
+            .mut_disallow_skipping_next_reasons()
+            .push("ad".to_owned());
+        restrictions
+            .mut_disallow_toggling_shuffle_reasons()
+            .push("ad".to_owned());
+        let mut state = ContextPlayerState::new();
+        state.set_context_uri("spotify:station:a".to_owned());
+        state.set_context_restrictions(restrictions);
+        assert_eq!(
+            load_restrictions(&state.write_to_bytes().unwrap()),
+            Restrictions {
+                can_skip_next: false,
+                can_shuffle: false,
+                ..Restrictions::default()
+            }
+        );
+    }
+
+    #[test]
+    fn resolved_context_has_a_name() {
+        let context: ResolvedContext =
+            serde_json::from_str(r#"{"metadata": {"context_description": "Mix"}, "pages": []}"#)
+                .unwrap();
+        assert_eq!(context.metadata.context_description.as_deref(), Some("Mix"));
+
+        let context = ResolvedContext::single("spotify:track:a");
+        assert_eq!(context.track_refs()[0].get_uri(), "spotify:track:a");
+        assert!(context.metadata.context_description.is_none());
+    }
+
+    #[test]
+    fn device_names_are_trimmed_and_limited() {
+        assert_eq!(
+            valid_device_name(" Kitchen \u{2013} Morning ").unwrap(),
+            "Kitchen \u{2013} Morning"
+        );
+        assert!(valid_device_name("  ").is_err());
+        assert!(valid_device_name(&"\u{e9}".repeat(64)).is_ok());
+        assert!(valid_device_name(&"a".repeat(65)).is_err());
+        assert!(valid_device_name("Kitchen\nDining").is_err());
+    }
+}
@@ -1,13 +1,13 @@
 use std::error::Error;
 
-use hyper::client::HttpConnector;
 use hyper::{Body, Client, Method, Request, Uri};
-use hyper_proxy::{Intercept, Proxy, ProxyConnector};
 use serde::Deserialize;
 use url::Url;
 
+use crate::proxytunnel::ProxiedConnector;
+
 const APRESOLVE_ENDPOINT: &str = "http://apresolve.spotify.com:80";
-const AP_FALLBACK: &str = "ap.spotify.com:443";
+pub(crate) const AP_FALLBACK: &str = "ap.spotify.com:443";
 const AP_BLACKLIST: [&str; 2] = ["ap-gew4.spotify.com", "ap-gue1.spotify.com"];
 
 #[derive(Clone, Debug, Deserialize)]
@@ -18,7 +18,8 @@ struct ApResolveData {
 async fn try_apresolve(
     proxy: Option<&Url>,
     ap_port: Option<u16>,
-) -> Result<String, Box<dyn Error>> {
+    remote_dns: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
     let port = ap_port.unwrap_or(443);
 
     let mut req = Request::new(Body::empty());
@@ -26,19 +27,8 @@ async fn try_apresolve(
     // panic safety: APRESOLVE_ENDPOINT above is valid url.
     *req.uri_mut() = APRESOLVE_ENDPOINT.parse().expect("invalid AP resolve URL");
 
-    let response = if let Some(url) = proxy {
-        // Panic safety: all URLs are valid URIs
-        let uri = url.to_string().parse().unwrap();
-        let proxy = Proxy::new(Intercept::All, uri);
-        let connector = HttpConnector::new();
-        let proxy_connector = ProxyConnector::from_proxy_unsecured(connector, proxy);
-        Client::builder()
-            .build(proxy_connector)
-            .request(req)
-            .await?
-    } else {
-        Client::new().request(req).await?
-    };
+    let connector = ProxiedConnector::new(proxy, remote_dns);
+    let response = Client::builder().build(connector).request(req).await?;
 
     let body = hyper::body::to_bytes(response.into_body()).await?;
     let data: ApResolveData = serde_json::from_slice(body.as_ref())?;
@@ -58,30 +48,31 @@ async fn try_apresolve(
         })
         .collect();
 
-    let ap = if ap_port.is_some() || proxy.is_some() {
+    let aps: Vec<String> = if ap_port.is_some() || proxy.is_some() {
         // filter on ports if specified on the command line...
-        aps.into_iter().find_map(|ap| {
-            if ap.parse::<Uri>().ok()?.port()? == port {
-                Some(ap)
-            } else {
-                None
-            }
-        })
+        aps.into_iter()
+            .filter(|ap| matches!(ap.parse::<Uri>().ok().and_then(|uri| uri.port_u16()), Some(p) if p == port))
+            .collect()
     } else {
-        // ...or pick the first on the list
-        aps.into_iter().next()
+        // ...or take them all, in the order of the list
+        aps
+    };
+
+    if aps.is_empty() {
+        return Err("Unable to resolve any viable access points.".into());
     }
-    .ok_or("Unable to resolve any viable access points.")?;
 
-    Ok(ap)
+    Ok(aps)
 }
 
-pub async fn apresolve(proxy: Option<&Url>, ap_port: Option<u16>) -> String {
-    try_apresolve(proxy, ap_port).await.unwrap_or_else(|e| {
-        warn!("Failed to resolve Access Point: {}", e);
-        warn!("Using fallback \"{}\"", AP_FALLBACK);
-        AP_FALLBACK.into()
-    })
+/// The access points to try in order, which are none when they can't be resolved.
+pub async fn apresolve(proxy: Option<&Url>, ap_port: Option<u16>, remote_dns: bool) -> Vec<String> {
+    try_apresolve(proxy, ap_port, remote_dns)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to resolve Access Point: {}", e);
+            Vec::new()
+        })
 }
 
 #[cfg(test)]
@@ -92,7 +83,7 @@ mod test {
 
     #[tokio::test]
     async fn test_apresolve() {
-        let ap = try_apresolve(None, None).await.unwrap();
+        let ap = try_apresolve(None, None, false).await.unwrap().remove(0);
 
         // Assert that the result contains a valid host and port
         ap.to_socket_addrs().unwrap().next().unwrap();
@@ -100,9 +91,11 @@ mod test {
 
     #[tokio::test]
     async fn test_apresolve_port_443() {
-        let ap = try_apresolve(None, Some(443)).await.unwrap();
+        let aps = try_apresolve(None, Some(443), false).await.unwrap();
 
-        let port = ap.to_socket_addrs().unwrap().next().unwrap().port();
-        assert_eq!(port, 443);
+        for ap in aps {
+            let port = ap.to_socket_addrs().unwrap().next().unwrap().port();
+            assert_eq!(port, 443);
+        }
     }
 }
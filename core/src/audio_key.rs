@@ -1,22 +1,61 @@
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::Write;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::sync::oneshot;
+use tokio::time;
 
 use crate::spotify_id::{FileId, SpotifyId};
-use crate::util::SeqGenerator;
+use crate::util::{self, SeqGenerator};
+
+/// How many of the keys fetched last are kept, so that playing a track again doesn't ask
+/// for its key again.
+pub const AUDIO_KEY_CACHE_SIZE: usize = 64;
 
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
 pub struct AudioKey(pub [u8; 16]);
 
-#[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
-pub struct AudioKeyError;
+#[derive(Debug, Error, Hash, PartialEq, Eq, Copy, Clone)]
+pub enum AudioKeyError {
+    // The server refused the key with an error code, like for tracks that aren't licensed in
+    // the country of the user. Asking again doesn't help.
+    #[error("The audio key was denied with error code {0:#06x}")]
+    Denied(u16),
+    #[error("No audio key came in time")]
+    Timeout,
+    #[error("Could not get audio key")]
+    Failed,
+}
+
+impl AudioKeyError {
+    /// Whether asking for the key again may get it.
+    pub fn is_transient(&self) -> bool {
+        !matches!(self, AudioKeyError::Denied(_))
+    }
+}
+
+// How often a key is asked for, and how long to wait for it each time and in between.
+#[derive(Clone, Copy)]
+struct Retries {
+    attempts: u32,
+    timeout: Duration,
+    backoff: Duration,
+}
+
+const RETRIES: Retries = Retries {
+    attempts: 3,
+    timeout: Duration::from_secs(5),
+    backoff: Duration::from_millis(500),
+};
 
 component! {
     AudioKeyManager : AudioKeyManagerInner {
         sequence: SeqGenerator<u32> = SeqGenerator::new(0),
         pending: HashMap<u32, oneshot::Sender<Result<AudioKey, AudioKeyError>>> = HashMap::new(),
+        // The keys fetched last, the newest at the back.
+        keys: VecDeque<((SpotifyId, FileId), AudioKey)> = VecDeque::new(),
     }
 }
 
@@ -39,24 +78,113 @@ impl AudioKeyManager {
                         data.as_ref()[0],
                         data.as_ref()[1]
                     );
-                    let _ = sender.send(Err(AudioKeyError));
+                    let code = BigEndian::read_u16(data.as_ref());
+                    let _ = sender.send(Err(AudioKeyError::Denied(code)));
                 }
                 _ => (),
             }
         }
     }
 
+    /// The key of the file, from the keys fetched last or else from the server. A request
+    /// that times out is made again a few times, one that is denied isn't.
+    ///
+    /// The request runs on the runtime of the session, so it can be awaited on any thread.
     pub async fn request(&self, track: SpotifyId, file: FileId) -> Result<AudioKey, AudioKeyError> {
-        let (tx, rx) = oneshot::channel();
+        let manager = self.clone();
+        let handle = self.session().runtime_handle().clone();
+        util::run_on(&handle, async move {
+            manager
+                .fetch(track, file, RETRIES, |seq| {
+                    manager.send_key_request(seq, track, file)
+                })
+                .await
+        })
+        .await
+        .unwrap_or(Err(AudioKeyError::Failed))
+    }
 
-        let seq = self.lock(move |inner| {
-            let seq = inner.sequence.get();
-            inner.pending.insert(seq, tx);
-            seq
-        });
+    async fn fetch<F>(
+        &self,
+        track: SpotifyId,
+        file: FileId,
+        retries: Retries,
+        mut send: F,
+    ) -> Result<AudioKey, AudioKeyError>
+    where
+        F: FnMut(u32),
+    {
+        if let Some(key) = self.cached(track, file) {
+            return Ok(key);
+        }
+
+        let mut backoff = retries.backoff;
+        let mut attempt = 1;
+        loop {
+            let (tx, rx) = oneshot::channel();
+            let seq = self.lock(move |inner| {
+                let seq = inner.sequence.get();
+                inner.pending.insert(seq, tx);
+                seq
+            });
+
+            send(seq);
+            let result = match time::timeout(retries.timeout, rx).await {
+                Ok(result) => result.unwrap_or(Err(AudioKeyError::Failed)),
+                Err(_) => {
+                    // A key that still comes in for it is ignored.
+                    self.lock(|inner| inner.pending.remove(&seq));
+                    Err(AudioKeyError::Timeout)
+                }
+            };
+
+            match result {
+                Ok(key) => {
+                    self.remember(track, file, key);
+                    return Ok(key);
+                }
+                // A request that failed because the session is gone isn't made again.
+                Err(e @ AudioKeyError::Timeout) if attempt < retries.attempts => {
+                    warn!(
+                        "Audio key request {} of {} for {} failed: {}, trying again in {:?}",
+                        attempt,
+                        retries.attempts,
+                        track.to_base62().unwrap_or_default(),
+                        e,
+                        backoff
+                    );
+                    time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn cached(&self, track: SpotifyId, file: FileId) -> Option<AudioKey> {
+        self.lock(|inner| {
+            inner
+                .keys
+                .iter()
+                .find(|(id, _)| *id == (track, file))
+                .map(|(_, key)| *key)
+        })
+    }
+
+    fn remember(&self, track: SpotifyId, file: FileId, key: AudioKey) {
+        self.lock(|inner| {
+            inner.keys.retain(|(id, _)| *id != (track, file));
+            if inner.keys.len() >= AUDIO_KEY_CACHE_SIZE {
+                inner.keys.pop_front();
+            }
+            inner.keys.push_back(((track, file), key));
+        })
+    }
 
-        self.send_key_request(seq, track, file);
-        rx.await.map_err(|_| AudioKeyError)?
+    // Fails the requests that are still waiting for their key.
+    pub(crate) fn shutdown(&self) {
+        self.lock(|inner| inner.pending.clear());
     }
 
     fn send_key_request(&self, seq: u32, track: SpotifyId, file: FileId) {
@@ -69,3 +197,94 @@ impl AudioKeyManager {
         self.session().send_packet(0xc, data)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::SessionWeak;
+
+    const FAST: Retries = Retries {
+        attempts: 3,
+        timeout: Duration::from_millis(50),
+        backoff: Duration::from_millis(1),
+    };
+
+    fn response(cmd: u8, seq: u32, body: &[u8]) -> (u8, Bytes) {
+        let mut packet = seq.to_be_bytes().to_vec();
+        packet.extend_from_slice(body);
+        (cmd, Bytes::from(packet))
+    }
+
+    #[tokio::test]
+    async fn flaky_key_requests_are_retried_and_cached() {
+        let manager = AudioKeyManager::new(SessionWeak::dead());
+        let track = SpotifyId::from_raw(&[1; 16]).unwrap();
+        let file = FileId([2; 20]);
+
+        // The first request goes unanswered, the second gets the key.
+        let mut requests = 0;
+        let responder = manager.clone();
+        let key = manager
+            .fetch(track, file, FAST, |seq| {
+                requests += 1;
+                if requests == 2 {
+                    let (cmd, data) = response(0xd, seq, &[7; 16]);
+                    responder.dispatch(cmd, data);
+                }
+            })
+            .await;
+        assert_eq!(key, Ok(AudioKey([7; 16])));
+        assert_eq!(requests, 2);
+
+        // Asking again doesn't make a request.
+        let key = manager.fetch(track, file, FAST, |_| unreachable!()).await;
+        assert_eq!(key, Ok(AudioKey([7; 16])));
+
+        // A denied key isn't asked for again.
+        let other = FileId([3; 20]);
+        let mut requests = 0;
+        let key = manager
+            .fetch(track, other, FAST, |seq| {
+                requests += 1;
+                let (cmd, data) = response(0xe, seq, &[0, 1]);
+                responder.dispatch(cmd, data);
+            })
+            .await;
+        assert_eq!(key, Err(AudioKeyError::Denied(1)));
+        assert_eq!(requests, 1);
+    }
+
+    #[test]
+    fn keys_can_be_requested_outside_of_the_runtime() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        let manager = AudioKeyManager::new(SessionWeak::dead());
+        let track = SpotifyId::from_raw(&[1; 16]).unwrap();
+        let file = FileId([2; 20]);
+
+        // Like the threads that load tracks, with a request that times out and is made again.
+        let (result_tx, result_rx) = oneshot::channel();
+        std::thread::spawn(move || {
+            let key = futures_executor::block_on(util::run_on(&handle, async move {
+                let responder = manager.clone();
+                let mut requests = 0;
+                manager
+                    .fetch(track, file, FAST, |seq| {
+                        requests += 1;
+                        if requests == 2 {
+                            let (cmd, data) = response(0xd, seq, &[7; 16]);
+                            responder.dispatch(cmd, data);
+                        }
+                    })
+                    .await
+            }));
+            let _ = result_tx.send(key);
+        });
+
+        let key = runtime.block_on(result_rx).unwrap();
+        assert_eq!(key, Some(Ok(AudioKey([7; 16]))));
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io::{self, Read};
 
 use aes::Aes192;
@@ -9,9 +10,10 @@ use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
 use crate::protocol::authentication::AuthenticationType;
+use crate::redact::Redacted;
 
 /// The credentials are used to log into the Spotify API.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Credentials {
     pub username: String,
 
@@ -25,6 +27,17 @@ pub struct Credentials {
     pub auth_data: Vec<u8>,
 }
 
+// The password, token or blob is left out of the log.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("auth_type", &self.auth_type)
+            .field("auth_data", &Redacted(&self.auth_data))
+            .finish()
+    }
+}
+
 impl Credentials {
     /// Intialize these credentials from a username and a password.
     ///
@@ -42,6 +55,16 @@ impl Credentials {
         }
     }
 
+    /// Log in with an OAuth access token, for the `streaming` scope. The username is the one
+    /// the token is for.
+    pub fn with_access_token(token: impl Into<String>) -> Credentials {
+        Credentials {
+            username: String::new(),
+            auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+            auth_data: token.into().into_bytes(),
+        }
+    }
+
     pub fn with_blob(
         username: impl Into<String>,
         encrypted_blob: impl AsRef<[u8]>,
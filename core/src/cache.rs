@@ -1,16 +1,102 @@
 use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{self, Error, ErrorKind, Read, Write};
+use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_ctr::cipher::generic_array::GenericArray;
+use aes_ctr::cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes128Ctr;
+use hmac::{Hmac, Mac, NewMac};
+use pbkdf2::pbkdf2;
 use priority_queue::PriorityQueue;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 
 use crate::authentication::Credentials;
 use crate::spotify_id::FileId;
 
+// What the files next to a cached audio file end with, that hold what it is checked against
+// and that it is written to before it is complete.
+const CHECK_EXTENSION: &str = "check";
+const PARTIAL_EXTENSION: &str = "partial";
+
+// How much of the beginning and of the end of a cached audio file is checksummed.
+const CHECK_BLOCK_SIZE: u64 = 64 * 1024;
+
+// What a cached audio file is checked against when it is opened, as it may have been cut
+// short, like by an unclean shutdown.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FileCheck {
+    size: u64,
+    // SHA-1 of the first and the last `CHECK_BLOCK_SIZE` bytes, base64.
+    head: String,
+    tail: String,
+}
+
+impl FileCheck {
+    fn of(file: &mut File, size: u64) -> io::Result<Self> {
+        let mut block = vec![0u8; CHECK_BLOCK_SIZE.min(size) as usize];
+
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut block)?;
+        let head = base64::encode(Sha1::digest(&block));
+
+        file.seek(SeekFrom::Start(size - block.len() as u64))?;
+        file.read_exact(&mut block)?;
+        let tail = base64::encode(Sha1::digest(&block));
+
+        Ok(Self { size, head, tail })
+    }
+
+    // Files that were cached before they were checked aren't checked.
+    fn verify(file: &mut File, check_path: &Path) -> io::Result<()> {
+        let expected: Self = match fs::read(check_path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let size = file.metadata()?.len();
+        if size != expected.size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("it has {} bytes instead of {}", size, expected.size),
+            ));
+        }
+
+        if Self::of(file, size)? != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "its checksum doesn't match",
+            ));
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+fn check_path(path: &Path) -> PathBuf {
+    path.with_extension(CHECK_EXTENSION)
+}
+
+// Removes the file that a cached audio file is checked against, if there is one.
+fn remove_check(path: &Path) {
+    match fs::remove_file(check_path(path)) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => warn!(
+            "Could not remove checksum of {:?} from cache dir: {}",
+            path, e
+        ),
+    }
+}
+
 /// Some kind of data structure that holds some paths, the size of these files and a timestamp.
 /// It keeps track of the file sizes and is able to pop the path with the oldest timestamp if
 /// a given limit is exceeded.
@@ -19,6 +105,10 @@ struct SizeLimiter {
     sizes: HashMap<PathBuf, u64>,
     size_limit: u64,
     in_use: u64,
+    // How many times each file is kept from being popped, see `pin`.
+    pinned: HashMap<PathBuf, usize>,
+    // How many files were popped and removed.
+    evictions: u64,
 }
 
 impl SizeLimiter {
@@ -29,6 +119,8 @@ impl SizeLimiter {
             sizes: HashMap::new(),
             size_limit: limit,
             in_use: 0,
+            pinned: HashMap::new(),
+            evictions: 0,
         }
     }
 
@@ -45,31 +137,65 @@ impl SizeLimiter {
         }
     }
 
+    /// Adds an entry that was found on disk, unless the file is known already, in which case
+    /// what is known is newer.
+    fn add_existing(&mut self, file: &Path, size: u64, accessed: SystemTime) {
+        if !self.sizes.contains_key(file) {
+            self.add(file, size, accessed);
+        }
+    }
+
+    /// Keeps the file from being popped until it is unpinned as many times as it was pinned.
+    fn pin(&mut self, file: &Path) {
+        *self.pinned.entry(file.to_owned()).or_insert(0) += 1;
+    }
+
+    fn unpin(&mut self, file: &Path) {
+        if let Some(count) = self.pinned.get_mut(file) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(file);
+            }
+        }
+    }
+
     /// Returns true if the limit is exceeded.
     fn exceeds_limit(&self) -> bool {
         self.in_use > self.size_limit
     }
 
-    /// Returns the least recently accessed file if the size of the cache exceeds
-    /// the limit.
+    /// Returns the least recently accessed file that isn't pinned if the size of the cache
+    /// exceeds the limit.
     ///
     /// The entry is removed from the data structure, but the caller is responsible
     /// to delete the file in the file system.
     fn pop(&mut self) -> Option<PathBuf> {
-        if self.exceeds_limit() {
-            let (next, _) = self
-                .queue
-                .pop()
-                .expect("in_use was > 0, so the queue should have contained an item.");
-            let size = self
-                .sizes
-                .remove(&next)
-                .expect("`queue` and `sizes` should have the same keys.");
-            self.in_use -= size;
-            Some(next)
-        } else {
-            None
+        let mut pinned = Vec::new();
+        let mut next = None;
+
+        while self.exceeds_limit() {
+            match self.queue.pop() {
+                Some((file, accessed)) if self.pinned.contains_key(&file) => {
+                    pinned.push((file, accessed))
+                }
+                Some((file, _)) => {
+                    let size = self
+                        .sizes
+                        .remove(&file)
+                        .expect("`queue` and `sizes` should have the same keys.");
+                    self.in_use -= size;
+                    next = Some(file);
+                    break;
+                }
+                // Only pinned files are left.
+                None => break,
+            }
+        }
+
+        for (file, accessed) in pinned {
+            self.queue.push(file, accessed);
         }
+        next
     }
 
     /// Updates the timestamp of an existing element. Returns `true` if the item did exist.
@@ -97,6 +223,9 @@ impl SizeLimiter {
 
 struct FsSizeLimiter {
     limiter: Mutex<SizeLimiter>,
+    // The files that are in the cache directory already are looked for in the background,
+    // and nothing is removed before they are all known.
+    scanning: AtomicBool,
 }
 
 impl FsSizeLimiter {
@@ -121,7 +250,10 @@ impl FsSizeLimiter {
     }
 
     /// Recursively search a directory for files and add them to the `limiter` struct.
-    fn init_dir(limiter: &mut SizeLimiter, path: &Path) {
+    ///
+    /// Incomplete files that weren't written to since `scan_start` are removed. The others may
+    /// still be downloaded to.
+    fn init_dir(&self, path: &Path, scan_start: SystemTime) {
         let list_dir = match fs::read_dir(path) {
             Ok(list_dir) => list_dir,
             Err(e) => {
@@ -141,13 +273,30 @@ impl FsSizeLimiter {
 
             match entry.file_type() {
                 Ok(file_type) if file_type.is_dir() || file_type.is_symlink() => {
-                    Self::init_dir(limiter, &entry.path())
+                    self.init_dir(&entry.path(), scan_start)
                 }
                 Ok(file_type) if file_type.is_file() => {
                     let path = entry.path();
+                    let extension = path.extension().and_then(|e| e.to_str());
+                    if extension == Some(CHECK_EXTENSION) {
+                        continue;
+                    }
+                    if extension == Some(PARTIAL_EXTENSION) {
+                        let modified = path.metadata().and_then(|metadata| metadata.modified());
+                        if matches!(modified, Ok(modified) if modified < scan_start) {
+                            // Left behind by a download that was cut short.
+                            debug!("Removing incomplete file {:?} from cache dir", path);
+                            let _ = fs::remove_file(&path);
+                        }
+                        continue;
+                    }
+
                     match Self::get_metadata(&path) {
                         Ok((access_time, size)) => {
-                            limiter.add(&path, size, access_time);
+                            self.limiter
+                                .lock()
+                                .unwrap()
+                                .add_existing(&path, size, access_time);
                         }
                         Err(e) => {
                             warn!("Could not read file {:?} in cache dir: {}", path, e)
@@ -187,7 +336,7 @@ impl FsSizeLimiter {
         self.limiter.lock().unwrap().remove(file);
     }
 
-    fn prune_internal<F: FnMut() -> Option<PathBuf>>(mut pop: F) {
+    fn prune_internal<F: FnMut() -> Option<PathBuf>>(mut pop: F) -> u64 {
         let mut first = true;
         let mut count = 0;
 
@@ -200,6 +349,7 @@ impl FsSizeLimiter {
             if let Err(e) = fs::remove_file(&file) {
                 warn!("Could not remove file {:?} from cache dir: {}", file, e);
             } else {
+                remove_check(&file);
                 count += 1;
             }
         }
@@ -207,31 +357,214 @@ impl FsSizeLimiter {
         if count > 0 {
             info!("Removed {} cache files.", count);
         }
+        count
     }
 
     fn prune(&self) {
-        Self::prune_internal(|| self.limiter.lock().unwrap().pop())
+        if self.scanning.load(Ordering::Acquire) {
+            return;
+        }
+
+        let count = Self::prune_internal(|| self.limiter.lock().unwrap().pop());
+        self.limiter.lock().unwrap().evictions += count;
     }
 
-    fn new(path: &Path, limit: u64) -> Self {
-        let mut limiter = SizeLimiter::new(limit);
+    fn scan(&self, path: &Path) {
+        self.init_dir(path, SystemTime::now());
+        self.scanning.store(false, Ordering::Release);
 
-        Self::init_dir(&mut limiter, path);
-        Self::prune_internal(|| limiter.pop());
+        let (files, size) = {
+            let limiter = self.limiter.lock().unwrap();
+            (limiter.sizes.len(), limiter.in_use)
+        };
+        debug!("Audio cache holds {} files of {} bytes", files, size);
+        self.prune();
+    }
 
-        Self {
-            limiter: Mutex::new(limiter),
+    // The files in the cache directory are looked for on a thread of its own, as that can
+    // take a while for a big cache.
+    fn new(path: &Path, limit: u64) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            limiter: Mutex::new(SizeLimiter::new(limit)),
+            scanning: AtomicBool::new(true),
+        });
+
+        let scanner = limiter.clone();
+        let scan_path = path.to_owned();
+        let spawned = thread::Builder::new()
+            .name("cache-scan".to_string())
+            .spawn(move || scanner.scan(&scan_path));
+        if let Err(e) = spawned {
+            warn!("Cannot scan the audio cache in the background: {}", e);
+            limiter.scan(path);
         }
+
+        limiter
+    }
+
+    fn stats(&self, stats: &mut CacheStats) {
+        let limiter = self.limiter.lock().unwrap();
+        stats.size = limiter.in_use;
+        stats.files = limiter.sizes.len();
+        stats.size_limit = Some(limiter.size_limit);
+        stats.evictions = limiter.evictions;
+        stats.scanning = self.scanning.load(Ordering::Acquire);
+    }
+}
+
+/// The usage of the audio file cache, see [`Cache::stats`]. The files and their size are
+/// only counted when the cache has a size limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The size of the cached files, in bytes.
+    pub size: u64,
+    pub files: usize,
+    pub size_limit: Option<u64>,
+    /// How many files were removed to keep the cache within its limit.
+    pub evictions: u64,
+    /// How many files were removed because they were corrupt, to be fetched again.
+    pub corrupt_files: u64,
+    /// Whether the files that were in the cache already are still being looked for, which
+    /// are then not all counted yet.
+    pub scanning: bool,
+}
+
+/// Keeps a file in the cache from being removed to stay within the size limit, for as long
+/// as it is kept. See [`Cache::pin_file`].
+pub struct PinnedFile {
+    limiter: Arc<FsSizeLimiter>,
+    path: PathBuf,
+}
+
+impl Drop for PinnedFile {
+    fn drop(&mut self) {
+        self.limiter.limiter.lock().unwrap().unpin(&self.path);
+    }
+}
+
+// The access points that worked last, the one that connected first.
+#[derive(Serialize, Deserialize)]
+struct CachedAccessPoints {
+    access_points: Vec<String>,
+    // Seconds since the epoch.
+    saved_at: u64,
+}
+
+// How many rounds of PBKDF2 the key of encrypted credentials is derived from the passphrase
+// with.
+const CREDENTIALS_KDF_ROUNDS: u32 = 100_000;
+
+// The most rounds a credentials file is trusted to ask for, so that a corrupted one can't keep
+// startup busy for hours.
+const MAX_CREDENTIALS_KDF_ROUNDS: u32 = 10 * CREDENTIALS_KDF_ROUNDS;
+
+// Credentials encrypted with AES-128-CTR and authenticated with HMAC-SHA1, with keys derived
+// from a passphrase. The binary fields are base64.
+#[derive(Serialize, Deserialize)]
+struct EncryptedCredentials {
+    rounds: u32,
+    salt: String,
+    iv: String,
+    data: String,
+    mac: String,
+}
+
+type HmacSha1 = Hmac<Sha1>;
+
+fn credentials_keys(passphrase: &str, salt: &[u8], rounds: u32) -> ([u8; 16], [u8; 20]) {
+    let mut keys = [0u8; 36];
+    pbkdf2::<HmacSha1>(passphrase.as_bytes(), salt, rounds, &mut keys);
+
+    let mut cipher_key = [0u8; 16];
+    let mut mac_key = [0u8; 20];
+    cipher_key.copy_from_slice(&keys[..16]);
+    mac_key.copy_from_slice(&keys[16..]);
+    (cipher_key, mac_key)
+}
+
+fn credentials_mac(mac_key: &[u8], iv: &[u8], data: &[u8]) -> HmacSha1 {
+    let mut mac = HmacSha1::new_from_slice(mac_key).expect("HMAC can take key of any size");
+    mac.update(iv);
+    mac.update(data);
+    mac
+}
+
+fn encrypt_credentials(plain: &[u8], passphrase: &str, rounds: u32) -> EncryptedCredentials {
+    let mut salt = [0u8; 16];
+    let mut iv = [0u8; 16];
+    rand::thread_rng().fill(&mut salt);
+    rand::thread_rng().fill(&mut iv);
+
+    let (cipher_key, mac_key) = credentials_keys(passphrase, &salt, rounds);
+    let mut data = plain.to_vec();
+    Aes128Ctr::new(
+        GenericArray::from_slice(&cipher_key),
+        GenericArray::from_slice(&iv),
+    )
+    .apply_keystream(&mut data);
+    let mac = credentials_mac(&mac_key, &iv, &data)
+        .finalize()
+        .into_bytes();
+
+    EncryptedCredentials {
+        rounds,
+        salt: base64::encode(salt),
+        iv: base64::encode(iv),
+        data: base64::encode(data),
+        mac: base64::encode(mac),
+    }
+}
+
+// Fails with `ErrorKind::InvalidData` for a wrong passphrase as well as for corrupted data,
+// which can't be told apart.
+fn decrypt_credentials(encrypted: &EncryptedCredentials, passphrase: &str) -> io::Result<Vec<u8>> {
+    let decode =
+        |field: &str| base64::decode(field).map_err(|e| Error::new(ErrorKind::InvalidData, e));
+    let salt = decode(&encrypted.salt)?;
+    let iv = decode(&encrypted.iv)?;
+    let mut data = decode(&encrypted.data)?;
+    let mac = decode(&encrypted.mac)?;
+    if iv.len() != 16 {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid IV"));
+    }
+    if !(1..=MAX_CREDENTIALS_KDF_ROUNDS).contains(&encrypted.rounds) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid number of rounds: {}", encrypted.rounds),
+        ));
     }
+
+    let (cipher_key, mac_key) = credentials_keys(passphrase, &salt, encrypted.rounds);
+    credentials_mac(&mac_key, &iv, &data)
+        .verify(&mac)
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "wrong passphrase, or corrupted credentials",
+            )
+        })?;
+
+    Aes128Ctr::new(
+        GenericArray::from_slice(&cipher_key),
+        GenericArray::from_slice(&iv),
+    )
+    .apply_keystream(&mut data);
+    Ok(data)
 }
 
-/// A cache for volume, credentials and audio files.
+/// A cache for volume, credentials, access points and audio files.
 #[derive(Clone)]
 pub struct Cache {
     credentials_location: Option<PathBuf>,
+    encrypted_credentials_location: Option<PathBuf>,
+    // Credentials are kept encrypted with a key derived from this, if it is set.
+    credentials_passphrase: Option<String>,
+    access_points_location: Option<PathBuf>,
     volume_location: Option<PathBuf>,
     audio_location: Option<PathBuf>,
     size_limiter: Option<Arc<FsSizeLimiter>>,
+    // How many audio files were found to be corrupt.
+    corrupt_files: Arc<AtomicU64>,
 }
 
 pub struct RemoveFileError(());
@@ -252,6 +585,12 @@ impl Cache {
         let credentials_location = credentials_path
             .as_ref()
             .map(|p| p.as_ref().join("credentials.json"));
+        let encrypted_credentials_location = credentials_path
+            .as_ref()
+            .map(|p| p.as_ref().join("credentials.enc.json"));
+        let access_points_location = credentials_path
+            .as_ref()
+            .map(|p| p.as_ref().join("access_points.json"));
 
         if let Some(location) = &volume_path {
             fs::create_dir_all(location)?;
@@ -263,9 +602,7 @@ impl Cache {
             fs::create_dir_all(location)?;
 
             if let Some(limit) = size_limit {
-                let limiter = FsSizeLimiter::new(location.as_ref(), limit);
-
-                size_limiter = Some(Arc::new(limiter));
+                size_limiter = Some(FsSizeLimiter::new(location.as_ref(), limit));
             }
         }
 
@@ -273,15 +610,65 @@ impl Cache {
 
         let cache = Cache {
             credentials_location,
+            encrypted_credentials_location,
+            credentials_passphrase: None,
+            access_points_location,
             volume_location,
             audio_location,
             size_limiter,
+            corrupt_files: Arc::new(AtomicU64::new(0)),
         };
 
         Ok(cache)
     }
 
+    /// Keeps the credentials encrypted with a key derived from `passphrase`, instead of in
+    /// plain text. Credentials that were cached in plain text before are encrypted the first
+    /// time they are read, and the plain text file is removed.
+    pub fn with_credentials_passphrase(mut self, passphrase: impl Into<String>) -> Self {
+        self.credentials_passphrase = Some(passphrase.into());
+        self
+    }
+
     pub fn credentials(&self) -> Option<Credentials> {
+        match &self.credentials_passphrase {
+            Some(passphrase) => self.encrypted_credentials(passphrase),
+            None => self.plain_credentials(),
+        }
+    }
+
+    fn encrypted_credentials(&self, passphrase: &str) -> Option<Credentials> {
+        let location = self.encrypted_credentials_location.as_ref()?;
+
+        let read = || {
+            let mut file = File::open(location)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            let encrypted: EncryptedCredentials = serde_json::from_str(&contents)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let plain = decrypt_credentials(&encrypted, passphrase)?;
+            serde_json::from_slice(&plain).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        };
+
+        match read() {
+            Ok(c) => Some(c),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let credentials = self.plain_credentials()?;
+                info!("Encrypting the cached credentials");
+                self.save_credentials(&credentials);
+                Some(credentials)
+            }
+            Err(e) => {
+                warn!(
+                    "Cannot decrypt the cached credentials, they are ignored and have to be given again: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn plain_credentials(&self) -> Option<Credentials> {
         let location = self.credentials_location.as_ref()?;
 
         // This closure is just convencience to enable the question mark operator
@@ -306,7 +693,9 @@ impl Cache {
     }
 
     pub fn save_credentials(&self, cred: &Credentials) {
-        if let Some(location) = &self.credentials_location {
+        if let Some(passphrase) = &self.credentials_passphrase {
+            self.save_encrypted_credentials(cred, passphrase);
+        } else if let Some(location) = &self.credentials_location {
             let result = File::create(location).and_then(|mut file| {
                 let data = serde_json::to_string(cred)?;
                 write!(file, "{}", data)
@@ -318,6 +707,88 @@ impl Cache {
         }
     }
 
+    fn save_encrypted_credentials(&self, cred: &Credentials, passphrase: &str) {
+        let location = match &self.encrypted_credentials_location {
+            Some(location) => location,
+            None => return,
+        };
+
+        let result = serde_json::to_vec(cred)
+            .map(|plain| encrypt_credentials(&plain, passphrase, CREDENTIALS_KDF_ROUNDS))
+            .and_then(|encrypted| serde_json::to_string(&encrypted))
+            .map_err(io::Error::from)
+            .and_then(|data| File::create(location).and_then(|mut file| write!(file, "{}", data)));
+        if let Err(e) = result {
+            warn!("Cannot save credentials to cache: {}", e);
+            return;
+        }
+
+        // They are no longer needed in plain text.
+        if let Some(plain_location) = &self.credentials_location {
+            match fs::remove_file(plain_location) {
+                Ok(()) => info!("Removed the plain text credentials from the cache"),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => warn!(
+                    "Cannot remove the plain text credentials from the cache: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    /// The access points saved with `save_access_points`, unless that was more than `max_age`
+    /// ago.
+    pub fn access_points(&self, max_age: Duration) -> Option<Vec<String>> {
+        let location = self.access_points_location.as_ref()?;
+
+        let read = || {
+            let mut file = File::open(location)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            serde_json::from_str::<CachedAccessPoints>(&contents)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        };
+
+        match read() {
+            Ok(cached) => {
+                let saved_at = UNIX_EPOCH + Duration::from_secs(cached.saved_at);
+                match SystemTime::now().duration_since(saved_at) {
+                    Ok(age) if age > max_age => {
+                        debug!("The cached access points are out of date");
+                        None
+                    }
+                    _ => Some(cached.access_points),
+                }
+            }
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Error reading access points from cache: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn save_access_points(&self, access_points: &[String]) {
+        if let Some(location) = &self.access_points_location {
+            let cached = CachedAccessPoints {
+                access_points: access_points.to_vec(),
+                saved_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or_default(),
+            };
+            let result = File::create(location).and_then(|mut file| {
+                let data = serde_json::to_string(&cached)?;
+                write!(file, "{}", data)
+            });
+
+            if let Err(e) = result {
+                warn!("Cannot save access points to cache: {}", e)
+            }
+        }
+    }
+
     pub fn volume(&self) -> Option<u16> {
         let location = self.volume_location.as_ref()?;
 
@@ -350,6 +821,10 @@ impl Cache {
         }
     }
 
+    pub fn caches_audio(&self) -> bool {
+        self.audio_location.is_some()
+    }
+
     fn file_path(&self, file: FileId) -> Option<PathBuf> {
         match file.to_base16() {
             Ok(name) => self.audio_location.as_ref().map(|location| {
@@ -364,14 +839,24 @@ impl Cache {
         }
     }
 
+    /// The cached file, which is removed instead if it is corrupt, so that it is fetched
+    /// again.
     pub fn file(&self, file: FileId) -> Option<File> {
         let path = self.file_path(file)?;
         match File::open(&path) {
-            Ok(file) => {
+            Ok(mut opened) => {
+                if let Err(e) = FileCheck::verify(&mut opened, &check_path(&path)) {
+                    warn!("Cached file {} is corrupt, removing it: {}", file, e);
+                    self.corrupt_files.fetch_add(1, Ordering::Relaxed);
+                    drop(opened);
+                    let _ = self.remove_file(file);
+                    return None;
+                }
+
                 if let Some(limiter) = self.size_limiter.as_deref() {
                     limiter.touch(&path);
                 }
-                Some(file)
+                Some(opened)
             }
             Err(e) => {
                 if e.kind() != ErrorKind::NotFound {
@@ -389,10 +874,36 @@ impl Cache {
             return;
         };
         let parent = path.parent().unwrap();
+        let partial = path.with_extension(PARTIAL_EXTENSION);
 
+        // The file only gets its name once it is complete, and what it is checked against
+        // after that.
         let result = fs::create_dir_all(parent)
-            .and_then(|_| File::create(&path))
-            .and_then(|mut file| io::copy(contents, &mut file));
+            .and_then(|_| {
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&partial)
+            })
+            .and_then(|mut file| {
+                let size = io::copy(contents, &mut file)?;
+                file.sync_all()?;
+                let check = FileCheck::of(&mut file, size)?;
+                Ok((size, check))
+            })
+            .and_then(|(size, check)| {
+                fs::rename(&partial, &path)?;
+                let check = serde_json::to_vec(&check)?;
+                fs::write(check_path(&path), check)?;
+                Ok(size)
+            });
+
+        if let Err(e) = &result {
+            warn!("Cannot save file {} to cache: {}", file, e);
+            let _ = fs::remove_file(&partial);
+        }
 
         if let Ok(size) = result {
             if let Some(limiter) = self.size_limiter.as_deref() {
@@ -402,6 +913,35 @@ impl Cache {
         }
     }
 
+    /// Keeps the file from being removed to stay within the size limit while the returned
+    /// guard is kept, like for files that are played or preloaded. `None` if the cache has
+    /// no size limit.
+    pub fn pin_file(&self, file: FileId) -> Option<PinnedFile> {
+        let limiter = self.size_limiter.as_ref()?;
+        let path = self.file_path(file)?;
+        limiter.limiter.lock().unwrap().pin(&path);
+        Some(PinnedFile {
+            limiter: limiter.clone(),
+            path,
+        })
+    }
+
+    /// The usage of the audio file cache, if audio files are cached.
+    pub fn stats(&self) -> Option<CacheStats> {
+        if !self.caches_audio() {
+            return None;
+        }
+
+        let mut stats = CacheStats {
+            corrupt_files: self.corrupt_files.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        if let Some(limiter) = self.size_limiter.as_deref() {
+            limiter.stats(&mut stats);
+        }
+        Some(stats)
+    }
+
     pub fn remove_file(&self, file: FileId) -> Result<(), RemoveFileError> {
         let path = self.file_path(file).ok_or(RemoveFileError(()))?;
 
@@ -409,6 +949,7 @@ impl Cache {
             warn!("Unable to remove file from cache: {}", err);
             Err(RemoveFileError(()))
         } else {
+            remove_check(&path);
             if let Some(limiter) = self.size_limiter.as_deref() {
                 limiter.remove(&path);
             }
@@ -467,4 +1008,148 @@ mod test {
         assert!(limiter.remove(Path::new("c")));
         assert!(!limiter.exceeds_limit());
     }
+
+    #[test]
+    fn pinned_files_are_not_evicted() {
+        let mut limiter = SizeLimiter::new(1000);
+
+        limiter.add(Path::new("a"), 500, ordered_time(1));
+        limiter.add(Path::new("b"), 500, ordered_time(2));
+        limiter.add(Path::new("c"), 500, ordered_time(3));
+        limiter.pin(Path::new("a"));
+        limiter.pin(Path::new("b"));
+
+        // a and b are older, but pinned.
+        assert_eq!(limiter.pop().as_deref(), Some(Path::new("c")));
+        assert_eq!(limiter.pop().as_deref(), None);
+
+        limiter.add(Path::new("d"), 500, ordered_time(4));
+        assert_eq!(limiter.pop().as_deref(), Some(Path::new("d")));
+
+        // Files found on disk don't replace what is known of them.
+        limiter.add_existing(Path::new("a"), 700, ordered_time(0));
+        assert_eq!(limiter.in_use, 1000);
+
+        limiter.unpin(Path::new("a"));
+        limiter.add(Path::new("e"), 500, ordered_time(5));
+        assert_eq!(limiter.pop().as_deref(), Some(Path::new("a")));
+        assert_eq!(limiter.pop().as_deref(), None);
+    }
+
+    #[test]
+    fn encrypted_credentials_need_the_passphrase() {
+        let plain = br#"{"username":"user","auth_type":1,"auth_data":"c2VjcmV0"}"#;
+        let encrypted = encrypt_credentials(plain, "passphrase", 16);
+        assert!(!encrypted.data.contains("c2VjcmV0"));
+        assert_eq!(
+            decrypt_credentials(&encrypted, "passphrase").unwrap(),
+            plain.to_vec()
+        );
+
+        let error = decrypt_credentials(&encrypted, "wrong").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let mut corrupted = encrypted;
+        corrupted.data = base64::encode(b"something else");
+        assert!(decrypt_credentials(&corrupted, "passphrase").is_err());
+
+        for rounds in [0, MAX_CREDENTIALS_KDF_ROUNDS + 1] {
+            let mut encrypted = encrypt_credentials(plain, "passphrase", 16);
+            encrypted.rounds = rounds;
+            let error = decrypt_credentials(&encrypted, "passphrase").unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn plain_credentials_are_encrypted_when_read() {
+        let dir = std::env::temp_dir().join(format!("librespot-creds-{}", std::process::id()));
+        let plain = Cache::new(Some(&dir), None, None, None).unwrap();
+        let credentials = Credentials::with_password("user", "secret");
+        plain.save_credentials(&credentials);
+        let plain_path = dir.join("credentials.json");
+        assert!(plain_path.exists());
+
+        let encrypted = plain.clone().with_credentials_passphrase("passphrase");
+        let read = encrypted.credentials().unwrap();
+        assert_eq!(read.username, credentials.username);
+        assert_eq!(read.auth_data, credentials.auth_data);
+        assert!(!plain_path.exists());
+
+        let contents = fs::read_to_string(dir.join("credentials.enc.json")).unwrap();
+        assert!(!contents.contains(&base64::encode("secret")));
+        let read = encrypted.credentials().unwrap();
+        assert_eq!(read.auth_data, credentials.auth_data);
+        assert!(plain.credentials().is_none());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn corrupt_files_are_removed() {
+        let dir = std::env::temp_dir().join(format!("librespot-files-{}", std::process::id()));
+        let cache = Cache::new(None, None, Some(&dir), None).unwrap();
+        let file = FileId([1; 20]);
+        let data = vec![7u8; 3 * CHECK_BLOCK_SIZE as usize];
+
+        cache.save_file(file, &mut &data[..]);
+        let mut cached = Vec::new();
+        cache.file(file).unwrap().read_to_end(&mut cached).unwrap();
+        assert_eq!(cached, data);
+
+        // Cut short, like by an unclean shutdown.
+        let path = cache.file_path(file).unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(1000)
+            .unwrap();
+        assert!(cache.file(file).is_none());
+        assert!(!path.exists());
+        assert_eq!(cache.stats().unwrap().corrupt_files, 1);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn only_abandoned_partial_files_are_removed() {
+        let dir = std::env::temp_dir().join(format!("librespot-partial-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let abandoned = dir.join("abandoned.partial");
+        let downloading = dir.join("downloading.partial");
+
+        fs::write(&abandoned, b"old").unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        let scan_start = SystemTime::now();
+        // Past the resolution of the timestamps of any file system.
+        std::thread::sleep(Duration::from_millis(1100));
+        fs::write(&downloading, b"new").unwrap();
+
+        let limiter = FsSizeLimiter {
+            limiter: Mutex::new(SizeLimiter::new(1000)),
+            scanning: AtomicBool::new(true),
+        };
+        limiter.init_dir(&dir, scan_start);
+        assert!(!abandoned.exists());
+        assert!(downloading.exists());
+        assert_eq!(limiter.limiter.lock().unwrap().in_use, 0);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn cached_access_points_expire() {
+        let dir = std::env::temp_dir().join(format!("librespot-cache-{}", std::process::id()));
+        let cache = Cache::new(Some(&dir), None, None, None).unwrap();
+
+        let aps = vec!["ap-a:4070".to_string(), "ap-b:443".to_string()];
+        cache.save_access_points(&aps);
+        assert_eq!(cache.access_points(Duration::from_secs(60)), Some(aps));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(cache.access_points(Duration::ZERO), None);
+
+        let _ = fs::remove_dir_all(dir);
+    }
 }
@@ -8,6 +8,7 @@ use bytes::Bytes;
 use futures_core::Stream;
 use futures_util::lock::BiLock;
 use futures_util::{ready, StreamExt};
+use thiserror::Error;
 use tokio::sync::mpsc;
 
 use crate::util::SeqGenerator;
@@ -19,13 +20,19 @@ component! {
         download_rate_estimate: usize = 0,
         download_measurement_start: Option<Instant> = None,
         download_measurement_bytes: usize = 0,
+        download_rate_limit: Option<usize> = None,
+        download_allowance: f64 = 0.0,
+        download_allowance_updated: Option<Instant> = None,
         invalid: bool = false,
     }
 }
 
 const ONE_SECOND_IN_MS: usize = 1000;
 
-#[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
+const BYTES_PER_KB: usize = 1024;
+
+#[derive(Debug, Error, Hash, PartialEq, Eq, Copy, Clone)]
+#[error("Audio channel failed")]
 pub struct ChannelError;
 
 pub struct Channel {
@@ -91,6 +98,9 @@ impl ChannelManager {
 
             inner.download_measurement_bytes += data.len();
 
+            inner.update_download_allowance(current_time);
+            inner.download_allowance -= data.len() as f64;
+
             if let Entry::Occupied(entry) = inner.channels.entry(id) {
                 let _ = entry.get().send((cmd, data));
             }
@@ -101,6 +111,31 @@ impl ChannelManager {
         self.lock(|inner| inner.download_rate_estimate)
     }
 
+    /// Limits how fast audio files are prefetched, in KB/s, or lifts the limit. Data that is
+    /// needed to play or seek right away is still downloaded as fast as possible.
+    pub fn set_download_rate_limit(&self, limit_kbps: Option<u32>) {
+        self.lock(|inner| {
+            inner.download_rate_limit = limit_kbps.map(|limit| limit as usize * BYTES_PER_KB);
+            inner.download_allowance = 0.0;
+            inner.download_allowance_updated = Some(Instant::now());
+        });
+    }
+
+    /// The download rate limit in bytes per second.
+    pub fn get_download_rate_limit(&self) -> Option<usize> {
+        self.lock(|inner| inner.download_rate_limit)
+    }
+
+    /// How many bytes may be prefetched right now without exceeding the download rate limit,
+    /// negative after it was exceeded, or `None` without a limit. The allowance grows with
+    /// the limit over time, and downloads of any kind use it up.
+    pub fn get_download_allowance(&self) -> Option<f64> {
+        self.lock(|inner| {
+            inner.update_download_allowance(Instant::now());
+            inner.download_rate_limit.map(|_| inner.download_allowance)
+        })
+    }
+
     pub(crate) fn shutdown(&self) {
         self.lock(|inner| {
             inner.invalid = true;
@@ -110,6 +145,20 @@ impl ChannelManager {
     }
 }
 
+impl ChannelManagerInner {
+    // Up to a second worth of the limit can be saved up, so that the average rate stays
+    // below the limit.
+    fn update_download_allowance(&mut self, now: Instant) {
+        if let Some(limit) = self.download_rate_limit {
+            if let Some(updated) = self.download_allowance_updated {
+                let earned = limit as f64 * (now - updated).as_secs_f64();
+                self.download_allowance = (self.download_allowance + earned).min(limit as f64);
+            }
+        }
+        self.download_allowance_updated = Some(now);
+    }
+}
+
 impl Channel {
     fn recv_packet(&mut self, cx: &mut Context<'_>) -> Poll<Result<Bytes, ChannelError>> {
         let (cmd, packet) = ready!(self.receiver.poll_recv(cx)).ok_or(ChannelError)?;
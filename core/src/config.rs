@@ -1,13 +1,91 @@
+use std::convert::TryFrom;
 use std::fmt;
 use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 
+// The longest device id accepted by `is_valid_device_id`.
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+
+// Device ids are sent as is in the zeroconf and Connect protocols, so custom ones are kept
+// to the likes of the derived ones: hex strings and UUIDs.
+pub fn is_valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty()
+        && device_id.len() <= MAX_DEVICE_ID_LEN
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// A country as the server knows it, by its two letter ISO 3166-1 code, like "SE".
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+pub struct CountryCode([u8; 2]);
+
+impl CountryCode {
+    pub fn as_str(&self) -> &str {
+        // panic safety: only ASCII letters are accepted.
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl FromStr for CountryCode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => Ok(CountryCode([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+            ])),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CountryCode({})", self.as_str())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SessionConfig {
     pub user_agent: String,
+    // identifies the device to Spotify and in zeroconf discovery, see `is_valid_device_id`
     pub device_id: String,
+    // an http, socks5 or socks5h proxy, where a socks5 one may take a username and password
     pub proxy: Option<Url>,
+    // have a SOCKS5 proxy resolve host names, like with a socks5h `proxy`
+    pub proxy_remote_dns: bool,
     pub ap_port: Option<u16>,
+    // host:port of access points to try in order, before resolving one
+    pub access_points: Vec<String>,
+    // only try `access_points`, without resolving one if they all fail
+    pub strict_access_points: bool,
+    // how long to wait for an access point, and for resolving them, before trying the next
+    pub ap_timeout: Duration,
+    // how long the access points that worked last are tried first, from the cache
+    pub ap_cache_ttl: Duration,
+    // initial limit in KB/s for prefetching audio, see `ChannelManager::set_download_rate_limit`
+    pub download_rate_limit: Option<u32>,
+    // how long to wait for the response to a Mercury request before it fails with
+    // `MercuryError::Timeout`, or forever
+    pub mercury_timeout: Option<Duration>,
+    // how often a Mercury GET request is made again when it times out or the server fails,
+    // waiting `mercury_retry_backoff` before the first retry and twice as long each time after
+    pub mercury_retries: u32,
+    pub mercury_retry_backoff: Duration,
+    // how many Mercury requests that can wait, like for metadata, are made at the same time,
+    // unlimited with `None`, see `rate_limit`
+    pub max_concurrent_requests: Option<usize>,
+    // the country to check the availability of tracks and episodes for instead of the one of
+    // the account, to test what plays elsewhere, see `Session::market`
+    pub country_override: Option<CountryCode>,
 }
 
 impl Default for SessionConfig {
@@ -17,7 +95,18 @@ impl Default for SessionConfig {
             user_agent: crate::version::VERSION_STRING.to_string(),
             device_id,
             proxy: None,
+            proxy_remote_dns: false,
             ap_port: None,
+            access_points: Vec::new(),
+            strict_access_points: false,
+            ap_timeout: Duration::from_secs(10),
+            ap_cache_ttl: Duration::from_secs(24 * 60 * 60),
+            download_rate_limit: None,
+            mercury_timeout: Some(Duration::from_secs(30)),
+            mercury_retries: 2,
+            mercury_retry_backoff: Duration::from_secs(1),
+            max_concurrent_requests: None,
+            country_override: None,
         }
     }
 }
@@ -49,7 +138,13 @@ impl FromStr for DeviceType {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         use self::DeviceType::*;
+        // "audio dongle", "audio_dongle" and "audio-dongle" all mean "audiodongle".
+        let s: String = s
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '_' | '-'))
+            .collect();
         match s.to_lowercase().as_ref() {
+            "unknown" => Ok(Unknown),
             "computer" => Ok(Computer),
             "tablet" => Ok(Tablet),
             "smartphone" => Ok(Smartphone),
@@ -59,18 +154,51 @@ impl FromStr for DeviceType {
             "stb" => Ok(Stb),
             "audiodongle" => Ok(AudioDongle),
             "gameconsole" => Ok(GameConsole),
-            "castaudio" => Ok(CastAudio),
+            "cast" | "castaudio" => Ok(CastAudio),
             "castvideo" => Ok(CastVideo),
             "automobile" => Ok(Automobile),
             "smartwatch" => Ok(Smartwatch),
             "chromebook" => Ok(Chromebook),
             "carthing" => Ok(CarThing),
+            "observer" => Ok(Observer),
             "homething" => Ok(HomeThing),
             _ => Err(()),
         }
     }
 }
 
+impl TryFrom<i64> for DeviceType {
+    type Error = ();
+    fn try_from(id: i64) -> Result<Self, Self::Error> {
+        use self::DeviceType::*;
+        [
+            Unknown,
+            Computer,
+            Tablet,
+            Smartphone,
+            Speaker,
+            Tv,
+            Avr,
+            Stb,
+            AudioDongle,
+            GameConsole,
+            CastAudio,
+            CastVideo,
+            Automobile,
+            Smartwatch,
+            Chromebook,
+            UnknownSpotify,
+            CarThing,
+            Observer,
+            HomeThing,
+        ]
+        .iter()
+        .copied()
+        .find(|device_type| *device_type as i64 == id)
+        .ok_or(())
+    }
+}
+
 impl From<&DeviceType> for &str {
     fn from(d: &DeviceType) -> &'static str {
         use self::DeviceType::*;
@@ -117,13 +245,273 @@ impl Default for DeviceType {
     }
 }
 
+// What to do when the last track of a context has played and repeat is off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndOfContextAction {
+    // Go back to the first track, but don't play it.
+    Stop,
+    // Continue with similar tracks.
+    Autoplay,
+    // Play the context again from the start, in a new order if shuffle is on.
+    RestartContext,
+}
+
+impl FromStr for EndOfContextAction {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::EndOfContextAction::*;
+        match s.to_lowercase().as_ref() {
+            "stop" => Ok(Stop),
+            "autoplay" => Ok(Autoplay),
+            "restart" => Ok(RestartContext),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<&EndOfContextAction> for &str {
+    fn from(action: &EndOfContextAction) -> &'static str {
+        use self::EndOfContextAction::*;
+        match action {
+            Stop => "stop",
+            Autoplay => "autoplay",
+            RestartContext => "restart",
+        }
+    }
+}
+
+impl From<EndOfContextAction> for &str {
+    fn from(action: EndOfContextAction) -> &'static str {
+        (&action).into()
+    }
+}
+
+impl fmt::Display for EndOfContextAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str: &str = self.into();
+        f.write_str(str)
+    }
+}
+
+impl Default for EndOfContextAction {
+    fn default() -> EndOfContextAction {
+        EndOfContextAction::Stop
+    }
+}
+
+// Whether autoplay goes on with similar tracks once the context ends, over what the
+// account and `ConnectConfig::end_of_context` say.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoplayMode {
+    // As `end_of_context` says, unless the account has autoplay turned off.
+    AccountDefault,
+    // Always autoplay, whatever the account and `end_of_context` say.
+    ForceOn,
+    // Never autoplay, `EndOfContextAction::Autoplay` stops instead.
+    ForceOff,
+}
+
+impl FromStr for AutoplayMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::AutoplayMode::*;
+        match s.to_lowercase().as_ref() {
+            "account" => Ok(AccountDefault),
+            "on" => Ok(ForceOn),
+            "off" => Ok(ForceOff),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<&AutoplayMode> for &str {
+    fn from(mode: &AutoplayMode) -> &'static str {
+        use self::AutoplayMode::*;
+        match mode {
+            AccountDefault => "account",
+            ForceOn => "on",
+            ForceOff => "off",
+        }
+    }
+}
+
+impl From<AutoplayMode> for &str {
+    fn from(mode: AutoplayMode) -> &'static str {
+        (&mode).into()
+    }
+}
+
+impl fmt::Display for AutoplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str: &str = self.into();
+        f.write_str(str)
+    }
+}
+
+impl Default for AutoplayMode {
+    fn default() -> AutoplayMode {
+        AutoplayMode::AccountDefault
+    }
+}
+
+// How the volume up / down steps of `ConnectConfig::volume_steps` are spaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumeStepSpacing {
+    // Equal in dB over the range of the volume curve, so that each sounds like the same change.
+    Db,
+    // Equal in the volume that is set, 0 - 65535, whatever the curve makes of it.
+    Linear,
+}
+
+impl FromStr for VolumeStepSpacing {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::VolumeStepSpacing::*;
+        match s.to_lowercase().as_ref() {
+            "db" | "log" => Ok(Db),
+            "linear" => Ok(Linear),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<&VolumeStepSpacing> for &str {
+    fn from(spacing: &VolumeStepSpacing) -> &'static str {
+        use self::VolumeStepSpacing::*;
+        match spacing {
+            Db => "db",
+            Linear => "linear",
+        }
+    }
+}
+
+impl From<VolumeStepSpacing> for &str {
+    fn from(spacing: VolumeStepSpacing) -> &'static str {
+        (&spacing).into()
+    }
+}
+
+impl fmt::Display for VolumeStepSpacing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str: &str = self.into();
+        f.write_str(str)
+    }
+}
+
+impl Default for VolumeStepSpacing {
+    fn default() -> VolumeStepSpacing {
+        VolumeStepSpacing::Db
+    }
+}
+
+// What plays again once it has ended. Other devices only know whether repeat is on, so
+// they see `Track` as `Context`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepeatMode {
+    Off,
+    Context,
+    Track,
+}
+
+impl From<&RepeatMode> for &str {
+    fn from(repeat: &RepeatMode) -> &'static str {
+        use self::RepeatMode::*;
+        match repeat {
+            Off => "off",
+            Context => "context",
+            Track => "track",
+        }
+    }
+}
+
+impl From<RepeatMode> for &str {
+    fn from(repeat: RepeatMode) -> &'static str {
+        (&repeat).into()
+    }
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str: &str = self.into();
+        f.write_str(str)
+    }
+}
+
+// Where what is playing comes from: the context that was loaded, the queue, or the station
+// that autoplay went on with after the context ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContextKind {
+    Context,
+    Queue,
+    Autoplay,
+}
+
+impl Default for ContextKind {
+    fn default() -> Self {
+        Self::Context
+    }
+}
+
+impl From<&ContextKind> for &str {
+    fn from(kind: &ContextKind) -> &'static str {
+        use self::ContextKind::*;
+        match kind {
+            Context => "context",
+            Queue => "queue",
+            Autoplay => "autoplay",
+        }
+    }
+}
+
+impl From<ContextKind> for &str {
+    fn from(kind: ContextKind) -> &'static str {
+        (&kind).into()
+    }
+}
+
+impl fmt::Display for ContextKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str: &str = self.into();
+        f.write_str(str)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ConnectConfig {
     pub name: String,
     pub device_type: DeviceType,
+    // the volume to set before anything is played, instead of keeping that of the mixer.
+    // It takes precedence over a volume cached from an earlier session, which is what
+    // librespot passes here when none is given.
     pub initial_volume: Option<u16>,
     pub has_volume_ctrl: bool,
-    pub autoplay: bool,
+    // how many presses of volume up take the volume from 0 to the maximum, spaced as
+    // `volume_step_spacing` says
+    pub volume_steps: u16,
+    pub volume_step_spacing: VolumeStepSpacing,
+    // snap every volume that is set, by a controller or through `Spirc`, to the nearest step
+    // instead of only the volume stepped up or down to
+    pub quantize_volume: bool,
+    pub end_of_context: EndOfContextAction,
+    // whether to autoplay regardless of the account and `end_of_context`
+    pub autoplay: AutoplayMode,
+    // start episodes at the position sent by the controller instead of from the beginning
+    pub resume_episodes: bool,
+    // play what is transferred to the device at startup as the controller says, instead of
+    // loading it paused until a play command
+    pub autoplay_on_connect: bool,
+    // skip explicit tracks instead of playing them. Tracks are also skipped when the account
+    // has explicit content filtered.
+    pub filter_explicit_content: bool,
+    // skip the explicit track that is playing when explicit content gets filtered, instead of
+    // letting it finish
+    pub filter_current_track: bool,
+    // only show the device to `allowed_controllers`, so that it doesn't appear on any other
+    // device. It can still be controlled through `Spirc`.
+    pub hidden: bool,
+    // the idents of the only devices that may control this one, if any are given or the
+    // device is hidden. Others are answered with the state as it is, and nothing changes.
+    pub allowed_controllers: Vec<String>,
 }
 
 impl Default for ConnectConfig {
@@ -133,7 +521,55 @@ impl Default for ConnectConfig {
             device_type: DeviceType::default(),
             initial_volume: Some(50),
             has_volume_ctrl: true,
-            autoplay: false,
+            volume_steps: 64,
+            volume_step_spacing: VolumeStepSpacing::default(),
+            quantize_volume: false,
+            end_of_context: EndOfContextAction::default(),
+            autoplay: AutoplayMode::default(),
+            resume_episodes: true,
+            autoplay_on_connect: true,
+            filter_explicit_content: false,
+            filter_current_track: false,
+            hidden: false,
+            allowed_controllers: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn device_ids() {
+        assert!(is_valid_device_id(
+            "8c5d2bbb3e5d0bdc1072ba4c8a6b4d6d4e74a427"
+        ));
+        assert!(is_valid_device_id("5b2e5d7c-9e0f-4a59-b6d2-1c0e8f3c6a11"));
+        assert!(is_valid_device_id(&"a".repeat(MAX_DEVICE_ID_LEN)));
+
+        assert!(!is_valid_device_id(""));
+        assert!(!is_valid_device_id(&"a".repeat(MAX_DEVICE_ID_LEN + 1)));
+        assert!(!is_valid_device_id("living room"));
+        assert!(!is_valid_device_id("id\"},"));
+    }
+
+    #[test]
+    fn device_types() {
+        assert_eq!("Speaker".parse(), Ok(DeviceType::Speaker));
+        assert_eq!("audio_dongle".parse(), Ok(DeviceType::AudioDongle));
+        assert_eq!("game-console".parse(), Ok(DeviceType::GameConsole));
+        assert_eq!("cast".parse(), Ok(DeviceType::CastAudio));
+        assert_eq!("TV".parse(), Ok(DeviceType::Tv));
+        assert_eq!("toaster".parse::<DeviceType>(), Err(()));
+    }
+
+    #[test]
+    fn country_codes() {
+        assert_eq!("se".parse::<CountryCode>().unwrap().as_str(), "SE");
+        assert_eq!("US".parse::<CountryCode>().unwrap().to_string(), "US");
+        assert_eq!("SWE".parse::<CountryCode>(), Err(()));
+        assert_eq!("S1".parse::<CountryCode>(), Err(()));
+        assert_eq!("".parse::<CountryCode>(), Err(()));
+    }
+}
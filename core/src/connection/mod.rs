@@ -17,6 +17,7 @@ use url::Url;
 use crate::authentication::Credentials;
 use crate::protocol::keyexchange::{APLoginFailed, ErrorCode};
 use crate::proxytunnel;
+use crate::redact;
 use crate::version;
 
 pub type Transport = Framed<TcpStream, ApCodec>;
@@ -40,6 +41,11 @@ fn login_error_message(code: &ErrorCode) -> &'static str {
 
 #[derive(Debug, Error)]
 pub enum AuthenticationError {
+    #[error("Login failed with reason: {}", login_error_message(&ErrorCode::BadCredentials))]
+    BadCredentials,
+    #[error("Login failed with reason: {}", login_error_message(&ErrorCode::PremiumAccountRequired))]
+    PremiumAccountRequired,
+    // Any of the other reasons.
     #[error("Login failed with reason: {}", login_error_message(.0))]
     LoginFailed(ErrorCode),
     #[error("Authentication failed: {0}")]
@@ -54,23 +60,17 @@ impl From<ProtobufError> for AuthenticationError {
 
 impl From<APLoginFailed> for AuthenticationError {
     fn from(login_failure: APLoginFailed) -> Self {
-        Self::LoginFailed(login_failure.get_error_code())
+        match login_failure.get_error_code() {
+            ErrorCode::BadCredentials => Self::BadCredentials,
+            ErrorCode::PremiumAccountRequired => Self::PremiumAccountRequired,
+            code => Self::LoginFailed(code),
+        }
     }
 }
 
-pub async fn connect(addr: String, proxy: Option<&Url>) -> io::Result<Transport> {
+pub async fn connect(addr: String, proxy: Option<&Url>, remote_dns: bool) -> io::Result<Transport> {
     let socket = if let Some(proxy_url) = proxy {
-        info!("Using proxy \"{}\"", proxy_url);
-
-        let socket_addr = proxy_url.socket_addrs(|| None).and_then(|addrs| {
-            addrs.into_iter().next().ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Can't resolve proxy server address",
-                )
-            })
-        })?;
-        let socket = TcpStream::connect(&socket_addr).await?;
+        info!("Using proxy \"{}\"", redact::url(proxy_url));
 
         let uri = addr.parse::<http::Uri>().map_err(|_| {
             io::Error::new(
@@ -84,14 +84,14 @@ pub async fn connect(addr: String, proxy: Option<&Url>) -> io::Result<Transport>
                 "The access point address contains no hostname",
             )
         })?;
-        let port = uri.port().ok_or_else(|| {
+        let port = uri.port_u16().ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "The access point address contains no port",
             )
         })?;
 
-        proxytunnel::proxy_connect(socket, host, port.as_str()).await?
+        proxytunnel::connect(proxy_url, host, port, remote_dns).await?
     } else {
         let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
             io::Error::new(
@@ -143,7 +143,13 @@ pub async fn authenticate(
     let data = packet.write_to_bytes().unwrap();
 
     transport.send((cmd, data)).await?;
-    let (cmd, data) = transport.next().await.expect("EOF")?;
+    // The access point may have closed the connection, like one that was idle for too long.
+    let (cmd, data) = transport.next().await.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "The access point closed the connection",
+        )
+    })??;
     match cmd {
         0xac => {
             let welcome_data = APWelcome::parse_from_bytes(data.as_ref())?;
@@ -166,3 +172,29 @@ pub async fn authenticate(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn closed_connection_fails_authentication() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let mut transport = Framed::new(stream, ApCodec::new(&[0; 32], &[0; 32]));
+        let credentials = Credentials::with_password("user", "password");
+        match authenticate(&mut transport, credentials, "device").await {
+            Err(AuthenticationError::IoError(e)) => {
+                assert_eq!(e.kind(), ErrorKind::UnexpectedEof)
+            }
+            result => panic!("Unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+}
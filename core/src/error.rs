@@ -0,0 +1,28 @@
+use thiserror::Error;
+
+use crate::audio_key::AudioKeyError;
+use crate::channel::ChannelError;
+use crate::mercury::MercuryError;
+use crate::session::{AuthenticationError, SessionError};
+use crate::spotify_id::SpotifyIdError;
+use crate::token::TokenError;
+
+/// Any of the errors of this crate, for callers that handle several of them. Each variant
+/// keeps the error it came from, so it can still be matched on.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Session(#[from] SessionError),
+    #[error(transparent)]
+    Authentication(#[from] AuthenticationError),
+    #[error(transparent)]
+    Mercury(#[from] MercuryError),
+    #[error(transparent)]
+    Channel(#[from] ChannelError),
+    #[error(transparent)]
+    AudioKey(#[from] AudioKeyError),
+    #[error(transparent)]
+    SpotifyId(#[from] SpotifyIdError),
+    #[error(transparent)]
+    Token(#[from] TokenError),
+}
@@ -1,5 +1,8 @@
+use std::time::Instant;
+
 use serde::Deserialize;
 
+use crate::token::TokenError;
 use crate::{mercury::MercuryError, session::Session};
 
 #[derive(Deserialize, Debug, Clone)]
@@ -11,16 +14,39 @@ pub struct Token {
     pub scope: Vec<String>,
 }
 
+/// A token for the comma separated `scopes`. Sessions connected with a `TokenProvider` get it
+/// from their provider with `Session::token`, and don't use `client_id`. The others ask the
+/// keymaster for one.
 pub async fn get_token(
     session: &Session,
     client_id: &str,
     scopes: &str,
 ) -> Result<Token, MercuryError> {
+    let scope_list = scopes.split(',').collect::<Vec<_>>();
+    match session.token(&scope_list).await {
+        Ok(token) => {
+            return Ok(Token {
+                access_token: token.access_token,
+                expires_in: token
+                    .expires_at
+                    .saturating_duration_since(Instant::now())
+                    .as_secs() as u32,
+                token_type: "Bearer".to_owned(),
+                scope: token.scopes,
+            })
+        }
+        Err(TokenError::NoProvider) => (),
+        Err(e) => {
+            warn!("Cannot get a token from the token provider: {}", e);
+            return Err(MercuryError::Failed);
+        }
+    }
+
     let url = format!(
         "hm://keymaster/token/authenticated?client_id={}&scope={}",
         client_id, scopes
     );
     let response = session.mercury().get(url).await?;
     let data = response.payload.first().expect("Empty payload");
-    serde_json::from_slice(data.as_ref()).map_err(|_| MercuryError)
+    serde_json::from_slice(data.as_ref()).map_err(|_| MercuryError::Failed)
 }
@@ -17,11 +17,18 @@ pub mod config;
 mod connection;
 #[doc(hidden)]
 pub mod diffie_hellman;
+mod error;
 pub mod keymaster;
 pub mod mercury;
 mod proxytunnel;
+pub mod rate_limit;
+pub mod redact;
 pub mod session;
 pub mod spotify_id;
+pub mod token;
 #[doc(hidden)]
 pub mod util;
 pub mod version;
+
+pub use crate::error::Error;
+pub use crate::proxytunnel::{ProxiedConnector, ProxyError};
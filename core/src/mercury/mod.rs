@@ -4,6 +4,7 @@ use std::mem;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
@@ -12,11 +13,15 @@ use protobuf::Message;
 use tokio::sync::{mpsc, oneshot};
 
 use crate::protocol;
+use crate::rate_limit::{self, RequestPriority};
 use crate::util::SeqGenerator;
 
 mod types;
 pub use self::types::*;
 
+mod push;
+pub use self::push::{PushMessage, PushSubscriptions, PUSH_SUBSCRIPTION_CAPACITY};
+
 mod sender;
 pub use self::sender::MercurySender;
 
@@ -33,17 +38,62 @@ pub struct MercuryPending {
     parts: Vec<Vec<u8>>,
     partial: Option<Vec<u8>>,
     callback: Option<oneshot::Sender<Result<MercuryResponse, MercuryError>>>,
+    pass_client_errors: bool,
 }
 
 pub struct MercuryFuture<T> {
-    receiver: oneshot::Receiver<Result<T, MercuryError>>,
+    future: Pin<Box<dyn Future<Output = Result<T, MercuryError>> + Send>>,
+}
+
+impl<T: Send + 'static> MercuryFuture<T> {
+    fn new(future: impl Future<Output = Result<T, MercuryError>> + Send + 'static) -> Self {
+        MercuryFuture {
+            future: Box::pin(future),
+        }
+    }
+
+    fn from_receiver(receiver: oneshot::Receiver<Result<T, MercuryError>>) -> Self {
+        Self::new(async move { receiver.await.map_err(|_| MercuryError::Failed)? })
+    }
 }
 
 impl<T> Future for MercuryFuture<T> {
     type Output = Result<T, MercuryError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.receiver.poll_unpin(cx).map_err(|_| MercuryError)?
+        self.future.poll_unpin(cx)
+    }
+}
+
+// Makes the request again while it fails in a way that may not last, up to `retries` more
+// times, with a backoff that doubles each time.
+async fn retrying<F>(
+    retries: u32,
+    mut backoff: Duration,
+    mut request: F,
+) -> Result<MercuryResponse, MercuryError>
+where
+    F: FnMut() -> MercuryFuture<MercuryResponse>,
+{
+    let mut retried = 0;
+    loop {
+        match request().await {
+            // The request that is made again waits for the rate limiter instead.
+            Err(e @ MercuryError::RateLimited(_)) if retried < retries => {
+                retried += 1;
+                warn!("{}, trying again after it ({} of {})", e, retried, retries);
+            }
+            Err(e) if e.is_retryable() && retried < retries => {
+                retried += 1;
+                warn!(
+                    "{}, trying again in {:?} ({} of {})",
+                    e, backoff, retried, retries
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
     }
 }
 
@@ -55,12 +105,110 @@ impl MercuryManager {
     }
 
     fn request(&self, req: MercuryRequest) -> MercuryFuture<MercuryResponse> {
+        self.start_request(req, false, RequestPriority::Normal)
+    }
+
+    // GET requests are made again when they time out or the server fails, see
+    // `SessionConfig::mercury_retries`. Others may have had their effect already. The retries
+    // run on the runtime of the session, as the future may be polled from a thread without
+    // one, like the player's, and so they are sent whether it is polled or not.
+    fn start_request(
+        &self,
+        req: MercuryRequest,
+        pass_client_errors: bool,
+        priority: RequestPriority,
+    ) -> MercuryFuture<MercuryResponse> {
+        let config = self.session().config().clone();
+        if req.method != MercuryMethod::Get || config.mercury_retries == 0 {
+            return self.send_request(&req, pass_client_errors, priority);
+        }
+
+        let manager = self.clone();
+        let (tx, rx) = oneshot::channel();
+        self.session().spawn(async move {
+            let response = retrying(config.mercury_retries, config.mercury_retry_backoff, || {
+                manager.send_request(&req, pass_client_errors, priority)
+            })
+            .await;
+            let _ = tx.send(response);
+        });
+        MercuryFuture::from_receiver(rx)
+    }
+
+    // GET requests that can wait are held back while the session is rate limited, see
+    // `rate_limit`. The others are sent right away, in order.
+    fn send_request(
+        &self,
+        req: &MercuryRequest,
+        pass_client_errors: bool,
+        priority: RequestPriority,
+    ) -> MercuryFuture<MercuryResponse> {
+        let (seq, future) = self.add_pending(pass_client_errors);
+
+        let cmd = req.method.command();
+        let data = req.encode(&seq);
+
+        let session = self.session();
+        let timeout = session.config().mercury_timeout;
+        let held_back = req.method == MercuryMethod::Get && priority == RequestPriority::Normal;
+        let unsent = if held_back {
+            Some(data)
+        } else {
+            session.send_packet(cmd, data);
+            None
+        };
+        if unsent.is_none() && timeout.is_none() {
+            return future;
+        }
+
+        // The waits run on the runtime of the session, as the future may be polled from a
+        // thread without one, like the player's.
+        let manager = self.clone();
+        let (tx, rx) = oneshot::channel();
+        session.spawn(async move {
+            let _permit = match unsent {
+                Some(data) => {
+                    let session = manager.session();
+                    let permit = session.rate_limiter().acquire(priority).await;
+                    session.send_packet(cmd, data);
+                    Some(permit)
+                }
+                None => None,
+            };
+            let _ = tx.send(manager.response_within(&seq, timeout, future).await);
+        });
+        MercuryFuture::from_receiver(rx)
+    }
+
+    // Waits up to `timeout` for the response to the request with `seq`, see `expire`.
+    async fn response_within(
+        &self,
+        seq: &[u8],
+        timeout: Option<Duration>,
+        response: MercuryFuture<MercuryResponse>,
+    ) -> Result<MercuryResponse, MercuryError> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return response.await,
+        };
+
+        match tokio::time::timeout(timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                self.expire(seq);
+                Err(MercuryError::Timeout)
+            }
+        }
+    }
+
+    fn add_pending(&self, pass_client_errors: bool) -> (Vec<u8>, MercuryFuture<MercuryResponse>) {
         let (tx, rx) = oneshot::channel();
 
         let pending = MercuryPending {
             parts: Vec::new(),
             partial: None,
             callback: Some(tx),
+            pass_client_errors,
         };
 
         let seq = self.next_seq();
@@ -70,20 +218,118 @@ impl MercuryManager {
             }
         });
 
-        let cmd = req.method.command();
-        let data = req.encode(&seq);
+        (seq, MercuryFuture::from_receiver(rx))
+    }
 
-        self.session().send_packet(cmd, data);
-        MercuryFuture { receiver: rx }
+    // Forgets a request that has timed out. Any of its response that still comes in is
+    // ignored.
+    fn expire(&self, seq: &[u8]) {
+        if self.lock(|inner| inner.pending.remove(seq)).is_some() {
+            warn!("Mercury request seq {:?} timed out", seq);
+        }
     }
 
+    /// The request is sent right away, without waiting for the future to be polled, unless
+    /// the session is rate limited. It is tried up to `SessionConfig::mercury_retries` more
+    /// times, so that it takes up to that many more `mercury_timeout`s to fail, plus the
+    /// `mercury_retry_backoff` in between: about 3 × 30 s and 3 s with the defaults.
     pub fn get<T: Into<String>>(&self, uri: T) -> MercuryFuture<MercuryResponse> {
-        self.request(MercuryRequest {
-            method: MercuryMethod::Get,
-            uri: uri.into(),
-            content_type: None,
-            payload: Vec::new(),
-        })
+        self.get_with_priority(uri, RequestPriority::Normal)
+    }
+
+    /// Like `get`, but an `Essential` request isn't held back while the session is rate
+    /// limited, see `rate_limit`. For what playback can't do without.
+    pub fn get_with_priority<T: Into<String>>(
+        &self,
+        uri: T,
+        priority: RequestPriority,
+    ) -> MercuryFuture<MercuryResponse> {
+        self.start_request(
+            MercuryRequest {
+                method: MercuryMethod::Get,
+                uri: uri.into(),
+                content_type: None,
+                payload: Vec::new(),
+            },
+            false,
+            priority,
+        )
+    }
+
+    /// Like `get`, but a 4xx status code resolves to the response instead of an error,
+    /// so that callers can tell a missing resource apart from a failed request.
+    pub fn get_with_status<T: Into<String>>(&self, uri: T) -> MercuryFuture<MercuryResponse> {
+        self.start_request(
+            MercuryRequest {
+                method: MercuryMethod::Get,
+                uri: uri.into(),
+                content_type: None,
+                payload: Vec::new(),
+            },
+            true,
+            RequestPriority::Normal,
+        )
+    }
+
+    /// Fetches several `uris` in a single request to the multi-get endpoint at `uri`.
+    ///
+    /// The responses are returned in the order of `uris`. Each one carries its own status
+    /// code, so a failure for one uri does not fail the others.
+    pub fn get_multiple<T: Into<String>>(
+        &self,
+        uri: T,
+        uris: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<MercuryResponse>, MercuryError>> + 'static {
+        let mut multi_get = protocol::mercury::MercuryMultiGetRequest::new();
+        for uri in &uris {
+            let mut request = protocol::mercury::MercuryRequest::new();
+            request.set_uri(uri.clone());
+            multi_get.mut_request().push(request);
+        }
+
+        let payload = multi_get.write_to_bytes().map_err(|e| {
+            warn!("Error serializing multi-get request: {}", e);
+            MercuryError::Failed
+        });
+
+        let request = payload.map(|payload| {
+            self.request(MercuryRequest {
+                method: MercuryMethod::Get,
+                uri: uri.into(),
+                content_type: Some("vnd.spotify/mercury-mget-request".to_string()),
+                payload: vec![payload],
+            })
+        });
+
+        async move {
+            let response = request?.await?;
+
+            let data = response.payload.first().ok_or(MercuryError::Failed)?;
+            let mut reply = protocol::mercury::MercuryMultiGetReply::parse_from_bytes(data)
+                .map_err(|e| {
+                    warn!("Error parsing multi-get reply: {}", e);
+                    MercuryError::Failed
+                })?;
+
+            if reply.get_reply().len() != uris.len() {
+                warn!(
+                    "Multi-get returned {} replies for {} requests",
+                    reply.get_reply().len(),
+                    uris.len()
+                );
+                return Err(MercuryError::Failed);
+            }
+
+            Ok(uris
+                .into_iter()
+                .zip(reply.take_reply().into_iter())
+                .map(|(uri, mut reply)| MercuryResponse {
+                    uri,
+                    status_code: reply.get_status_code(),
+                    payload: vec![reply.take_body()],
+                })
+                .collect())
+        }
     }
 
     pub fn send<T: Into<String>>(&self, uri: T, data: Vec<u8>) -> MercuryFuture<MercuryResponse> {
@@ -158,6 +404,7 @@ impl MercuryManager {
                 parts: Vec::new(),
                 partial: None,
                 callback: None,
+                pass_client_errors: false,
             },
             None => {
                 warn!("Ignore seq {:?} cmd {:x}", seq, cmd);
@@ -201,16 +448,49 @@ impl MercuryManager {
             payload: pending.parts,
         };
 
-        if response.status_code >= 500 {
-            panic!("Spotify servers returned an error. Restart librespot.");
-        } else if response.status_code >= 400 {
+        if response.status_code == 429 {
+            let retry_after =
+                rate_limit::retry_after(header.get_user_fields().iter().map(|field| {
+                    (
+                        field.get_key(),
+                        std::str::from_utf8(field.get_value()).unwrap_or(""),
+                    )
+                }));
+            warn!("rate limited for uri {}", &response.uri);
+            self.session().rate_limited(retry_after);
+            if let Some(cb) = pending.callback {
+                let _ = cb.send(Err(MercuryError::RateLimited(retry_after)));
+            }
+        } else if response.status_code >= 500 {
+            warn!(
+                "server error {} for uri {}",
+                response.status_code, &response.uri
+            );
+            if let Some(cb) = pending.callback {
+                let _ = cb.send(Err(MercuryError::Server(response.status_code)));
+            }
+        } else if response.status_code >= 400 && !pending.pass_client_errors {
             warn!("error {} for uri {}", response.status_code, &response.uri);
             if let Some(cb) = pending.callback {
-                let _ = cb.send(Err(MercuryError));
+                let _ = cb.send(Err(MercuryError::Failed));
             }
         } else if cmd == 0xb5 {
+            let message = PushMessage {
+                uri: response.uri.clone(),
+                content_type: header
+                    .has_content_type()
+                    .then(|| header.get_content_type().to_owned()),
+                headers: header
+                    .get_user_fields()
+                    .iter()
+                    .map(|field| (field.get_key().to_owned(), field.get_value().to_owned()))
+                    .collect(),
+                payload: response.payload.clone(),
+            };
+            let pushed = self.session().push_subscriptions().dispatch(&message);
+
             self.lock(|inner| {
-                let mut found = false;
+                let mut found = pushed;
 
                 // TODO: This is just a workaround to make utf-8 encoded usernames work.
                 // A better solution would be to use an uri struct and urlencode it directly
@@ -255,3 +535,100 @@ impl MercuryManager {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::SessionWeak;
+
+    fn manager() -> MercuryManager {
+        MercuryManager::new(SessionWeak::dead())
+    }
+
+    fn response_packet(seq: &[u8], status_code: i32) -> Bytes {
+        let mut header = protocol::mercury::Header::new();
+        header.set_uri("hm://test".to_string());
+        header.set_status_code(status_code);
+        let header = header.write_to_bytes().unwrap();
+
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&(seq.len() as u16).to_be_bytes());
+        packet.extend_from_slice(seq);
+        packet.push(1); // Flags: FINAL
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet.extend_from_slice(&(header.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&header);
+        Bytes::from(packet)
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[tokio::test]
+    async fn unanswered_request_times_out() {
+        tokio::time::pause();
+        let manager = manager();
+        let (seq, future) = manager.add_pending(false);
+
+        let start = tokio::time::Instant::now();
+        let response = manager.response_within(&seq, Some(TIMEOUT), future).await;
+        assert_eq!(response.unwrap_err(), MercuryError::Timeout);
+        assert!(start.elapsed() >= TIMEOUT);
+        assert!(manager.lock(|inner| inner.pending.is_empty()));
+
+        // A late response is dropped.
+        manager.dispatch(0xb2, response_packet(&seq, 200));
+        assert!(manager.lock(|inner| inner.pending.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_retried() {
+        tokio::time::pause();
+        let manager = manager();
+        let mut attempts = 0;
+        let response = retrying(2, Duration::from_millis(1), || {
+            let (seq, future) = manager.add_pending(false);
+            attempts += 1;
+            match attempts {
+                1 => {
+                    let manager = manager.clone();
+                    return MercuryFuture::new(async move {
+                        manager.response_within(&seq, Some(TIMEOUT), future).await
+                    });
+                }
+                2 => manager.dispatch(0xb2, response_packet(&seq, 503)),
+                _ => manager.dispatch(0xb2, response_packet(&seq, 200)),
+            }
+            future
+        })
+        .await;
+        assert_eq!(response.unwrap().status_code, 200);
+        assert_eq!(attempts, 3);
+
+        // A client error isn't retried.
+        let mut attempts = 0;
+        let response = retrying(2, Duration::from_millis(1), || {
+            let (seq, future) = manager.add_pending(false);
+            attempts += 1;
+            manager.dispatch(0xb2, response_packet(&seq, 404));
+            future
+        })
+        .await;
+        assert_eq!(response.unwrap_err(), MercuryError::Failed);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn answered_request_does_not_time_out() {
+        tokio::time::pause();
+        let manager = manager();
+        let (seq, future) = manager.add_pending(false);
+
+        let start = tokio::time::Instant::now();
+        manager.dispatch(0xb2, response_packet(&seq, 200));
+        let response = manager.response_within(&seq, Some(TIMEOUT), future).await;
+
+        assert_eq!(response.unwrap().status_code, 200);
+        assert!(start.elapsed() < TIMEOUT);
+        assert!(manager.lock(|inner| inner.pending.is_empty()));
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// How many messages a push subscription holds before its receiver takes them. Messages
+/// that come in while it is full are dropped for that subscription, so that one receiver
+/// that falls behind holds up neither the connection nor the other subscriptions.
+pub const PUSH_SUBSCRIPTION_CAPACITY: usize = 64;
+
+/// A message the server pushed to the session, such as an update of a playlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushMessage {
+    pub uri: String,
+    pub content_type: Option<String>,
+    /// The user fields of the header of the message.
+    pub headers: HashMap<String, Vec<u8>>,
+    pub payload: Vec<Vec<u8>>,
+}
+
+struct PushSubscription {
+    uri_prefix: String,
+    tx: mpsc::Sender<PushMessage>,
+}
+
+/// The subscriptions to the messages that the server pushes to a session, by the prefix of
+/// their uri. They receive every message that comes in for them, whatever subscribed the
+/// session to it with the server.
+#[derive(Clone, Default)]
+pub struct PushSubscriptions(Arc<Mutex<Vec<PushSubscription>>>);
+
+impl PushSubscriptions {
+    /// Receives the pushed messages whose uri starts with `uri_prefix`, until the receiver is
+    /// dropped. See `PUSH_SUBSCRIPTION_CAPACITY` for what happens when it falls behind.
+    pub fn subscribe(&self, uri_prefix: impl Into<String>) -> mpsc::Receiver<PushMessage> {
+        let (tx, rx) = mpsc::channel(PUSH_SUBSCRIPTION_CAPACITY);
+        self.lock().push(PushSubscription {
+            uri_prefix: uri_prefix.into(),
+            tx,
+        });
+        rx
+    }
+
+    /// Moves the subscriptions of a session that was lost over to this one, so that they keep
+    /// receiving messages after reconnecting.
+    pub fn carry_over(&self, previous: &PushSubscriptions) {
+        if Arc::ptr_eq(&self.0, &previous.0) {
+            return;
+        }
+
+        let mut carried = std::mem::take(&mut *previous.lock());
+        self.lock().append(&mut carried);
+    }
+
+    // Hands the message to the subscriptions it is for, and tells whether there were any.
+    pub(crate) fn dispatch(&self, message: &PushMessage) -> bool {
+        let mut found = false;
+
+        self.lock().retain(|subscription| {
+            if !message.uri.starts_with(&subscription.uri_prefix) {
+                return true;
+            }
+
+            found = true;
+            match subscription.tx.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Dropping push message for uri={}, its subscription to {} is full",
+                        message.uri, subscription.uri_prefix
+                    );
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+
+        found
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<PushSubscription>> {
+        self.0.lock().expect("Mutex poisoned")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(uri: &str) -> PushMessage {
+        PushMessage {
+            uri: uri.to_owned(),
+            content_type: None,
+            headers: HashMap::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn messages_go_to_matching_subscriptions() {
+        let subscriptions = PushSubscriptions::default();
+        let mut playlists = subscriptions.subscribe("hm://playlist/");
+        let mut all = subscriptions.subscribe("hm://");
+
+        assert!(subscriptions.dispatch(&message("hm://playlist/user/a/rootlist")));
+        assert!(subscriptions.dispatch(&message("hm://remote/user/a/")));
+        assert!(!subscriptions.dispatch(&message("spotify:user:a")));
+
+        assert_eq!(
+            playlists.try_recv().unwrap().uri,
+            "hm://playlist/user/a/rootlist"
+        );
+        assert!(playlists.try_recv().is_err());
+        assert_eq!(all.try_recv().unwrap().uri, "hm://playlist/user/a/rootlist");
+        assert_eq!(all.try_recv().unwrap().uri, "hm://remote/user/a/");
+
+        drop(all);
+        subscriptions.dispatch(&message("hm://remote/user/a/"));
+        assert_eq!(subscriptions.lock().len(), 1);
+    }
+
+    #[test]
+    fn full_subscriptions_drop_messages() {
+        let subscriptions = PushSubscriptions::default();
+        let mut rx = subscriptions.subscribe("hm://");
+        for _ in 0..PUSH_SUBSCRIPTION_CAPACITY + 1 {
+            subscriptions.dispatch(&message("hm://remote/user/a/"));
+        }
+
+        for _ in 0..PUSH_SUBSCRIPTION_CAPACITY {
+            assert!(rx.try_recv().is_ok());
+        }
+        assert!(rx.try_recv().is_err());
+
+        subscriptions.dispatch(&message("hm://remote/user/a/"));
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn carried_over_subscriptions_receive_from_the_new_session() {
+        let lost = PushSubscriptions::default();
+        let mut rx = lost.subscribe("hm://playlist/");
+
+        let subscriptions = PushSubscriptions::default();
+        subscriptions.carry_over(&lost);
+        subscriptions.carry_over(&subscriptions.clone());
+        assert!(!lost.dispatch(&message("hm://playlist/user/a/rootlist")));
+        assert!(subscriptions.dispatch(&message("hm://playlist/user/a/rootlist")));
+        assert!(rx.try_recv().is_ok());
+        assert_eq!(subscriptions.lock().len(), 1);
+    }
+}
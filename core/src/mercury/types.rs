@@ -1,6 +1,8 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use protobuf::Message;
 use std::io::Write;
+use std::time::Duration;
+use thiserror::Error;
 
 use crate::protocol;
 
@@ -27,8 +29,31 @@ pub struct MercuryResponse {
     pub payload: Vec<Vec<u8>>,
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
-pub struct MercuryError;
+#[derive(Debug, Error, Hash, PartialEq, Eq, Copy, Clone)]
+pub enum MercuryError {
+    // The request failed, or was answered with an error status.
+    #[error("Mercury request failed")]
+    Failed,
+    // No response came within `SessionConfig::mercury_timeout`.
+    #[error("Mercury request timed out")]
+    Timeout,
+    // The server failed to handle the request, with a 5xx status code.
+    #[error("Mercury request failed with server error {0}")]
+    Server(i32),
+    // The server answered with 429, and asked to wait this long before making requests again.
+    #[error("Mercury request was rate limited for {0:?}")]
+    RateLimited(Duration),
+}
+
+impl MercuryError {
+    /// Whether the same request may succeed when it is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            MercuryError::Timeout | MercuryError::Server(_) | MercuryError::RateLimited(_)
+        )
+    }
+}
 
 impl ToString for MercuryMethod {
     fn to_string(&self) -> String {
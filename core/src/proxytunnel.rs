@@ -1,6 +1,192 @@
+use std::error::Error;
+use std::future::Future;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_proxy::{Intercept, Proxy, ProxyConnector, ProxyStream};
+use percent_encoding::percent_decode_str;
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{self, TcpStream};
+use url::Url;
+
+use crate::redact;
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USERNAME_PASSWORD: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 0x01;
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+
+/// Why a connection through the proxy in `SessionConfig::proxy` failed. It is the inner
+/// error of the `io::Error` that the connection fails with, so that proxy failures can be told
+/// apart from failures of the endpoint itself.
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("Cannot reach the proxy {proxy}: {source}")]
+    Unreachable { proxy: String, source: io::Error },
+    #[error("The proxy offers none of the supported authentication methods")]
+    NoAcceptableMethod,
+    #[error("The proxy rejected the username and password")]
+    AuthenticationFailed,
+    #[error("The proxy could not connect to {target}: {reason}")]
+    TargetFailed { target: String, reason: String },
+    #[error("Invalid response from the proxy: {0}")]
+    Protocol(String),
+    #[error("Unsupported proxy type \"{0}\", use http, socks5 or socks5h")]
+    UnsupportedScheme(String),
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(e: ProxyError) -> Self {
+        let kind = match e {
+            ProxyError::Unreachable { ref source, .. } => source.kind(),
+            ProxyError::NoAcceptableMethod | ProxyError::AuthenticationFailed => {
+                io::ErrorKind::PermissionDenied
+            }
+            ProxyError::TargetFailed { .. } => io::ErrorKind::ConnectionRefused,
+            ProxyError::Protocol(_) => io::ErrorKind::InvalidData,
+            ProxyError::UnsupportedScheme(_) => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+// Whether the proxy URL is for a SOCKS5 proxy, rather than an HTTP one.
+pub fn is_socks(proxy: &Url) -> bool {
+    matches!(proxy.scheme(), "socks5" | "socks5h")
+}
+
+/// A hyper connector that goes through the proxy in `SessionConfig::proxy`, if there is one,
+/// for HTTP requests that should take the same way as the connection to the access point.
+#[derive(Clone)]
+pub struct ProxiedConnector(Connector);
+
+#[derive(Clone)]
+enum Connector {
+    Direct(HttpConnector),
+    Http(ProxyConnector<HttpConnector>),
+    // hyper-proxy doesn't do SOCKS5.
+    Socks { proxy: Url, remote_dns: bool },
+}
+
+impl ProxiedConnector {
+    pub fn new(proxy: Option<&Url>, remote_dns: bool) -> Self {
+        Self(match proxy {
+            Some(proxy) if is_socks(proxy) => Connector::Socks {
+                proxy: proxy.clone(),
+                remote_dns,
+            },
+            Some(proxy) => {
+                // Panic safety: all URLs are valid URIs
+                let uri = proxy.to_string().parse().unwrap();
+                let proxy = Proxy::new(Intercept::All, uri);
+                Connector::Http(ProxyConnector::from_proxy_unsecured(
+                    HttpConnector::new(),
+                    proxy,
+                ))
+            }
+            None => Connector::Direct(HttpConnector::new()),
+        })
+    }
+}
+
+impl Service<Uri> for ProxiedConnector {
+    type Response = ProxyStream<TcpStream>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.0 {
+            Connector::Direct(ref mut connector) => connector.poll_ready(cx).map_err(Into::into),
+            Connector::Http(ref mut connector) => connector.poll_ready(cx).map_err(Into::into),
+            Connector::Socks { .. } => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self.0 {
+            Connector::Direct(ref mut connector) => {
+                let connecting = connector.call(uri);
+                Box::pin(async move { Ok(ProxyStream::NoProxy(connecting.await?)) })
+            }
+            Connector::Http(ref mut connector) => {
+                let connecting = connector.call(uri);
+                Box::pin(async move { Ok(connecting.await?) })
+            }
+            Connector::Socks {
+                ref proxy,
+                remote_dns,
+            } => {
+                let proxy = proxy.clone();
+                Box::pin(async move {
+                    let host = uri.host().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "The URL contains no hostname")
+                    })?;
+                    let port = uri.port_u16().unwrap_or(80);
+                    let stream = connect(&proxy, host, port, remote_dns).await?;
+                    Ok(ProxyStream::NoProxy(stream))
+                })
+            }
+        }
+    }
+}
+
+// Connects to `host:port` through the proxy. With `remote_dns`, or a socks5h URL, a SOCKS5
+// proxy resolves the host, otherwise it is resolved here. HTTP proxies always resolve it.
+pub async fn connect(
+    proxy: &Url,
+    host: &str,
+    port: u16,
+    remote_dns: bool,
+) -> io::Result<TcpStream> {
+    if !matches!(proxy.scheme(), "http" | "socks5" | "socks5h") {
+        return Err(ProxyError::UnsupportedScheme(proxy.scheme().to_string()).into());
+    }
+
+    let unreachable = |source| ProxyError::Unreachable {
+        proxy: match (proxy.host_str(), proxy.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            _ => redact::url(proxy),
+        },
+        source,
+    };
+    let addr = proxy
+        .socket_addrs(|| None)
+        .and_then(|addrs| {
+            addrs.into_iter().next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Can't resolve proxy server address",
+                )
+            })
+        })
+        .map_err(unreachable)?;
+    let socket = TcpStream::connect(&addr).await.map_err(unreachable)?;
+
+    if is_socks(proxy) {
+        let username = percent_decode_str(proxy.username()).decode_utf8_lossy();
+        let password = proxy
+            .password()
+            .map(|password| percent_decode_str(password).decode_utf8_lossy());
+        let auth = match (username.is_empty(), &password) {
+            (true, None) => None,
+            (_, password) => Some((&*username, password.as_deref().unwrap_or(""))),
+        };
+        let remote_dns = remote_dns || proxy.scheme() == "socks5h";
+        socks5_connect(socket, host, port, auth, remote_dns).await
+    } else {
+        proxy_connect(socket, host, &port.to_string()).await
+    }
+}
 
 pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
     mut proxy_connection: T,
@@ -22,7 +208,7 @@ pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
     loop {
         let bytes_read = proxy_connection.read(&mut buffer[offset..]).await?;
         if bytes_read == 0 {
-            return Err(io::Error::new(io::ErrorKind::Other, "Early EOF from proxy"));
+            return Err(ProxyError::Protocol("Early EOF from proxy".to_string()).into());
         }
         offset += bytes_read;
 
@@ -31,20 +217,22 @@ pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
 
         let status = response
             .parse(&buffer[..offset])
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            .map_err(|err| ProxyError::Protocol(err.to_string()))?;
 
         if status.is_complete() {
             return match response.code {
                 Some(200) => Ok(proxy_connection), // Proxy says all is well
                 Some(code) => {
                     let reason = response.reason.unwrap_or("no reason");
-                    let msg = format!("Proxy responded with {}: {}", code, reason);
-                    Err(io::Error::new(io::ErrorKind::Other, msg))
+                    Err(ProxyError::TargetFailed {
+                        target: format!("{}:{}", connect_host, connect_port),
+                        reason: format!("Proxy responded with {}: {}", code, reason),
+                    }
+                    .into())
+                }
+                None => {
+                    Err(ProxyError::Protocol("Malformed response from proxy".to_string()).into())
                 }
-                None => Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Malformed response from proxy",
-                )),
             };
         }
 
@@ -53,3 +241,219 @@ pub async fn proxy_connect<T: AsyncRead + AsyncWrite + Unpin>(
         }
     }
 }
+
+fn socks_reply_reason(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+// Asks a SOCKS5 proxy to connect to `host:port`, see RFC 1928, and RFC 1929 for the username
+// and password.
+pub async fn socks5_connect<T: AsyncRead + AsyncWrite + Unpin>(
+    mut proxy_connection: T,
+    host: &str,
+    port: u16,
+    auth: Option<(&str, &str)>,
+    remote_dns: bool,
+) -> io::Result<T> {
+    let target = format!("{}:{}", host, port);
+    let too_long = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5 only takes names and credentials of up to 255 bytes",
+        )
+    };
+
+    if auth.is_some() {
+        proxy_connection
+            .write_all(&[SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USERNAME_PASSWORD])
+            .await?;
+    } else {
+        proxy_connection
+            .write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTH])
+            .await?;
+    }
+
+    let mut choice = [0u8; 2];
+    proxy_connection.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(ProxyError::Protocol(format!("SOCKS version {}", choice[0])).into());
+    }
+    match (choice[1], auth) {
+        (SOCKS_NO_AUTH, _) => (),
+        (SOCKS_USERNAME_PASSWORD, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(too_long());
+            }
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            proxy_connection.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            proxy_connection.read_exact(&mut status).await?;
+            if status[0] != 0x01 {
+                return Err(
+                    ProxyError::Protocol(format!("Authentication version {}", status[0])).into(),
+                );
+            }
+            if status[1] != 0 {
+                return Err(ProxyError::AuthenticationFailed.into());
+            }
+        }
+        (SOCKS_NO_ACCEPTABLE_METHOD, _) | (SOCKS_USERNAME_PASSWORD, None) => {
+            return Err(ProxyError::NoAcceptableMethod.into())
+        }
+        (method, _) => {
+            return Err(ProxyError::Protocol(format!("Authentication method {}", method)).into())
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0x00];
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) if remote_dns => None,
+        Err(_) => {
+            let addr: Option<SocketAddr> = net::lookup_host(&target).await?.next();
+            let addr = addr.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("Can't resolve {}", target))
+            })?;
+            Some(addr.ip())
+        }
+    };
+    match ip {
+        Some(IpAddr::V4(ip)) => {
+            request.push(SOCKS_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(IpAddr::V6(ip)) => {
+            request.push(SOCKS_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        None => {
+            if host.len() > 255 {
+                return Err(too_long());
+            }
+            request.push(SOCKS_ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    proxy_connection.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    proxy_connection.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(ProxyError::Protocol(format!("SOCKS version {}", reply[0])).into());
+    }
+    if reply[1] != 0 {
+        return Err(ProxyError::TargetFailed {
+            target,
+            reason: socks_reply_reason(reply[1]).to_string(),
+        }
+        .into());
+    }
+
+    // The address the proxy connected from, which isn't needed.
+    let bound_len = match reply[3] {
+        SOCKS_ATYP_IPV4 => 4,
+        SOCKS_ATYP_IPV6 => 16,
+        SOCKS_ATYP_DOMAIN => proxy_connection.read_u8().await? as usize,
+        atyp => return Err(ProxyError::Protocol(format!("Address type {}", atyp)).into()),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    proxy_connection.read_exact(&mut bound).await?;
+
+    Ok(proxy_connection)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Plays the proxy side of a SOCKS5 handshake with a username and password.
+    async fn serve_socks5<T: AsyncRead + AsyncWrite + Unpin>(client: T, reply: u8) -> Vec<u8> {
+        serve_socks5_auth(client, [1, 0], reply).await
+    }
+
+    async fn serve_socks5_auth<T: AsyncRead + AsyncWrite + Unpin>(
+        mut client: T,
+        auth_reply: [u8; 2],
+        reply: u8,
+    ) -> Vec<u8> {
+        let mut greeting = [0u8; 4];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 2, 0, 2]);
+        client.write_all(&[5, 2]).await.unwrap();
+
+        let mut auth = [0u8; 11];
+        client.read_exact(&mut auth).await.unwrap();
+        assert_eq!(&auth, b"\x01\x04user\x04pass");
+        client.write_all(&auth_reply).await.unwrap();
+        if auth_reply != [1, 0] {
+            return Vec::new();
+        }
+
+        let mut request = [0u8; 5];
+        client.read_exact(&mut request).await.unwrap();
+        let mut rest = vec![0u8; request[4] as usize + 2];
+        client.read_exact(&mut rest).await.unwrap();
+        client
+            .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+
+        rest
+    }
+
+    #[tokio::test]
+    async fn socks5_resolves_remotely() {
+        let (client, proxy) = tokio::io::duplex(1024);
+        let server = tokio::spawn(serve_socks5(proxy, 0));
+
+        socks5_connect(client, "ap.spotify.com", 4070, Some(("user", "pass")), true)
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), b"ap.spotify.com\x0f\xe6");
+    }
+
+    #[tokio::test]
+    async fn socks5_target_failure() {
+        let (client, proxy) = tokio::io::duplex(1024);
+        tokio::spawn(serve_socks5(proxy, 5));
+
+        let e = socks5_connect(client, "ap.spotify.com", 4070, Some(("user", "pass")), true)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(matches!(
+            e.get_ref().and_then(|e| e.downcast_ref()),
+            Some(ProxyError::TargetFailed { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn socks5_auth_reply_of_another_version() {
+        let (client, proxy) = tokio::io::duplex(1024);
+        tokio::spawn(serve_socks5_auth(proxy, [5, 0], 0));
+
+        let e = socks5_connect(client, "ap.spotify.com", 4070, Some(("user", "pass")), true)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.get_ref().and_then(|e| e.downcast_ref()),
+            Some(ProxyError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+//! Keeps a session from being rate limited, and from failing everything once it is. When
+//! the server answers a request with 429, the requests that can wait are held back until the
+//! time it asked for has passed, while the ones playback depends on go ahead. The number of
+//! requests that can wait which are made at the same time can be limited as well, see
+//! `SessionConfig::max_concurrent_requests`.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// How long requests are held back after a 429 that doesn't say, with `Retry-After`, how
+/// long to wait.
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestPriority {
+    // Needed for playback or control, like audio keys, Connect messages and the metadata of
+    // the track or context that is being loaded. These are neither held back nor counted.
+    Essential,
+    // Everything else, like metadata.
+    Normal,
+}
+
+/// The rate limiting of a session, see the module.
+pub struct RateLimiter {
+    paused_until: Mutex<Option<Instant>>,
+    permits: Option<Arc<Semaphore>>,
+}
+
+/// Allows a request to be made, for as long as it is kept.
+pub struct RequestPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl RateLimiter {
+    /// Without `max_concurrent`, any number of requests can be made at the same time.
+    pub fn new(max_concurrent: Option<usize>) -> Self {
+        Self {
+            paused_until: Mutex::new(None),
+            permits: max_concurrent.map(|permits| Arc::new(Semaphore::new(permits.max(1)))),
+        }
+    }
+
+    /// Holds back the requests that can wait for `retry_after`. A pause that goes on for
+    /// longer already is kept, in which case this returns `false`.
+    pub fn rate_limited(&self, retry_after: Duration) -> bool {
+        let until = Instant::now() + retry_after;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if matches!(*paused_until, Some(paused) if paused >= until) {
+            return false;
+        }
+        *paused_until = Some(until);
+
+        warn!(
+            "Rate limited, holding back requests for {} ms",
+            retry_after.as_millis()
+        );
+        true
+    }
+
+    /// How long the requests that can wait are still held back for.
+    pub fn remaining_pause(&self) -> Option<Duration> {
+        let paused_until = (*self.paused_until.lock().unwrap())?;
+        let remaining = paused_until.saturating_duration_since(Instant::now());
+        if remaining > Duration::ZERO {
+            Some(remaining)
+        } else {
+            None
+        }
+    }
+
+    /// Waits until a request of the priority can be made. Waiting for the pause needs the
+    /// timers of a Tokio runtime, which an `Essential` request doesn't.
+    pub async fn acquire(&self, priority: RequestPriority) -> RequestPermit {
+        if priority == RequestPriority::Essential {
+            return RequestPermit { _permit: None };
+        }
+
+        loop {
+            while let Some(remaining) = self.remaining_pause() {
+                tokio::time::sleep(remaining).await;
+            }
+
+            let permit = match &self.permits {
+                // panic safety: the semaphore is never closed.
+                Some(permits) => Some(permits.clone().acquire_owned().await.unwrap()),
+                None => None,
+            };
+
+            // Rate limited again while waiting for the permit.
+            if self.remaining_pause().is_none() {
+                return RequestPermit { _permit: permit };
+            }
+        }
+    }
+}
+
+/// How long a 429 response asks to wait with its `Retry-After` header, in seconds.
+pub fn retry_after<'a>(mut headers: impl Iterator<Item = (&'a str, &'a str)>) -> Duration {
+    headers
+        .find(|(key, _)| key.eq_ignore_ascii_case("retry-after"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_that_can_wait_are_held_back() {
+        let limiter = RateLimiter::new(Some(1));
+
+        assert!(limiter.rate_limited(Duration::from_millis(50)));
+        // A shorter pause doesn't cut it short.
+        assert!(!limiter.rate_limited(Duration::from_millis(10)));
+
+        let start = Instant::now();
+        let _essential = limiter.acquire(RequestPriority::Essential).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        let normal = limiter.acquire(RequestPriority::Normal).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The only permit is taken.
+        let second = limiter.acquire(RequestPriority::Normal);
+        assert!(tokio::time::timeout(Duration::from_millis(10), second)
+            .await
+            .is_err());
+        drop(normal);
+        limiter.acquire(RequestPriority::Normal).await;
+
+        assert_eq!(
+            retry_after(std::iter::once(("Retry-After", "2"))),
+            Duration::from_secs(2)
+        );
+        assert_eq!(retry_after(std::iter::empty()), DEFAULT_RETRY_AFTER);
+    }
+}
@@ -1,11 +1,12 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::task::Context;
 use std::task::Poll;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
@@ -13,17 +14,27 @@ use futures_core::TryStream;
 use futures_util::{future, ready, StreamExt, TryStreamExt};
 use once_cell::sync::OnceCell;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::apresolve::apresolve;
+use crate::apresolve::{apresolve, AP_FALLBACK};
 use crate::audio_key::AudioKeyManager;
 use crate::authentication::Credentials;
 use crate::cache::Cache;
 use crate::channel::ChannelManager;
-use crate::config::SessionConfig;
-use crate::connection::{self, AuthenticationError};
-use crate::mercury::MercuryManager;
+use crate::config::{CountryCode, SessionConfig};
+use crate::connection;
+pub use crate::connection::AuthenticationError;
+use crate::mercury::{MercuryManager, PushMessage, PushSubscriptions};
+use crate::rate_limit::RateLimiter;
+use crate::token::{Token, TokenCache, TokenError, TokenProvider, LOGIN_SCOPES};
+
+/// How long `Session::shutdown` waits for the connection to close.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a connection from `Session::prewarm` is kept for the credentials to come.
+pub const PREWARM_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Error)]
 pub enum SessionError {
@@ -31,10 +42,176 @@ pub enum SessionError {
     AuthenticationError(#[from] AuthenticationError),
     #[error("Cannot create session: {0}")]
     IoError(#[from] io::Error),
+    #[error(transparent)]
+    Token(#[from] TokenError),
+}
+
+/// Something that happened to a session, see `Session::subscribe_events`.
+///
+/// The session only sends `RateLimited` and `CountryChanged` itself. The others are for
+/// whatever connects and reconnects sessions, like librespot, to pass on along with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The session was rate limited, and holds back requests for metadata and the like for
+    /// `wait_ms`, see `rate_limit`.
+    RateLimited { wait_ms: u64 },
+    /// The server sent the country of the account, see `Session::country`, which it does
+    /// right after logging in and again when it changes.
+    CountryChanged { country: CountryCode },
+    /// The session was connected, at startup or after one was lost, to `access_point` after
+    /// trying `connect_attempts` of them. The country of the account is given if the server
+    /// sent it already, `CountryChanged` follows otherwise.
+    Connected {
+        session_id: usize,
+        user_name: String,
+        access_point: String,
+        connect_attempts: u32,
+        country: Option<CountryCode>,
+    },
+    /// The session was lost, or shut down.
+    Disconnected {
+        session_id: usize,
+        user_name: String,
+    },
+    /// A session is connected again in `delay_ms`, for the `attempt`th time since one was lost.
+    Reconnecting { attempt: u32, delay_ms: u64 },
+}
+
+/// The access point a session connected to, and how many it tried, including that one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessPoint {
+    pub address: String,
+    pub attempts: u32,
+}
+
+// The access points tried while connecting, and why the last one failed.
+#[derive(Default)]
+struct ApAttempts {
+    tried: Vec<String>,
+    last_error: Option<io::Error>,
+}
+
+impl ApAttempts {
+    async fn connect(&mut self, config: &SessionConfig, ap: &str) -> Option<connection::Transport> {
+        if self.tried.iter().any(|tried| tried == ap) {
+            return None;
+        }
+        self.tried.push(ap.to_string());
+
+        info!("Connecting to AP \"{}\"", ap);
+        let connecting = connection::connect(
+            ap.to_string(),
+            config.proxy.as_ref(),
+            config.proxy_remote_dns,
+        );
+        let result = match time::timeout(config.ap_timeout, connecting).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no connection after {:?}", config.ap_timeout),
+            )),
+        };
+
+        match result {
+            Ok(conn) => {
+                info!("Using AP \"{}\"", ap);
+                Some(conn)
+            }
+            Err(e) => {
+                warn!("Could not connect to AP \"{}\": {}", ap, e);
+                self.last_error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn connected(self, cache: Option<&Cache>, known: &[String]) -> AccessPoint {
+        // panic safety: only called after a successful attempt, which is the last one.
+        let address = self.tried.last().cloned().unwrap();
+        if let Some(cache) = cache {
+            let mut access_points = vec![address.clone()];
+            access_points.extend(known.iter().filter(|ap| **ap != address).cloned());
+            cache.save_access_points(&access_points);
+        }
+
+        AccessPoint {
+            address,
+            attempts: self.tried.len() as u32,
+        }
+    }
+
+    fn error(self) -> io::Error {
+        self.last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No access point to connect to")
+        })
+    }
+}
+
+/// A connection to an access point that is set up, handshake included, but not authenticated
+/// yet. See [`Session::prewarm`].
+pub struct PrewarmedConnection {
+    // Taken and closed once the connection expires.
+    conn: Arc<Mutex<Option<connection::Transport>>>,
+    access_point: AccessPoint,
+    config: SessionConfig,
+    cache: Option<Cache>,
+    expires_at: Instant,
+}
+
+impl PrewarmedConnection {
+    /// The access point that is connected to.
+    pub fn access_point(&self) -> &AccessPoint {
+        &self.access_point
+    }
+
+    /// Whether the connection is past `PREWARM_TIMEOUT`, after which it is no longer used.
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    /// Authenticates on the connection, like `Session::connect`. An expired connection is
+    /// discarded and a new one made, as is one that the access point closed in the meantime.
+    pub async fn connect(
+        self,
+        credentials: Credentials,
+        store_credentials: bool,
+    ) -> Result<(Session, Credentials), SessionError> {
+        let conn = self.conn.lock().unwrap().take();
+        let conn = match conn {
+            Some(conn) if !self.is_expired() => conn,
+            _ => {
+                debug!("The pre-warmed connection expired, connecting again");
+                return Session::connect(self.config, credentials, self.cache, store_credentials)
+                    .await;
+            }
+        };
+
+        let result = Session::authenticate(
+            conn,
+            self.access_point,
+            self.config.clone(),
+            credentials.clone(),
+            self.cache.clone(),
+            store_credentials,
+        )
+        .await;
+        match result {
+            Err(SessionError::AuthenticationError(AuthenticationError::IoError(e))) => {
+                warn!("The pre-warmed connection failed: {}, connecting again", e);
+                Session::connect(self.config, credentials, self.cache, store_credentials).await
+            }
+            result => result,
+        }
+    }
 }
 
 struct SessionData {
-    country: String,
+    country: Option<CountryCode>,
+    country_subscribers: Vec<mpsc::UnboundedSender<CountryCode>>,
+    event_subscribers: Vec<mpsc::UnboundedSender<SessionEvent>>,
+    // The attributes of the account, from the product info the server sends.
+    user_attributes: HashMap<String, String>,
+    user_attribute_subscribers: Vec<mpsc::UnboundedSender<()>>,
     time_delta: i64,
     canonical_username: String,
     invalid: bool,
@@ -42,14 +219,23 @@ struct SessionData {
 
 struct SessionInternal {
     config: SessionConfig,
+    access_point: AccessPoint,
     data: RwLock<SessionData>,
 
-    tx_connection: mpsc::UnboundedSender<(u8, Vec<u8>)>,
+    // `None` closes the connection, after the packets sent before it.
+    tx_connection: mpsc::UnboundedSender<Option<(u8, Vec<u8>)>>,
+    // Resolves once the connection is closed.
+    closed: Mutex<Option<oneshot::Receiver<()>>>,
 
     audio_key: OnceCell<AudioKeyManager>,
     channel: OnceCell<ChannelManager>,
     mercury: OnceCell<MercuryManager>,
     cache: Option<Arc<Cache>>,
+    tokens: Option<TokenCache>,
+    // How many sessions were lost before this one, see `take_over_from`.
+    reconnects: AtomicU64,
+    push_subscriptions: PushSubscriptions,
+    rate_limiter: RateLimiter,
 
     handle: tokio::runtime::Handle,
 
@@ -68,24 +254,105 @@ impl Session {
         cache: Option<Cache>,
         store_credentials: bool,
     ) -> Result<(Session, Credentials), SessionError> {
-        let ap = apresolve(config.proxy.as_ref(), config.ap_port).await;
+        let (conn, access_point) = Self::connect_to_ap(&config, cache.as_ref()).await?;
+        Self::authenticate(
+            conn,
+            access_point,
+            config,
+            credentials,
+            cache,
+            store_credentials,
+        )
+        .await
+    }
 
-        info!("Connecting to AP \"{}\"", ap);
-        let mut conn = connection::connect(ap, config.proxy.as_ref()).await?;
+    /// Connects to an access point ahead of the credentials, such as while a login screen is
+    /// shown, so that authenticating with [`PrewarmedConnection::connect`] is quick. The
+    /// connection is only used for `PREWARM_TIMEOUT`.
+    pub async fn prewarm(
+        config: SessionConfig,
+        cache: Option<Cache>,
+    ) -> Result<PrewarmedConnection, SessionError> {
+        let (conn, access_point) = Self::connect_to_ap(&config, cache.as_ref()).await?;
+        debug!("Pre-warmed a connection to {}", access_point.address);
+
+        let conn = Arc::new(Mutex::new(Some(conn)));
+        tokio::spawn(close_when_expired(Arc::downgrade(&conn), PREWARM_TIMEOUT));
 
+        Ok(PrewarmedConnection {
+            conn,
+            access_point,
+            config,
+            cache,
+            expires_at: Instant::now() + PREWARM_TIMEOUT,
+        })
+    }
+
+    async fn authenticate(
+        mut conn: connection::Transport,
+        access_point: AccessPoint,
+        config: SessionConfig,
+        credentials: Credentials,
+        cache: Option<Cache>,
+        store_credentials: bool,
+    ) -> Result<(Session, Credentials), SessionError> {
         let reusable_credentials =
             connection::authenticate(&mut conn, credentials, &config.device_id).await?;
         info!("Authenticated as \"{}\" !", reusable_credentials.username);
         if let Some(cache) = &cache {
             if store_credentials {
-                cache.save_credentials(&reusable_credentials);
+                // Encrypting them takes a while, which would hold up the runtime.
+                let cache = cache.clone();
+                let credentials = reusable_credentials.clone();
+                let saved =
+                    tokio::task::spawn_blocking(move || cache.save_credentials(&credentials)).await;
+                if let Err(e) = saved {
+                    warn!("Cannot save credentials to cache: {}", e);
+                }
             }
         }
 
         let session = Session::create(
             conn,
+            access_point,
+            config,
+            cache,
+            None,
+            reusable_credentials.username.clone(),
+            tokio::runtime::Handle::current(),
+        );
+
+        Ok((session, reusable_credentials))
+    }
+
+    /// Connects with the access tokens of `provider` instead of credentials, see
+    /// [`Session::token`]. The credentials it returns are the reusable ones the server sent,
+    /// which aren't stored in the cache.
+    pub async fn connect_with_token_provider(
+        config: SessionConfig,
+        provider: Arc<dyn TokenProvider>,
+        cache: Option<Cache>,
+    ) -> Result<(Session, Credentials), SessionError> {
+        let tokens = TokenCache::new(provider);
+
+        let device_id = &config.device_id;
+        let ((conn, access_point), reusable_credentials) = authenticate_with_token(
+            &tokens,
+            || async { Ok(Self::connect_to_ap(&config, cache.as_ref()).await?) },
+            |(mut conn, access_point), credentials| async move {
+                let result = connection::authenticate(&mut conn, credentials, device_id).await;
+                result.map(|credentials| ((conn, access_point), credentials))
+            },
+        )
+        .await?;
+        info!("Authenticated as \"{}\" !", reusable_credentials.username);
+
+        let session = Session::create(
+            conn,
+            access_point,
             config,
             cache,
+            Some(tokens),
             reusable_credentials.username.clone(),
             tokio::runtime::Handle::current(),
         );
@@ -93,40 +360,115 @@ impl Session {
         Ok((session, reusable_credentials))
     }
 
+    // Tries the configured access points, then the ones that worked last, then the resolved
+    // ones and at last the fallback, until one takes the connection.
+    async fn connect_to_ap(
+        config: &SessionConfig,
+        cache: Option<&Cache>,
+    ) -> io::Result<(connection::Transport, AccessPoint)> {
+        let mut attempts = ApAttempts::default();
+
+        let cached = match cache {
+            Some(cache) if !config.strict_access_points => {
+                cache.access_points(config.ap_cache_ttl).unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        for ap in config.access_points.iter().chain(&cached) {
+            if let Some(conn) = attempts.connect(config, ap).await {
+                return Ok((conn, attempts.connected(cache, &cached)));
+            }
+        }
+
+        if config.strict_access_points {
+            return Err(attempts.error());
+        } else if !attempts.tried.is_empty() {
+            warn!("None of the configured or cached APs could be reached, resolving them");
+        }
+
+        let resolving = apresolve(
+            config.proxy.as_ref(),
+            config.ap_port,
+            config.proxy_remote_dns,
+        );
+        let resolved = time::timeout(config.ap_timeout, resolving)
+            .await
+            .unwrap_or_else(|_| {
+                warn!("Resolving APs timed out after {:?}", config.ap_timeout);
+                Vec::new()
+            });
+        if resolved.is_empty() {
+            warn!("Using fallback \"{}\"", AP_FALLBACK);
+        }
+
+        for ap in resolved
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::once(AP_FALLBACK))
+        {
+            if let Some(conn) = attempts.connect(config, ap).await {
+                return Ok((conn, attempts.connected(cache, &resolved)));
+            }
+        }
+
+        Err(attempts.error())
+    }
+
     fn create(
         transport: connection::Transport,
+        access_point: AccessPoint,
         config: SessionConfig,
         cache: Option<Cache>,
+        tokens: Option<TokenCache>,
         username: String,
         handle: tokio::runtime::Handle,
     ) -> Session {
         let (sink, stream) = transport.split();
 
         let (sender_tx, sender_rx) = mpsc::unbounded_channel();
+        let (closed_tx, closed_rx) = oneshot::channel();
         let session_id = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
 
         debug!("new Session[{}]", session_id);
 
+        let rate_limiter = RateLimiter::new(config.max_concurrent_requests);
         let session = Session(Arc::new(SessionInternal {
             config,
+            access_point,
             data: RwLock::new(SessionData {
-                country: String::new(),
+                country: None,
+                country_subscribers: Vec::new(),
+                event_subscribers: Vec::new(),
+                user_attributes: HashMap::new(),
+                user_attribute_subscribers: Vec::new(),
                 canonical_username: username,
                 invalid: false,
                 time_delta: 0,
             }),
             tx_connection: sender_tx,
+            closed: Mutex::new(Some(closed_rx)),
             cache: cache.map(Arc::new),
+            tokens,
             audio_key: OnceCell::new(),
             channel: OnceCell::new(),
             mercury: OnceCell::new(),
+            reconnects: AtomicU64::new(0),
+            push_subscriptions: PushSubscriptions::default(),
+            rate_limiter,
             handle,
             session_id,
         }));
 
-        let sender_task = UnboundedReceiverStream::new(sender_rx)
-            .map(Ok)
-            .forward(sink);
+        let sender_task = async move {
+            let result = UnboundedReceiverStream::new(sender_rx)
+                .take_while(|packet| future::ready(packet.is_some()))
+                .filter_map(future::ready)
+                .map(Ok)
+                .forward(sink)
+                .await;
+            let _ = closed_tx.send(());
+            result
+        };
         let receiver_task = DispatchTask(stream, session.weak());
 
         tokio::spawn(async move {
@@ -147,9 +489,11 @@ impl Session {
     }
 
     pub fn channel(&self) -> &ChannelManager {
-        self.0
-            .channel
-            .get_or_init(|| ChannelManager::new(self.weak()))
+        self.0.channel.get_or_init(|| {
+            let channel = ChannelManager::new(self.weak());
+            channel.set_download_rate_limit(self.config().download_rate_limit);
+            channel
+        })
     }
 
     pub fn mercury(&self) -> &MercuryManager {
@@ -162,6 +506,11 @@ impl Session {
         self.0.data.read().unwrap().time_delta
     }
 
+    /// The runtime the session runs on, see `util::run_on`.
+    pub fn runtime_handle(&self) -> &tokio::runtime::Handle {
+        &self.0.handle
+    }
+
     pub fn spawn<T>(&self, task: T)
     where
         T: Future + Send + 'static,
@@ -197,9 +546,37 @@ impl Session {
             }
             0x4a => (),
             0x1b => {
-                let country = String::from_utf8(data.as_ref().to_owned()).unwrap();
-                info!("Country: {:?}", country);
-                self.0.data.write().unwrap().country = country;
+                let country = match String::from_utf8_lossy(data.as_ref()).parse() {
+                    Ok(country) => country,
+                    Err(_) => {
+                        warn!(
+                            "Invalid country {:?}",
+                            String::from_utf8_lossy(data.as_ref())
+                        );
+                        return;
+                    }
+                };
+                info!("Country: {}", country);
+
+                let mut data = self.0.data.write().unwrap();
+                if data.country.replace(country) != Some(country) {
+                    data.country_subscribers
+                        .retain(|subscriber| subscriber.send(country).is_ok());
+                    drop(data);
+                    self.send_event(SessionEvent::CountryChanged { country });
+                }
+            }
+            0x50 => {
+                let product_info = String::from_utf8_lossy(data.as_ref());
+                let attributes = parse_product_info(&product_info);
+                debug!("Account attributes: {:?}", attributes);
+
+                let mut data = self.0.data.write().unwrap();
+                if data.user_attributes != attributes {
+                    data.user_attributes = attributes;
+                    data.user_attribute_subscribers
+                        .retain(|subscriber| subscriber.send(()).is_ok());
+                }
             }
 
             0x9 | 0xa => self.channel().dispatch(cmd, data),
@@ -210,14 +587,92 @@ impl Session {
     }
 
     pub fn send_packet(&self, cmd: u8, data: Vec<u8>) {
-        self.0.tx_connection.send((cmd, data)).unwrap();
+        if self.0.tx_connection.send(Some((cmd, data))).is_err() {
+            debug!("Not sending packet {:#x}, the connection is closed", cmd);
+        }
     }
 
     pub fn cache(&self) -> Option<&Arc<Cache>> {
         self.0.cache.as_ref()
     }
 
-    fn config(&self) -> &SessionConfig {
+    /// Takes over from `previous`, a session that was lost and that this one reconnected for:
+    /// counts the reconnect, and carries its push subscriptions over.
+    pub fn take_over_from(&self, previous: &Session) {
+        self.0
+            .reconnects
+            .store(previous.reconnects() + 1, Ordering::Relaxed);
+        self.push_subscriptions()
+            .carry_over(previous.push_subscriptions());
+    }
+
+    /// How many times the connection was made again after it was lost, counting over the
+    /// sessions this one took over from.
+    pub fn reconnects(&self) -> u64 {
+        self.0.reconnects.load(Ordering::Relaxed)
+    }
+
+    /// Holds back requests while the session is rate limited, see `rate_limit`.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.0.rate_limiter
+    }
+
+    // Holds back requests for `retry_after`, and tells the subscribers if that pauses them
+    // for longer than they were already.
+    pub(crate) fn rate_limited(&self, retry_after: Duration) {
+        if self.0.rate_limiter.rate_limited(retry_after) {
+            self.send_event(SessionEvent::RateLimited {
+                wait_ms: retry_after.as_millis() as u64,
+            });
+        }
+    }
+
+    /// Receives what happens to the session from now on, see `SessionEvent`.
+    pub fn subscribe_events(&self) -> mpsc::UnboundedReceiver<SessionEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.0.data.write().unwrap().event_subscribers.push(tx);
+        rx
+    }
+
+    fn send_event(&self, event: SessionEvent) {
+        self.0
+            .data
+            .write()
+            .unwrap()
+            .event_subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    pub fn push_subscriptions(&self) -> &PushSubscriptions {
+        &self.0.push_subscriptions
+    }
+
+    /// Receives the messages the server pushes to the session whose uri starts with
+    /// `uri_prefix`, like `hm://playlist/`. The server pushes only what the session is
+    /// subscribed to with it, see `MercuryManager::subscribe`. The subscription ends with the
+    /// session, unless the next one takes it over with `Session::take_over_from`.
+    pub fn subscribe_pushes(&self, uri_prefix: impl Into<String>) -> mpsc::Receiver<PushMessage> {
+        self.0.push_subscriptions.subscribe(uri_prefix)
+    }
+
+    /// An access token for the scopes from the provider the session was connected with, which
+    /// is asked again once the token expires.
+    pub async fn token(&self, scopes: &[&str]) -> Result<Token, TokenError> {
+        match &self.0.tokens {
+            Some(tokens) => tokens.get(scopes).await,
+            None => Err(TokenError::NoProvider),
+        }
+    }
+
+    /// Forgets the token for the scopes, for when it was rejected with a 401, so that the next
+    /// call to [`Session::token`] asks the provider for a new one.
+    pub fn invalidate_token(&self, scopes: &[&str]) {
+        if let Some(tokens) = &self.0.tokens {
+            tokens.invalidate(scopes);
+        }
+    }
+
+    pub(crate) fn config(&self) -> &SessionConfig {
         &self.0.config
     }
 
@@ -225,8 +680,57 @@ impl Session {
         self.0.data.read().unwrap().canonical_username.clone()
     }
 
-    pub fn country(&self) -> String {
-        self.0.data.read().unwrap().country.clone()
+    /// The country of the account, once the server has sent it, which it does right after
+    /// logging in. It sends it again when it changes.
+    pub fn country(&self) -> Option<CountryCode> {
+        self.0.data.read().unwrap().country
+    }
+
+    /// Receives the country of the account each time it changes, see `country`.
+    pub fn subscribe_country(&self) -> mpsc::UnboundedReceiver<CountryCode> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.0.data.write().unwrap().country_subscribers.push(tx);
+        rx
+    }
+
+    /// The country of the account, waiting up to `timeout` for the server to send it.
+    pub async fn wait_for_country(&self, timeout: Duration) -> Option<CountryCode> {
+        let mut changes = self.subscribe_country();
+        if let Some(country) = self.country() {
+            return Some(country);
+        }
+        time::timeout(timeout, changes.recv()).await.ok().flatten()
+    }
+
+    /// The country that tracks and episodes must be available in to be played: the one of
+    /// `SessionConfig::country_override`, or else the one of the account.
+    pub fn market(&self) -> Option<CountryCode> {
+        self.config().country_override.or_else(|| self.country())
+    }
+
+    /// An attribute of the account, like `filter-explicit-content`, once the server has sent
+    /// them. The server sends them again when they change.
+    pub fn user_attribute(&self, key: &str) -> Option<String> {
+        self.0
+            .data
+            .read()
+            .unwrap()
+            .user_attributes
+            .get(key)
+            .cloned()
+    }
+
+    /// Receives a message each time the attributes of the account change, see
+    /// `user_attribute`.
+    pub fn subscribe_user_attributes(&self) -> mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.0
+            .data
+            .write()
+            .unwrap()
+            .user_attribute_subscribers
+            .push(tx);
+        rx
     }
 
     pub fn device_id(&self) -> &str {
@@ -237,15 +741,44 @@ impl Session {
         SessionWeak(Arc::downgrade(&self.0))
     }
 
+    pub fn access_point(&self) -> &AccessPoint {
+        &self.0.access_point
+    }
+
     pub fn session_id(&self) -> usize {
         self.0.session_id
     }
 
-    pub fn shutdown(&self) {
+    /// Marks the session as lost, and fails the requests and downloads that are still
+    /// waiting for the server. The connection is left to close when the session is dropped.
+    pub fn invalidate(&self) {
         debug!("Invalidating session[{}]", self.0.session_id);
         self.0.data.write().unwrap().invalid = true;
         self.mercury().shutdown();
         self.channel().shutdown();
+        self.audio_key().shutdown();
+    }
+
+    /// Ends the session cleanly: it is invalidated, and the connection to the access point is
+    /// closed after the packets that were sent before, like the goodbye of `Spirc::shutdown`.
+    /// Resolves once the connection is closed, or after `SHUTDOWN_TIMEOUT`.
+    ///
+    /// The cache is written to as things change, so there is nothing left to write.
+    pub async fn shutdown(self) {
+        debug!("Shutting down session[{}]", self.0.session_id);
+        let closed = self.0.closed.lock().unwrap().take();
+
+        self.invalidate();
+        let _ = self.0.tx_connection.send(None);
+
+        if let Some(closed) = closed {
+            if time::timeout(SHUTDOWN_TIMEOUT, closed).await.is_err() {
+                warn!(
+                    "The connection of session[{}] did not close within {:?}",
+                    self.0.session_id, SHUTDOWN_TIMEOUT
+                );
+            }
+        }
     }
 
     pub fn is_invalid(&self) -> bool {
@@ -253,10 +786,92 @@ impl Session {
     }
 }
 
+// Logs in on a connection from `connect` with a token from `tokens`. The token may have been
+// revoked before it expired, so when it is rejected, a new one is asked for and tried once on
+// a new connection.
+async fn authenticate_with_token<T, C, CF, A, AF>(
+    tokens: &TokenCache,
+    mut connect: C,
+    mut authenticate: A,
+) -> Result<(T, Credentials), SessionError>
+where
+    C: FnMut() -> CF,
+    CF: Future<Output = Result<T, SessionError>>,
+    A: FnMut(T, Credentials) -> AF,
+    AF: Future<Output = Result<(T, Credentials), AuthenticationError>>,
+{
+    let conn = connect().await?;
+    let token = tokens.get(LOGIN_SCOPES).await?;
+    match authenticate(conn, Credentials::with_access_token(token.access_token)).await {
+        Err(AuthenticationError::BadCredentials) => {
+            warn!("The access token was rejected, getting a new one");
+            tokens.invalidate(LOGIN_SCOPES);
+            let conn = connect().await?;
+            let token = tokens.get(LOGIN_SCOPES).await?;
+            let credentials = Credentials::with_access_token(token.access_token);
+            Ok(authenticate(conn, credentials).await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+// Closes a pre-warmed connection that wasn't used within `timeout`, so that it isn't kept
+// open for nothing.
+async fn close_when_expired(conn: Weak<Mutex<Option<connection::Transport>>>, timeout: Duration) {
+    time::sleep(timeout).await;
+    if let Some(conn) = conn.upgrade() {
+        if conn.lock().unwrap().take().is_some() {
+            debug!("Closing the pre-warmed connection, it expired");
+        }
+    }
+}
+
+// The product info is a small XML document, `<products><product>` with an element for each
+// attribute of the account, like `<type>premium</type>`.
+fn parse_product_info(xml: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = match xml.find("<product>") {
+        Some(start) => &xml[start + "<product>".len()..],
+        None => return attributes,
+    };
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let name = match rest.find('>') {
+            Some(end) => &rest[..end],
+            None => break,
+        };
+        if name.starts_with('/') {
+            if name == "/product" {
+                break;
+            }
+            continue;
+        }
+
+        rest = &rest[name.len() + 1..];
+        let closing = format!("</{}>", name);
+        if let Some(end) = rest.find(&closing) {
+            let value = &rest[..end];
+            if !value.contains('<') {
+                attributes.insert(name.to_owned(), value.trim().to_owned());
+                rest = &rest[end + closing.len()..];
+            }
+        }
+    }
+
+    attributes
+}
+
 #[derive(Clone)]
 pub struct SessionWeak(Weak<SessionInternal>);
 
 impl SessionWeak {
+    // A session that is gone, for testing components without connecting.
+    #[cfg(test)]
+    pub(crate) fn dead() -> SessionWeak {
+        SessionWeak(Weak::new())
+    }
+
     fn try_upgrade(&self) -> Option<Session> {
         self.0.upgrade().map(Session)
     }
@@ -294,11 +909,11 @@ where
                 Some(Ok(t)) => t,
                 None => {
                     warn!("Connection to server closed.");
-                    session.shutdown();
+                    session.invalidate();
                     return Poll::Ready(Ok(()));
                 }
                 Some(Err(e)) => {
-                    session.shutdown();
+                    session.invalidate();
                     return Poll::Ready(Err(e));
                 }
             };
@@ -316,3 +931,166 @@ where
         debug!("drop Dispatch");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        authenticate_with_token, close_when_expired, parse_product_info, AccessPoint, Session,
+        SessionError,
+    };
+    use crate::config::SessionConfig;
+    use crate::connection::{ApCodec, AuthenticationError};
+    use crate::token::{Token, TokenCache, TokenError, TokenProvider};
+    use async_trait::async_trait;
+    use futures_util::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    // Hands out "token 0", "token 1" and so on.
+    struct Counting(AtomicUsize);
+
+    #[async_trait]
+    impl TokenProvider for Counting {
+        async fn get_access_token(&self, _scopes: &[&str]) -> Result<Token, TokenError> {
+            let n = self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(Token::new(
+                format!("token {}", n),
+                Duration::from_secs(3600),
+                Vec::new(),
+            ))
+        }
+    }
+
+    // Logs in on numbered connections with an access point that accepts only `accepted`.
+    async fn log_in(accepted: &'static [u8]) -> (usize, Result<usize, SessionError>) {
+        let tokens = TokenCache::new(Arc::new(Counting(AtomicUsize::new(0))));
+        let connections = AtomicUsize::new(0);
+        let result = authenticate_with_token(
+            &tokens,
+            || {
+                let conn = connections.fetch_add(1, Ordering::Relaxed);
+                async move { Ok(conn) }
+            },
+            |conn, credentials| async move {
+                if credentials.auth_data == accepted {
+                    Ok((conn, credentials))
+                } else {
+                    Err(AuthenticationError::BadCredentials)
+                }
+            },
+        )
+        .await;
+        (
+            connections.load(Ordering::Relaxed),
+            result.map(|(conn, _)| conn),
+        )
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_replaced_once() {
+        let (connections, result) = log_in(b"token 0").await;
+        assert_eq!(connections, 1);
+        assert_eq!(result.unwrap(), 0);
+
+        // The new token is tried on a new connection.
+        let (connections, result) = log_in(b"token 1").await;
+        assert_eq!(connections, 2);
+        assert_eq!(result.unwrap(), 1);
+
+        let (connections, result) = log_in(b"token 2").await;
+        assert_eq!(connections, 2);
+        assert!(matches!(
+            result,
+            Err(SessionError::AuthenticationError(
+                AuthenticationError::BadCredentials
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_prewarmed_connection_is_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let conn = Arc::new(Mutex::new(Some(Framed::new(
+            stream,
+            ApCodec::new(&[0; 32], &[0; 32]),
+        ))));
+
+        tokio::time::pause();
+        tokio::spawn(close_when_expired(
+            Arc::downgrade(&conn),
+            Duration::from_secs(30),
+        ));
+        tokio::task::yield_now().await;
+        assert!(conn.lock().unwrap().is_some());
+        tokio::time::advance(Duration::from_secs(31)).await;
+        tokio::time::resume();
+
+        // The other end sees the connection close.
+        let mut buf = [0; 1];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        assert!(conn.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_what_was_queued_and_closes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = Framed::new(server, ApCodec::new(&[0; 32], &[0; 32]));
+
+        let session = Session::create(
+            Framed::new(stream, ApCodec::new(&[0; 32], &[0; 32])),
+            AccessPoint {
+                address: "127.0.0.1:4070".to_owned(),
+                attempts: 1,
+            },
+            SessionConfig::default(),
+            None,
+            None,
+            "user".to_owned(),
+            tokio::runtime::Handle::current(),
+        );
+        session.send_packet(0x49, b"goodbye".to_vec());
+        session.clone().shutdown().await;
+        assert!(session.is_invalid());
+
+        let (cmd, data) = server.next().await.unwrap().unwrap();
+        assert_eq!((cmd, &data[..]), (0x49, &b"goodbye"[..]));
+        assert!(server.next().await.is_none());
+
+        // Nothing is sent once the connection is closed.
+        session.send_packet(0x49, b"too late".to_vec());
+    }
+
+    #[test]
+    fn product_info_attributes() {
+        let attributes = parse_product_info(
+            "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\
+             <products><product>\
+             <type>premium</type>\
+             <filter-explicit-content>1</filter-explicit-content>\
+             <ads><ad>none</ad></ads>\
+             </product></products>",
+        );
+        assert_eq!(attributes.get("type").map(String::as_str), Some("premium"));
+        assert_eq!(
+            attributes
+                .get("filter-explicit-content")
+                .map(String::as_str),
+            Some("1")
+        );
+        assert_eq!(attributes.get("ad").map(String::as_str), Some("none"));
+        assert!(!attributes.contains_key("ads"));
+        assert!(parse_product_info("").is_empty());
+    }
+}
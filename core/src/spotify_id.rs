@@ -3,6 +3,7 @@
 use std::convert::TryInto;
 use std::fmt;
 use std::string::FromUtf8Error;
+use thiserror::Error;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum SpotifyAudioType {
@@ -37,7 +38,8 @@ pub struct SpotifyId {
     pub audio_type: SpotifyAudioType,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Error, Copy, Clone, PartialEq, Eq, Hash)]
+#[error("Invalid Spotify id")]
 pub struct SpotifyIdError;
 
 const BASE62_DIGITS: &[u8; 62] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
@@ -0,0 +1,335 @@
+//! Access tokens that the application supplies, for sessions that log in without credentials
+//! of their own. See [`Session::connect_with_token_provider`](crate::session::Session).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hyper::client::connect::Connect;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use serde::Deserialize;
+use thiserror::Error;
+use url::Url;
+
+use crate::proxytunnel::ProxiedConnector;
+use crate::redact::Redacted;
+
+/// The token endpoint of the Spotify accounts service.
+pub const SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+
+/// The scopes a token needs to log in to an access point with.
+pub const LOGIN_SCOPES: &[&str] = &["streaming"];
+
+// A token is fetched again this long before it expires, so that it doesn't expire in use.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("No token provider is set for this session")]
+    NoProvider,
+    #[error("Cannot request an access token: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("Invalid token endpoint {0}")]
+    InvalidUrl(String),
+    #[error("The token endpoint responded with {status}: {body}")]
+    Rejected { status: u16, body: String },
+    #[error("Invalid token response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+    // For `TokenProvider` implementations that fail for their own reasons.
+    #[error("Cannot get an access token: {0}")]
+    Failed(String),
+}
+
+/// An OAuth access token, and when it expires.
+#[derive(Clone)]
+pub struct Token {
+    pub access_token: String,
+    pub expires_at: Instant,
+    pub scopes: Vec<String>,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("access_token", &Redacted(&self.access_token))
+            .field("expires_at", &self.expires_at)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl Token {
+    pub fn new(access_token: impl Into<String>, expires_in: Duration, scopes: Vec<String>) -> Self {
+        Self {
+            access_token: access_token.into(),
+            expires_at: Instant::now() + expires_in,
+            scopes,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() + EXPIRY_MARGIN >= self.expires_at
+    }
+}
+
+/// Where a session gets its access tokens from, instead of logging in with credentials. It is
+/// asked again when a token expires or is rejected.
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    async fn get_access_token(&self, scopes: &[&str]) -> Result<Token, TokenError>;
+}
+
+// The tokens a session got from its provider, by the scopes they were asked for.
+pub(crate) struct TokenCache {
+    provider: Arc<dyn TokenProvider>,
+    tokens: Mutex<HashMap<String, Token>>,
+}
+
+impl TokenCache {
+    pub(crate) fn new(provider: Arc<dyn TokenProvider>) -> Self {
+        Self {
+            provider,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(scopes: &[&str]) -> String {
+        let mut scopes = scopes.to_vec();
+        scopes.sort_unstable();
+        scopes.join(" ")
+    }
+
+    pub(crate) async fn get(&self, scopes: &[&str]) -> Result<Token, TokenError> {
+        let key = Self::key(scopes);
+        if let Some(token) = self.tokens.lock().unwrap().get(&key) {
+            if !token.is_expired() {
+                return Ok(token.clone());
+            }
+        }
+
+        debug!("Getting an access token for \"{}\"", key);
+        let token = self.provider.get_access_token(scopes).await?;
+        self.tokens.lock().unwrap().insert(key, token.clone());
+        Ok(token)
+    }
+
+    pub(crate) fn invalidate(&self, scopes: &[&str]) {
+        self.tokens.lock().unwrap().remove(&Self::key(scopes));
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+    #[serde(default)]
+    scope: String,
+    refresh_token: Option<String>,
+}
+
+/// A [`TokenProvider`] that trades a refresh token for access tokens at an OAuth token
+/// endpoint, keeping the new refresh token when the endpoint rotates it. The scopes are the
+/// ones the refresh token was granted, whatever is asked for.
+///
+/// librespot has no TLS of its own, so [`RefreshTokenProvider::new`] only reaches `http://`
+/// endpoints, like a relay on the local network that forwards to [`SPOTIFY_TOKEN_URL`]. To
+/// reach that directly, pass [`RefreshTokenProvider::with_client`] a hyper client with a
+/// connector that speaks HTTPS, such as the one of `hyper-rustls` or `hyper-tls`.
+pub struct RefreshTokenProvider<C = HttpConnector> {
+    client: Client<C>,
+    token_url: String,
+    client_id: String,
+    refresh_token: Mutex<String>,
+}
+
+impl RefreshTokenProvider {
+    /// A provider that reaches the token endpoint over plain HTTP, such as a relay on the
+    /// local network. Requests to an `https://` endpoint fail with [`TokenError::Http`].
+    pub fn new(
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+        refresh_token: impl Into<String>,
+    ) -> Self {
+        Self::with_client(Client::new(), token_url, client_id, refresh_token)
+    }
+}
+
+impl RefreshTokenProvider<ProxiedConnector> {
+    /// Like [`RefreshTokenProvider::new`], but reaches the endpoint through `proxy`, with the
+    /// same meaning as `SessionConfig::proxy` and `SessionConfig::proxy_remote_dns`.
+    pub fn with_proxy(
+        proxy: Option<&Url>,
+        remote_dns: bool,
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+        refresh_token: impl Into<String>,
+    ) -> Self {
+        let client = Client::builder().build(ProxiedConnector::new(proxy, remote_dns));
+        Self::with_client(client, token_url, client_id, refresh_token)
+    }
+}
+
+impl<C> RefreshTokenProvider<C> {
+    pub fn with_client(
+        client: Client<C>,
+        token_url: impl Into<String>,
+        client_id: impl Into<String>,
+        refresh_token: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            token_url: token_url.into(),
+            client_id: client_id.into(),
+            refresh_token: Mutex::new(refresh_token.into()),
+        }
+    }
+
+    /// The refresh token to use next time, which may have been rotated by the endpoint.
+    pub fn refresh_token(&self) -> String {
+        self.refresh_token.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl<C> TokenProvider for RefreshTokenProvider<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    async fn get_access_token(&self, _scopes: &[&str]) -> Result<Token, TokenError> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "refresh_token")
+            .append_pair("refresh_token", &self.refresh_token())
+            .append_pair("client_id", &self.client_id)
+            .finish();
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.token_url)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .map_err(|_| TokenError::InvalidUrl(self.token_url.clone()))?;
+
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(TokenError::Rejected {
+                status: status.as_u16(),
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+
+        let response: TokenResponse = serde_json::from_slice(&body)?;
+        if let Some(refresh_token) = response.refresh_token {
+            *self.refresh_token.lock().unwrap() = refresh_token;
+        }
+
+        Ok(Token::new(
+            response.access_token,
+            Duration::from_secs(response.expires_in),
+            response
+                .scope
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    struct Counting(AtomicUsize);
+
+    #[async_trait]
+    impl TokenProvider for Counting {
+        async fn get_access_token(&self, scopes: &[&str]) -> Result<Token, TokenError> {
+            let n = self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(Token::new(
+                format!("token {}", n),
+                Duration::from_secs(3600),
+                scopes.iter().map(|scope| scope.to_string()).collect(),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_asks_again_after_invalidation() {
+        let cache = TokenCache::new(Arc::new(Counting(AtomicUsize::new(0))));
+
+        let first = cache
+            .get(&["streaming", "user-read-private"])
+            .await
+            .unwrap();
+        let again = cache
+            .get(&["user-read-private", "streaming"])
+            .await
+            .unwrap();
+        assert_eq!(first.access_token, again.access_token);
+
+        cache.invalidate(&["streaming", "user-read-private"]);
+        let fresh = cache
+            .get(&["streaming", "user-read-private"])
+            .await
+            .unwrap();
+        assert_ne!(first.access_token, fresh.access_token);
+    }
+
+    #[tokio::test]
+    async fn refresh_goes_through_a_socks5_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Url::parse(&format!("socks5h://{}", listener.local_addr().unwrap())).unwrap();
+
+        // The proxy, which answers the token request itself rather than connect anywhere.
+        let server = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            client.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 5];
+            client.read_exact(&mut request).await.unwrap();
+            let mut target = vec![0u8; request[4] as usize + 2];
+            client.read_exact(&mut target).await.unwrap();
+            client
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+
+            let mut http = Vec::new();
+            while !http.ends_with(b"client_id=id") {
+                let mut buf = [0u8; 1024];
+                let n = client.read(&mut buf).await.unwrap();
+                assert_ne!(n, 0);
+                http.extend_from_slice(&buf[..n]);
+            }
+            let body = r#"{"access_token":"access","expires_in":3600,"scope":"streaming"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            client.write_all(response.as_bytes()).await.unwrap();
+
+            target
+        });
+
+        let provider = RefreshTokenProvider::with_proxy(
+            Some(&proxy),
+            false,
+            "http://token.example/api/token",
+            "id",
+            "refresh",
+        );
+        let token = provider.get_access_token(LOGIN_SCOPES).await.unwrap();
+        assert_eq!(token.access_token, "access");
+        assert_eq!(server.await.unwrap(), b"token.example\x00\x50");
+    }
+}
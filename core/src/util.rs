@@ -1,5 +1,19 @@
+use std::future::Future;
 use std::mem;
 
+use tokio::runtime::Handle;
+
+/// Runs the future on the runtime and waits for its output, so that a future that needs the
+/// runtime, such as for its timers, can be awaited on a thread outside of it, like with
+/// `futures_executor::block_on`. None if the runtime shut down before the future was done.
+pub async fn run_on<F>(handle: &Handle, future: F) -> Option<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    handle.spawn(future).await.ok()
+}
+
 pub trait Seq {
     fn next(&self) -> Self;
 }
@@ -0,0 +1,59 @@
+use std::io::BufRead;
+use std::thread;
+
+use futures::StreamExt;
+use librespot_discovery::{DeviceType, Pairing};
+use sha1::{Digest, Sha1};
+use simple_logger::SimpleLogger;
+use tokio::sync::mpsc;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    SimpleLogger::new()
+        .with_level(log::LevelFilter::Debug)
+        .init()
+        .unwrap();
+
+    let name = "Librespot";
+    let device_id = hex::encode(Sha1::digest(name.as_bytes()));
+
+    let mut server = librespot_discovery::Discovery::builder(device_id)
+        .name(name)
+        .device_type(DeviceType::Computer)
+        .pairing(Pairing::default())
+        .launch()
+        .unwrap();
+    let mut pairing_requests = server.pairing_requests().unwrap();
+
+    let (codes_tx, mut codes) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    let _ = codes_tx.send(line.trim().to_owned());
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            Some(request) = pairing_requests.recv() => {
+                println!(
+                    "{} asks to log in as {}, type {} to accept",
+                    request.client_name, request.username, request.code
+                );
+            }
+            Some(code) = codes.recv() => {
+                if !server.confirm_pairing(&code) {
+                    println!("No request is waiting for {}", code);
+                }
+            }
+            credentials = server.next() => match credentials {
+                Some(credentials) => println!("Received {:?}", credentials),
+                None => break,
+            }
+        }
+    }
+}
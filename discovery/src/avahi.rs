@@ -0,0 +1,364 @@
+use std::env;
+use std::ffi::OsString;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use futures_core::ready;
+use log::debug;
+use tokio::sync::oneshot;
+use tokio::time::{self, Timeout};
+
+use crate::Error;
+
+const AVAHI_PUBLISH: &str = "avahi-publish";
+
+// Where the daemon listens while it is running.
+const AVAHI_SOCKETS: &[&str] = &["/run/avahi-daemon/socket", "/var/run/avahi-daemon/socket"];
+
+// How long to wait for the daemon to take the service.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Whether the daemon is running and `avahi-publish` is installed. A socket that is left over
+// from a daemon that is gone refuses to be connected to.
+pub fn is_running() -> bool {
+    daemon_listens(AVAHI_SOCKETS) && find_program(AVAHI_PUBLISH, env::var_os("PATH")).is_some()
+}
+
+#[cfg(unix)]
+fn daemon_listens<P: AsRef<Path>>(sockets: &[P]) -> bool {
+    sockets
+        .iter()
+        .any(|socket| UnixStream::connect(socket).is_ok())
+}
+
+// Avahi only runs on Unix, elsewhere the built-in responder is used.
+#[cfg(not(unix))]
+fn daemon_listens<P: AsRef<Path>>(_sockets: &[P]) -> bool {
+    false
+}
+
+fn find_program(program: &str, path: Option<OsString>) -> Option<PathBuf> {
+    env::split_paths(&path?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+// The host that a device which is restricted to some addresses is advertised on, so that it
+// resolves to those only. It is named after the device, to be the same on every restart.
+pub fn host_name(device_id: &str) -> String {
+    let id = device_id
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .take(8)
+        .collect::<String>();
+    format!("librespot-{}.local", id.to_lowercase())
+}
+
+// A service that the Avahi daemon advertises, which is withdrawn on drop. It is registered
+// with `avahi-publish`, which hands it to the daemon over D-Bus and keeps it registered for
+// as long as it runs. Unless it is advertised with all addresses, its host and each of the
+// addresses are registered too. `avahi-publish` can't tell the daemon about interfaces, so
+// like the built-in responder, the daemon still answers on all of them.
+pub struct AvahiService {
+    publishes: Vec<Publish>,
+}
+
+impl AvahiService {
+    pub fn register(
+        name: &str,
+        regtype: &str,
+        port: u16,
+        txt: &[&str],
+        host: Option<(&str, &[IpAddr])>,
+    ) -> io::Result<Self> {
+        let mut publishes = Vec::new();
+        if let Some((host_name, addresses)) = host {
+            for address in addresses {
+                publishes.push(Publish::spawn(&address_args(host_name, address))?);
+            }
+        }
+        let host_name = host.map(|(host_name, _)| host_name);
+        publishes.push(Publish::spawn(&service_args(
+            name, regtype, port, txt, host_name,
+        ))?);
+        Ok(Self { publishes })
+    }
+
+    // Ready once the daemon took all of it, or with why it didn't.
+    pub fn poll_established(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let mut pending = false;
+        for publish in &mut self.publishes {
+            match publish.poll_established(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => pending = true,
+            }
+        }
+
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+fn service_args(
+    name: &str,
+    regtype: &str,
+    port: u16,
+    txt: &[&str],
+    host_name: Option<&str>,
+) -> Vec<String> {
+    let mut args = vec!["--service".to_owned()];
+    if let Some(host_name) = host_name {
+        args.push("--host".to_owned());
+        args.push(host_name.to_owned());
+    }
+    args.extend([name.to_owned(), regtype.to_owned(), port.to_string()]);
+    args.extend(txt.iter().map(|txt| txt.to_string()));
+    args
+}
+
+// The host of the system already has the reverse records of its addresses.
+fn address_args(host_name: &str, address: &IpAddr) -> Vec<String> {
+    vec![
+        "--address".to_owned(),
+        "--no-reverse".to_owned(),
+        host_name.to_owned(),
+        address.to_string(),
+    ]
+}
+
+// Whether the daemon took what `avahi-publish` registered, or why it didn't.
+type Established = oneshot::Receiver<Result<(), String>>;
+
+// An `avahi-publish` that keeps what it registered for as long as it runs.
+struct Publish {
+    child: Child,
+    // Until the daemon took it, or didn't in time.
+    established: Option<Pin<Box<Timeout<Established>>>>,
+}
+
+impl Publish {
+    fn spawn(args: &[String]) -> io::Result<Self> {
+        let mut child = Command::new(AVAHI_PUBLISH)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || watch_output(BufReader::new(stderr).lines(), tx));
+        Ok(Self::new(child, rx))
+    }
+
+    fn new(child: Child, established: Established) -> Self {
+        Self {
+            child,
+            established: Some(Box::pin(time::timeout(REGISTER_TIMEOUT, established))),
+        }
+    }
+
+    fn poll_established(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let established = match self.established.as_mut() {
+            Some(established) => established,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        let result = ready!(established.as_mut().poll(cx));
+        self.established = None;
+        Poll::Ready(match result {
+            // The output is only left unread if its reader panicked.
+            Ok(result) => result
+                .unwrap_or_else(|_| Err(String::new()))
+                .map_err(Error::AvahiRejected),
+            Err(_) => Err(Error::AvahiError(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} did not establish the service in time", AVAHI_PUBLISH),
+            ))),
+        })
+    }
+}
+
+impl Drop for Publish {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// `avahi-publish` says on stderr when what it publishes was established, or why it wasn't.
+// Reading goes on after that, so that it can still tell about renames.
+fn watch_output(
+    lines: impl Iterator<Item = io::Result<String>>,
+    established: oneshot::Sender<Result<(), String>>,
+) {
+    let mut established = Some(established);
+    let mut last_line = String::new();
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        debug!("{}: {}", AVAHI_PUBLISH, line);
+        if line.starts_with("Established under name") {
+            if let Some(tx) = established.take() {
+                let _ = tx.send(Ok(()));
+            }
+        } else {
+            last_line = line;
+        }
+    }
+
+    if let Some(tx) = established {
+        let _ = tx.send(Err(last_line));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+
+    fn lines(lines: &[&str]) -> impl Iterator<Item = io::Result<String>> {
+        lines
+            .iter()
+            .map(|line| Ok(line.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn established_once_avahi_publish_says_so() {
+        let (tx, mut rx) = oneshot::channel();
+        watch_output(
+            lines(&[
+                "Established under name 'Librespot'",
+                "Host name conflict, retrying with Librespot #2",
+            ]),
+            tx,
+        );
+        assert_eq!(rx.try_recv().unwrap(), Ok(()));
+
+        let (tx, mut rx) = oneshot::channel();
+        watch_output(
+            lines(&["Daemon not running", "Failed to create client object"]),
+            tx,
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Err("Failed to create client object".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn not_established_in_time() {
+        time::pause();
+        let child = Command::new("sleep").arg("60").spawn().unwrap();
+        let (_tx, rx) = oneshot::channel();
+        let mut publish = Publish::new(child, rx);
+
+        let result = futures::future::poll_fn(|cx| publish.poll_established(cx));
+        tokio::pin!(result);
+        assert!(futures::poll!(&mut result).is_pending());
+        time::advance(REGISTER_TIMEOUT).await;
+        assert!(matches!(
+            result.await,
+            Err(Error::AvahiError(e)) if e.kind() == io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn a_running_daemon_takes_connections() {
+        let dir = env::temp_dir().join(format!("librespot-avahi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("socket");
+        let _ = fs::remove_file(&socket);
+
+        let listener = UnixListener::bind(&socket).unwrap();
+        assert!(daemon_listens(&[dir.join("missing"), socket.clone()]));
+
+        // What is left behind when the daemon is gone.
+        drop(listener);
+        assert!(!daemon_listens(&[&socket]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn avahi_publish_is_looked_up_in_the_path() {
+        let dir = env::temp_dir().join(format!("librespot-avahi-bin-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = env::join_paths([dir.join("missing"), dir.clone()]).unwrap();
+        assert_eq!(find_program(AVAHI_PUBLISH, Some(path.clone())), None);
+
+        fs::write(dir.join(AVAHI_PUBLISH), "").unwrap();
+        assert_eq!(
+            find_program(AVAHI_PUBLISH, Some(path)),
+            Some(dir.join(AVAHI_PUBLISH))
+        );
+        assert_eq!(find_program(AVAHI_PUBLISH, None), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restricted_services_have_a_host_of_their_own() {
+        assert_eq!(
+            service_args(
+                "Librespot",
+                "_spotify-connect._tcp",
+                1234,
+                &["VERSION=1.0"],
+                None
+            ),
+            [
+                "--service",
+                "Librespot",
+                "_spotify-connect._tcp",
+                "1234",
+                "VERSION=1.0"
+            ]
+        );
+
+        let host_name = host_name("8E0A-f3c2b9d1e7");
+        assert_eq!(host_name, "librespot-8e0af3c2.local");
+        assert_eq!(
+            service_args(
+                "Librespot",
+                "_spotify-connect._tcp",
+                1234,
+                &[],
+                Some(&host_name)
+            ),
+            [
+                "--service",
+                "--host",
+                "librespot-8e0af3c2.local",
+                "Librespot",
+                "_spotify-connect._tcp",
+                "1234"
+            ]
+        );
+        assert_eq!(
+            address_args(&host_name, &"192.168.1.2".parse().unwrap()),
+            [
+                "--address",
+                "--no-reverse",
+                "librespot-8e0af3c2.local",
+                "192.168.1.2"
+            ]
+        );
+    }
+}
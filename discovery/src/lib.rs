@@ -9,16 +9,28 @@
 
 #![warn(clippy::all, missing_docs, rust_2018_idioms)]
 
+#[cfg(not(feature = "with-dns-sd"))]
+mod avahi;
 mod server;
 
 use std::borrow::Cow;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use futures_core::Stream;
+use hyper::{Body, Request, Response};
 use librespot_core as core;
+#[cfg(not(feature = "with-dns-sd"))]
+use log::debug;
+use log::warn;
 use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant, Interval};
 
 use self::server::DiscoveryServer;
 
@@ -28,23 +40,188 @@ pub use crate::core::authentication::Credentials;
 /// Determining the icon in the list of available devices.
 pub use crate::core::config::DeviceType;
 
+/// How requests to pair are confirmed, see [`Builder::pairing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pairing {
+    /// How long a request waits to be confirmed.
+    pub timeout: Duration,
+    /// Whether a request that wasn't confirmed in time is accepted, rather than rejected.
+    pub accept_on_timeout: bool,
+}
+
+impl Default for Pairing {
+    fn default() -> Pairing {
+        Pairing {
+            timeout: Duration::from_secs(60),
+            accept_on_timeout: false,
+        }
+    }
+}
+
+/// A Spotify client that asks to log this device in, which waits for
+/// [`Discovery::confirm_pairing`] with the code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingRequest {
+    /// The name of the client, such as the name of a phone, if it gave one.
+    pub client_name: String,
+    /// The user to log in as.
+    pub username: String,
+    /// A code of 4 digits, to show to the user so that they can tell it is their request.
+    pub code: String,
+}
+
+/// A Spotify client that tried to log this device in as a user that isn't allowed, see
+/// [`Builder::allowed_users`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginRejected {
+    /// The user it tried to log in as.
+    pub username: String,
+    /// The name of the client, if it gave one.
+    pub client_name: String,
+}
+
+// How often to look again for the addresses of the interfaces to use, while they have none.
+const LISTEN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+const SERVICE_TYPE: &str = "_spotify-connect._tcp";
+const SERVICE_VERSION: &str = "VERSION=1.0";
+
 /// Makes this device visible to Spotify clients in the local network.
 ///
 /// `Discovery` implements the [`Stream`] trait. Every time this device
 /// is selected in the list of available devices, it yields [`Credentials`].
 pub struct Discovery {
     server: DiscoveryServer,
+    port: u16,
+    embedded: bool,
+    path: String,
+    name: Cow<'static, str>,
+    interfaces: Vec<String>,
+    address_family: AddressFamily,
+    // The addresses the server listens on, and those the device is advertised with.
+    listening: Vec<IpAddr>,
+    advertised: Vec<IpAddr>,
+    // Until the device is advertised, tries to again now and then. When it is restricted to
+    // some interfaces or addresses, goes on to look for addresses that come up later.
+    retry: Option<Interval>,
 
     #[cfg(not(feature = "with-dns-sd"))]
-    _svc: libmdns::Service,
+    mdns_backend: MdnsBackend,
+    // What the device is advertised on with Avahi when it is restricted.
+    #[cfg(not(feature = "with-dns-sd"))]
+    host_name: String,
+    #[cfg(not(feature = "with-dns-sd"))]
+    _svc: Option<Advertisement>,
     #[cfg(feature = "with-dns-sd")]
-    _svc: dns_sd::DNSService,
+    _svc: Option<dns_sd::DNSService>,
+}
+
+#[cfg(not(feature = "with-dns-sd"))]
+enum Advertisement {
+    Libmdns {
+        responder: libmdns::Responder,
+        _svc: libmdns::Service,
+    },
+    Avahi {
+        service: avahi::AvahiService,
+        // Whether the daemon took the service, which it is waited for in `poll_next`.
+        established: bool,
+    },
+}
+
+/// What advertises this device over mDNS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdnsBackend {
+    /// Avahi if its daemon is running and its `avahi-publish` tool is installed, and the
+    /// built-in responder if it isn't or the device can't be registered with it.
+    Auto,
+    /// The Avahi daemon, which needs its `avahi-publish` tool. When the daemon doesn't
+    /// take the device, it is tried again later.
+    Avahi,
+    /// The responder that is built in, which can conflict with a daemon that is running.
+    Libmdns,
+}
+
+impl FromStr for MdnsBackend {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "auto" => Ok(MdnsBackend::Auto),
+            "avahi" => Ok(MdnsBackend::Avahi),
+            "libmdns" => Ok(MdnsBackend::Libmdns),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for MdnsBackend {
+    fn default() -> MdnsBackend {
+        MdnsBackend::Libmdns
+    }
+}
+
+/// Answers the requests of Spotify clients for a [`Discovery`] that is embedded in another
+/// HTTP server, see [`Builder::embedded`].
+#[derive(Clone)]
+pub struct DiscoveryHandler(Arc<server::RequestHandler>);
+
+impl DiscoveryHandler {
+    /// Answers a request that was routed to the path the device is advertised with. Those
+    /// with `action=getInfo` or `action=addUser` get what Spotify clients expect, the others
+    /// get `404 Not Found`.
+    pub async fn handle_request(&self, request: Request<Body>) -> hyper::Result<Response<Body>> {
+        self.0.clone().handle(request).await
+    }
+}
+
+/// The addresses to advertise this device with and to listen on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// IPv4 and IPv6 addresses.
+    Any,
+    /// Only IPv4 addresses.
+    Ipv4,
+    /// Only IPv6 addresses.
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn contains(self, address: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => address.is_ipv4(),
+            AddressFamily::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "any" => Ok(AddressFamily::Any),
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for AddressFamily {
+    fn default() -> AddressFamily {
+        AddressFamily::Any
+    }
 }
 
 /// A builder for [`Discovery`].
 pub struct Builder {
     server_config: server::Config,
     port: u16,
+    embedded: bool,
+    path: String,
+    interfaces: Vec<String>,
+    address_family: AddressFamily,
+    mdns_backend: MdnsBackend,
 }
 
 /// Errors that can occur while setting up a [`Discovery`] instance.
@@ -56,6 +233,18 @@ pub enum Error {
     /// Setting up the http server failed.
     #[error("Setting up the http server failed: {0}")]
     HttpServerError(#[from] hyper::Error),
+    /// The port that was set with [`Builder::port`] is used by something else.
+    #[error("The port {0} is already in use")]
+    PortInUse(u16),
+    /// Finding the addresses of the network interfaces failed.
+    #[error("Listing the network interfaces failed: {0}")]
+    InterfacesError(io::Error),
+    /// Registering the device with the Avahi daemon failed.
+    #[error("Registering with Avahi failed: {0}")]
+    AvahiError(io::Error),
+    /// The Avahi daemon didn't take the device, for the reason `avahi-publish` gave.
+    #[error("Avahi did not take the device: {0}")]
+    AvahiRejected(String),
 }
 
 impl Builder {
@@ -66,8 +255,15 @@ impl Builder {
                 name: "Librespot".into(),
                 device_type: DeviceType::default(),
                 device_id: device_id.into(),
+                pairing: None,
+                allowed_users: Vec::new(),
             },
             port: 0,
+            embedded: false,
+            path: "/".to_owned(),
+            interfaces: Vec::new(),
+            address_family: AddressFamily::default(),
+            mdns_backend: MdnsBackend::default(),
         }
     }
 
@@ -84,41 +280,203 @@ impl Builder {
     }
 
     /// Sets the port on which it should listen to incoming connections.
-    /// The default value `0` means any port.
+    /// The default value `0` means any port. Launching fails with [`Error::PortInUse`]
+    /// when another port is taken.
     pub fn port(mut self, port: u16) -> Self {
         self.port = port;
         self
     }
 
+    /// Doesn't listen at all, and advertises the device at `port`, where an HTTP server of
+    /// the application passes the requests for the path set with [`Builder::path`] to
+    /// [`Discovery::handler`].
+    pub fn embedded(mut self, port: u16) -> Self {
+        self.embedded = true;
+        self.port = port;
+        self
+    }
+
+    /// Sets the path that Spotify clients are told to send their requests to. Default is
+    /// `"/"`. The built-in server answers on every path, so this is only useful with
+    /// [`Builder::embedded`].
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Only uses the network interfaces with these names, such as `eth0`. The device is
+    /// then advertised with their addresses only, and only listens on those. Interfaces
+    /// that have no address yet are waited for, and addresses that come up later are added.
+    /// Default is all interfaces.
+    ///
+    /// With `with-dns-sd`, only listening is restricted, as the system daemon advertises
+    /// the device with all addresses.
+    pub fn interfaces<I, S>(mut self, interfaces: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.interfaces = interfaces.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only uses addresses of this family, like [`Builder::interfaces`] does for interfaces.
+    /// Default is `AddressFamily::Any`.
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.address_family = address_family;
+        self
+    }
+
+    /// Holds every client that logs the device in until the request is confirmed with
+    /// [`Discovery::confirm_pairing`], so that nobody else on the network can take the
+    /// device over. The requests come from [`Discovery::pairing_requests`]. Those that are
+    /// rejected or time out get an error, which the client shows as a failed login.
+    /// Default is to accept every client.
+    pub fn pairing(mut self, pairing: Pairing) -> Self {
+        self.server_config.pairing = Some(pairing);
+        self
+    }
+
+    /// Only accepts logins as these users, ignoring case. The others get an error, which the
+    /// client shows as a failed login, and are reported by [`Discovery::rejected_logins`].
+    /// Default is to accept every user.
+    pub fn allowed_users<I, S>(mut self, users: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.server_config.allowed_users = users
+            .into_iter()
+            .map(|user| user.as_ref().to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Sets what advertises the device. Default is `MdnsBackend::Libmdns`. Ignored with
+    /// `with-dns-sd`, which always registers with the system daemon.
+    pub fn mdns_backend(mut self, mdns_backend: MdnsBackend) -> Self {
+        self.mdns_backend = mdns_backend;
+        self
+    }
+
     /// Sets up the [`Discovery`] instance.
     ///
     /// # Errors
     /// If setting up the mdns service or creating the server fails, this function returns an error.
+    /// With `MdnsBackend::Avahi`, that includes `avahi-publish` not being installed.
+    /// When the interfaces or the address family are restricted, it is tried again later
+    /// instead, as the addresses may not be up yet.
     pub fn launch(self) -> Result<Discovery, Error> {
-        let mut port = self.port;
-        let name = self.server_config.name.clone().into_owned();
-        let server = DiscoveryServer::new(self.server_config, &mut port)?;
+        let restricted = !self.interfaces.is_empty() || self.address_family != AddressFamily::Any;
 
-        #[cfg(feature = "with-dns-sd")]
-        let svc = dns_sd::DNSService::register(
-            Some(name.as_ref()),
-            "_spotify-connect._tcp",
-            None,
-            None,
-            port,
-            &["VERSION=1.0", "CPath=/"],
-        )
-        .map_err(|e| Error::DnsSdError(io::Error::new(io::ErrorKind::Unsupported, e)))?;
+        let mut discovery = Discovery {
+            name: self.server_config.name.clone(),
+            #[cfg(not(feature = "with-dns-sd"))]
+            host_name: avahi::host_name(&self.server_config.device_id),
+            server: DiscoveryServer::new(self.server_config),
+            port: self.port,
+            embedded: self.embedded,
+            path: self.path,
+            interfaces: self.interfaces,
+            address_family: self.address_family,
+            listening: Vec::new(),
+            advertised: Vec::new(),
+            retry: None,
+            #[cfg(not(feature = "with-dns-sd"))]
+            mdns_backend: self.mdns_backend,
+            _svc: None,
+        };
 
-        #[cfg(not(feature = "with-dns-sd"))]
-        let svc = libmdns::Responder::spawn(&tokio::runtime::Handle::current())?.register(
-            "_spotify-connect._tcp".to_owned(),
-            name,
-            port,
-            &["VERSION=1.0", "CPath=/"],
-        );
+        match discovery.advertise() {
+            Ok(true) if restricted => discovery.retry_later(),
+            Ok(true) => (),
+            Ok(false) => {
+                warn!("The zeroconf interfaces have no addresses yet, waiting for them");
+                discovery.retry_later();
+            }
+            Err(e) if restricted => {
+                warn!("Could not set up discovery yet, retrying: {}", e);
+                discovery.retry_later();
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(discovery)
+    }
+}
 
-        Ok(Discovery { server, _svc: svc })
+// The addresses to advertise the device with and to listen on, or `None` for all of them.
+fn allowed_addresses(
+    interfaces: &[String],
+    address_family: AddressFamily,
+) -> Result<Option<Vec<IpAddr>>, Error> {
+    if interfaces.is_empty() && address_family == AddressFamily::Any {
+        return Ok(None);
+    }
+
+    let addresses = if_addrs::get_if_addrs()
+        .map_err(Error::InterfacesError)?
+        .into_iter()
+        .map(|interface| (interface.name.clone(), interface.ip()));
+
+    Ok(Some(filter_addresses(
+        addresses,
+        interfaces,
+        address_family,
+    )))
+}
+
+// The addresses of the interfaces, by their name, that are in `interfaces` if those are
+// given, and in the address family.
+fn filter_addresses(
+    addresses: impl IntoIterator<Item = (String, IpAddr)>,
+    interfaces: &[String],
+    address_family: AddressFamily,
+) -> Vec<IpAddr> {
+    addresses
+        .into_iter()
+        .filter(|(name, _)| interfaces.is_empty() || interfaces.contains(name))
+        .map(|(_, address)| address)
+        .filter(|address| address_family.contains(address))
+        // These can't be listened on without knowing which interface they are for.
+        .filter(|address| !matches!(address, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80))
+        .collect()
+}
+
+// The addresses of `addresses` that aren't listened on yet.
+fn new_addresses(listening: &[IpAddr], addresses: &[IpAddr]) -> Vec<IpAddr> {
+    addresses
+        .iter()
+        .filter(|address| !listening.contains(address))
+        .copied()
+        .collect()
+}
+
+#[cfg(feature = "with-dns-sd")]
+fn register_dns_sd(name: &str, port: u16, txt: &[&str]) -> Result<dns_sd::DNSService, Error> {
+    dns_sd::DNSService::register(Some(name), SERVICE_TYPE, None, None, port, txt)
+        .map_err(|e| Error::DnsSdError(io::Error::new(io::ErrorKind::Unsupported, e)))
+}
+
+#[cfg(not(feature = "with-dns-sd"))]
+fn register_mdns(
+    responder: &libmdns::Responder,
+    name: String,
+    port: u16,
+    txt: &[&str],
+) -> libmdns::Service {
+    responder.register(SERVICE_TYPE.to_owned(), name, port, txt)
+}
+
+// Tells `AddrInUse` apart from the other reasons the port couldn't be listened on.
+fn listen_error(e: hyper::Error, port: u16) -> Error {
+    let source =
+        std::error::Error::source(&e).and_then(|source| source.downcast_ref::<io::Error>());
+    let in_use = matches!(source, Some(source) if source.kind() == io::ErrorKind::AddrInUse);
+    if in_use {
+        Error::PortInUse(port)
+    } else {
+        Error::HttpServerError(e)
     }
 }
 
@@ -132,12 +490,404 @@ impl Discovery {
     pub fn new(device_id: impl Into<String>) -> Result<Self, Error> {
         Self::builder(device_id).launch()
     }
+
+    /// Changes the name that is displayed, and advertises the device again under it.
+    ///
+    /// # Errors
+    /// If advertising the device under the new name fails, this function returns an error.
+    /// The device is then still advertised under the old name.
+    pub fn set_name(&mut self, name: impl Into<Cow<'static, str>>) -> Result<(), Error> {
+        let name = name.into();
+        let cpath = self.cpath();
+        let txt = [SERVICE_VERSION, &cpath];
+
+        #[cfg(feature = "with-dns-sd")]
+        if self._svc.is_some() {
+            self._svc = Some(register_dns_sd(&name, self.port, &txt)?);
+        }
+        #[cfg(not(feature = "with-dns-sd"))]
+        match &mut self._svc {
+            Some(Advertisement::Libmdns {
+                responder,
+                _svc: service,
+            }) => {
+                *service = register_mdns(responder, name.clone().into_owned(), self.port, &txt);
+            }
+            Some(Advertisement::Avahi { .. }) => {
+                let service = avahi::AvahiService::register(
+                    &name,
+                    SERVICE_TYPE,
+                    self.port,
+                    &txt,
+                    self.avahi_host(),
+                )
+                .map_err(Error::AvahiError)?;
+                self._svc = Some(Advertisement::Avahi {
+                    service,
+                    established: false,
+                });
+            }
+            None => (),
+        }
+
+        self.server.set_name(name.clone());
+        self.name = name;
+        Ok(())
+    }
+
+    /// Changes the device type that is displayed. Devices that look this one up after that
+    /// are told the new type.
+    pub fn set_device_type(&mut self, device_type: DeviceType) {
+        self.server.set_device_type(device_type);
+    }
+
+    /// The port the device is advertised at. Unless it is embedded, that is the one that is
+    /// listened on, and it is `0` while the interfaces have no addresses if any port was
+    /// allowed.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// What answers the requests of Spotify clients, for an HTTP server that the device is
+    /// embedded in with [`Builder::embedded`].
+    pub fn handler(&self) -> DiscoveryHandler {
+        DiscoveryHandler(self.server.handler())
+    }
+
+    /// The requests to pair, with [`Builder::pairing`]. Only the first call returns them.
+    pub fn pairing_requests(&mut self) -> Option<mpsc::UnboundedReceiver<PairingRequest>> {
+        self.server.take_pairing_requests()
+    }
+
+    /// The logins that are rejected from now on, with [`Builder::allowed_users`]. Until this
+    /// is called they are only logged. Only the first call returns them.
+    pub fn rejected_logins(&mut self) -> Option<mpsc::UnboundedReceiver<LoginRejected>> {
+        self.server.take_rejected_logins()
+    }
+
+    /// Accepts the request to pair that has this code, and tells whether one was waiting.
+    pub fn confirm_pairing(&self, code: &str) -> bool {
+        self.server.complete_pairing(code, true)
+    }
+
+    /// Rejects the request to pair that has this code, and tells whether one was waiting.
+    pub fn reject_pairing(&self, code: &str) -> bool {
+        self.server.complete_pairing(code, false)
+    }
+}
+
+impl Discovery {
+    // Listens on the allowed addresses and advertises the device with them. Tells whether
+    // it did, which it doesn't while the interfaces it is restricted to have no addresses.
+    // Addresses that came up since it was last called are listened on and advertised too.
+    fn advertise(&mut self) -> Result<bool, Error> {
+        let allowed = allowed_addresses(&self.interfaces, self.address_family)?;
+        let listen = match &allowed {
+            None => vec![Ipv4Addr::UNSPECIFIED.into()],
+            Some(addresses) if addresses.is_empty() => return Ok(false),
+            Some(addresses) => addresses.clone(),
+        };
+        if self._svc.is_some() && listen == self.advertised {
+            return Ok(true);
+        }
+
+        // Addresses are listened on once, also when advertising failed after it.
+        let new = new_addresses(&self.listening, &listen);
+        if !self.embedded && !new.is_empty() {
+            self.port = self
+                .server
+                .listen(&new, self.port)
+                .map_err(|e| listen_error(e, self.port))?;
+            self.listening.extend(new);
+        }
+
+        #[cfg(feature = "with-dns-sd")]
+        {
+            let cpath = self.cpath();
+            self._svc = Some(register_dns_sd(
+                &self.name,
+                self.port,
+                &[SERVICE_VERSION, &cpath],
+            )?);
+        }
+        #[cfg(not(feature = "with-dns-sd"))]
+        {
+            self._svc = Some(self.register(allowed)?);
+        }
+
+        self.advertised = listen;
+        Ok(true)
+    }
+
+    // Registers with Avahi, which takes the service later, see `poll_avahi`, or with the
+    // built-in responder. `allowed` are the addresses to advertise, or `None` for all.
+    #[cfg(not(feature = "with-dns-sd"))]
+    fn register(&self, allowed: Option<Vec<IpAddr>>) -> Result<Advertisement, Error> {
+        let use_avahi = match self.mdns_backend {
+            MdnsBackend::Auto => avahi::is_running(),
+            MdnsBackend::Avahi => true,
+            MdnsBackend::Libmdns => false,
+        };
+
+        if use_avahi {
+            let cpath = self.cpath();
+            let txt = [SERVICE_VERSION, &cpath];
+            let host = allowed
+                .as_deref()
+                .map(|addresses| (self.host_name.as_str(), addresses));
+            match avahi::AvahiService::register(&self.name, SERVICE_TYPE, self.port, &txt, host) {
+                Ok(service) => {
+                    return Ok(Advertisement::Avahi {
+                        service,
+                        established: false,
+                    })
+                }
+                Err(e) if self.mdns_backend == MdnsBackend::Auto => {
+                    warn!(
+                        "Could not register with Avahi, using the built-in responder instead: {}",
+                        e
+                    );
+                }
+                Err(e) => return Err(Error::AvahiError(e)),
+            }
+        }
+
+        self.register_libmdns(allowed.unwrap_or_default())
+    }
+
+    #[cfg(not(feature = "with-dns-sd"))]
+    fn register_libmdns(&self, allowed: Vec<IpAddr>) -> Result<Advertisement, Error> {
+        let cpath = self.cpath();
+        let txt = [SERVICE_VERSION, &cpath];
+
+        // The responder only leaves out addresses that are not allowed, it still answers on
+        // all interfaces.
+        let responder =
+            libmdns::Responder::spawn_with_ip_list(&tokio::runtime::Handle::current(), allowed)?;
+        let svc = register_mdns(&responder, self.name.clone().into_owned(), self.port, &txt);
+        Ok(Advertisement::Libmdns {
+            responder,
+            _svc: svc,
+        })
+    }
+
+    // The host to advertise the device on with Avahi, with the addresses it is restricted to.
+    #[cfg(not(feature = "with-dns-sd"))]
+    fn avahi_host(&self) -> Option<(&str, &[IpAddr])> {
+        if self.is_restricted() {
+            Some((&self.host_name, &self.advertised))
+        } else {
+            None
+        }
+    }
+
+    // Waits for Avahi to take the service. If it doesn't, `MdnsBackend::Auto` goes on with the
+    // built-in responder, and `MdnsBackend::Avahi` tries again later.
+    #[cfg(not(feature = "with-dns-sd"))]
+    fn poll_avahi(&mut self, cx: &mut Context<'_>) {
+        let result = match &mut self._svc {
+            Some(Advertisement::Avahi {
+                service,
+                established: established @ false,
+            }) => match service.poll_established(cx) {
+                Poll::Ready(Ok(())) => {
+                    debug!("Registered with Avahi");
+                    *established = true;
+                    return;
+                }
+                Poll::Ready(Err(e)) => e,
+                Poll::Pending => return,
+            },
+            _ => return,
+        };
+
+        self._svc = None;
+        if self.mdns_backend == MdnsBackend::Auto {
+            warn!(
+                "Could not register with Avahi, using the built-in responder instead: {}",
+                result
+            );
+            let allowed = self
+                .avahi_host()
+                .map_or_else(Vec::new, |(_, addresses)| addresses.to_vec());
+            match self.register_libmdns(allowed) {
+                Ok(advertisement) => {
+                    self._svc = Some(advertisement);
+                    return;
+                }
+                Err(e) => warn!("Could not set up discovery yet, retrying: {}", e),
+            }
+        } else {
+            warn!("Could not register with Avahi, retrying: {}", result);
+        }
+        if self.retry.is_none() {
+            self.retry_later();
+        }
+    }
+
+    fn is_restricted(&self) -> bool {
+        !self.interfaces.is_empty() || self.address_family != AddressFamily::Any
+    }
+
+    fn cpath(&self) -> String {
+        format!("CPath={}", self.path)
+    }
+
+    fn retry_later(&mut self) {
+        self.retry = Some(time::interval_at(
+            Instant::now() + LISTEN_RETRY_INTERVAL,
+            LISTEN_RETRY_INTERVAL,
+        ));
+    }
 }
 
 impl Stream for Discovery {
     type Item = Credentials;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        #[cfg(not(feature = "with-dns-sd"))]
+        self.poll_avahi(cx);
+
+        while let Some(retry) = self.retry.as_mut() {
+            if retry.poll_tick(cx).is_pending() {
+                break;
+            }
+
+            match self.advertise() {
+                Ok(true) if !self.is_restricted() => self.retry = None,
+                Ok(_) => (),
+                Err(e) => warn!("Could not set up discovery yet, retrying: {}", e),
+            }
+        }
+
         Pin::new(&mut self.server).poll_next(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn interfaces() -> Vec<(String, IpAddr)> {
+        vec![
+            ("lo".to_owned(), Ipv4Addr::LOCALHOST.into()),
+            ("lo".to_owned(), Ipv6Addr::LOCALHOST.into()),
+            ("eth0".to_owned(), Ipv4Addr::new(192, 168, 1, 2).into()),
+            ("eth0".to_owned(), "fe80::1".parse().unwrap()),
+            ("eth0".to_owned(), "2001:db8::2".parse().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn unrestricted_allows_all_addresses() {
+        assert_eq!(allowed_addresses(&[], AddressFamily::Any).unwrap(), None);
+    }
+
+    #[test]
+    fn addresses_of_the_interfaces() {
+        let eth0 = ["eth0".to_owned()];
+        assert_eq!(
+            filter_addresses(interfaces(), &eth0, AddressFamily::Any),
+            [
+                IpAddr::from(Ipv4Addr::new(192, 168, 1, 2)),
+                "2001:db8::2".parse().unwrap()
+            ]
+        );
+        assert!(
+            filter_addresses(interfaces(), &["wlan0".to_owned()], AddressFamily::Any).is_empty()
+        );
+        assert_eq!(
+            filter_addresses(interfaces(), &[], AddressFamily::Any).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn addresses_of_the_family() {
+        assert_eq!(
+            filter_addresses(interfaces(), &[], AddressFamily::Ipv4),
+            [
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+                Ipv4Addr::new(192, 168, 1, 2).into()
+            ]
+        );
+        assert_eq!(
+            filter_addresses(interfaces(), &["eth0".to_owned()], AddressFamily::Ipv6),
+            [IpAddr::from("2001:db8::2".parse::<Ipv6Addr>().unwrap())]
+        );
+        assert_eq!("IPv6".parse::<AddressFamily>(), Ok(AddressFamily::Ipv6));
+        assert!("ipv5".parse::<AddressFamily>().is_err());
+    }
+
+    #[tokio::test]
+    async fn taken_port_is_reported() {
+        let taken = std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let result = Discovery::builder("test-device").port(port).launch();
+        assert!(matches!(result, Err(Error::PortInUse(p)) if p == port));
+    }
+
+    #[tokio::test]
+    async fn embedded_device_is_answered_by_its_handler() {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let app = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = app.local_addr().unwrap().port();
+        let discovery = Discovery::builder("test-device")
+            .embedded(port)
+            .path("/spotify")
+            .launch()
+            .unwrap();
+        assert_eq!(discovery.port(), port);
+
+        let handler = discovery.handler();
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                    let handler = handler.clone();
+                    async move {
+                        if request.uri().path() == "/spotify" {
+                            handler.handle_request(request).await
+                        } else {
+                            Ok(Response::new(Body::from("other")))
+                        }
+                    }
+                }))
+            }
+        });
+        tokio::spawn(hyper::Server::from_tcp(app).unwrap().serve(make_service));
+
+        let get = |query: &str| {
+            let uri = format!("http://127.0.0.1:{}/spotify?{}", port, query);
+            async move {
+                let response = hyper::Client::new()
+                    .get(uri.parse().unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+
+        let (status, body) = get("action=getInfo").await;
+        assert_eq!(status, hyper::StatusCode::OK);
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["deviceID"], "test-device");
+
+        let (status, _) = get("action=other").await;
+        assert_eq!(status, hyper::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn only_new_addresses_are_listened_on() {
+        let first = IpAddr::from(Ipv4Addr::new(192, 168, 1, 2));
+        let second = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(new_addresses(&[], &[first]), [first]);
+        assert_eq!(new_addresses(&[first], &[first, second]), [second]);
+        assert!(new_addresses(&[first, second], &[second]).is_empty());
+    }
+}
@@ -1,9 +1,9 @@
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context, Poll};
 
 use aes_ctr::cipher::generic_array::GenericArray;
@@ -11,9 +11,11 @@ use aes_ctr::cipher::{NewStreamCipher, SyncStreamCipher};
 use aes_ctr::Aes128Ctr;
 use futures_core::Stream;
 use hmac::{Hmac, Mac, NewMac};
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use log::{debug, warn};
+use log::{debug, info, warn};
+use rand::Rng;
 use serde_json::json;
 use sha1::{Digest, Sha1};
 use tokio::sync::{mpsc, oneshot};
@@ -21,6 +23,8 @@ use tokio::sync::{mpsc, oneshot};
 use crate::core::authentication::Credentials;
 use crate::core::config::DeviceType;
 use crate::core::diffie_hellman::DhLocalKeys;
+use crate::core::redact;
+use crate::{LoginRejected, Pairing, PairingRequest};
 
 type Params<'a> = BTreeMap<Cow<'a, str>, Cow<'a, str>>;
 
@@ -28,30 +32,54 @@ pub struct Config {
     pub name: Cow<'static, str>,
     pub device_type: DeviceType,
     pub device_id: String,
+    pub pairing: Option<Pairing>,
+    // Lowercase, empty to allow everyone.
+    pub allowed_users: Vec<String>,
 }
 
-struct RequestHandler {
+pub struct RequestHandler {
     config: Config,
+    // The name and type as they are now, which can change after the server started.
+    name: RwLock<Cow<'static, str>>,
+    device_type: RwLock<DeviceType>,
     keys: DhLocalKeys,
     tx: mpsc::UnboundedSender<Credentials>,
+    pairing_tx: mpsc::UnboundedSender<PairingRequest>,
+    // Only there once someone listens, so that rejected logins don't pile up unread.
+    rejected_tx: Mutex<Option<mpsc::UnboundedSender<LoginRejected>>>,
+    // The requests to pair that wait to be confirmed, by their code.
+    pending_pairings: Mutex<HashMap<String, oneshot::Sender<bool>>>,
 }
 
 impl RequestHandler {
-    fn new(config: Config) -> (Self, mpsc::UnboundedReceiver<Credentials>) {
+    fn new(
+        config: Config,
+    ) -> (
+        Self,
+        mpsc::UnboundedReceiver<Credentials>,
+        mpsc::UnboundedReceiver<PairingRequest>,
+    ) {
         let (tx, rx) = mpsc::unbounded_channel();
+        let (pairing_tx, pairing_rx) = mpsc::unbounded_channel();
 
         let discovery = Self {
+            name: RwLock::new(config.name.clone()),
+            device_type: RwLock::new(config.device_type),
             config,
             keys: DhLocalKeys::random(&mut rand::thread_rng()),
             tx,
+            pairing_tx,
+            rejected_tx: Mutex::new(None),
+            pending_pairings: Mutex::new(HashMap::new()),
         };
 
-        (discovery, rx)
+        (discovery, rx, pairing_rx)
     }
 
     fn handle_get_info(&self) -> Response<hyper::Body> {
         let public_key = base64::encode(&self.keys.public_key());
-        let device_type: &str = self.config.device_type.into();
+        let device_type: &str = (*self.device_type.read().unwrap()).into();
+        let name = self.name.read().unwrap().clone();
 
         let body = json!({
             "status": 101,
@@ -59,7 +87,7 @@ impl RequestHandler {
             "spotifyError": 0,
             "version": "2.7.1",
             "deviceID": (self.config.device_id),
-            "remoteName": (self.config.name),
+            "remoteName": (name),
             "activeUser": "",
             "publicKey": (public_key),
             "deviceType": (device_type),
@@ -76,8 +104,24 @@ impl RequestHandler {
         Response::new(Body::from(body))
     }
 
-    fn handle_add_user(&self, params: &Params<'_>) -> Response<hyper::Body> {
+    async fn handle_add_user(&self, params: &Params<'_>) -> Response<hyper::Body> {
         let username = params.get("userName").unwrap().as_ref();
+        let client_name = params
+            .get("deviceName")
+            .map_or_else(String::new, |name| name.to_string());
+
+        let allowed = &self.config.allowed_users;
+        if !allowed.is_empty() && !allowed.contains(&username.to_lowercase()) {
+            warn!("Login as user {:?} is not allowed", username);
+            if let Some(rejected_tx) = &*self.rejected_tx.lock().unwrap() {
+                let _ = rejected_tx.send(LoginRejected {
+                    username: username.to_owned(),
+                    client_name,
+                });
+            }
+            return Self::login_failed();
+        }
+
         let encrypted_blob = params.get("blob").unwrap();
         let client_key = params.get("clientKey").unwrap();
 
@@ -134,6 +178,12 @@ impl RequestHandler {
 
         let credentials = Credentials::with_blob(username, &decrypted, &self.config.device_id);
 
+        if let Some(pairing) = self.config.pairing {
+            if !self.confirm_pairing(pairing, username, client_name).await {
+                return Self::login_failed();
+            }
+        }
+
         self.tx.send(credentials).unwrap();
 
         let result = json!({
@@ -146,13 +196,67 @@ impl RequestHandler {
         Response::new(Body::from(body))
     }
 
+    // What the client shows as a failed login.
+    fn login_failed() -> Response<hyper::Body> {
+        let result = json!({
+            "status": 105,
+            "spotifyError": 0,
+            "statusString": "ERROR-LOGIN-FAILED"
+        });
+
+        let body = result.to_string();
+        Response::new(Body::from(body))
+    }
+
+    // Holds the request until it is confirmed or rejected with its code, or until it times out.
+    async fn confirm_pairing(&self, pairing: Pairing, username: &str, client_name: String) -> bool {
+        let (confirm_tx, confirm_rx) = oneshot::channel();
+        let code = {
+            let mut pending = self.pending_pairings.lock().unwrap();
+            let mut rng = rand::thread_rng();
+            let code = loop {
+                let code = format!("{:04}", rng.gen_range(0..10_000));
+                if !pending.contains_key(&code) {
+                    break code;
+                }
+            };
+            pending.insert(code.clone(), confirm_tx);
+            code
+        };
+
+        info!(
+            "Pairing requested for user {:?} by {:?}, waiting for code {}",
+            username, client_name, code
+        );
+        let _ = self.pairing_tx.send(PairingRequest {
+            client_name,
+            username: username.to_owned(),
+            code: code.clone(),
+        });
+
+        let accepted = match tokio::time::timeout(pairing.timeout, confirm_rx).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(_)) => false,
+            Err(_) => {
+                debug!("Pairing with code {} timed out", code);
+                pairing.accept_on_timeout
+            }
+        };
+        self.pending_pairings.lock().unwrap().remove(&code);
+
+        if !accepted {
+            warn!("Pairing for user {:?} was rejected", username);
+        }
+        accepted
+    }
+
     fn not_found(&self) -> Response<hyper::Body> {
         let mut res = Response::default();
         *res.status_mut() = StatusCode::NOT_FOUND;
         res
     }
 
-    async fn handle(self: Arc<Self>, request: Request<Body>) -> hyper::Result<Response<Body>> {
+    pub async fn handle(self: Arc<Self>, request: Request<Body>) -> hyper::Result<Response<Body>> {
         let mut params = Params::new();
 
         let (parts, body) = request.into_parts();
@@ -163,7 +267,11 @@ impl RequestHandler {
         }
 
         if parts.method != Method::GET {
-            debug!("{:?} {:?} {:?}", parts.method, parts.uri.path(), params);
+            let logged: BTreeMap<_, _> = params
+                .iter()
+                .map(|(name, value)| (name.as_ref(), redact::param(name, value)))
+                .collect();
+            debug!("{:?} {:?} {:?}", parts.method, parts.uri.path(), logged);
         }
 
         let body = hyper::body::to_bytes(body).await?;
@@ -174,55 +282,114 @@ impl RequestHandler {
 
         Ok(match (parts.method, action) {
             (Method::GET, Some("getInfo")) => self.handle_get_info(),
-            (Method::POST, Some("addUser")) => self.handle_add_user(&params),
+            (Method::POST, Some("addUser")) => self.handle_add_user(&params).await,
             _ => self.not_found(),
         })
     }
 }
 
 pub struct DiscoveryServer {
+    handler: Arc<RequestHandler>,
     cred_rx: mpsc::UnboundedReceiver<Credentials>,
-    _close_tx: oneshot::Sender<Infallible>,
+    pairing_rx: Option<mpsc::UnboundedReceiver<PairingRequest>>,
+    // One for each address that is listened on.
+    close_tx: Vec<oneshot::Sender<Infallible>>,
 }
 
 impl DiscoveryServer {
-    pub fn new(config: Config, port: &mut u16) -> hyper::Result<Self> {
-        let (discovery, cred_rx) = RequestHandler::new(config);
-        let discovery = Arc::new(discovery);
+    pub fn new(config: Config) -> Self {
+        let (discovery, cred_rx, pairing_rx) = RequestHandler::new(config);
 
-        let (close_tx, close_rx) = oneshot::channel();
+        Self {
+            handler: Arc::new(discovery),
+            cred_rx,
+            pairing_rx: Some(pairing_rx),
+            close_tx: Vec::new(),
+        }
+    }
 
-        let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *port);
+    // Starts listening on all of `addresses` at `port`, or at the port the first of them
+    // gets when it is 0, and returns that port. Nothing is listened on unless all of them
+    // could be bound.
+    pub fn listen(&mut self, addresses: &[IpAddr], mut port: u16) -> hyper::Result<u16> {
+        let mut incomings = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let incoming = AddrIncoming::bind(&SocketAddr::new(*address, port))?;
+            port = incoming.local_addr().port();
+            incomings.push(incoming);
+        }
 
-        let make_service = make_service_fn(move |_| {
-            let discovery = discovery.clone();
-            async move {
-                Ok::<_, hyper::Error>(service_fn(move |request| discovery.clone().handle(request)))
-            }
-        });
+        for incoming in incomings {
+            let address = incoming.local_addr();
+            let discovery = self.handler.clone();
+            let make_service = make_service_fn(move |_| {
+                let discovery = discovery.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |request| {
+                        discovery.clone().handle(request)
+                    }))
+                }
+            });
 
-        let server = hyper::Server::try_bind(&address)?.serve(make_service);
+            let server = hyper::Server::builder(incoming).serve(make_service);
+            debug!("Zeroconf server listening on {}", address);
 
-        *port = server.local_addr().port();
-        debug!("Zeroconf server listening on 0.0.0.0:{}", *port);
+            let (close_tx, close_rx) = oneshot::channel();
+            self.close_tx.push(close_tx);
 
-        tokio::spawn(async {
-            let result = server
-                .with_graceful_shutdown(async {
-                    close_rx.await.unwrap_err();
-                    debug!("Shutting down discovery server");
-                })
-                .await;
+            tokio::spawn(async {
+                let result = server
+                    .with_graceful_shutdown(async {
+                        close_rx.await.unwrap_err();
+                        debug!("Shutting down discovery server");
+                    })
+                    .await;
 
-            if let Err(e) = result {
-                warn!("Discovery server failed: {}", e);
-            }
-        });
+                if let Err(e) = result {
+                    warn!("Discovery server failed: {}", e);
+                }
+            });
+        }
 
-        Ok(Self {
-            cred_rx,
-            _close_tx: close_tx,
-        })
+        Ok(port)
+    }
+}
+
+impl DiscoveryServer {
+    pub fn handler(&self) -> Arc<RequestHandler> {
+        self.handler.clone()
+    }
+
+    pub fn set_name(&self, name: Cow<'static, str>) {
+        *self.handler.name.write().unwrap() = name;
+    }
+
+    pub fn set_device_type(&self, device_type: DeviceType) {
+        *self.handler.device_type.write().unwrap() = device_type;
+    }
+
+    pub fn take_pairing_requests(&mut self) -> Option<mpsc::UnboundedReceiver<PairingRequest>> {
+        self.pairing_rx.take()
+    }
+
+    pub fn take_rejected_logins(&mut self) -> Option<mpsc::UnboundedReceiver<LoginRejected>> {
+        let mut rejected_tx = self.handler.rejected_tx.lock().unwrap();
+        if rejected_tx.is_some() {
+            return None;
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        *rejected_tx = Some(tx);
+        Some(rx)
+    }
+
+    // Completes the request to pair that waits for `code`, and tells whether there was one.
+    pub fn complete_pairing(&self, code: &str, accept: bool) -> bool {
+        let confirm_tx = self.handler.pending_pairings.lock().unwrap().remove(code);
+        match confirm_tx {
+            Some(confirm_tx) => confirm_tx.send(accept).is_ok(),
+            None => false,
+        }
     }
 }
 
@@ -233,3 +400,69 @@ impl Stream for DiscoveryServer {
         self.cred_rx.poll_recv(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn config() -> Config {
+        Config {
+            name: "Librespot".into(),
+            device_type: DeviceType::default(),
+            device_id: "test-device".to_owned(),
+            pairing: None,
+            allowed_users: Vec::new(),
+        }
+    }
+
+    async fn get_info(uri: String) -> serde_json::Value {
+        let response = hyper::Client::new()
+            .get(uri.parse().unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn standalone() {
+        let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let mut server = DiscoveryServer::new(config());
+        let port = server.listen(&[localhost], 0).unwrap();
+
+        let info = get_info(format!("http://127.0.0.1:{}/?action=getInfo", port)).await;
+        assert_eq!(info["deviceID"], "test-device");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_users() {
+        let mut server = DiscoveryServer::new(Config {
+            allowed_users: vec!["owner".to_owned()],
+            ..config()
+        });
+        let login = Request::post("/")
+            .body(Body::from("action=addUser&userName=Early&deviceName=Phone"))
+            .unwrap();
+        server.handler().handle(login).await.unwrap();
+
+        let mut rejected = server.take_rejected_logins().unwrap();
+        assert!(server.take_rejected_logins().is_none());
+
+        let request = Request::post("/")
+            .body(Body::from(
+                "action=addUser&userName=Someone&deviceName=Phone",
+            ))
+            .unwrap();
+        let response = server.handler().handle(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result["status"], 105);
+
+        let login = rejected.try_recv().unwrap();
+        assert_eq!(login.username, "Someone");
+        assert_eq!(login.client_name, "Phone");
+        // The one from before anyone listened was not kept.
+        assert!(rejected.try_recv().is_err());
+    }
+}
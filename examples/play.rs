@@ -32,7 +32,7 @@ async fn main() {
         .unwrap();
 
     let (mut player, _) = Player::new(player_config, session, Box::new(NoOpVolume), move || {
-        backend(None, audio_format)
+        backend(None, audio_format, &Default::default())
     });
 
     player.load(track, true, 0);
@@ -0,0 +1,42 @@
+use std::env;
+
+use librespot::core::authentication::Credentials;
+use librespot::core::config::SessionConfig;
+use librespot::core::session::Session;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let session_config = SessionConfig::default();
+
+    let args: Vec<_> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} USERNAME PASSWORD", args[0]);
+        return;
+    }
+    let credentials = Credentials::with_password(&args[1], &args[2]);
+
+    let (session, _) = Session::connect(session_config, credentials, None, false)
+        .await
+        .unwrap();
+
+    let mut updates = session.subscribe_pushes("hm://playlist/");
+
+    // The server only pushes updates of the playlists the session is subscribed to.
+    let rootlist = format!("hm://playlist/user/{}/rootlist", session.username());
+    let _rootlist_updates = session.mercury().subscribe(rootlist).await.unwrap();
+
+    println!(
+        "Waiting for changes to the playlists of {}",
+        session.username()
+    );
+    while let Some(update) = updates.recv().await {
+        println!(
+            "{} ({}): {} parts, headers {:?}",
+            update.uri,
+            update.content_type.as_deref().unwrap_or("no content type"),
+            update.payload.len(),
+            update.headers.keys().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use librespot_core::mercury::MercuryError;
+use librespot_core::spotify_id::{SpotifyId, SpotifyIdError};
+use protobuf::ProtobufError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Mercury(#[from] MercuryError),
+    #[error("Error {status_code} for {uri}")]
+    Status { uri: String, status_code: i32 },
+    #[error("Empty response")]
+    EmptyResponse,
+    #[error("Invalid Spotify id")]
+    InvalidId(#[from] SpotifyIdError),
+    #[error("{0:?} is not a track or episode")]
+    NotPlayable(SpotifyId),
+    #[error("Could not parse response")]
+    Protobuf(#[from] ProtobufError),
+    #[error("Could not parse response")]
+    Json(#[from] serde_json::Error),
+}
+
+impl MetadataError {
+    /// Whether the same request may succeed when it is retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, MetadataError::Mercury(e) if e.is_retryable())
+    }
+}
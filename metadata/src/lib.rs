@@ -7,16 +7,31 @@ extern crate log;
 extern crate async_trait;
 
 pub mod cover;
+mod error;
+mod lyrics;
 use std::collections::HashMap;
 use std::string::FromUtf8Error;
 
-use librespot_core::mercury::MercuryError;
+use librespot_core::config::CountryCode;
+use librespot_core::rate_limit::RequestPriority;
 use librespot_core::session::Session;
 use librespot_core::spotify_id::{FileId, SpotifyAudioType, SpotifyId, SpotifyIdError};
 use librespot_protocol as protocol;
 use protobuf::Message;
 
+pub use crate::error::MetadataError;
+pub use crate::lyrics::{Lyrics, LyricsLine};
 pub use crate::protocol::metadata::AudioFile_Format as FileFormat;
+pub use crate::protocol::metadata::Image_Size as ImageSize;
+
+/// The largest number of ids sent in one multi-get request, bigger requests are split.
+pub const MULTI_GET_LIMIT: usize = 100;
+
+// The country that availability is checked for in `market`, see `Session::market`. Nothing
+// is available in an unknown one.
+fn market_country(market: Option<CountryCode>) -> String {
+    market.map(|market| market.to_string()).unwrap_or_default()
+}
 
 fn countrylist_contains(list: &str, country: &str) -> bool {
     list.chunks(2).any(|cc| cc == country)
@@ -53,6 +68,43 @@ where
         && (!has_allowed || countrylist_contains(allowed.as_str(), country))
 }
 
+const IMAGE_CDN_URL: &str = "https://i.scdn.co/image/";
+
+fn parse_images<'s, I>(images: I) -> Vec<Image>
+where
+    I: IntoIterator<Item = &'s protocol::metadata::Image>,
+{
+    images
+        .into_iter()
+        .filter_map(|image| {
+            if image.has_file_id() {
+                let mut dst = [0u8; 20];
+                dst.clone_from_slice(image.get_file_id());
+                let id = FileId(dst);
+                Some(Image {
+                    url: format!("{}{}", IMAGE_CDN_URL, id),
+                    id,
+                    size: image.get_size(),
+                    width: image.get_width(),
+                    height: image.get_height(),
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// An image in one of the sizes Spotify offers, with its https URL on the image CDN
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub id: FileId,
+    pub url: String,
+    pub size: ImageSize,
+    pub width: i32,
+    pub height: i32,
+}
+
 // A wrapper with fields the player needs
 #[derive(Debug, Clone)]
 pub struct AudioItem {
@@ -66,30 +118,47 @@ pub struct AudioItem {
 }
 
 impl AudioItem {
-    pub async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<Self, MercuryError> {
+    pub async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<Self, MetadataError> {
+        Self::get_audio_item_with_priority(session, id, RequestPriority::Normal).await
+    }
+
+    /// Like `get_audio_item`, see `Metadata::get_with_priority`.
+    pub async fn get_audio_item_with_priority(
+        session: &Session,
+        id: SpotifyId,
+        priority: RequestPriority,
+    ) -> Result<Self, MetadataError> {
         match id.audio_type {
-            SpotifyAudioType::Track => Track::get_audio_item(session, id).await,
-            SpotifyAudioType::Podcast => Episode::get_audio_item(session, id).await,
-            SpotifyAudioType::NonPlayable => Err(MercuryError),
+            SpotifyAudioType::Track => Track::get_audio_item(session, id, priority).await,
+            SpotifyAudioType::Podcast => Episode::get_audio_item(session, id, priority).await,
+            SpotifyAudioType::NonPlayable => Err(MetadataError::NotPlayable(id)),
         }
     }
 }
 
 #[async_trait]
 trait AudioFiles {
-    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, MercuryError>;
+    async fn get_audio_item(
+        session: &Session,
+        id: SpotifyId,
+        priority: RequestPriority,
+    ) -> Result<AudioItem, MetadataError>;
 }
 
 #[async_trait]
 impl AudioFiles for Track {
-    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, MercuryError> {
+    async fn get_audio_item(
+        session: &Session,
+        id: SpotifyId,
+        priority: RequestPriority,
+    ) -> Result<AudioItem, MetadataError> {
         match id.to_base62() {
             Err(e) => {
                 warn!("Invalid Track SpotifyId: {}", e);
-                Err(MercuryError)
+                Err(SpotifyIdError.into())
             }
             Ok(uri) => {
-                let item = Self::get(session, id).await?;
+                let item = Self::get_with_priority(session, id, priority).await?;
                 Ok(AudioItem {
                     id,
                     uri: format!("spotify:track:{}", uri),
@@ -106,14 +175,18 @@ impl AudioFiles for Track {
 
 #[async_trait]
 impl AudioFiles for Episode {
-    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, MercuryError> {
+    async fn get_audio_item(
+        session: &Session,
+        id: SpotifyId,
+        priority: RequestPriority,
+    ) -> Result<AudioItem, MetadataError> {
         match id.to_base62() {
             Err(e) => {
                 warn!("Invalid Episode SpotifyId: {}", e);
-                Err(MercuryError)
+                Err(SpotifyIdError.into())
             }
             Ok(uri) => {
-                let item = Self::get(session, id).await?;
+                let item = Self::get_with_priority(session, id, priority).await?;
                 Ok(AudioItem {
                     id,
                     uri: format!("spotify:episode:{}", uri),
@@ -133,37 +206,126 @@ pub trait Metadata: Send + Sized + 'static {
     type Message: protobuf::Message;
 
     fn request_url(id: SpotifyId) -> Result<String, FromUtf8Error>;
-    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, SpotifyIdError>;
+    /// Availability is checked for `market`, see `Session::market`.
+    fn parse(msg: &Self::Message, market: Option<CountryCode>) -> Result<Self, SpotifyIdError>;
+
+    /// The multi-get endpoint for this type, if one exists.
+    fn batch_request_url() -> Option<&'static str> {
+        None
+    }
+
+    async fn get(session: &Session, id: SpotifyId) -> Result<Self, MetadataError> {
+        Self::get_with_priority(session, id, RequestPriority::Normal).await
+    }
 
-    async fn get(session: &Session, id: SpotifyId) -> Result<Self, MercuryError> {
+    /// Like `get`, but an `Essential` request isn't held back while the session is rate
+    /// limited, see `MercuryManager::get_with_priority`.
+    async fn get_with_priority(
+        session: &Session,
+        id: SpotifyId,
+        priority: RequestPriority,
+    ) -> Result<Self, MetadataError> {
         match Self::request_url(id) {
             Err(e) => {
                 warn!("Invalid SpotifyId: {}", e);
-                Err(MercuryError)
+                Err(SpotifyIdError.into())
             }
             Ok(uri) => {
-                let response = session.mercury().get(uri).await?;
+                let response = session.mercury().get_with_priority(uri, priority).await?;
                 match response.payload.first() {
                     None => {
                         warn!("Empty payload");
-                        Err(MercuryError)
+                        Err(MetadataError::EmptyResponse)
                     }
-                    Some(data) => match Self::Message::parse_from_bytes(data) {
-                        Err(e) => {
-                            warn!("Error parsing message from bytes: {}", e);
-                            Err(MercuryError)
-                        }
-                        Ok(msg) => match Self::parse(&msg, session) {
-                            Err(e) => {
-                                warn!("Error parsing message: {:?}", e);
-                                Err(MercuryError)
+                    Some(data) => Self::parse_payload(data, session.market()),
+                }
+            }
+        }
+    }
+
+    /// Fetches several items at once, keyed by id.
+    ///
+    /// Types with a multi-get endpoint are fetched in batches of at most
+    /// `MULTI_GET_LIMIT` ids, others fall back to one request per id. An id
+    /// that fails doesn't affect the others in its batch.
+    async fn get_multiple(
+        session: &Session,
+        ids: &[SpotifyId],
+    ) -> HashMap<SpotifyId, Result<Self, MetadataError>> {
+        let mut results = HashMap::with_capacity(ids.len());
+
+        let batch_url = match Self::batch_request_url() {
+            Some(batch_url) => batch_url,
+            None => {
+                for id in ids {
+                    results.insert(*id, Self::get(session, *id).await);
+                }
+                return results;
+            }
+        };
+
+        for batch in ids.chunks(MULTI_GET_LIMIT) {
+            let mut batch_ids = Vec::with_capacity(batch.len());
+            let mut uris = Vec::with_capacity(batch.len());
+            for id in batch {
+                match Self::request_url(*id) {
+                    Ok(uri) => {
+                        batch_ids.push(*id);
+                        uris.push(uri);
+                    }
+                    Err(e) => {
+                        warn!("Invalid SpotifyId: {}", e);
+                        results.insert(*id, Err(SpotifyIdError.into()));
+                    }
+                }
+            }
+
+            match session.mercury().get_multiple(batch_url, uris).await {
+                Err(e) => {
+                    for id in batch_ids {
+                        results.insert(id, Err(e.into()));
+                    }
+                }
+                Ok(responses) => {
+                    for (id, response) in batch_ids.into_iter().zip(responses) {
+                        let result = if response.status_code >= 400 {
+                            warn!("error {} for uri {}", response.status_code, response.uri);
+                            Err(MetadataError::Status {
+                                uri: response.uri,
+                                status_code: response.status_code,
+                            })
+                        } else {
+                            match response.payload.first() {
+                                None => {
+                                    warn!("Empty payload");
+                                    Err(MetadataError::EmptyResponse)
+                                }
+                                Some(data) => Self::parse_payload(data, session.market()),
                             }
-                            Ok(parsed_msg) => Ok(parsed_msg),
-                        },
-                    },
+                        };
+                        results.insert(id, result);
+                    }
                 }
             }
         }
+
+        results
+    }
+
+    fn parse_payload(data: &[u8], market: Option<CountryCode>) -> Result<Self, MetadataError> {
+        match Self::Message::parse_from_bytes(data) {
+            Err(e) => {
+                warn!("Error parsing message from bytes: {}", e);
+                Err(e.into())
+            }
+            Ok(msg) => match Self::parse(&msg, market) {
+                Err(e) => {
+                    warn!("Error parsing message: {:?}", e);
+                    Err(e.into())
+                }
+                Ok(parsed_msg) => Ok(parsed_msg),
+            },
+        }
     }
 }
 
@@ -177,6 +339,36 @@ pub struct Track {
     pub files: HashMap<FileFormat, FileId>,
     pub alternatives: Vec<SpotifyId>,
     pub available: bool,
+    pub restrictions: Vec<protocol::metadata::Restriction>,
+    pub explicit: bool,
+}
+
+impl Track {
+    /// Returns whether this track itself may be played in `country`.
+    pub fn is_available_in(&self, country: &str) -> bool {
+        parse_restrictions(&self.restrictions, country, "premium")
+    }
+
+    /// Resolves the id that should be played in `country`.
+    ///
+    /// This is the id of the track itself if it is available, otherwise the first of
+    /// its alternatives that is. Returns `None` if neither is playable. The alternatives
+    /// are fetched as `Essential` requests, as they are for playback.
+    pub async fn playable_in(&self, session: &Session, country: &str) -> Option<SpotifyId> {
+        if self.is_available_in(country) {
+            return Some(self.id);
+        }
+
+        for alternative in &self.alternatives {
+            match Self::get_with_priority(session, *alternative, RequestPriority::Essential).await {
+                Ok(track) if track.is_available_in(country) => return Some(track.id),
+                Ok(_) => (),
+                Err(_) => debug!("Unable to get alternative {:?}", alternative),
+            }
+        }
+
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -186,6 +378,7 @@ pub struct Album {
     pub artists: Vec<SpotifyId>,
     pub tracks: Vec<SpotifyId>,
     pub covers: Vec<FileId>,
+    pub images: Vec<Image>,
 }
 
 #[derive(Debug, Clone)]
@@ -224,6 +417,8 @@ pub struct Artist {
     pub id: SpotifyId,
     pub name: String,
     pub top_tracks: Vec<SpotifyId>,
+    pub albums: Vec<SpotifyId>,
+    pub images: Vec<Image>,
 }
 
 impl Metadata for Track {
@@ -234,8 +429,12 @@ impl Metadata for Track {
         Ok(format!("hm://metadata/3/track/{}", id))
     }
 
-    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, SpotifyIdError> {
-        let country = session.country();
+    fn batch_request_url() -> Option<&'static str> {
+        Some("hm://metadata/3/tracks")
+    }
+
+    fn parse(msg: &Self::Message, market: Option<CountryCode>) -> Result<Self, SpotifyIdError> {
+        let country = market_country(market);
 
         let artists = msg
             .get_artist()
@@ -276,6 +475,8 @@ impl Metadata for Track {
                 .filter_map(|alt| SpotifyId::from_raw(alt.get_gid()).ok())
                 .collect(),
             available: parse_restrictions(msg.get_restriction(), &country, "premium"),
+            restrictions: msg.get_restriction().to_vec(),
+            explicit: msg.get_explicit(),
         })
     }
 }
@@ -288,7 +489,11 @@ impl Metadata for Album {
         Ok(format!("hm://metadata/3/album/{}", id))
     }
 
-    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, SpotifyIdError> {
+    fn batch_request_url() -> Option<&'static str> {
+        Some("hm://metadata/3/albums")
+    }
+
+    fn parse(msg: &Self::Message, _: Option<CountryCode>) -> Result<Self, SpotifyIdError> {
         let artists = msg
             .get_artist()
             .iter()
@@ -329,12 +534,19 @@ impl Metadata for Album {
             })
             .collect();
 
+        let images = if msg.has_cover_group() {
+            parse_images(msg.get_cover_group().get_image())
+        } else {
+            parse_images(msg.get_cover())
+        };
+
         Ok(Album {
             id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             artists,
             tracks,
             covers,
+            images,
         })
     }
 }
@@ -347,7 +559,7 @@ impl Metadata for Playlist {
         Ok(format!("hm://playlist/v2/playlist/{}", id))
     }
 
-    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, SpotifyIdError> {
+    fn parse(msg: &Self::Message, _: Option<CountryCode>) -> Result<Self, SpotifyIdError> {
         let tracks = msg
             .get_contents()
             .get_items()
@@ -384,8 +596,12 @@ impl Metadata for Artist {
         Ok(format!("hm://metadata/3/artist/{}", id))
     }
 
-    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, SpotifyIdError> {
-        let country = session.country();
+    fn batch_request_url() -> Option<&'static str> {
+        Some("hm://metadata/3/artists")
+    }
+
+    fn parse(msg: &Self::Message, market: Option<CountryCode>) -> Result<Self, SpotifyIdError> {
+        let country = market_country(market);
 
         let top_tracks: Vec<SpotifyId> = match msg
             .get_top_track()
@@ -406,10 +622,32 @@ impl Metadata for Artist {
             None => Vec::new(),
         };
 
+        let albums = msg
+            .get_album_group()
+            .iter()
+            .chain(msg.get_single_group())
+            .flat_map(|group| group.get_album())
+            .filter_map(|album| {
+                if album.has_gid() {
+                    SpotifyId::from_raw(album.get_gid()).ok()
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let images = if msg.has_portrait_group() {
+            parse_images(msg.get_portrait_group().get_image())
+        } else {
+            parse_images(msg.get_portrait())
+        };
+
         Ok(Artist {
             id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             top_tracks,
+            albums,
+            images,
         })
     }
 }
@@ -423,8 +661,12 @@ impl Metadata for Episode {
         Ok(format!("hm://metadata/3/episode/{}", id))
     }
 
-    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, SpotifyIdError> {
-        let country = session.country();
+    fn batch_request_url() -> Option<&'static str> {
+        Some("hm://metadata/3/episodes")
+    }
+
+    fn parse(msg: &Self::Message, market: Option<CountryCode>) -> Result<Self, SpotifyIdError> {
+        let country = market_country(market);
 
         let files = msg
             .get_file()
@@ -472,7 +714,11 @@ impl Metadata for Show {
         Ok(format!("hm://metadata/3/show/{}", id))
     }
 
-    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, SpotifyIdError> {
+    fn batch_request_url() -> Option<&'static str> {
+        Some("hm://metadata/3/shows")
+    }
+
+    fn parse(msg: &Self::Message, _: Option<CountryCode>) -> Result<Self, SpotifyIdError> {
         let episodes = msg
             .get_episode()
             .iter()
@@ -532,3 +778,51 @@ impl<'s> Iterator for StrChunks<'s> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(allowed: &str, forbidden: &str) -> protocol::metadata::Track {
+        let mut restriction = protocol::metadata::Restriction::new();
+        restriction.set_catalogue_str(protobuf::RepeatedField::from_vec(
+            vec!["premium".to_owned()],
+        ));
+        if !allowed.is_empty() {
+            restriction.set_countries_allowed(allowed.to_owned());
+        }
+        if !forbidden.is_empty() {
+            restriction.set_countries_forbidden(forbidden.to_owned());
+        }
+
+        let mut msg = protocol::metadata::Track::new();
+        msg.set_gid(vec![1; 16]);
+        msg.mut_album().set_gid(vec![2; 16]);
+        msg.mut_restriction().push(restriction);
+        msg
+    }
+
+    fn market(country: &str) -> Option<CountryCode> {
+        Some(country.parse().unwrap())
+    }
+
+    #[test]
+    fn track_availability_is_for_the_market() {
+        let allowed = track("SEDK", "");
+        assert!(Track::parse(&allowed, market("se")).unwrap().available);
+        assert!(!Track::parse(&allowed, market("DE")).unwrap().available);
+        assert!(!Track::parse(&allowed, None).unwrap().available);
+
+        let forbidden = track("", "DE");
+        assert!(Track::parse(&forbidden, market("SE")).unwrap().available);
+        assert!(!Track::parse(&forbidden, market("DE")).unwrap().available);
+    }
+
+    #[test]
+    fn parsed_payload_is_for_the_market() {
+        let data = track("SE", "").write_to_bytes().unwrap();
+        assert!(Track::parse_payload(&data, market("SE")).unwrap().available);
+        assert!(!Track::parse_payload(&data, market("NO")).unwrap().available);
+        assert!(Track::parse_payload(b"\xff", market("SE")).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use serde::Deserialize;
+
+use librespot_core::session::Session;
+use librespot_core::spotify_id::{SpotifyId, SpotifyIdError};
+
+use crate::MetadataError;
+
+#[derive(Debug, Clone, Default)]
+pub struct Lyrics {
+    // Whether the lines carry timestamps. Unsynced lyrics have all lines start at 0.
+    pub synced: bool,
+    pub lines: Vec<LyricsLine>,
+    pub provider: String,
+    pub language: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LyricsLine {
+    pub start_time_ms: u32,
+    pub words: String,
+}
+
+#[derive(Deserialize)]
+struct LyricsResponse {
+    lyrics: LyricsData,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LyricsData {
+    #[serde(default)]
+    sync_type: String,
+    #[serde(default)]
+    lines: Vec<LyricsLineData>,
+    #[serde(default)]
+    provider: String,
+    #[serde(default)]
+    language: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LyricsLineData {
+    // The endpoint sends timestamps as strings.
+    start_time_ms: String,
+    words: String,
+}
+
+impl Lyrics {
+    /// Fetches the lyrics of a track.
+    ///
+    /// Tracks without lyrics yield an empty `Lyrics` rather than an error.
+    pub async fn get(session: &Session, track_id: SpotifyId) -> Result<Self, MetadataError> {
+        let id = track_id.to_base62().map_err(|e| {
+            warn!("Invalid Track SpotifyId: {}", e);
+            SpotifyIdError
+        })?;
+        let uri = format!("hm://color-lyrics/v2/track/{}?format=json", id);
+
+        let response = session.mercury().get_with_status(uri).await?;
+        match response.status_code {
+            404 => return Ok(Self::default()),
+            code if code >= 400 => {
+                warn!("error {} for uri {}", code, response.uri);
+                return Err(MetadataError::Status {
+                    uri: response.uri,
+                    status_code: code,
+                });
+            }
+            _ => (),
+        }
+
+        let data = response.payload.first().ok_or_else(|| {
+            warn!("Empty payload");
+            MetadataError::EmptyResponse
+        })?;
+        let lyrics: LyricsResponse = serde_json::from_slice(data).map_err(|e| {
+            warn!("Error parsing lyrics: {}", e);
+            e
+        })?;
+
+        Ok(lyrics.lyrics.into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+impl From<LyricsData> for Lyrics {
+    fn from(data: LyricsData) -> Self {
+        Self {
+            synced: data.sync_type == "LINE_SYNCED",
+            lines: data
+                .lines
+                .into_iter()
+                .map(|line| LyricsLine {
+                    start_time_ms: line.start_time_ms.parse().unwrap_or_default(),
+                    words: line.words,
+                })
+                .collect(),
+            provider: data.provider,
+            language: data.language,
+        }
+    }
+}
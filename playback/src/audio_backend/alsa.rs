@@ -1,21 +1,36 @@
-use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use super::{
+    find_device, DeviceInfo, Open, RouteChange, Sink, SinkAsBytes, SinkError, SinkOptions,
+    SinkResult,
+};
 use crate::config::AudioFormat;
 use crate::convert::Converter;
 use crate::decoder::AudioPacket;
 use crate::{NUM_CHANNELS, SAMPLE_RATE};
+use alsa::ctl::{ElemId, ElemIface};
 use alsa::device_name::HintIter;
+use alsa::nix::errno::Errno;
 use alsa::pcm::{Access, Format, Frames, HwParams, PCM};
-use alsa::{Direction, ValueOr};
+use alsa::{Direction, HCtl, ValueOr};
 use std::process::exit;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 const MAX_BUFFER: Frames = (SAMPLE_RATE / 2) as Frames;
 const MIN_BUFFER: Frames = (SAMPLE_RATE / 10) as Frames;
 const ZERO_FRAMES: Frames = 0;
 
+// How often the jacks of the card are read while playing.
+const JACK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 const MAX_PERIOD_DIVISOR: Frames = 4;
 const MIN_PERIOD_DIVISOR: Frames = 10;
 
+// The consumer channel status bits, see IEC 60958-3.
+const IEC958_AES0_CON_NOT_COPYRIGHT: u8 = 0x04;
+const IEC958_AES1_CON_ORIGINAL: u8 = 0x80;
+const IEC958_AES1_CON_PCM_CODER: u8 = 0x02;
+const IEC958_AES2_CON_SOURCE_UNSPEC: u8 = 0x00;
+
 #[derive(Debug, Error)]
 enum AlsaError {
     #[error("<AlsaSink> Device {device} Unsupported Format {alsa_format:?} ({format:?}), {e}")]
@@ -99,7 +114,91 @@ pub struct AlsaSink {
     pcm: Option<PCM>,
     format: AudioFormat,
     device: String,
+    buffer_time: Option<Duration>,
+    period_count: Option<u32>,
+    // The device with the IEC958 channel status, tried before `device`.
+    iec958_device: Option<String>,
     period_buffer: Vec<u8>,
+    underruns: u64,
+    jacks: Option<Jacks>,
+}
+
+// The jack detection controls of the card that is played on, such as "Headphone Jack".
+struct Jacks {
+    hctl: HCtl,
+    // Whether something was plugged into each jack when it was last read.
+    jacks: Vec<(ElemId, bool)>,
+    checked: Instant,
+}
+
+impl Jacks {
+    // Only cards that are played on directly have jacks, not software devices like dmix.
+    fn open(pcm: &PCM) -> Option<Self> {
+        let card = pcm.info().ok()?.get_card();
+        if card < 0 {
+            return None;
+        }
+
+        let hctl = HCtl::new(&format!("hw:{}", card), true).ok()?;
+        hctl.load().ok()?;
+        let ids: Vec<ElemId> = hctl
+            .elem_iter()
+            .filter_map(|elem| elem.get_id().ok())
+            .filter(|id| {
+                id.get_interface() == ElemIface::Card && is_output_jack(id.get_name().unwrap_or(""))
+            })
+            .collect();
+        let jacks: Vec<_> = ids
+            .into_iter()
+            .filter_map(|id| {
+                let plugged = read_jack(&hctl, &id)?;
+                Some((id, plugged))
+            })
+            .collect();
+        if jacks.is_empty() {
+            return None;
+        }
+
+        debug!("Watching {} jack(s) of card {}", jacks.len(), card);
+        Some(Self {
+            hctl,
+            jacks,
+            checked: Instant::now(),
+        })
+    }
+
+    // An unplugged jack wins over one that was plugged in at the same time.
+    fn poll(&mut self) -> Option<RouteChange> {
+        if self.checked.elapsed() < JACK_POLL_INTERVAL {
+            return None;
+        }
+        self.checked = Instant::now();
+
+        let mut change = None;
+        for (id, plugged) in self.jacks.iter_mut() {
+            let now = match read_jack(&self.hctl, id) {
+                Some(now) => now,
+                None => continue,
+            };
+            if now != *plugged {
+                *plugged = now;
+                if !now {
+                    change = Some(RouteChange::JackUnplugged);
+                } else if change.is_none() {
+                    change = Some(RouteChange::JackPlugged);
+                }
+            }
+        }
+        change
+    }
+}
+
+fn is_output_jack(name: &str) -> bool {
+    name.ends_with(" Jack") && (name.contains("Headphone") || name.contains("Line Out"))
+}
+
+fn read_jack(hctl: &HCtl, id: &ElemId) -> Option<bool> {
+    hctl.find_elem(id)?.read().ok()?.get_boolean(0)
 }
 
 fn list_compatible_devices() -> SinkResult<()> {
@@ -162,7 +261,113 @@ fn list_compatible_devices() -> SinkResult<()> {
     Ok(())
 }
 
-fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)> {
+// Only reads the device hints, so unlike `list_compatible_devices` it opens nothing.
+pub fn list_output_devices() -> SinkResult<Vec<DeviceInfo>> {
+    let hints = HintIter::new_str(None, "pcm").map_err(AlsaError::Parsing)?;
+
+    Ok(hints
+        // Devices without a direction support both.
+        .filter(|hint| matches!(hint.direction, None | Some(Direction::Playback)))
+        .filter_map(|hint| {
+            let id = hint.name?;
+            Some(DeviceInfo {
+                default: id == "default",
+                description: hint.desc.unwrap_or_default(),
+                id,
+            })
+        })
+        .collect())
+}
+
+// Resolves a device given by part of its description to its name. Names that aren't
+// listed, such as those of plugins defined on the fly, are passed to ALSA as they are.
+fn resolve_device(name: &str) -> SinkResult<String> {
+    let devices = match list_output_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!(
+                "Unable to list devices to match \"{}\" against, {}",
+                name, e
+            );
+            return Ok(name.to_string());
+        }
+    };
+
+    match find_device(&devices, name)? {
+        Some(device) => {
+            if device.id != name {
+                info!("Using device {} for \"{}\"", device.id, name);
+            }
+            Ok(device.id.clone())
+        }
+        None => Ok(name.to_string()),
+    }
+}
+
+// The channel status of linear PCM audio at our sample rate. The non-audio flag stays off,
+// and the word length belongs in AES4, which the ALSA iec958 devices don't take.
+fn iec958_channel_status() -> String {
+    let aes3 = match SAMPLE_RATE {
+        32000 => 0x03,
+        44100 => 0x00,
+        48000 => 0x02,
+        _ => 0x01,
+    };
+
+    format!(
+        "AES0=0x{:02x},AES1=0x{:02x},AES2=0x{:02x},AES3=0x{:02x}",
+        IEC958_AES0_CON_NOT_COPYRIGHT,
+        IEC958_AES1_CON_ORIGINAL | IEC958_AES1_CON_PCM_CODER,
+        IEC958_AES2_CON_SOURCE_UNSPEC,
+        aes3
+    )
+}
+
+// Adds the channel status to the arguments of an `iec958` device.
+fn iec958_device(device: &str, options: &SinkOptions) -> Option<String> {
+    if !options.alsa_iec958 && options.alsa_iec958_aes.is_none() {
+        return None;
+    }
+
+    if !device.starts_with("iec958") {
+        warn!(
+            "Device {} is not an iec958 device, opening it without an IEC958 channel status",
+            device
+        );
+        return None;
+    }
+
+    let channel_status = options
+        .alsa_iec958_aes
+        .clone()
+        .unwrap_or_else(iec958_channel_status);
+
+    Some(match device.find(':') {
+        Some(_) => format!("{},{}", device, channel_status),
+        None => format!("{}:{}", device, channel_status),
+    })
+}
+
+// Clamps a requested buffer or period size to the range the device reports.
+fn clamp_frames(what: &str, requested: Frames, min: Frames, max: Frames) -> Frames {
+    if min > max || (min..=max).contains(&requested) {
+        return requested;
+    }
+
+    let clamped = requested.max(min).min(max);
+    warn!(
+        "Requested {} size of {} Frames is outside of the device's range of {} - {} Frames, using {} Frames",
+        what, requested, min, max, clamped
+    );
+    clamped
+}
+
+fn open_device(
+    dev_name: &str,
+    format: AudioFormat,
+    buffer_time: Option<Duration>,
+    period_count: Option<u32>,
+) -> SinkResult<(PCM, usize)> {
     let pcm = PCM::new(dev_name, Direction::Playback, false).map_err(|e| AlsaError::PcmSetUp {
         device: dev_name.to_string(),
         e,
@@ -240,8 +445,19 @@ fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)>
                 Ok(s) => s,
             };
 
+            // A requested buffer time replaces the desired range.
+            let (min_buffer, max_buffer) = match buffer_time {
+                Some(buffer_time) => {
+                    let requested =
+                        (buffer_time.as_micros() * SAMPLE_RATE as u128 / 1_000_000) as Frames;
+                    let size = clamp_frames("Buffer", requested, min, max);
+                    (size, size)
+                }
+                None => (MIN_BUFFER, MAX_BUFFER),
+            };
+
             let buffer_size = if min < max {
-                match (MIN_BUFFER..=MAX_BUFFER)
+                match (min_buffer..=max_buffer)
                     .rev()
                     .find(|f| (min..=max).contains(f))
                 {
@@ -269,8 +485,8 @@ fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)>
             if buffer_size == ZERO_FRAMES {
                 trace!(
                     "Desired Buffer Frame range: {:?} - {:?}",
-                    MIN_BUFFER,
-                    MAX_BUFFER
+                    min_buffer,
+                    max_buffer
                 );
 
                 trace!(
@@ -303,10 +519,20 @@ fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)>
                     Ok(s) => s,
                 };
 
-                let max_period = buffer_size / MAX_PERIOD_DIVISOR;
-                let min_period = buffer_size / MIN_PERIOD_DIVISOR;
+                // A requested period count replaces the desired range.
+                let (min_period, max_period) = match period_count {
+                    Some(period_count) => {
+                        let requested = buffer_size / period_count as Frames;
+                        let size = clamp_frames("Period", requested, min, max);
+                        (size, size)
+                    }
+                    None => (
+                        buffer_size / MIN_PERIOD_DIVISOR,
+                        buffer_size / MAX_PERIOD_DIVISOR,
+                    ),
+                };
 
-                let period_size = if min < max && min_period < max_period {
+                let period_size = if min < max && min_period <= max_period {
                     match (min_period..=max_period)
                         .rev()
                         .find(|f| (min..=max).contains(f))
@@ -384,6 +610,14 @@ fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)>
         trace!("Actual Frames per Buffer: {:?}", frames_per_buffer);
         trace!("Actual Frames per Period: {:?}", frames_per_period);
 
+        info!(
+            "Negotiated ALSA Buffer of {} Frames ({} ms) in {} Periods of {} Frames",
+            frames_per_buffer,
+            frames_per_buffer * 1000 / SAMPLE_RATE as Frames,
+            frames_per_buffer / frames_per_period.max(1),
+            frames_per_period
+        );
+
         // Let ALSA do the math for us.
         pcm.frames_to_bytes(frames_per_period) as usize
     };
@@ -395,6 +629,14 @@ fn open_device(dev_name: &str, format: AudioFormat) -> SinkResult<(PCM, usize)>
 
 impl Open for AlsaSink {
     fn open(device: Option<String>, format: AudioFormat) -> Self {
+        Self::open_with_options(device, format, &SinkOptions::default())
+    }
+
+    fn open_with_options(
+        device: Option<String>,
+        format: AudioFormat,
+        options: &SinkOptions,
+    ) -> Self {
         let name = match device.as_deref() {
             Some("?") => match list_compatible_devices() {
                 Ok(_) => {
@@ -405,18 +647,25 @@ impl Open for AlsaSink {
                     exit(1);
                 }
             },
-            Some(device) => device,
-            None => "default",
-        }
-        .to_string();
+            Some(device) => resolve_device(device).unwrap_or_else(|e| {
+                error!("{}", e);
+                exit(1);
+            }),
+            None => "default".to_string(),
+        };
 
         info!("Using AlsaSink with format: {:?}", format);
 
         Self {
             pcm: None,
             format,
+            iec958_device: iec958_device(&name, options),
             device: name,
+            buffer_time: options.alsa_buffer_time,
+            period_count: options.alsa_period_count,
             period_buffer: vec![],
+            underruns: 0,
+            jacks: None,
         }
     }
 }
@@ -424,7 +673,36 @@ impl Open for AlsaSink {
 impl Sink for AlsaSink {
     fn start(&mut self) -> SinkResult<()> {
         if self.pcm.is_none() {
-            let (pcm, bytes_per_period) = open_device(&self.device, self.format)?;
+            let iec958 = self.iec958_device.as_ref().map(|device| {
+                open_device(device, self.format, self.buffer_time, self.period_count)
+            });
+
+            let (pcm, bytes_per_period) = match iec958 {
+                Some(Ok(opened)) => opened,
+                Some(Err(e)) => {
+                    // Not every device takes a channel status, don't try again.
+                    warn!(
+                        "{}, opening {} without an IEC958 channel status instead",
+                        e, self.device
+                    );
+                    self.iec958_device = None;
+                    open_device(
+                        &self.device,
+                        self.format,
+                        self.buffer_time,
+                        self.period_count,
+                    )?
+                }
+                None => open_device(
+                    &self.device,
+                    self.format,
+                    self.buffer_time,
+                    self.period_count,
+                )?,
+            };
+            if self.jacks.is_none() {
+                self.jacks = Jacks::open(&pcm);
+            }
             self.pcm = Some(pcm);
 
             if self.period_buffer.capacity() != bytes_per_period {
@@ -445,15 +723,38 @@ impl Sink for AlsaSink {
         // Zero fill the remainder of the period buffer and
         // write any leftover data before draining the actual PCM buffer.
         self.period_buffer.resize(self.period_buffer.capacity(), 0);
-        self.write_buf()?;
+        let written = self.write_buf();
 
+        // Close the PCM even if the write failed, so that start() opens the device again.
         let pcm = self.pcm.take().ok_or(AlsaError::NotConnected)?;
+        self.period_buffer.clear();
+        written?;
 
         pcm.drain().map_err(AlsaError::DrainFailure)?;
 
         Ok(())
     }
 
+    fn take_underruns(&mut self) -> u64 {
+        std::mem::take(&mut self.underruns)
+    }
+
+    fn take_route_change(&mut self) -> Option<RouteChange> {
+        self.pcm.as_ref()?;
+        self.jacks.as_mut()?.poll()
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        // The frames queued in the PCM, plus those still waiting in the period buffer.
+        let delay = self.pcm.as_ref()?.delay().ok()?.max(0) as u64;
+        let pending =
+            (self.period_buffer.len() / (self.format.size() * NUM_CHANNELS as usize)) as u64;
+
+        Some(Duration::from_micros(
+            (delay + pending) * 1_000_000 / SAMPLE_RATE as u64,
+        ))
+    }
+
     sink_as_bytes!();
 }
 
@@ -492,14 +793,25 @@ impl AlsaSink {
         let pcm = self.pcm.as_mut().ok_or(AlsaError::NotConnected)?;
 
         if let Err(e) = pcm.io_bytes().writei(&self.period_buffer) {
-            // Capture and log the original error as a warning, and then try to recover.
-            // If recovery fails then forward that error back to player.
-            warn!(
-                "Error writing from AlsaSink buffer to PCM, trying to recover, {}",
-                e
-            );
+            if e.errno() == Errno::EPIPE {
+                // An underrun: prepare the PCM and write the period again, the sink stays open.
+                debug!("AlsaSink buffer underrun, recovering");
+                self.underruns += 1;
+                pcm.prepare().map_err(AlsaError::OnWrite)?;
+            } else {
+                // Capture and log the original error as a warning, and then try to recover.
+                // If recovery fails then forward that error back to player.
+                warn!(
+                    "Error writing from AlsaSink buffer to PCM, trying to recover, {}",
+                    e
+                );
+
+                pcm.try_recover(e, false).map_err(AlsaError::OnWrite)?
+            }
 
-            pcm.try_recover(e, false).map_err(AlsaError::OnWrite)?
+            pcm.io_bytes()
+                .writei(&self.period_buffer)
+                .map_err(AlsaError::OnWrite)?;
         }
 
         self.period_buffer.clear();
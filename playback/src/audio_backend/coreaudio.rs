@@ -0,0 +1,837 @@
+use super::ring_buffer::RingBuffer;
+use super::{
+    find_device, AmbiguousDevice, DeviceInfo, Open, RouteChange, Sink, SinkAsBytes, SinkError,
+    SinkOptions, SinkResult,
+};
+use crate::config::AudioFormat;
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+use core_foundation_sys::base::{CFRelease, CFTypeRef};
+use core_foundation_sys::string::{kCFStringEncodingUTF8, CFStringGetCString, CFStringRef};
+use coreaudio_sys::{
+    kAudioDevicePropertyDataSource, kAudioDevicePropertyDeviceNameCFString,
+    kAudioDevicePropertyDeviceUID, kAudioDevicePropertyNominalSampleRate,
+    kAudioDevicePropertyStreams, kAudioFormatFlagIsFloat, kAudioFormatFlagIsPacked,
+    kAudioFormatFlagIsSignedInteger, kAudioFormatLinearPCM, kAudioHardwareNoError,
+    kAudioHardwarePropertyDefaultOutputDevice, kAudioHardwarePropertyDevices,
+    kAudioObjectPropertyElementMaster, kAudioObjectPropertyScopeGlobal,
+    kAudioObjectPropertyScopeOutput, kAudioObjectSystemObject,
+    kAudioOutputUnitProperty_CurrentDevice, kAudioUnitManufacturer_Apple,
+    kAudioUnitProperty_SetRenderCallback, kAudioUnitProperty_StreamFormat, kAudioUnitScope_Global,
+    kAudioUnitScope_Input, kAudioUnitSubType_HALOutput, kAudioUnitType_Output,
+    AURenderCallbackStruct, AudioBufferList, AudioComponentDescription, AudioComponentFindNext,
+    AudioComponentInstanceDispose, AudioComponentInstanceNew, AudioDeviceID,
+    AudioObjectAddPropertyListener, AudioObjectGetPropertyData, AudioObjectGetPropertyDataSize,
+    AudioObjectID, AudioObjectPropertyAddress, AudioObjectPropertyScope,
+    AudioObjectPropertySelector, AudioObjectRemovePropertyListener, AudioObjectSetPropertyData,
+    AudioOutputUnitStart, AudioOutputUnitStop, AudioStreamBasicDescription, AudioTimeStamp,
+    AudioUnit, AudioUnitInitialize, AudioUnitRenderActionFlags, AudioUnitSetProperty,
+    AudioUnitUninitialize, OSStatus,
+};
+use thiserror::Error;
+
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_char, c_void};
+use std::process::exit;
+use std::ptr::{null, null_mut};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How much audio is queued for the render callback.
+const BUFFER_TIME: Duration = Duration::from_millis(250);
+
+// How long a write waits for the device to take audio before it gives up on it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How often a write that waits for room, or a drain, looks at the queue again. The render
+// callback must not block, so it can't wake them.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The data source of built in outputs while headphones are plugged in, 'hdpn'.
+const HEADPHONES: u32 = 0x6864_706e;
+
+#[derive(Debug, Error)]
+enum CoreAudioError {
+    #[error("<CoreAudioSink> {call} Failed With Status {status}")]
+    Call {
+        call: &'static str,
+        status: OSStatus,
+    },
+
+    #[error("<CoreAudioSink> There is No HAL Output Audio Unit")]
+    NoAudioUnit,
+
+    #[error("<CoreAudioSink> Device \"{0}\" is Not Available")]
+    DeviceNotAvailable(String),
+
+    #[error("<CoreAudioSink> {0}")]
+    AmbiguousDevice(#[from] AmbiguousDevice),
+
+    #[error("<CoreAudioSink> The Device Stopped Taking Audio")]
+    Stalled,
+
+    #[error("<CoreAudioSink>")]
+    NotConnected,
+}
+
+impl From<CoreAudioError> for SinkError {
+    fn from(e: CoreAudioError) -> SinkError {
+        use CoreAudioError::*;
+        let es = e.to_string();
+        match e {
+            Call { .. } | NoAudioUnit | DeviceNotAvailable(_) => SinkError::ConnectionRefused(es),
+            AmbiguousDevice(_) => SinkError::InvalidParams(es),
+            Stalled => SinkError::OnWrite(es),
+            NotConnected => SinkError::NotConnected(es),
+        }
+    }
+}
+
+fn check(status: OSStatus, call: &'static str) -> Result<(), CoreAudioError> {
+    if status != kAudioHardwareNoError as OSStatus {
+        Err(CoreAudioError::Call { call, status })
+    } else {
+        Ok(())
+    }
+}
+
+fn address(
+    selector: AudioObjectPropertySelector,
+    scope: AudioObjectPropertyScope,
+) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: scope,
+        mElement: kAudioObjectPropertyElementMaster,
+    }
+}
+
+unsafe fn get_property<T>(
+    object: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+    value: &mut T,
+) -> Result<(), CoreAudioError> {
+    let mut size = mem::size_of::<T>() as u32;
+    check(
+        AudioObjectGetPropertyData(
+            object,
+            address,
+            0,
+            null(),
+            &mut size,
+            value as *mut _ as *mut c_void,
+        ),
+        "AudioObjectGetPropertyData",
+    )
+}
+
+unsafe fn string_property(
+    device: AudioDeviceID,
+    selector: AudioObjectPropertySelector,
+) -> Result<String, CoreAudioError> {
+    let mut string: CFStringRef = null();
+    get_property(
+        device,
+        &address(selector, kAudioObjectPropertyScopeGlobal),
+        &mut string,
+    )?;
+
+    let mut buffer = [0 as c_char; 512];
+    let converted = CFStringGetCString(
+        string,
+        buffer.as_mut_ptr(),
+        buffer.len() as isize,
+        kCFStringEncodingUTF8,
+    ) != 0;
+    CFRelease(string as CFTypeRef);
+
+    if converted {
+        Ok(CStr::from_ptr(buffer.as_ptr())
+            .to_string_lossy()
+            .into_owned())
+    } else {
+        Ok(String::new())
+    }
+}
+
+unsafe fn default_output_device() -> Result<AudioDeviceID, CoreAudioError> {
+    let mut device: AudioDeviceID = 0;
+    get_property(
+        kAudioObjectSystemObject,
+        &address(
+            kAudioHardwarePropertyDefaultOutputDevice,
+            kAudioObjectPropertyScopeGlobal,
+        ),
+        &mut device,
+    )?;
+    Ok(device)
+}
+
+unsafe fn has_output(device: AudioDeviceID) -> bool {
+    let mut size = 0;
+    let status = AudioObjectGetPropertyDataSize(
+        device,
+        &address(kAudioDevicePropertyStreams, kAudioObjectPropertyScopeOutput),
+        0,
+        null(),
+        &mut size,
+    );
+    status == kAudioHardwareNoError as OSStatus && size > 0
+}
+
+// The devices that can play, by UID.
+unsafe fn output_devices() -> Result<Vec<(AudioDeviceID, DeviceInfo)>, CoreAudioError> {
+    let devices_address = address(
+        kAudioHardwarePropertyDevices,
+        kAudioObjectPropertyScopeGlobal,
+    );
+
+    let mut size = 0;
+    check(
+        AudioObjectGetPropertyDataSize(
+            kAudioObjectSystemObject,
+            &devices_address,
+            0,
+            null(),
+            &mut size,
+        ),
+        "AudioObjectGetPropertyDataSize",
+    )?;
+
+    let mut ids: Vec<AudioDeviceID> = vec![0; size as usize / mem::size_of::<AudioDeviceID>()];
+    check(
+        AudioObjectGetPropertyData(
+            kAudioObjectSystemObject,
+            &devices_address,
+            0,
+            null(),
+            &mut size,
+            ids.as_mut_ptr() as *mut c_void,
+        ),
+        "AudioObjectGetPropertyData",
+    )?;
+    ids.truncate(size as usize / mem::size_of::<AudioDeviceID>());
+
+    let default = default_output_device().ok();
+
+    let mut devices = Vec::new();
+    for id in ids.into_iter().filter(|&id| has_output(id)) {
+        devices.push((
+            id,
+            DeviceInfo {
+                id: string_property(id, kAudioDevicePropertyDeviceUID)?,
+                description: string_property(id, kAudioDevicePropertyDeviceNameCFString)?,
+                default: Some(id) == default,
+            },
+        ));
+    }
+    Ok(devices)
+}
+
+// CoreAudio identifies devices by their UID, such as `BuiltInSpeakerDevice`, and describes
+// them with their name, such as `MacBook Pro Speakers`.
+pub fn list_output_devices() -> SinkResult<Vec<DeviceInfo>> {
+    let devices = unsafe { output_devices()? };
+    Ok(devices.into_iter().map(|(_, device)| device).collect())
+}
+
+fn list_outputs() -> Result<(), CoreAudioError> {
+    println!("\n\n\tAvailable CoreAudio device(s):\n");
+    println!("\t------------------------------------------------------\n");
+
+    for (id, device) in unsafe { output_devices()? } {
+        println!("\tDevice:\n\n\t\t{}\n", device.id);
+
+        let default = if device.default { " (default)" } else { "" };
+        println!("\tDescription:\n\n\t\t{}{}\n", device.description, default);
+
+        if let Ok(rate) = unsafe { nominal_rate(id) } {
+            println!("\tSample Rate:\n\n\t\t{} Hz\n", rate);
+        }
+
+        println!("\t------------------------------------------------------\n");
+    }
+
+    Ok(())
+}
+
+unsafe fn nominal_rate(device: AudioDeviceID) -> Result<f64, CoreAudioError> {
+    let mut rate = 0.0f64;
+    get_property(
+        device,
+        &address(
+            kAudioDevicePropertyNominalSampleRate,
+            kAudioObjectPropertyScopeGlobal,
+        ),
+        &mut rate,
+    )?;
+    Ok(rate)
+}
+
+unsafe fn set_nominal_rate(device: AudioDeviceID, rate: f64) -> Result<(), CoreAudioError> {
+    check(
+        AudioObjectSetPropertyData(
+            device,
+            &address(
+                kAudioDevicePropertyNominalSampleRate,
+                kAudioObjectPropertyScopeGlobal,
+            ),
+            0,
+            null(),
+            mem::size_of::<f64>() as u32,
+            &rate as *const _ as *const c_void,
+        ),
+        "AudioObjectSetPropertyData",
+    )
+}
+
+// What the sink and the render callback share. The render callback runs on a real time
+// thread, so nothing in here is locked.
+struct Shared {
+    // Written by the sink, read by the render callback.
+    queue: RingBuffer,
+    // Running out of audio only counts as an underrun while playing, not while draining.
+    playing: AtomicBool,
+    underruns: AtomicU64,
+    default_changed: AtomicBool,
+    // Set when the data source of the device changed, such as when headphones were plugged in.
+    source_changed: AtomicBool,
+}
+
+unsafe extern "C" fn render(
+    shared: *mut c_void,
+    _flags: *mut AudioUnitRenderActionFlags,
+    _time: *const AudioTimeStamp,
+    _bus: u32,
+    _frames: u32,
+    data: *mut AudioBufferList,
+) -> OSStatus {
+    let shared = &*(shared as *const Shared);
+    let buffers = slice::from_raw_parts_mut(
+        (*data).mBuffers.as_mut_ptr(),
+        (*data).mNumberBuffers as usize,
+    );
+
+    let mut short = false;
+    for buffer in buffers {
+        let out = slice::from_raw_parts_mut(buffer.mData as *mut u8, buffer.mDataByteSize as usize);
+        let len = shared.queue.pop(out);
+        if len < out.len() {
+            out[len..].iter_mut().for_each(|sample| *sample = 0);
+            short = true;
+        }
+    }
+
+    if short && shared.playing.load(Ordering::Relaxed) {
+        shared.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    kAudioHardwareNoError as OSStatus
+}
+
+unsafe extern "C" fn default_listener(
+    _object: AudioObjectID,
+    _n_addresses: u32,
+    _addresses: *const AudioObjectPropertyAddress,
+    shared: *mut c_void,
+) -> OSStatus {
+    let shared = &*(shared as *const Shared);
+    shared.default_changed.store(true, Ordering::Relaxed);
+    kAudioHardwareNoError as OSStatus
+}
+
+unsafe extern "C" fn source_listener(
+    _object: AudioObjectID,
+    _n_addresses: u32,
+    _addresses: *const AudioObjectPropertyAddress,
+    shared: *mut c_void,
+) -> OSStatus {
+    let shared = &*(shared as *const Shared);
+    shared.source_changed.store(true, Ordering::Relaxed);
+    kAudioHardwareNoError as OSStatus
+}
+
+fn source_address() -> AudioObjectPropertyAddress {
+    address(
+        kAudioDevicePropertyDataSource,
+        kAudioObjectPropertyScopeOutput,
+    )
+}
+
+// The data source of a device, for devices that have several, such as built in outputs.
+unsafe fn data_source(device: AudioDeviceID) -> Option<u32> {
+    let mut source = 0u32;
+    get_property(device, &source_address(), &mut source).ok()?;
+    Some(source)
+}
+
+fn stream_format(format: AudioFormat) -> AudioStreamBasicDescription {
+    let (bits, flags) = match format {
+        AudioFormat::F32 => (32, kAudioFormatFlagIsFloat),
+        AudioFormat::S32 => (32, kAudioFormatFlagIsSignedInteger),
+        AudioFormat::S16 => (16, kAudioFormatFlagIsSignedInteger),
+        _ => unreachable!(),
+    };
+    let bytes_per_frame = NUM_CHANNELS as u32 * bits / 8;
+
+    AudioStreamBasicDescription {
+        mSampleRate: SAMPLE_RATE as f64,
+        mFormatID: kAudioFormatLinearPCM,
+        mFormatFlags: flags | kAudioFormatFlagIsPacked,
+        mBytesPerPacket: bytes_per_frame,
+        mFramesPerPacket: 1,
+        mBytesPerFrame: bytes_per_frame,
+        mChannelsPerFrame: NUM_CHANNELS as u32,
+        mBitsPerChannel: bits,
+        mReserved: 0,
+    }
+}
+
+// How many bytes of the format make up `BUFFER_TIME`.
+fn capacity(format: AudioFormat) -> usize {
+    let frames = (BUFFER_TIME.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+    frames * stream_format(format).mBytesPerFrame as usize
+}
+
+// A HAL output unit that plays from the queue on one device, which is stopped on drop.
+struct Unit {
+    unit: AudioUnit,
+    device: AudioDeviceID,
+    // The rate to switch the device back to, when it was switched to ours.
+    original_rate: Option<f64>,
+    // The data source playing, if it is watched for changes.
+    source: Option<u32>,
+    shared: Arc<Shared>,
+}
+
+impl Unit {
+    unsafe fn open(
+        device: AudioDeviceID,
+        format: AudioFormat,
+        match_rate: bool,
+        shared: &Arc<Shared>,
+    ) -> Result<Self, CoreAudioError> {
+        let description = AudioComponentDescription {
+            componentType: kAudioUnitType_Output,
+            componentSubType: kAudioUnitSubType_HALOutput,
+            componentManufacturer: kAudioUnitManufacturer_Apple,
+            componentFlags: 0,
+            componentFlagsMask: 0,
+        };
+        let component = AudioComponentFindNext(null_mut(), &description);
+        if component.is_null() {
+            return Err(CoreAudioError::NoAudioUnit);
+        }
+
+        let mut unit: AudioUnit = null_mut();
+        check(
+            AudioComponentInstanceNew(component, &mut unit),
+            "AudioComponentInstanceNew",
+        )?;
+        // From here on, the unit is disposed of on errors.
+        let mut unit = Self {
+            unit,
+            device,
+            original_rate: None,
+            source: None,
+            shared: shared.clone(),
+        };
+
+        if let Some(source) = data_source(device) {
+            let status = AudioObjectAddPropertyListener(
+                device,
+                &source_address(),
+                Some(source_listener),
+                Arc::as_ptr(shared) as *mut c_void,
+            );
+            if status == kAudioHardwareNoError as OSStatus {
+                unit.source = Some(source);
+            } else {
+                debug!(
+                    "Could not observe the data source of the CoreAudio device, status {}",
+                    status
+                );
+            }
+        }
+
+        let device_rate = nominal_rate(device)?;
+        if device_rate != SAMPLE_RATE as f64 {
+            if match_rate {
+                match set_nominal_rate(device, SAMPLE_RATE as f64) {
+                    Ok(()) => {
+                        info!(
+                            "Switched the CoreAudio device from {} Hz to {} Hz",
+                            device_rate, SAMPLE_RATE
+                        );
+                        unit.original_rate = Some(device_rate);
+                    }
+                    Err(e) => warn!(
+                        "Cannot switch the CoreAudio device to {} Hz, resampling to {} Hz: {}",
+                        SAMPLE_RATE, device_rate, e
+                    ),
+                }
+            } else {
+                info!(
+                    "CoreAudio resamples to the {} Hz of the device",
+                    device_rate
+                );
+            }
+        }
+
+        unit.set_property(
+            kAudioOutputUnitProperty_CurrentDevice,
+            kAudioUnitScope_Global,
+            &device,
+        )?;
+        unit.set_property(
+            kAudioUnitProperty_StreamFormat,
+            kAudioUnitScope_Input,
+            &stream_format(format),
+        )?;
+        unit.set_property(
+            kAudioUnitProperty_SetRenderCallback,
+            kAudioUnitScope_Input,
+            &AURenderCallbackStruct {
+                inputProc: Some(render),
+                inputProcRefCon: Arc::as_ptr(shared) as *mut c_void,
+            },
+        )?;
+
+        check(AudioUnitInitialize(unit.unit), "AudioUnitInitialize")?;
+        check(AudioOutputUnitStart(unit.unit), "AudioOutputUnitStart")?;
+
+        Ok(unit)
+    }
+
+    unsafe fn set_property<T>(
+        &self,
+        property: u32,
+        scope: u32,
+        value: &T,
+    ) -> Result<(), CoreAudioError> {
+        check(
+            AudioUnitSetProperty(
+                self.unit,
+                property,
+                scope,
+                0,
+                value as *const _ as *const c_void,
+                mem::size_of::<T>() as u32,
+            ),
+            "AudioUnitSetProperty",
+        )
+    }
+}
+
+impl Drop for Unit {
+    fn drop(&mut self) {
+        unsafe {
+            if self.source.is_some() {
+                AudioObjectRemovePropertyListener(
+                    self.device,
+                    &source_address(),
+                    Some(source_listener),
+                    Arc::as_ptr(&self.shared) as *mut c_void,
+                );
+            }
+
+            AudioOutputUnitStop(self.unit);
+            AudioUnitUninitialize(self.unit);
+            AudioComponentInstanceDispose(self.unit);
+
+            if let Some(rate) = self.original_rate {
+                if let Err(e) = set_nominal_rate(self.device, rate) {
+                    warn!(
+                        "Cannot switch the CoreAudio device back to {} Hz: {}",
+                        rate, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+pub struct CoreAudioSink {
+    device: Option<String>,
+    format: AudioFormat,
+    follow_default: bool,
+    match_rate: bool,
+    shared: Arc<Shared>,
+    unit: Option<Unit>,
+    route_change: Option<RouteChange>,
+}
+
+impl Open for CoreAudioSink {
+    fn open(device: Option<String>, format: AudioFormat) -> Self {
+        Self::open_with_options(device, format, &SinkOptions::default())
+    }
+
+    fn open_with_options(
+        device: Option<String>,
+        format: AudioFormat,
+        options: &SinkOptions,
+    ) -> Self {
+        if device.as_deref() == Some("?") {
+            match list_outputs() {
+                Ok(_) => exit(0),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+
+        let actual_format = match format {
+            AudioFormat::F32 | AudioFormat::S32 | AudioFormat::S16 => format,
+            _ => {
+                warn!("CoreAudio currently does not support {:?} output", format);
+                AudioFormat::F32
+            }
+        };
+
+        info!("Using CoreAudioSink with format: {:?}", actual_format);
+
+        let sink = Self {
+            follow_default: options.coreaudio_follow_default && device.is_none(),
+            device,
+            format: actual_format,
+            match_rate: options.coreaudio_match_rate,
+            shared: Arc::new(Shared {
+                queue: RingBuffer::new(capacity(actual_format)),
+                playing: AtomicBool::new(false),
+                underruns: AtomicU64::new(0),
+                default_changed: AtomicBool::new(false),
+                source_changed: AtomicBool::new(false),
+            }),
+            unit: None,
+            route_change: None,
+        };
+
+        // The default device is watched to report route changes, even if playback doesn't
+        // follow it.
+        if sink.device.is_none() {
+            let status = unsafe {
+                AudioObjectAddPropertyListener(
+                    kAudioObjectSystemObject,
+                    &sink.default_address(),
+                    Some(default_listener),
+                    Arc::as_ptr(&sink.shared) as *mut c_void,
+                )
+            };
+            if status != kAudioHardwareNoError as OSStatus {
+                warn!(
+                    "Could not observe the default CoreAudio device, status {}, playback stays on the device it started on",
+                    status
+                );
+            }
+        }
+
+        sink
+    }
+}
+
+impl Drop for CoreAudioSink {
+    // The listener gets a pointer to the shared state, so it has to be removed first.
+    fn drop(&mut self) {
+        self.unit = None;
+        if self.device.is_none() {
+            unsafe {
+                AudioObjectRemovePropertyListener(
+                    kAudioObjectSystemObject,
+                    &self.default_address(),
+                    Some(default_listener),
+                    Arc::as_ptr(&self.shared) as *mut c_void,
+                );
+            }
+        }
+    }
+}
+
+impl CoreAudioSink {
+    pub const NAME: &'static str = "coreaudio";
+
+    fn default_address(&self) -> AudioObjectPropertyAddress {
+        address(
+            kAudioHardwarePropertyDefaultOutputDevice,
+            kAudioObjectPropertyScopeGlobal,
+        )
+    }
+
+    fn frame_size(&self) -> usize {
+        stream_format(self.format).mBytesPerFrame as usize
+    }
+
+    unsafe fn find_device(&self) -> Result<AudioDeviceID, CoreAudioError> {
+        match &self.device {
+            None => default_output_device(),
+            Some(name) => {
+                let devices = output_devices()?;
+                let infos: Vec<_> = devices.iter().map(|(_, info)| info.clone()).collect();
+                let info = find_device(&infos, name)?
+                    .ok_or_else(|| CoreAudioError::DeviceNotAvailable(name.clone()))?;
+                Ok(devices
+                    .iter()
+                    .find(|(_, device)| device.id == info.id)
+                    .map(|(id, _)| *id)
+                    .unwrap_or_default())
+            }
+        }
+    }
+
+    // Moves to the new default device if it changed, with what is queued for the old one.
+    fn move_to_new_default(&mut self) -> Result<(), CoreAudioError> {
+        if !self.shared.default_changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let current = match &self.unit {
+            Some(unit) => unit.device,
+            None => return Ok(()),
+        };
+        let device = unsafe { default_output_device()? };
+        if device == current {
+            return Ok(());
+        }
+
+        let name = unsafe { string_property(device, kAudioDevicePropertyDeviceNameCFString) }
+            .unwrap_or_default();
+        self.route_change = Some(RouteChange::DefaultDevice);
+        if !self.follow_default {
+            info!(
+                "The default CoreAudio device changed to {}, playback stays where it is",
+                name
+            );
+            return Ok(());
+        }
+
+        info!(
+            "Moving playback to the new default CoreAudio device {}",
+            name
+        );
+
+        self.unit = None;
+        self.unit =
+            Some(unsafe { Unit::open(device, self.format, self.match_rate, &self.shared)? });
+        Ok(())
+    }
+
+    // Notices when the device switched between its data sources, such as from the internal
+    // speakers to the headphones.
+    fn check_source(&mut self) {
+        if !self.shared.source_changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let unit = match self.unit.as_mut() {
+            Some(unit) => unit,
+            None => return,
+        };
+        let source = match unsafe { data_source(unit.device) } {
+            Some(source) => source,
+            None => return,
+        };
+        if unit.source == Some(source) {
+            return;
+        }
+
+        unit.source = Some(source);
+        let change = if source == HEADPHONES {
+            RouteChange::JackPlugged
+        } else {
+            RouteChange::JackUnplugged
+        };
+        debug!(
+            "The CoreAudio device switched its data source: {:?}",
+            change
+        );
+        self.route_change = Some(change);
+    }
+
+    // Waits until the queue has played, for at most as long as it lasts.
+    fn drain(&self) {
+        let deadline = Instant::now() + BUFFER_TIME;
+        while !self.shared.queue.is_empty() && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl Sink for CoreAudioSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.unit.is_none() {
+            let unit = unsafe {
+                let device = self.find_device()?;
+                Unit::open(device, self.format, self.match_rate, &self.shared)?
+            };
+            // A change from before there was a unit is already taken into account.
+            self.shared.default_changed.store(false, Ordering::Relaxed);
+            self.shared.source_changed.store(false, Ordering::Relaxed);
+            self.unit = Some(unit);
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        if self.unit.is_none() {
+            return Err(CoreAudioError::NotConnected.into());
+        }
+
+        self.shared.playing.store(false, Ordering::Relaxed);
+        self.drain();
+        self.unit = None;
+        // The render callback is gone with the unit, so what is left can be dropped.
+        self.shared.queue.clear();
+        Ok(())
+    }
+
+    fn take_underruns(&mut self) -> u64 {
+        self.shared.underruns.swap(0, Ordering::Relaxed)
+    }
+
+    fn take_route_change(&mut self) -> Option<RouteChange> {
+        self.route_change.take()
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        self.unit.as_ref()?;
+        let queued = self.shared.queue.len() / self.frame_size();
+        Some(Duration::from_secs_f64(queued as f64 / SAMPLE_RATE as f64))
+    }
+
+    sink_as_bytes!();
+}
+
+impl SinkAsBytes for CoreAudioSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        self.move_to_new_default()?;
+        self.check_source();
+        if self.unit.is_none() {
+            return Err(CoreAudioError::NotConnected.into());
+        }
+        self.shared.playing.store(true, Ordering::Relaxed);
+
+        let mut data = data;
+        let mut waiting_since = None;
+        while !data.is_empty() {
+            let len = self.shared.queue.push(data);
+            data = &data[len..];
+
+            if len > 0 {
+                waiting_since = None;
+            } else if waiting_since.get_or_insert_with(Instant::now).elapsed() >= WRITE_TIMEOUT {
+                return Err(CoreAudioError::Stalled.into());
+            } else {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        Ok(())
+    }
+}
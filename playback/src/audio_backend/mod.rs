@@ -1,6 +1,8 @@
 use crate::config::AudioFormat;
 use crate::convert::Converter;
+use crate::core::spotify_id::SpotifyId;
 use crate::decoder::AudioPacket;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -17,8 +19,140 @@ pub enum SinkError {
 
 pub type SinkResult<T> = Result<T, SinkError>;
 
+/// Settings for specific backends, which the others ignore.
+#[derive(Clone, Debug, Default)]
+pub struct SinkOptions {
+    /// Length of the ALSA buffer, instead of picking one between 100 and 500 ms.
+    pub alsa_buffer_time: Option<Duration>,
+    /// Number of periods the ALSA buffer is split into, instead of 4 to 10.
+    pub alsa_period_count: Option<u32>,
+    /// Open `iec958` ALSA devices with the IEC958 channel status of the audio that is
+    /// played, for receivers that mute without it.
+    pub alsa_iec958: bool,
+    /// The IEC958 channel status to open `iec958` ALSA devices with instead, such as
+    /// `AES0=0x04,AES1=0x82,AES2=0x00,AES3=0x00`.
+    pub alsa_iec958_aes: Option<String>,
+    /// How much of the most recent audio is written again to a subprocess that was
+    /// restarted, to make up for what it lost when it died.
+    pub subprocess_replay: Duration,
+    /// Open WASAPI devices in exclusive mode, so that the audio reaches them unchanged.
+    /// Shared mode is used when the device can't be opened like that.
+    pub wasapi_exclusive: bool,
+    /// Move playback to the new default CoreAudio device when the default changes, unless a
+    /// device was given.
+    pub coreaudio_follow_default: bool,
+    /// Switch the CoreAudio device to the sample rate of the audio while it is used, so that
+    /// it isn't resampled. Otherwise CoreAudio resamples to whatever rate the device has.
+    pub coreaudio_match_rate: bool,
+    /// Move playback to the new default PulseAudio sink when the default changes, unless a
+    /// device was given.
+    pub pulseaudio_follow_default: bool,
+}
+
+/// The track that is being played, for backends that pass it on.
+#[derive(Clone, Debug)]
+pub struct TrackMetadata {
+    pub track_id: SpotifyId,
+    pub name: String,
+    pub duration_ms: u32,
+}
+
+/// An output device of a backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// What to pass as the device to use it.
+    pub id: String,
+    pub description: String,
+    /// Whether the backend uses this device when none is given.
+    pub default: bool,
+}
+
+#[derive(Debug, Error)]
+#[error("Device \"{name}\" is Ambiguous, it Matches {}", .candidates.join(", "))]
+pub struct AmbiguousDevice {
+    pub name: String,
+    pub candidates: Vec<String>,
+}
+
+impl From<AmbiguousDevice> for SinkError {
+    fn from(e: AmbiguousDevice) -> SinkError {
+        SinkError::InvalidParams(e.to_string())
+    }
+}
+
+/// Finds the device `name` refers to, which is either the id of a device, or a part of the
+/// description of a single device, ignoring case. A description that matches as a whole is
+/// preferred over those that only contain `name`. Returns `None` if no device matches.
+pub fn find_device<'a>(
+    devices: &'a [DeviceInfo],
+    name: &str,
+) -> Result<Option<&'a DeviceInfo>, AmbiguousDevice> {
+    if let Some(device) = devices.iter().find(|device| device.id == name) {
+        return Ok(Some(device));
+    }
+
+    let name_lower = name.to_lowercase();
+    let mut matches: Vec<_> = devices
+        .iter()
+        .filter(|device| device.description.to_lowercase() == name_lower)
+        .collect();
+
+    if matches.is_empty() {
+        matches = devices
+            .iter()
+            .filter(|device| {
+                device.description.to_lowercase().contains(&name_lower)
+                    || device.id.to_lowercase().contains(&name_lower)
+            })
+            .collect();
+    }
+
+    match matches.len() {
+        0 => Ok(None),
+        1 => Ok(Some(matches[0])),
+        _ => Err(AmbiguousDevice {
+            name: name.to_string(),
+            candidates: matches
+                .iter()
+                .map(|device| {
+                    let description = device.description.lines().next().unwrap_or_default();
+                    format!("{} ({})", device.id, description)
+                })
+                .collect(),
+        }),
+    }
+}
+
 pub trait Open {
     fn open(_: Option<String>, format: AudioFormat) -> Self;
+    fn open_with_options(device: Option<String>, format: AudioFormat, _: &SinkOptions) -> Self
+    where
+        Self: Sized,
+    {
+        Self::open(device, format)
+    }
+}
+
+/// How the output of a sink changed while it played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteChange {
+    /// The system default device changed.
+    DefaultDevice,
+    /// The device that was played on went away.
+    DeviceRemoved,
+    /// Headphones or a line out were unplugged from the device.
+    JackUnplugged,
+    /// Headphones or a line out were plugged into the device.
+    JackPlugged,
+}
+
+impl RouteChange {
+    /// Whether the audio now comes out of somewhere the listener may not expect, such as the
+    /// speakers after the headphones were unplugged. Not so for a new default device, which
+    /// the sink either keeps playing past or follows because it was asked to.
+    pub fn is_away(self) -> bool {
+        matches!(self, Self::DeviceRemoved | Self::JackUnplugged)
+    }
 }
 
 pub trait Sink {
@@ -29,16 +163,38 @@ pub trait Sink {
         Ok(())
     }
     fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()>;
+    // Whether the sink can take the raw Ogg stream that is written in passthrough mode.
+    fn supports_passthrough(&self) -> bool {
+        false
+    }
+    // The number of buffer underruns the sink recovered from since the last call.
+    fn take_underruns(&mut self) -> u64 {
+        0
+    }
+    // How the output route changed since the last call, if the sink can tell.
+    fn take_route_change(&mut self) -> Option<RouteChange> {
+        None
+    }
+    // Called when a new track starts playing.
+    fn track_changed(&mut self, _: &TrackMetadata) {}
+    // How long it takes for audio that is written now to be heard, if the sink knows.
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
 }
 
-pub type SinkBuilder = fn(Option<String>, AudioFormat) -> Box<dyn Sink>;
+pub type SinkBuilder = fn(Option<String>, AudioFormat, &SinkOptions) -> Box<dyn Sink>;
 
 pub trait SinkAsBytes {
     fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()>;
 }
 
-fn mk_sink<S: Sink + Open + 'static>(device: Option<String>, format: AudioFormat) -> Box<dyn Sink> {
-    Box::new(S::open(device, format))
+fn mk_sink<S: Sink + Open + 'static>(
+    device: Option<String>,
+    format: AudioFormat,
+    options: &SinkOptions,
+) -> Box<dyn Sink> {
+    Box::new(S::open_with_options(device, format, options))
 }
 
 // reuse code for various backends
@@ -92,6 +248,11 @@ mod pulseaudio;
 #[cfg(feature = "pulseaudio-backend")]
 use self::pulseaudio::PulseAudioSink;
 
+#[cfg(feature = "pipewire-backend")]
+mod pipewire;
+#[cfg(feature = "pipewire-backend")]
+use self::pipewire::PipeWireSink;
+
 #[cfg(feature = "jackaudio-backend")]
 mod jackaudio;
 #[cfg(feature = "jackaudio-backend")]
@@ -112,12 +273,30 @@ mod sdl;
 #[cfg(feature = "sdl-backend")]
 use self::sdl::SdlSink;
 
+#[cfg(all(target_os = "macos", feature = "coreaudio-backend"))]
+mod coreaudio;
+#[cfg(any(test, all(target_os = "macos", feature = "coreaudio-backend")))]
+mod ring_buffer;
+#[cfg(all(target_os = "macos", feature = "coreaudio-backend"))]
+use self::coreaudio::CoreAudioSink;
+
+#[cfg(all(windows, feature = "wasapi-backend"))]
+mod wasapi;
+#[cfg(all(windows, feature = "wasapi-backend"))]
+use self::wasapi::WasapiSink;
+
 mod pipe;
 use self::pipe::StdoutSink;
 
 mod subprocess;
 use self::subprocess::SubprocessSink;
 
+mod wav;
+use self::wav::WavSink;
+
+mod tee;
+pub use self::tee::{TeeOutput, TeeSink};
+
 pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     #[cfg(feature = "rodio-backend")]
     (RodioSink::NAME, rodio::mk_rodio), // default goes first
@@ -127,6 +306,8 @@ pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     (PortAudioSink::NAME, mk_sink::<PortAudioSink>),
     #[cfg(feature = "pulseaudio-backend")]
     (PulseAudioSink::NAME, mk_sink::<PulseAudioSink>),
+    #[cfg(feature = "pipewire-backend")]
+    (PipeWireSink::NAME, mk_sink::<PipeWireSink>),
     #[cfg(feature = "jackaudio-backend")]
     (JackSink::NAME, mk_sink::<JackSink>),
     #[cfg(feature = "gstreamer-backend")]
@@ -135,10 +316,41 @@ pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     ("rodiojack", rodio::mk_rodiojack),
     #[cfg(feature = "sdl-backend")]
     (SdlSink::NAME, mk_sink::<SdlSink>),
+    #[cfg(all(target_os = "macos", feature = "coreaudio-backend"))]
+    (CoreAudioSink::NAME, mk_sink::<CoreAudioSink>),
+    #[cfg(all(windows, feature = "wasapi-backend"))]
+    (WasapiSink::NAME, mk_sink::<WasapiSink>),
     (StdoutSink::NAME, mk_sink::<StdoutSink>),
     (SubprocessSink::NAME, mk_sink::<SubprocessSink>),
+    (WavSink::NAME, mk_sink::<WavSink>),
 ];
 
+/// Lists the output devices of `backend`, without opening any of them. Backends that
+/// can't list their devices return an empty list.
+pub fn list_output_devices(backend: &str) -> SinkResult<Vec<DeviceInfo>> {
+    match backend {
+        #[cfg(feature = "alsa-backend")]
+        AlsaSink::NAME => alsa::list_output_devices(),
+        #[cfg(feature = "rodio-backend")]
+        RodioSink::NAME => rodio::list_output_devices(&cpal::default_host()),
+        #[cfg(feature = "rodiojack-backend")]
+        "rodiojack" => {
+            let host = cpal::host_from_id(cpal::HostId::Jack)
+                .map_err(|e| SinkError::ConnectionRefused(e.to_string()))?;
+            rodio::list_output_devices(&host)
+        }
+        #[cfg(all(target_os = "macos", feature = "coreaudio-backend"))]
+        CoreAudioSink::NAME => coreaudio::list_output_devices(),
+        #[cfg(all(windows, feature = "wasapi-backend"))]
+        WasapiSink::NAME => wasapi::list_output_devices(),
+        _ if BACKENDS.iter().any(|(name, _)| *name == backend) => Ok(Vec::new()),
+        _ => Err(SinkError::InvalidParams(format!(
+            "Unknown Backend {}",
+            backend
+        ))),
+    }
+}
+
 pub fn find(name: Option<String>) -> Option<SinkBuilder> {
     if let Some(name) = name {
         BACKENDS
@@ -149,3 +361,38 @@ pub fn find(name: Option<String>) -> Option<SinkBuilder> {
         BACKENDS.first().map(|backend| backend.1)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn device(id: &str, description: &str) -> DeviceInfo {
+        DeviceInfo {
+            id: id.to_string(),
+            description: description.to_string(),
+            default: false,
+        }
+    }
+
+    #[test]
+    fn find_device_by_description() {
+        let devices = [
+            device("hw:CARD=PCH,DEV=0", "HDA Intel PCH, ALC892 Analog"),
+            device("hw:CARD=DAC,DEV=0", "USB Audio DAC, USB Audio"),
+            device("hw:CARD=DAC2,DEV=0", "USB Audio DAC 2, USB Audio"),
+            device("usb", "USB Audio DAC"),
+        ];
+
+        let find = |name| find_device(&devices, name).map(|d| d.map(|d| d.id.as_str()));
+        assert_eq!(
+            find("hw:CARD=DAC,DEV=0").unwrap(),
+            Some("hw:CARD=DAC,DEV=0")
+        );
+        assert_eq!(find("usb audio dac").unwrap(), Some("usb"));
+        assert_eq!(find("alc892").unwrap(), Some("hw:CARD=PCH,DEV=0"));
+        assert_eq!(find("HDMI").unwrap(), None);
+
+        let e = find("USB Audio").unwrap_err();
+        assert_eq!(e.candidates.len(), 3);
+    }
+}
@@ -89,6 +89,10 @@ impl Sink for StdoutSink {
         Ok(())
     }
 
+    fn supports_passthrough(&self) -> bool {
+        true
+    }
+
     sink_as_bytes!();
 }
 
@@ -0,0 +1,489 @@
+use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use crate::config::AudioFormat;
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+use pipewire as pw;
+use pw::properties::Properties;
+use pw::spa::param::audio::{AudioFormat as SpaAudioFormat, AudioInfoRaw};
+use pw::spa::pod::{serialize::PodSerializer, Object, Pod, Value};
+use pw::stream::{Stream, StreamFlags, StreamState};
+use std::collections::VecDeque;
+use std::env;
+use std::io::Cursor;
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+// Half a second of audio is buffered ahead of the graph.
+const BUFFER_FRAMES: usize = SAMPLE_RATE as usize / 2;
+
+// The quantum we ask the graph for. The graph may run at a different quantum (and change
+// it at any time), which only changes how much audio each process cycle takes.
+const NODE_LATENCY_FRAMES: u32 = 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A stream that stays paused by the graph this long while we have audio for it has been
+// corked, for example by a session manager policy.
+const CORK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+enum PipeWireError {
+    #[error("<PipeWireSink> Failed to Connect to PipeWire, {0}")]
+    ConnectionRefused(String),
+
+    #[error("<PipeWireSink> Stream Corked by PipeWire")]
+    Corked,
+
+    #[error("<PipeWireSink> Stream Disconnected, {0}")]
+    Disconnected(String),
+
+    #[error("<PipeWireSink>")]
+    NotConnected,
+}
+
+impl From<PipeWireError> for SinkError {
+    fn from(e: PipeWireError) -> SinkError {
+        use PipeWireError::*;
+        let es = e.to_string();
+        match e {
+            ConnectionRefused(_) => SinkError::ConnectionRefused(es),
+            Corked | Disconnected(_) | NotConnected => SinkError::NotConnected(es),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum StreamStatus {
+    Connecting,
+    // Connected, but not (or no longer) scheduled by the graph.
+    Paused,
+    Streaming,
+    Disconnected(String),
+}
+
+// The state shared between the player thread and the PipeWire main loop thread.
+struct Shared {
+    samples: VecDeque<u8>,
+    status: StreamStatus,
+}
+
+#[derive(Clone)]
+struct SharedStream {
+    shared: Arc<Mutex<Shared>>,
+    changed: Arc<Condvar>,
+}
+
+impl SharedStream {
+    fn new() -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                samples: VecDeque::new(),
+                status: StreamStatus::Connecting,
+            })),
+            changed: Arc::new(Condvar::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+
+    fn wait_timeout_while<'a, F>(
+        &self,
+        guard: MutexGuard<'a, Shared>,
+        timeout: Duration,
+        condition: F,
+    ) -> MutexGuard<'a, Shared>
+    where
+        F: FnMut(&mut Shared) -> bool,
+    {
+        self.changed
+            .wait_timeout_while(guard, timeout, condition)
+            .unwrap()
+            .0
+    }
+
+    fn notify(&self) {
+        self.changed.notify_all();
+    }
+
+    fn set_status(&self, status: StreamStatus) {
+        self.lock().status = status;
+        self.notify();
+    }
+}
+
+struct StreamSettings {
+    device: Option<String>,
+    app_name: String,
+    icon_name: String,
+    format: AudioFormat,
+}
+
+struct Connection {
+    shared: SharedStream,
+    quit: pw::channel::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Connection {
+    fn close(self) {
+        let _ = self.quit.send(());
+        let _ = self.thread.join();
+    }
+}
+
+pub struct PipeWireSink {
+    connection: Option<Connection>,
+    settings: Arc<StreamSettings>,
+    format: AudioFormat,
+}
+
+impl Open for PipeWireSink {
+    fn open(device: Option<String>, format: AudioFormat) -> Self {
+        let app_name =
+            env::var("PIPEWIRE_PROP_application.name").unwrap_or_else(|_| "librespot".to_string());
+        let icon_name = env::var("PIPEWIRE_PROP_application.icon-name")
+            .unwrap_or_else(|_| "audio-x-generic".to_string());
+
+        info!("Using PipeWireSink with format: {:?}", format);
+
+        Self {
+            connection: None,
+            settings: Arc::new(StreamSettings {
+                device,
+                app_name,
+                icon_name,
+                format,
+            }),
+            format,
+        }
+    }
+}
+
+impl Sink for PipeWireSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.connection.is_none() {
+            let connection = self.connect()?;
+
+            // Wait until the stream is linked to a node, so that a missing target node is
+            // reported here rather than on the first write.
+            let status = {
+                let shared = connection.shared.lock();
+                let shared =
+                    connection
+                        .shared
+                        .wait_timeout_while(shared, CONNECT_TIMEOUT, |shared| {
+                            shared.status == StreamStatus::Connecting
+                        });
+                shared.status.clone()
+            };
+
+            match status {
+                StreamStatus::Paused | StreamStatus::Streaming => {
+                    self.connection = Some(connection);
+                }
+                StreamStatus::Connecting => {
+                    connection.close();
+                    return Err(PipeWireError::ConnectionRefused(
+                        "Timed out connecting the stream".to_string(),
+                    )
+                    .into());
+                }
+                StreamStatus::Disconnected(e) => {
+                    connection.close();
+                    return Err(PipeWireError::ConnectionRefused(e).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        let connection = self.connection.take().ok_or(PipeWireError::NotConnected)?;
+
+        // Let the graph play out what is buffered, unless it stopped taking audio.
+        {
+            let shared = connection.shared.lock();
+            let buffered_ms = shared.samples.len() as u64 * 1000 / self.bytes_per_second();
+            let _ = connection.shared.wait_timeout_while(
+                shared,
+                Duration::from_millis(buffered_ms) + CORK_TIMEOUT,
+                |shared| !shared.samples.is_empty() && shared.status == StreamStatus::Streaming,
+            );
+        }
+
+        connection.close();
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        // What we buffer ahead of the graph, plus the quantum the graph is processing. The
+        // graph may run at a different quantum, so the latter is an estimate.
+        let connection = self.connection.as_ref()?;
+        let buffered_us =
+            connection.shared.lock().samples.len() as u64 * 1_000_000 / self.bytes_per_second();
+        let quantum_us = NODE_LATENCY_FRAMES as u64 * 1_000_000 / SAMPLE_RATE as u64;
+
+        Some(Duration::from_micros(buffered_us + quantum_us))
+    }
+
+    sink_as_bytes!();
+}
+
+impl SinkAsBytes for PipeWireSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or(PipeWireError::NotConnected)?;
+        let capacity = BUFFER_FRAMES * self.bytes_per_frame();
+
+        let mut data = data;
+        while !data.is_empty() {
+            let shared = connection.shared.lock();
+            let mut shared = connection
+                .shared
+                .wait_timeout_while(shared, CORK_TIMEOUT, |shared| {
+                    shared.samples.len() >= capacity
+                        && !matches!(shared.status, StreamStatus::Disconnected(_))
+                });
+
+            if let StreamStatus::Disconnected(ref e) = shared.status {
+                return Err(PipeWireError::Disconnected(e.clone()).into());
+            }
+
+            if shared.samples.len() >= capacity {
+                // The graph took nothing for a while: the stream is corked.
+                return Err(PipeWireError::Corked.into());
+            }
+
+            let len = data.len().min(capacity - shared.samples.len());
+            shared.samples.extend(&data[..len]);
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+}
+
+impl PipeWireSink {
+    pub const NAME: &'static str = "pipewire";
+
+    fn bytes_per_frame(&self) -> usize {
+        self.format.size() * NUM_CHANNELS as usize
+    }
+
+    fn bytes_per_second(&self) -> u64 {
+        (self.bytes_per_frame() * SAMPLE_RATE as usize) as u64
+    }
+
+    fn connect(&self) -> Result<Connection, PipeWireError> {
+        let shared = SharedStream::new();
+        let (quit_tx, quit_rx) = pw::channel::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let thread = {
+            let shared = shared.clone();
+            let settings = self.settings.clone();
+            thread::Builder::new()
+                .name("pipewire".to_string())
+                .spawn(move || {
+                    if let Err(e) = run_stream(&settings, &shared, quit_rx, &ready_tx) {
+                        let e = e.to_string();
+                        let _ = ready_tx.send(Err(e.clone()));
+                        shared.set_status(StreamStatus::Disconnected(e));
+                    }
+                })
+                .map_err(|e| PipeWireError::ConnectionRefused(e.to_string()))?
+        };
+
+        let connection = Connection {
+            shared,
+            quit: quit_tx,
+            thread,
+        };
+
+        match ready_rx.recv_timeout(CONNECT_TIMEOUT) {
+            Ok(Ok(())) => Ok(connection),
+            Ok(Err(e)) => {
+                connection.close();
+                Err(PipeWireError::ConnectionRefused(e))
+            }
+            Err(_) => {
+                connection.close();
+                Err(PipeWireError::ConnectionRefused(
+                    "Timed out connecting to PipeWire".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+// Runs the PipeWire main loop with our playback stream until `quit` receives a message.
+fn run_stream(
+    settings: &StreamSettings,
+    shared: &SharedStream,
+    quit: pw::channel::Receiver<()>,
+    ready: &mpsc::Sender<Result<(), String>>,
+) -> Result<(), pw::Error> {
+    pw::init();
+
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+
+    let mut properties = Properties::new();
+    properties.insert(*pw::keys::MEDIA_TYPE, "Audio");
+    properties.insert(*pw::keys::MEDIA_CATEGORY, "Playback");
+    properties.insert(*pw::keys::MEDIA_ROLE, "Music");
+    properties.insert(*pw::keys::APP_NAME, settings.app_name.as_str());
+    properties.insert(*pw::keys::APP_ICON_NAME, settings.icon_name.as_str());
+    properties.insert(*pw::keys::NODE_NAME, "librespot");
+    properties.insert(
+        *pw::keys::NODE_LATENCY,
+        format!("{}/{}", NODE_LATENCY_FRAMES, SAMPLE_RATE),
+    );
+    if let Some(ref device) = settings.device {
+        properties.insert(*pw::keys::TARGET_OBJECT, device.as_str());
+        // Don't let the session manager move us to another node when the target goes
+        // away, so that the player notices and can wait for it to come back.
+        properties.insert(*pw::keys::NODE_DONT_RECONNECT, "true");
+    }
+
+    let stream = Stream::new(&core, "librespot", properties)?;
+
+    let bytes_per_frame = settings.format.size() * NUM_CHANNELS as usize;
+
+    let _listener = stream
+        .add_local_listener_with_user_data(shared.clone())
+        .state_changed(|_, shared, old, new| {
+            debug!("PipeWire stream state changed from {:?} to {:?}", old, new);
+            let status = match new {
+                StreamState::Connecting => StreamStatus::Connecting,
+                StreamState::Paused => StreamStatus::Paused,
+                StreamState::Streaming => StreamStatus::Streaming,
+                StreamState::Unconnected => {
+                    StreamStatus::Disconnected("Target node is gone".to_string())
+                }
+                StreamState::Error(e) => StreamStatus::Disconnected(e),
+            };
+            shared.set_status(status);
+        })
+        .param_changed(|_, _, id, param| {
+            let param = match param {
+                Some(param) if id == pw::spa::param::ParamType::Format.as_raw() => param,
+                _ => return,
+            };
+
+            // The stream's adapter converts our format and rate to whatever the graph
+            // runs at, so a rate change of the graph only needs to be noted.
+            let mut info = AudioInfoRaw::new();
+            if info.parse(param).is_ok() {
+                debug!(
+                    "PipeWire negotiated {:?} at {} Hz with {} channels",
+                    info.format(),
+                    info.rate(),
+                    info.channels()
+                );
+            }
+        })
+        .process(move |stream, shared| {
+            let mut buffer = match stream.dequeue_buffer() {
+                Some(buffer) => buffer,
+                None => return,
+            };
+
+            // The graph tells us how many frames it wants for this cycle, which follows its
+            // current quantum and rate.
+            let requested = buffer.requested() as usize;
+
+            let datas = buffer.datas_mut();
+            let data = match datas.first_mut() {
+                Some(data) => data,
+                None => return,
+            };
+
+            let written = match data.data() {
+                Some(slice) => {
+                    let mut frames = slice.len() / bytes_per_frame;
+                    if requested > 0 {
+                        frames = frames.min(requested);
+                    }
+                    let len = frames * bytes_per_frame;
+
+                    let mut guard = shared.lock();
+                    let available = guard.samples.len().min(len);
+                    for (dst, src) in slice[..available]
+                        .iter_mut()
+                        .zip(guard.samples.drain(..available))
+                    {
+                        *dst = src;
+                    }
+                    drop(guard);
+                    shared.notify();
+
+                    // Fill an underrun with silence rather than stalling the graph.
+                    slice[available..len].iter_mut().for_each(|byte| *byte = 0);
+                    len
+                }
+                None => 0,
+            };
+
+            let chunk = data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = bytes_per_frame as _;
+            *chunk.size_mut() = written as _;
+        })
+        .register()?;
+
+    let format = match settings.format {
+        AudioFormat::F64 => SpaAudioFormat::F64LE,
+        AudioFormat::F32 => SpaAudioFormat::F32LE,
+        AudioFormat::S32 => SpaAudioFormat::S32LE,
+        AudioFormat::S24 => SpaAudioFormat::S24_32LE,
+        AudioFormat::S24_3 => SpaAudioFormat::S24LE,
+        AudioFormat::S16 => SpaAudioFormat::S16LE,
+    };
+
+    let mut info = AudioInfoRaw::new();
+    info.set_format(format);
+    info.set_rate(SAMPLE_RATE);
+    info.set_channels(NUM_CHANNELS as u32);
+
+    let values: Vec<u8> = PodSerializer::serialize(
+        Cursor::new(Vec::new()),
+        &Value::Object(Object {
+            type_: pw::spa::sys::SPA_TYPE_OBJECT_Format,
+            id: pw::spa::sys::SPA_PARAM_EnumFormat,
+            properties: info.into(),
+        }),
+    )
+    .map_err(|_| pw::Error::CreationFailed)?
+    .0
+    .into_inner();
+
+    let mut params = [Pod::from_bytes(&values).ok_or(pw::Error::CreationFailed)?];
+
+    stream.connect(
+        pw::spa::utils::Direction::Output,
+        None,
+        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+        &mut params,
+    )?;
+
+    let _quit = quit.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        move |_| mainloop.quit()
+    });
+
+    let _ = ready.send(Ok(()));
+    mainloop.run();
+
+    let _ = stream.disconnect();
+    Ok(())
+}
@@ -1,13 +1,27 @@
-use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use super::{Open, RouteChange, Sink, SinkAsBytes, SinkError, SinkOptions, SinkResult};
 use crate::config::AudioFormat;
 use crate::convert::Converter;
 use crate::decoder::AudioPacket;
 use crate::{NUM_CHANNELS, SAMPLE_RATE};
+use libpulse_binding::context::subscribe::{Facility, InterestMaskSet};
+use libpulse_binding::context::{Context, FlagSet as ContextFlagSet, State as ContextState};
+use libpulse_binding::mainloop::standard::Mainloop;
+use libpulse_binding::time::MicroSeconds;
 use libpulse_binding::{self as pulse, error::PAErr, stream::Direction};
 use libpulse_simple_binding::Simple;
+use std::cell::Cell;
 use std::env;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
+// How long the thread that watches the server waits for events at most, before it looks
+// whether the sink is still around.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Error)]
 enum PulseError {
     #[error("<PulseAudioSink> Unsupported Pulseaudio Sample Spec, Format {pulse_format:?} ({format:?}), Channels {channels}, Rate {rate}")]
@@ -44,16 +58,133 @@ impl From<PulseError> for SinkError {
     }
 }
 
+// The default sink of the server, which a thread of its own keeps up to date while the
+// `PulseAudioSink` is around.
+struct DefaultSinkWatcher {
+    name: Mutex<Option<String>>,
+    changed: AtomicBool,
+}
+
+impl DefaultSinkWatcher {
+    fn spawn(app_name: &str) -> Option<Arc<Self>> {
+        let watcher = Arc::new(Self {
+            name: Mutex::new(None),
+            changed: AtomicBool::new(false),
+        });
+
+        let shared = watcher.clone();
+        let app_name = app_name.to_string();
+        let spawned = thread::Builder::new()
+            .name("pulseaudio-watch".to_string())
+            .spawn(move || {
+                if let Err(e) = shared.run(&app_name) {
+                    warn!("<PulseAudioSink> Cannot watch the default sink: {}", e);
+                }
+            });
+
+        match spawned {
+            Ok(_) => Some(watcher),
+            Err(e) => {
+                warn!("<PulseAudioSink> Cannot watch the default sink: {}", e);
+                None
+            }
+        }
+    }
+
+    fn run(self: &Arc<Self>, app_name: &str) -> Result<(), String> {
+        let mut mainloop = Mainloop::new().ok_or("cannot create main loop")?;
+        let mut context = Context::new(&mainloop, app_name).ok_or("cannot create context")?;
+        context
+            .connect(None, ContextFlagSet::NOAUTOSPAWN, None)
+            .map_err(|e| format!("{}", e))?;
+
+        // The server, and with it the default sink, changed. It is asked for once connected.
+        let server_changed = Rc::new(Cell::new(true));
+        let changed = server_changed.clone();
+        context.set_subscribe_callback(Some(Box::new(move |facility, _, _| {
+            if facility == Some(Facility::Server) {
+                changed.set(true);
+            }
+        })));
+
+        let mut subscribed = false;
+        // Until the sink is dropped.
+        while Arc::strong_count(self) > 1 {
+            // Like a blocking `iterate`, which nothing would wake up once the sink is gone.
+            mainloop
+                .prepare(Some(MicroSeconds(WATCH_TIMEOUT.as_micros() as u64)))
+                .and_then(|_| mainloop.poll())
+                .and_then(|_| mainloop.dispatch())
+                .map_err(|e| format!("The main loop stopped: {}", e))?;
+
+            match context.get_state() {
+                ContextState::Ready => {
+                    if !subscribed {
+                        context.subscribe(InterestMaskSet::SERVER, |_| ());
+                        subscribed = true;
+                    }
+
+                    if server_changed.replace(false) {
+                        let watcher = self.clone();
+                        context.introspect().get_server_info(move |info| {
+                            let name = info.default_sink_name.as_ref().map(|name| name.to_string());
+                            watcher.update(name);
+                        });
+                    }
+                }
+                ContextState::Failed | ContextState::Terminated => {
+                    return Err("The connection to the server was lost".to_string());
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, name: Option<String>) {
+        let mut current = self.name.lock().unwrap();
+        if *current != name {
+            // The first one that is found isn't a change.
+            if current.is_some() {
+                self.changed.store(true, Ordering::Relaxed);
+            }
+            *current = name;
+        }
+    }
+
+    // The new default sink, if it changed since the last call.
+    fn take_change(&self) -> Option<Option<String>> {
+        if self.changed.swap(false, Ordering::Relaxed) {
+            Some(self.name.lock().unwrap().clone())
+        } else {
+            None
+        }
+    }
+}
+
 pub struct PulseAudioSink {
     sink: Option<Simple>,
     device: Option<String>,
     app_name: String,
     stream_desc: String,
     format: AudioFormat,
+    // Only without a device, whose stream goes to the default sink.
+    default_sink: Option<Arc<DefaultSinkWatcher>>,
+    follow_default: bool,
+    route_change: Option<RouteChange>,
 }
 
 impl Open for PulseAudioSink {
     fn open(device: Option<String>, format: AudioFormat) -> Self {
+        Self::open_with_options(device, format, &SinkOptions::default())
+    }
+
+    fn open_with_options(
+        device: Option<String>,
+        format: AudioFormat,
+        options: &SinkOptions,
+    ) -> Self {
         let app_name = env::var("PULSE_PROP_application.name").unwrap_or_default();
         let stream_desc = env::var("PULSE_PROP_stream.description").unwrap_or_default();
 
@@ -66,12 +197,21 @@ impl Open for PulseAudioSink {
 
         info!("Using PulseAudioSink with format: {:?}", actual_format);
 
+        let default_sink = if device.is_none() {
+            DefaultSinkWatcher::spawn(&app_name)
+        } else {
+            None
+        };
+
         Self {
             sink: None,
             device,
             app_name,
             stream_desc,
             format: actual_format,
+            default_sink,
+            follow_default: options.pulseaudio_follow_default,
+            route_change: None,
         }
     }
 }
@@ -79,46 +219,8 @@ impl Open for PulseAudioSink {
 impl Sink for PulseAudioSink {
     fn start(&mut self) -> SinkResult<()> {
         if self.sink.is_none() {
-            // PulseAudio calls S24 and S24_3 different from the rest of the world
-            let pulse_format = match self.format {
-                AudioFormat::F32 => pulse::sample::Format::FLOAT32NE,
-                AudioFormat::S32 => pulse::sample::Format::S32NE,
-                AudioFormat::S24 => pulse::sample::Format::S24_32NE,
-                AudioFormat::S24_3 => pulse::sample::Format::S24NE,
-                AudioFormat::S16 => pulse::sample::Format::S16NE,
-                _ => unreachable!(),
-            };
-
-            let sample_spec = pulse::sample::Spec {
-                format: pulse_format,
-                channels: NUM_CHANNELS,
-                rate: SAMPLE_RATE,
-            };
-
-            if !sample_spec.is_valid() {
-                let pulse_error = PulseError::InvalidSampleSpec {
-                    pulse_format,
-                    format: self.format,
-                    channels: NUM_CHANNELS,
-                    rate: SAMPLE_RATE,
-                };
-
-                return Err(SinkError::from(pulse_error));
-            }
-
-            let sink = Simple::new(
-                None,                   // Use the default server.
-                &self.app_name,         // Our application's name.
-                Direction::Playback,    // Direction.
-                self.device.as_deref(), // Our device (sink) name.
-                &self.stream_desc,      // Description of our stream.
-                &sample_spec,           // Our sample format.
-                None,                   // Use default channel map.
-                None,                   // Use default buffering attributes.
-            )
-            .map_err(PulseError::ConnectionRefused)?;
-
-            self.sink = Some(sink);
+            let device = self.device.clone();
+            self.connect(device.as_deref())?;
         }
 
         Ok(())
@@ -131,14 +233,35 @@ impl Sink for PulseAudioSink {
         Ok(())
     }
 
+    fn latency(&self) -> Option<Duration> {
+        let latency = self.sink.as_ref()?.get_latency().ok()?;
+        Some(Duration::from_micros(latency.0))
+    }
+
+    fn take_route_change(&mut self) -> Option<RouteChange> {
+        self.route_change.take()
+    }
+
     sink_as_bytes!();
 }
 
 impl SinkAsBytes for PulseAudioSink {
     fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        self.move_to_new_default()?;
+
         let sink = self.sink.as_mut().ok_or(PulseError::NotConnected)?;
+        if let Err(e) = sink.write(data) {
+            // Like when the server restarted. The stream is opened again and gets what failed
+            // to be written, so that nothing is skipped.
+            warn!("<PulseAudioSink> Writing failed: {}, connecting again", e);
+            self.sink = None;
+            let device = self.device.clone();
+            self.connect(device.as_deref())
+                .map_err(|_| PulseError::OnWrite(e))?;
 
-        sink.write(data).map_err(PulseError::OnWrite)?;
+            let sink = self.sink.as_mut().ok_or(PulseError::NotConnected)?;
+            sink.write(data).map_err(PulseError::OnWrite)?;
+        }
 
         Ok(())
     }
@@ -146,4 +269,78 @@ impl SinkAsBytes for PulseAudioSink {
 
 impl PulseAudioSink {
     pub const NAME: &'static str = "pulseaudio";
+
+    fn connect(&mut self, device: Option<&str>) -> SinkResult<()> {
+        // PulseAudio calls S24 and S24_3 different from the rest of the world
+        let pulse_format = match self.format {
+            AudioFormat::F32 => pulse::sample::Format::FLOAT32NE,
+            AudioFormat::S32 => pulse::sample::Format::S32NE,
+            AudioFormat::S24 => pulse::sample::Format::S24_32NE,
+            AudioFormat::S24_3 => pulse::sample::Format::S24NE,
+            AudioFormat::S16 => pulse::sample::Format::S16NE,
+            _ => unreachable!(),
+        };
+
+        let sample_spec = pulse::sample::Spec {
+            format: pulse_format,
+            channels: NUM_CHANNELS,
+            rate: SAMPLE_RATE,
+        };
+
+        if !sample_spec.is_valid() {
+            let pulse_error = PulseError::InvalidSampleSpec {
+                pulse_format,
+                format: self.format,
+                channels: NUM_CHANNELS,
+                rate: SAMPLE_RATE,
+            };
+
+            return Err(SinkError::from(pulse_error));
+        }
+
+        let sink = Simple::new(
+            None,                // Use the default server.
+            &self.app_name,      // Our application's name.
+            Direction::Playback, // Direction.
+            device,              // Our device (sink) name.
+            &self.stream_desc,   // Description of our stream.
+            &sample_spec,        // Our sample format.
+            None,                // Use default channel map.
+            None,                // Use default buffering attributes.
+        )
+        .map_err(PulseError::ConnectionRefused)?;
+
+        self.sink = Some(sink);
+        Ok(())
+    }
+
+    // The stream is only moved when asked to, as newer servers move the streams that weren't
+    // opened on a device of their own anyway. Moving it explicitly also helps when the server
+    // would restore it to the sink it played on before.
+    fn move_to_new_default(&mut self) -> SinkResult<()> {
+        let name = match self.default_sink.as_ref().and_then(|w| w.take_change()) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let name_str = name.as_deref().unwrap_or("none");
+
+        self.route_change = Some(RouteChange::DefaultDevice);
+        if !self.follow_default || self.sink.is_none() {
+            info!("<PulseAudioSink> The default sink changed to {}", name_str);
+            return Ok(());
+        }
+
+        info!(
+            "<PulseAudioSink> Moving playback to the new default sink {}",
+            name_str
+        );
+
+        // What was written already is played out first, so that the position stays the same.
+        if let Some(sink) = self.sink.take() {
+            if let Err(e) = sink.drain() {
+                debug!("<PulseAudioSink> Cannot drain before moving: {}", e);
+            }
+        }
+        self.connect(name.as_deref())
+    }
 }
@@ -0,0 +1,138 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A queue of bytes between one producer and one consumer that neither of them locks, so
+// that it can be read from a real time audio callback.
+pub struct RingBuffer {
+    buffer: Box<[UnsafeCell<u8>]>,
+    // How many bytes were ever pushed and popped. What lies between them is queued.
+    pushed: AtomicUsize,
+    popped: AtomicUsize,
+}
+
+// The producer only writes bytes that aren't queued, and the consumer only reads queued
+// ones, which the counters hand over between them.
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+            pushed: AtomicUsize::new(0),
+            popped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn len(&self) -> usize {
+        let popped = self.popped.load(Ordering::Acquire);
+        self.pushed.load(Ordering::Acquire).wrapping_sub(popped)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Queues as much of `data` as there is room for and returns how much that was. Must only
+    // be called by the producer.
+    pub fn push(&self, data: &[u8]) -> usize {
+        let pushed = self.pushed.load(Ordering::Relaxed);
+        let queued = pushed.wrapping_sub(self.popped.load(Ordering::Acquire));
+        let len = data.len().min(self.capacity() - queued);
+
+        for (i, byte) in data[..len].iter().enumerate() {
+            let index = pushed.wrapping_add(i) % self.capacity();
+            unsafe { *self.buffer[index].get() = *byte };
+        }
+        self.pushed
+            .store(pushed.wrapping_add(len), Ordering::Release);
+
+        len
+    }
+
+    // Fills `out` with as much as is queued and returns how much that was. Must only be
+    // called by the consumer.
+    pub fn pop(&self, out: &mut [u8]) -> usize {
+        let popped = self.popped.load(Ordering::Relaxed);
+        let queued = self.pushed.load(Ordering::Acquire).wrapping_sub(popped);
+        let len = out.len().min(queued);
+
+        for (i, byte) in out[..len].iter_mut().enumerate() {
+            let index = popped.wrapping_add(i) % self.capacity();
+            *byte = unsafe { *self.buffer[index].get() };
+        }
+        self.popped
+            .store(popped.wrapping_add(len), Ordering::Release);
+
+        len
+    }
+
+    // Drops what is queued. Takes the part of the consumer, so it must only be called while
+    // nothing pops.
+    pub fn clear(&self) {
+        let pushed = self.pushed.load(Ordering::Acquire);
+        self.popped.store(pushed, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn bytes_come_out_in_order_across_the_end() {
+        let ring = RingBuffer::new(4);
+        let mut out = [0; 4];
+
+        assert_eq!(ring.push(&[1, 2, 3]), 3);
+        assert_eq!(ring.pop(&mut out[..2]), 2);
+        assert_eq!(out[..2], [1, 2]);
+
+        // Only three more fit, wrapping around the end of the buffer.
+        assert_eq!(ring.push(&[4, 5, 6, 7]), 3);
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.push(&[8]), 0);
+
+        assert_eq!(ring.pop(&mut out), 4);
+        assert_eq!(out, [3, 4, 5, 6]);
+        assert!(ring.is_empty());
+
+        ring.push(&[9, 10]);
+        ring.clear();
+        assert_eq!(ring.pop(&mut out), 0);
+    }
+
+    #[test]
+    fn a_consumer_on_another_thread_gets_everything() {
+        let ring = Arc::new(RingBuffer::new(7));
+        let data = (0..10_000u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        let consumer = {
+            let ring = ring.clone();
+            let expected = data.len();
+            thread::spawn(move || {
+                let mut received = Vec::with_capacity(expected);
+                let mut out = [0; 5];
+                while received.len() < expected {
+                    let len = ring.pop(&mut out);
+                    received.extend_from_slice(&out[..len]);
+                    thread::yield_now();
+                }
+                received
+            })
+        };
+
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            rest = &rest[ring.push(rest)..];
+            thread::yield_now();
+        }
+
+        assert_eq!(consumer.join().unwrap(), data);
+    }
+}
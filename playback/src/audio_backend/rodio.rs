@@ -5,7 +5,7 @@ use std::time::Duration;
 use cpal::traits::{DeviceTrait, HostTrait};
 use thiserror::Error;
 
-use super::{Sink, SinkError, SinkResult};
+use super::{find_device, AmbiguousDevice, DeviceInfo, Sink, SinkError, SinkOptions, SinkResult};
 use crate::config::AudioFormat;
 use crate::convert::Converter;
 use crate::decoder::AudioPacket;
@@ -18,12 +18,12 @@ use crate::{NUM_CHANNELS, SAMPLE_RATE};
 compile_error!("Rodio JACK backend is currently only supported on linux.");
 
 #[cfg(feature = "rodio-backend")]
-pub fn mk_rodio(device: Option<String>, format: AudioFormat) -> Box<dyn Sink> {
+pub fn mk_rodio(device: Option<String>, format: AudioFormat, _: &SinkOptions) -> Box<dyn Sink> {
     Box::new(open(cpal::default_host(), device, format))
 }
 
 #[cfg(feature = "rodiojack-backend")]
-pub fn mk_rodiojack(device: Option<String>, format: AudioFormat) -> Box<dyn Sink> {
+pub fn mk_rodiojack(device: Option<String>, format: AudioFormat, _: &SinkOptions) -> Box<dyn Sink> {
     Box::new(open(
         cpal::host_from_id(cpal::HostId::Jack).unwrap(),
         device,
@@ -37,6 +37,8 @@ pub enum RodioError {
     NoDeviceAvailable,
     #[error("<RodioSink> device \"{0}\" is Not Available")]
     DeviceNotAvailable(String),
+    #[error("<RodioSink> {0}")]
+    AmbiguousDevice(#[from] AmbiguousDevice),
     #[error("<RodioSink> Play Error: {0}")]
     PlayError(#[from] rodio::PlayError),
     #[error("<RodioSink> Stream Error: {0}")]
@@ -54,7 +56,7 @@ impl From<RodioError> for SinkError {
         match e {
             StreamError(_) | PlayError(_) | Samples(_) => SinkError::OnWrite(es),
             NoDeviceAvailable | DeviceNotAvailable(_) => SinkError::ConnectionRefused(es),
-            DevicesError(_) => SinkError::InvalidParams(es),
+            DevicesError(_) | AmbiguousDevice(_) => SinkError::InvalidParams(es),
         }
     }
 }
@@ -131,6 +133,24 @@ fn list_outputs(host: &cpal::Host) -> Result<(), cpal::DevicesError> {
     Ok(())
 }
 
+// cpal only knows the names of devices, so they serve as both id and description.
+pub fn list_output_devices(host: &cpal::Host) -> SinkResult<Vec<DeviceInfo>> {
+    let default_device_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    Ok(host
+        .output_devices()
+        .map_err(RodioError::from)?
+        .filter_map(|device| device.name().ok()) // Ignore devices for which getting name fails
+        .map(|name| DeviceInfo {
+            default: Some(&name) == default_device_name.as_ref(),
+            description: name.clone(),
+            id: name,
+        })
+        .collect())
+}
+
 fn create_sink(
     host: &cpal::Host,
     device: Option<String>,
@@ -144,9 +164,16 @@ fn create_sink(
             }
         },
         Some(device_name) => {
-            host.output_devices()?
-                .find(|d| d.name().ok().map_or(false, |name| name == device_name)) // Ignore devices for which getting name fails
+            let devices = list_output_devices(host)
+                .map_err(|_| RodioError::DeviceNotAvailable(device_name.to_string()))?;
+            let id = find_device(&devices, device_name)?
                 .ok_or_else(|| RodioError::DeviceNotAvailable(device_name.to_string()))?
+                .id
+                .clone();
+
+            host.output_devices()?
+                .find(|d| matches!(d.name(), Ok(name) if name == id))
+                .ok_or(RodioError::DeviceNotAvailable(id))?
         }
         None => host
             .default_output_device()
@@ -1,13 +1,22 @@
-use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use super::{Open, Sink, SinkAsBytes, SinkError, SinkOptions, SinkResult, TrackMetadata};
 use crate::config::AudioFormat;
 use crate::convert::Converter;
 use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, PAGES_PER_MS};
 use shell_words::split;
 
+use std::collections::VecDeque;
 use std::io::{ErrorKind, Write};
-use std::process::{exit, Child, Command, Stdio};
+use std::process::{exit, Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
+// How often a subprocess that died is restarted within a single write, waiting twice as
+// long before each attempt, before giving up and leaving it to the player to retry.
+const MAX_RESTARTS: u32 = 5;
+const RESTART_BACKOFF: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Error)]
 enum SubprocessError {
     #[error("<SubprocessSink> {0}")]
@@ -34,6 +43,9 @@ enum SubprocessError {
     #[error("<SubprocessSink> The Subprocess is no longer able to accept Bytes")]
     WriteZero,
 
+    #[error("<SubprocessSink> The Subprocess Exited with {0}")]
+    Exited(ExitStatus),
+
     #[error("<SubprocessSink> Missing Required Shell Command")]
     MissingCommand,
 
@@ -49,9 +61,8 @@ impl From<SubprocessError> for SinkError {
         use SubprocessError::*;
         let es = e.to_string();
         match e {
-            FlushFailure(_) | KillFailure(_) | WaitFailure(_) | OnWrite(_) | WriteZero => {
-                SinkError::OnWrite(es)
-            }
+            FlushFailure(_) | KillFailure(_) | WaitFailure(_) | OnWrite(_) | WriteZero
+            | Exited(_) => SinkError::OnWrite(es),
             SpawnFailure { .. } => SinkError::ConnectionRefused(es),
             MissingCommand | InvalidArgs { .. } => SinkError::InvalidParams(es),
             NoChild | NoStdin => SinkError::NotConnected(es),
@@ -63,10 +74,22 @@ pub struct SubprocessSink {
     shell_command: Option<String>,
     child: Option<Child>,
     format: AudioFormat,
+    track: Option<TrackMetadata>,
+    // The most recent audio, written again to a restarted subprocess.
+    replay: VecDeque<u8>,
+    replay_len: usize,
 }
 
 impl Open for SubprocessSink {
     fn open(shell_command: Option<String>, format: AudioFormat) -> Self {
+        Self::open_with_options(shell_command, format, &SinkOptions::default())
+    }
+
+    fn open_with_options(
+        shell_command: Option<String>,
+        format: AudioFormat,
+        options: &SinkOptions,
+    ) -> Self {
         if let Some("?") = shell_command.as_deref() {
             println!("\nUsage:\n\nOutput to a Subprocess:\n\n\t--backend subprocess --device {{shell_command}}\n");
             exit(0);
@@ -74,10 +97,17 @@ impl Open for SubprocessSink {
 
         info!("Using SubprocessSink with format: {:?}", format);
 
+        // Whole frames only, so that the replayed audio starts on a frame boundary.
+        let frame_len = format.size() * NUM_CHANNELS as usize;
+        let replay_frames = (options.subprocess_replay.as_millis() as f64 * PAGES_PER_MS) as usize;
+
         Self {
             shell_command,
             child: None,
             format,
+            track: None,
+            replay: VecDeque::new(),
+            replay_len: replay_frames * frame_len,
         }
     }
 }
@@ -92,9 +122,19 @@ impl Sink for SubprocessSink {
                         e,
                     })?;
 
-                    Command::new(&args[0])
-                        .args(&args[1..])
-                        .stdin(Stdio::piped())
+                    let mut command_builder = Command::new(&args[0]);
+                    command_builder.args(&args[1..]).stdin(Stdio::piped());
+
+                    if let Some(ref track) = self.track {
+                        if let Ok(track_id) = track.track_id.to_base62() {
+                            command_builder.env("TRACK_ID", track_id);
+                        }
+                        command_builder
+                            .env("TRACK_NAME", &track.name)
+                            .env("DURATION_MS", track.duration_ms.to_string());
+                    }
+
+                    command_builder
                         .spawn()
                         .map_err(|e| SubprocessError::SpawnFailure {
                             command: command.to_string(),
@@ -109,6 +149,9 @@ impl Sink for SubprocessSink {
     }
 
     fn stop(&mut self) -> SinkResult<()> {
+        // Playback resumes where it stopped, there is nothing to make up for.
+        self.replay.clear();
+
         let child = &mut self.child.take().ok_or(SubprocessError::NoChild)?;
 
         match child.try_wait() {
@@ -133,14 +176,30 @@ impl Sink for SubprocessSink {
         }
     }
 
+    fn supports_passthrough(&self) -> bool {
+        true
+    }
+
+    fn track_changed(&mut self, track: &TrackMetadata) {
+        self.track = Some(track.clone());
+    }
+
     sink_as_bytes!();
 }
 
 impl SinkAsBytes for SubprocessSink {
     fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
-        // We get one attempted restart per write.
+        // We get a limited number of restarts per write.
         // We don't want to get stuck in a restart loop.
-        let mut restarted = false;
+        let mut restarts = 0;
+
+        // Catch a subprocess that exited before a write to it fails.
+        if let Some(child) = self.child.as_mut() {
+            if let Ok(Some(status)) = child.try_wait() {
+                self.restart(SubprocessError::Exited(status), &mut restarts)?;
+            }
+        }
+
         let mut start_index = 0;
         let data_len = data.len();
         let mut end_index = data_len;
@@ -161,7 +220,7 @@ impl SinkAsBytes for SubprocessSink {
                     // means we shouldn't try to write to the
                     // process anymore so let's try a restart
                     // if we haven't already.
-                    self.try_restart(SubprocessError::WriteZero, &mut restarted)?;
+                    self.restart(SubprocessError::WriteZero, &mut restarts)?;
 
                     continue;
                 }
@@ -171,6 +230,7 @@ impl SinkAsBytes for SubprocessSink {
                     end_index = data_len.min(start_index + bytes_written);
 
                     if end_index == data_len {
+                        self.remember(data);
                         break Ok(());
                     }
                 }
@@ -179,7 +239,7 @@ impl SinkAsBytes for SubprocessSink {
                 Err(e) => {
                     // Very possibly fatal,
                     // but let's try a restart anyway if we haven't already.
-                    self.try_restart(SubprocessError::OnWrite(e), &mut restarted)?;
+                    self.restart(SubprocessError::OnWrite(e), &mut restarts)?;
 
                     continue;
                 }
@@ -191,14 +251,70 @@ impl SinkAsBytes for SubprocessSink {
 impl SubprocessSink {
     pub const NAME: &'static str = "subprocess";
 
-    fn try_restart(&mut self, e: SubprocessError, restarted: &mut bool) -> SinkResult<()> {
-        // If the restart fails throw the original error back.
-        if !*restarted && self.stop().is_ok() && self.start().is_ok() {
-            *restarted = true;
+    fn restart(&mut self, e: SubprocessError, restarts: &mut u32) -> SinkResult<()> {
+        warn!("{}", e);
+
+        while *restarts < MAX_RESTARTS {
+            if *restarts > 0 {
+                thread::sleep(RESTART_BACKOFF * 2u32.pow(*restarts - 1));
+            }
+            *restarts += 1;
+
+            // Reap the old subprocess, whether it's still running or not.
+            if let Some(mut child) = self.child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+
+            match self.start().and_then(|_| self.replay()) {
+                Ok(()) => {
+                    info!("<SubprocessSink> Restarted the Subprocess");
+                    return Ok(());
+                }
+                Err(restart_error) => {
+                    warn!(
+                        "<SubprocessSink> Failed to Restart the Subprocess: {}",
+                        restart_error
+                    )
+                }
+            }
+        }
+
+        // If the restarts fail throw the original error back.
+        Err(e.into())
+    }
 
-            Ok(())
-        } else {
-            Err(e.into())
+    fn remember(&mut self, data: &[u8]) {
+        if self.replay_len == 0 {
+            return;
         }
+
+        self.replay.extend(data);
+        let excess = self.replay.len().saturating_sub(self.replay_len);
+        self.replay.drain(..excess);
+    }
+
+    fn replay(&mut self) -> SinkResult<()> {
+        if self.replay.is_empty() {
+            return Ok(());
+        }
+
+        debug!("<SubprocessSink> Replaying {} Bytes", self.replay.len());
+
+        let stdin = self
+            .child
+            .as_mut()
+            .ok_or(SubprocessError::NoChild)?
+            .stdin
+            .as_mut()
+            .ok_or(SubprocessError::NoStdin)?;
+
+        let (front, back) = self.replay.as_slices();
+        stdin
+            .write_all(front)
+            .and_then(|_| stdin.write_all(back))
+            .map_err(SubprocessError::OnWrite)?;
+
+        Ok(())
     }
 }
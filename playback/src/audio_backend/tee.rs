@@ -0,0 +1,175 @@
+use super::{RouteChange, Sink, SinkResult, TrackMetadata};
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+use crate::dither::DithererBuilder;
+
+use std::time::Duration;
+
+/// One of the sinks a `TeeSink` writes to, with the ditherer for the format it was opened
+/// with.
+pub struct TeeOutput {
+    sink: Box<dyn Sink>,
+    converter: Converter,
+}
+
+impl TeeOutput {
+    pub fn new(sink: Box<dyn Sink>, ditherer: Option<DithererBuilder>) -> Self {
+        Self {
+            sink,
+            converter: Converter::new(ditherer),
+        }
+    }
+}
+
+/// Writes the same audio to several sinks, each in its own format.
+///
+/// Volume and normalisation are applied by the player before the samples get here, so all
+/// outputs share them. Converting to the format of an output, and dithering it, happens for
+/// each output on its own with the ditherer it was given, so that an S16 output can be
+/// dithered while an S32 or float one isn't. The converter that is passed to `write` is not
+/// used.
+pub struct TeeSink {
+    outputs: Vec<TeeOutput>,
+}
+
+impl TeeSink {
+    pub fn new(outputs: Vec<TeeOutput>) -> Self {
+        Self { outputs }
+    }
+}
+
+impl Sink for TeeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        for output in self.outputs.iter_mut() {
+            output.sink.start()?;
+        }
+        Ok(())
+    }
+
+    // Stops every output, even when one of them fails to, and returns the first error.
+    fn stop(&mut self) -> SinkResult<()> {
+        let mut result = Ok(());
+        for output in self.outputs.iter_mut() {
+            if let Err(e) = output.sink.stop() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+        if let Some((last, others)) = self.outputs.split_last_mut() {
+            for output in others {
+                output.sink.write(packet.clone(), &mut output.converter)?;
+            }
+            last.sink.write(packet, &mut last.converter)?;
+        }
+        Ok(())
+    }
+
+    fn supports_passthrough(&self) -> bool {
+        self.outputs
+            .iter()
+            .all(|output| output.sink.supports_passthrough())
+    }
+
+    fn take_underruns(&mut self) -> u64 {
+        self.outputs
+            .iter_mut()
+            .map(|output| output.sink.take_underruns())
+            .sum()
+    }
+
+    // Asks every output, so that none of them reports the same change again later. A change
+    // that moved an output away wins over one that didn't.
+    fn take_route_change(&mut self) -> Option<RouteChange> {
+        let mut change = None;
+        for output in self.outputs.iter_mut() {
+            match (output.sink.take_route_change(), change) {
+                (Some(new), None) => change = Some(new),
+                (Some(new), Some(old)) if new.is_away() && !old.is_away() => change = Some(new),
+                _ => (),
+            }
+        }
+        change
+    }
+
+    fn track_changed(&mut self, metadata: &TrackMetadata) {
+        for output in self.outputs.iter_mut() {
+            output.sink.track_changed(metadata);
+        }
+    }
+
+    // The audio is heard when the slowest output plays it.
+    fn latency(&self) -> Option<Duration> {
+        self.outputs
+            .iter()
+            .filter_map(|output| output.sink.latency())
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct S16Sink(Arc<Mutex<Vec<i16>>>);
+
+    impl Sink for S16Sink {
+        fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+            let samples = converter.f64_to_s16(packet.samples().unwrap());
+            self.0.lock().unwrap().extend(samples);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn outputs_convert_on_their_own() {
+        let plain = Arc::new(Mutex::new(Vec::new()));
+        let dithered = Arc::new(Mutex::new(Vec::new()));
+        let mut tee = TeeSink::new(vec![
+            TeeOutput::new(Box::new(S16Sink(plain.clone())), None),
+            TeeOutput::new(
+                Box::new(S16Sink(dithered.clone())),
+                crate::dither::find_ditherer(Some("tpdf".to_string())),
+            ),
+        ]);
+
+        let samples = vec![0.25; 4096];
+        tee.write(AudioPacket::Samples(samples), &mut Converter::new(None))
+            .unwrap();
+
+        let plain = plain.lock().unwrap();
+        let dithered = dithered.lock().unwrap();
+        assert!(plain.iter().all(|sample| *sample == 8192));
+        assert_eq!(dithered.len(), plain.len());
+        assert!(dithered.iter().any(|sample| *sample != 8192));
+    }
+
+    struct RouteSink(Option<RouteChange>);
+
+    impl Sink for RouteSink {
+        fn write(&mut self, _: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            Ok(())
+        }
+
+        fn take_route_change(&mut self) -> Option<RouteChange> {
+            self.0.take()
+        }
+    }
+
+    #[test]
+    fn route_changes_away_win() {
+        let mut tee = TeeSink::new(vec![
+            TeeOutput::new(Box::new(RouteSink(Some(RouteChange::JackPlugged))), None),
+            TeeOutput::new(Box::new(RouteSink(Some(RouteChange::JackUnplugged))), None),
+            TeeOutput::new(Box::new(RouteSink(Some(RouteChange::DefaultDevice))), None),
+        ]);
+
+        assert_eq!(tee.take_route_change(), Some(RouteChange::JackUnplugged));
+        assert_eq!(tee.take_route_change(), None);
+    }
+}
@@ -0,0 +1,734 @@
+use super::{
+    find_device, AmbiguousDevice, DeviceInfo, Open, RouteChange, Sink, SinkAsBytes, SinkError,
+    SinkOptions, SinkResult,
+};
+use crate::config::AudioFormat;
+use crate::convert::{i24, Converter};
+use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::ops::Deref;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::process::exit;
+use std::ptr;
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+use winapi::shared::guiddef::IsEqualGUID;
+use winapi::shared::ksmedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_PCM};
+use winapi::shared::minwindef::{DWORD, LPVOID};
+use winapi::shared::mmreg::{
+    SPEAKER_FRONT_LEFT, SPEAKER_FRONT_RIGHT, WAVEFORMATEX, WAVEFORMATEXTENSIBLE,
+    WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_IEEE_FLOAT,
+};
+use winapi::shared::winerror::{FAILED, RPC_E_CHANGED_MODE, S_OK};
+use winapi::shared::wtypes::VT_LPWSTR;
+use winapi::um::audioclient::{
+    IAudioClient, IAudioRenderClient, AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED,
+    AUDCLNT_E_DEVICE_INVALIDATED,
+};
+use winapi::um::audiosessiontypes::{
+    AUDCLNT_SHAREMODE, AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED,
+};
+use winapi::um::combaseapi::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, PropVariantClear, CLSCTX_ALL,
+};
+use winapi::um::coml2api::STGM_READ;
+use winapi::um::functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName;
+use winapi::um::mmdeviceapi::{
+    eConsole, eRender, CLSID_MMDeviceEnumerator, IMMDevice, IMMDeviceEnumerator,
+    DEVICE_STATE_ACTIVE,
+};
+use winapi::um::objbase::COINIT_MULTITHREADED;
+use winapi::um::propidl::PROPVARIANT;
+use winapi::um::unknwnbase::IUnknown;
+use winapi::um::winnt::{HRESULT, LPWSTR};
+use winapi::Interface;
+
+// Missing from winapi. They let the audio engine convert the format and resample in shared
+// mode, which it otherwise only plays in the format of its mixer.
+const AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM: DWORD = 0x8000_0000;
+const AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY: DWORD = 0x0800_0000;
+
+// How much audio the device buffers, in units of 100 ns.
+const BUFFER_DURATION: i64 = 2_000_000;
+
+// How long to wait for room in the buffer when it is full.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How often the default device is looked up while playing on it, to report when it changed.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const FORMATS: &[AudioFormat] = &[
+    AudioFormat::S16,
+    AudioFormat::S24,
+    AudioFormat::S24_3,
+    AudioFormat::S32,
+    AudioFormat::F32,
+];
+
+#[derive(Debug, Error)]
+enum WasapiError {
+    #[error("<WasapiSink> {call} Failed With HRESULT {hresult:#010x}")]
+    Call {
+        call: &'static str,
+        hresult: HRESULT,
+    },
+
+    #[error("<WasapiSink> Device \"{0}\" is Not Available")]
+    DeviceNotAvailable(String),
+
+    #[error("<WasapiSink> {0}")]
+    AmbiguousDevice(#[from] AmbiguousDevice),
+
+    #[error("<WasapiSink>")]
+    NotConnected,
+}
+
+impl From<WasapiError> for SinkError {
+    fn from(e: WasapiError) -> SinkError {
+        use WasapiError::*;
+        let es = e.to_string();
+        match e {
+            Call { .. } | DeviceNotAvailable(_) => SinkError::ConnectionRefused(es),
+            AmbiguousDevice(_) => SinkError::InvalidParams(es),
+            NotConnected => SinkError::NotConnected(es),
+        }
+    }
+}
+
+fn check(hresult: HRESULT, call: &'static str) -> Result<(), WasapiError> {
+    if FAILED(hresult) {
+        Err(WasapiError::Call { call, hresult })
+    } else {
+        Ok(())
+    }
+}
+
+// A COM object that is released on drop.
+struct ComPtr<T: Interface>(*mut T);
+
+impl<T: Interface> Deref for ComPtr<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: Interface> Drop for ComPtr<T> {
+    fn drop(&mut self) {
+        unsafe {
+            (*(self.0 as *mut IUnknown)).Release();
+        }
+    }
+}
+
+// Calls a method that returns a new COM object through `ptr`.
+unsafe fn create<T: Interface>(
+    call: &'static str,
+    f: impl FnOnce(*mut *mut T) -> HRESULT,
+) -> Result<ComPtr<T>, WasapiError> {
+    let mut ptr = ptr::null_mut();
+    check(f(&mut ptr), call)?;
+    Ok(ComPtr(ptr))
+}
+
+unsafe fn from_wide(s: *const u16) -> String {
+    let len = (0..).take_while(|&i| *s.offset(i) != 0).count();
+    OsString::from_wide(slice::from_raw_parts(s, len))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// COM is set up for every thread that uses it, and stays set up until the thread ends.
+fn enumerator() -> Result<ComPtr<IMMDeviceEnumerator>, WasapiError> {
+    unsafe {
+        let hresult = CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED);
+        // The thread was already set up for a single threaded apartment, which works too.
+        if hresult != RPC_E_CHANGED_MODE {
+            check(hresult, "CoInitializeEx")?;
+        }
+
+        create("CoCreateInstance", |ptr| {
+            CoCreateInstance(
+                &CLSID_MMDeviceEnumerator,
+                ptr::null_mut(),
+                CLSCTX_ALL,
+                &IMMDeviceEnumerator::uuidof(),
+                ptr as *mut LPVOID,
+            )
+        })
+    }
+}
+
+unsafe fn default_device(
+    enumerator: &IMMDeviceEnumerator,
+) -> Result<ComPtr<IMMDevice>, WasapiError> {
+    create("IMMDeviceEnumerator::GetDefaultAudioEndpoint", |ptr| {
+        enumerator.GetDefaultAudioEndpoint(eRender, eConsole, ptr)
+    })
+}
+
+unsafe fn device_id(device: &IMMDevice) -> Result<String, WasapiError> {
+    let mut id: LPWSTR = ptr::null_mut();
+    check(device.GetId(&mut id), "IMMDevice::GetId")?;
+    let result = from_wide(id);
+    CoTaskMemFree(id as LPVOID);
+    Ok(result)
+}
+
+unsafe fn friendly_name(device: &IMMDevice) -> Result<String, WasapiError> {
+    let store = create("IMMDevice::OpenPropertyStore", |ptr| {
+        device.OpenPropertyStore(STGM_READ, ptr)
+    })?;
+
+    let mut value: PROPVARIANT = mem::zeroed();
+    check(
+        store.GetValue(&PKEY_Device_FriendlyName, &mut value),
+        "IPropertyStore::GetValue",
+    )?;
+    let name = if value.vt == VT_LPWSTR as u16 {
+        from_wide(*value.data.pwszVal())
+    } else {
+        String::new()
+    };
+    PropVariantClear(&mut value);
+    Ok(name)
+}
+
+unsafe fn activate(device: &IMMDevice) -> Result<ComPtr<IAudioClient>, WasapiError> {
+    create("IMMDevice::Activate", |ptr| {
+        device.Activate(
+            &IAudioClient::uuidof(),
+            CLSCTX_ALL,
+            ptr::null_mut(),
+            ptr as *mut LPVOID,
+        )
+    })
+}
+
+fn devices(enumerator: &IMMDeviceEnumerator) -> Result<Vec<DeviceInfo>, WasapiError> {
+    unsafe {
+        // There is none when no device is active.
+        let default_id = match default_device(enumerator) {
+            Ok(device) => Some(device_id(&device)?),
+            Err(_) => None,
+        };
+
+        let collection = create("IMMDeviceEnumerator::EnumAudioEndpoints", |ptr| {
+            enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE, ptr)
+        })?;
+        // winapi declares the out parameter as `*const UINT`, but it is written to.
+        let mut count: u32 = 0;
+        check(
+            collection.GetCount(&mut count as *mut u32 as *const u32),
+            "IMMDeviceCollection::GetCount",
+        )?;
+
+        let mut devices = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            let device = create("IMMDeviceCollection::Item", |ptr| collection.Item(i, ptr))?;
+            let id = device_id(&device)?;
+            devices.push(DeviceInfo {
+                default: default_id.as_ref() == Some(&id),
+                description: friendly_name(&device)?,
+                id,
+            });
+        }
+
+        Ok(devices)
+    }
+}
+
+// WASAPI identifies devices by ids such as `{0.0.0.00000000}.{...}`, and describes them with
+// their friendly name, such as `Speakers (Realtek High Definition Audio)`.
+pub fn list_output_devices() -> SinkResult<Vec<DeviceInfo>> {
+    let enumerator = enumerator()?;
+    Ok(devices(&enumerator)?)
+}
+
+fn list_outputs() -> Result<(), WasapiError> {
+    let enumerator = enumerator()?;
+
+    println!("\n\n\tAvailable WASAPI device(s):\n");
+    println!("\t------------------------------------------------------\n");
+
+    for device in devices(&enumerator)? {
+        println!("\tDevice:\n\n\t\t{}\n", device.id);
+
+        let default = if device.default { " (default)" } else { "" };
+        println!("\tDescription:\n\n\t\t{}{}\n", device.description, default);
+
+        let exclusive_formats: Vec<_> = unsafe {
+            exclusive_formats(&enumerator, &device.id)
+                .unwrap_or_default()
+                .iter()
+                .map(|format| format!("{:?}", format))
+                .collect()
+        };
+        println!(
+            "\tExclusive Mode Format(s):\n\n\t\t{}\n",
+            if exclusive_formats.is_empty() {
+                "None".to_string()
+            } else {
+                exclusive_formats.join(" ")
+            }
+        );
+
+        println!("\t------------------------------------------------------\n");
+    }
+
+    Ok(())
+}
+
+// The formats the device can be opened with in exclusive mode.
+unsafe fn exclusive_formats(
+    enumerator: &IMMDeviceEnumerator,
+    id: &str,
+) -> Result<Vec<AudioFormat>, WasapiError> {
+    let device = device_by_id(enumerator, id)?;
+    let client = activate(&device)?;
+
+    Ok(FORMATS
+        .iter()
+        .copied()
+        .filter(|format| {
+            let wave_format = wave_format(*format);
+            client.IsFormatSupported(
+                AUDCLNT_SHAREMODE_EXCLUSIVE,
+                &wave_format as *const _ as *const WAVEFORMATEX,
+                ptr::null_mut(),
+            ) == S_OK
+        })
+        .collect())
+}
+
+unsafe fn device_by_id(
+    enumerator: &IMMDeviceEnumerator,
+    id: &str,
+) -> Result<ComPtr<IMMDevice>, WasapiError> {
+    let id: Vec<u16> = OsStr::new(id).encode_wide().chain(Some(0)).collect();
+    create("IMMDeviceEnumerator::GetDevice", |ptr| {
+        enumerator.GetDevice(id.as_ptr(), ptr)
+    })
+}
+
+fn wave_format(format: AudioFormat) -> WAVEFORMATEXTENSIBLE {
+    let (container_bits, valid_bits, sub_format) = match format {
+        AudioFormat::F32 => (32, 32, KSDATAFORMAT_SUBTYPE_IEEE_FLOAT),
+        AudioFormat::S32 => (32, 32, KSDATAFORMAT_SUBTYPE_PCM),
+        AudioFormat::S24 => (32, 24, KSDATAFORMAT_SUBTYPE_PCM),
+        AudioFormat::S24_3 => (24, 24, KSDATAFORMAT_SUBTYPE_PCM),
+        AudioFormat::S16 => (16, 16, KSDATAFORMAT_SUBTYPE_PCM),
+        _ => unreachable!(),
+    };
+    let block_align = NUM_CHANNELS as u16 * container_bits / 8;
+
+    WAVEFORMATEXTENSIBLE {
+        Format: WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_EXTENSIBLE,
+            nChannels: NUM_CHANNELS as u16,
+            nSamplesPerSec: SAMPLE_RATE,
+            nAvgBytesPerSec: SAMPLE_RATE * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: container_bits,
+            cbSize: (mem::size_of::<WAVEFORMATEXTENSIBLE>() - mem::size_of::<WAVEFORMATEX>())
+                as u16,
+        },
+        Samples: valid_bits,
+        dwChannelMask: SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT,
+        SubFormat: sub_format,
+    }
+}
+
+// Such as `32 bit float, 2 channels, 48000 Hz`.
+unsafe fn describe(format: *const WAVEFORMATEX) -> String {
+    let wave_format = ptr::read_unaligned(format);
+    let float = match wave_format.wFormatTag {
+        WAVE_FORMAT_IEEE_FLOAT => true,
+        WAVE_FORMAT_EXTENSIBLE => {
+            let extensible = ptr::read_unaligned(format as *const WAVEFORMATEXTENSIBLE);
+            IsEqualGUID(&{ extensible.SubFormat }, &KSDATAFORMAT_SUBTYPE_IEEE_FLOAT)
+        }
+        _ => false,
+    };
+
+    format!(
+        "{} bit {}, {} channels, {} Hz",
+        { wave_format.wBitsPerSample },
+        if float { "float" } else { "integer" },
+        { wave_format.nChannels },
+        { wave_format.nSamplesPerSec }
+    )
+}
+
+struct Stream {
+    // Released before the client it came from.
+    render: ComPtr<IAudioRenderClient>,
+    client: ComPtr<IAudioClient>,
+    buffer_frames: u32,
+    frame_size: usize,
+}
+
+impl Stream {
+    fn open(
+        device: &IMMDevice,
+        format: AudioFormat,
+        share_mode: AUDCLNT_SHAREMODE,
+    ) -> Result<Self, WasapiError> {
+        let wave_format = wave_format(format);
+        let exclusive = share_mode == AUDCLNT_SHAREMODE_EXCLUSIVE;
+        let flags = if exclusive {
+            0
+        } else {
+            AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY
+        };
+
+        unsafe {
+            let initialize = |client: &IAudioClient, duration| {
+                client.Initialize(
+                    share_mode,
+                    flags,
+                    duration,
+                    0,
+                    &wave_format as *const _ as *const WAVEFORMATEX,
+                    ptr::null(),
+                )
+            };
+
+            let mut client = activate(device)?;
+            let mut hresult = initialize(&client, BUFFER_DURATION);
+
+            // In exclusive mode the buffer has to hold a whole number of the periods the
+            // device transfers, which takes a new client with the size it rounded to.
+            if hresult == AUDCLNT_E_BUFFER_SIZE_NOT_ALIGNED {
+                let mut frames = 0;
+                check(
+                    client.GetBufferSize(&mut frames),
+                    "IAudioClient::GetBufferSize",
+                )?;
+                let rate = SAMPLE_RATE as i64;
+                let duration = (10_000_000 * frames as i64 + rate / 2) / rate;
+
+                client = activate(device)?;
+                hresult = initialize(&client, duration);
+            }
+            check(hresult, "IAudioClient::Initialize")?;
+
+            let mut buffer_frames = 0;
+            check(
+                client.GetBufferSize(&mut buffer_frames),
+                "IAudioClient::GetBufferSize",
+            )?;
+
+            let render = create("IAudioClient::GetService", |ptr| {
+                client.GetService(&IAudioRenderClient::uuidof(), ptr as *mut LPVOID)
+            })?;
+
+            if exclusive {
+                info!(
+                    "Opened WASAPI device in exclusive mode with {}",
+                    describe(&wave_format as *const _ as *const WAVEFORMATEX)
+                );
+            } else {
+                let mut mix_format = ptr::null_mut();
+                check(
+                    client.GetMixFormat(&mut mix_format),
+                    "IAudioClient::GetMixFormat",
+                )?;
+                info!(
+                    "Opened WASAPI device in shared mode, which converts {:?} to {}",
+                    format,
+                    describe(mix_format)
+                );
+                CoTaskMemFree(mix_format as LPVOID);
+            }
+
+            Ok(Self {
+                render,
+                client,
+                buffer_frames,
+                frame_size: wave_format.Format.nBlockAlign as usize,
+            })
+        }
+    }
+
+    // The frames that were written and are not played yet.
+    fn padding(&self) -> Result<u32, WasapiError> {
+        let mut padding = 0;
+        unsafe {
+            check(
+                self.client.GetCurrentPadding(&mut padding),
+                "IAudioClient::GetCurrentPadding",
+            )?;
+        }
+        Ok(padding)
+    }
+
+    fn buffer_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.buffer_frames as f64 / SAMPLE_RATE as f64)
+    }
+
+    // Waits until what was written is played, for at most as long as the buffer lasts.
+    fn drain(&self) {
+        let deadline = Instant::now() + self.buffer_duration();
+        while Instant::now() < deadline {
+            match self.padding() {
+                Ok(0) | Err(_) => break,
+                Ok(_) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    fn write(&self, mut data: &[u8]) -> Result<(), WasapiError> {
+        while !data.is_empty() {
+            let available = self.buffer_frames - self.padding()?;
+            if available == 0 {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+
+            let frames = available.min((data.len() / self.frame_size) as u32);
+            if frames == 0 {
+                break;
+            }
+            let len = frames as usize * self.frame_size;
+
+            unsafe {
+                let mut buffer = ptr::null_mut();
+                check(
+                    self.render.GetBuffer(frames, &mut buffer),
+                    "IAudioRenderClient::GetBuffer",
+                )?;
+                ptr::copy_nonoverlapping(data.as_ptr(), buffer, len);
+                check(
+                    self.render.ReleaseBuffer(frames, 0),
+                    "IAudioRenderClient::ReleaseBuffer",
+                )?;
+            }
+
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+}
+
+pub struct WasapiSink {
+    device: Option<String>,
+    format: AudioFormat,
+    exclusive: bool,
+    stream: Option<Stream>,
+    // The id of the device the stream plays on, and when the default was last looked up.
+    stream_device: Option<String>,
+    default_checked: Instant,
+    route_change: Option<RouteChange>,
+}
+
+impl Open for WasapiSink {
+    fn open(device: Option<String>, format: AudioFormat) -> Self {
+        Self::open_with_options(device, format, &SinkOptions::default())
+    }
+
+    fn open_with_options(
+        device: Option<String>,
+        format: AudioFormat,
+        options: &SinkOptions,
+    ) -> Self {
+        if device.as_deref() == Some("?") {
+            match list_outputs() {
+                Ok(_) => exit(0),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+
+        let mut actual_format = format;
+
+        if actual_format == AudioFormat::F64 {
+            warn!("WASAPI currently does not support F64 output");
+            actual_format = AudioFormat::F32;
+        }
+
+        info!("Using WasapiSink with format: {:?}", actual_format);
+
+        Self {
+            device,
+            format: actual_format,
+            exclusive: options.wasapi_exclusive,
+            stream: None,
+            stream_device: None,
+            default_checked: Instant::now(),
+            route_change: None,
+        }
+    }
+}
+
+impl WasapiSink {
+    pub const NAME: &'static str = "wasapi";
+
+    // Opens a stream, and returns it with the id of its device.
+    fn open_stream(&self) -> Result<(Stream, String), WasapiError> {
+        let enumerator = enumerator()?;
+        let device = unsafe {
+            match &self.device {
+                None => default_device(&enumerator)?,
+                Some(name) => {
+                    let devices = devices(&enumerator)?;
+                    let device = find_device(&devices, name)?
+                        .ok_or_else(|| WasapiError::DeviceNotAvailable(name.clone()))?;
+                    device_by_id(&enumerator, &device.id)?
+                }
+            }
+        };
+        let id = unsafe { device_id(&device)? };
+
+        if self.exclusive {
+            match Stream::open(&device, self.format, AUDCLNT_SHAREMODE_EXCLUSIVE) {
+                Ok(stream) => return Ok((stream, id)),
+                Err(e) => warn!(
+                    "Cannot open the WASAPI device in exclusive mode, using shared mode: {}",
+                    e
+                ),
+            }
+        }
+
+        Ok((
+            Stream::open(&device, self.format, AUDCLNT_SHAREMODE_SHARED)?,
+            id,
+        ))
+    }
+
+    // Playback stays on the old default device, as long as it is there.
+    fn check_default(&mut self) {
+        if self.device.is_some() || self.default_checked.elapsed() < DEFAULT_POLL_INTERVAL {
+            return;
+        }
+        self.default_checked = Instant::now();
+
+        let id = enumerator()
+            .and_then(|enumerator| unsafe { device_id(&*default_device(&enumerator)?) });
+        if let Ok(id) = id {
+            if self.stream_device.as_ref() != Some(&id) {
+                info!("The default WASAPI device changed, playback stays where it is");
+                self.stream_device = Some(id);
+                self.route_change = Some(RouteChange::DefaultDevice);
+            }
+        }
+    }
+}
+
+impl Sink for WasapiSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.stream.is_none() {
+            let (stream, id) = self.open_stream()?;
+            unsafe {
+                check(stream.client.Start(), "IAudioClient::Start")?;
+            }
+            self.stream = Some(stream);
+            self.stream_device = Some(id);
+            self.default_checked = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    // The device is released, so that others can use it in exclusive mode while paused.
+    fn stop(&mut self) -> SinkResult<()> {
+        let stream = self.stream.take().ok_or(WasapiError::NotConnected)?;
+
+        stream.drain();
+        unsafe {
+            check(stream.client.Stop(), "IAudioClient::Stop")?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        use zerocopy::AsBytes;
+        match packet {
+            AudioPacket::Samples(samples) => match self.format {
+                AudioFormat::F32 => {
+                    let samples_f32: &[f32] = &converter.f64_to_f32(&samples);
+                    self.write_bytes(samples_f32.as_bytes())
+                }
+                AudioFormat::S32 => {
+                    let samples_s32: &[i32] = &converter.f64_to_s32(&samples);
+                    self.write_bytes(samples_s32.as_bytes())
+                }
+                // WASAPI takes 24 bit samples in the upper bits of their 32 bit container.
+                AudioFormat::S24 => {
+                    let samples_s24: Vec<i32> = converter
+                        .f64_to_s24(&samples)
+                        .iter()
+                        .map(|sample| sample << 8)
+                        .collect();
+                    self.write_bytes(samples_s24.as_bytes())
+                }
+                AudioFormat::S24_3 => {
+                    let samples_s24_3: &[i24] = &converter.f64_to_s24_3(&samples);
+                    self.write_bytes(samples_s24_3.as_bytes())
+                }
+                AudioFormat::S16 => {
+                    let samples_s16: &[i16] = &converter.f64_to_s16(&samples);
+                    self.write_bytes(samples_s16.as_bytes())
+                }
+                _ => unreachable!(),
+            },
+            AudioPacket::OggData(_) => Err(SinkError::InvalidParams(
+                "<WasapiSink> Passthrough is Not Supported".to_string(),
+            )),
+        }
+    }
+
+    fn take_route_change(&mut self) -> Option<RouteChange> {
+        self.route_change.take()
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        let stream = self.stream.as_ref()?;
+        let padding = stream.padding().ok()?;
+
+        // What the engine adds on top of the buffer, in units of 100 ns.
+        let mut stream_latency = 0;
+        unsafe {
+            stream.client.GetStreamLatency(&mut stream_latency);
+        }
+
+        Some(
+            Duration::from_secs_f64(padding as f64 / SAMPLE_RATE as f64)
+                + Duration::from_nanos(stream_latency.max(0) as u64 * 100),
+        )
+    }
+}
+
+impl SinkAsBytes for WasapiSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        self.check_default();
+        let stream = self.stream.as_ref().ok_or(WasapiError::NotConnected)?;
+
+        match stream.write(data) {
+            Ok(()) => Ok(()),
+            // The device went away. Without a device set, playback moves to the new default
+            // device when the sink is started again.
+            Err(
+                e @ WasapiError::Call {
+                    hresult: AUDCLNT_E_DEVICE_INVALIDATED,
+                    ..
+                },
+            ) => {
+                self.stream = None;
+                self.route_change = Some(RouteChange::DeviceRemoved);
+                Err(SinkError::OnWrite(e.to_string()))
+            }
+            Err(e) => Err(SinkError::OnWrite(e.to_string())),
+        }
+    }
+}
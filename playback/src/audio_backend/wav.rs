@@ -0,0 +1,219 @@
+use super::{Open, Sink, SinkAsBytes, SinkError, SinkResult};
+use crate::config::AudioFormat;
+use crate::convert::Converter;
+use crate::decoder::AudioPacket;
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::process::exit;
+use thiserror::Error;
+
+const HEADER_LEN: u64 = 68;
+
+// The sizes in the header while the file is still being written, which players read as
+// "until the end of the file". That way a file that is never finalized still plays.
+const UNKNOWN_SIZE: u32 = u32::MAX;
+
+const KSDATAFORMAT_SUBTYPE_PCM: [u8; 16] = [
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+const KSDATAFORMAT_SUBTYPE_IEEE_FLOAT: [u8; 16] = [
+    0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+#[derive(Debug, Error)]
+enum WavError {
+    #[error("<WavSink> {0}")]
+    OnWrite(std::io::Error),
+
+    #[error("<WavSink> File Path {file} Can Not be Opened and/or Created, {e}")]
+    OpenFailure { file: String, e: std::io::Error },
+
+    #[error("<WavSink> Failed to Finalize the File, {0}")]
+    FinalizeFailure(std::io::Error),
+
+    #[error("<WavSink> Missing Required File Path")]
+    MissingFile,
+
+    #[error("<WavSink> The File is None")]
+    NoOutput,
+}
+
+impl From<WavError> for SinkError {
+    fn from(e: WavError) -> SinkError {
+        use WavError::*;
+        let es = e.to_string();
+        match e {
+            FinalizeFailure(_) | OnWrite(_) => SinkError::OnWrite(es),
+            OpenFailure { .. } => SinkError::ConnectionRefused(es),
+            MissingFile => SinkError::InvalidParams(es),
+            NoOutput => SinkError::NotConnected(es),
+        }
+    }
+}
+
+pub struct WavSink {
+    output: Option<File>,
+    file: Option<String>,
+    format: AudioFormat,
+    data_len: u64,
+}
+
+impl Open for WavSink {
+    fn open(file: Option<String>, format: AudioFormat) -> Self {
+        if let Some("?") = file.as_deref() {
+            println!(
+                "\nUsage:\n\nOutput to a WAV file:\n\n\t--backend wav --device {{filename}}\n"
+            );
+            exit(0);
+        }
+
+        info!("Using WavSink with format: {:?}", format);
+
+        Self {
+            output: None,
+            file,
+            format,
+            data_len: 0,
+        }
+    }
+}
+
+impl Sink for WavSink {
+    fn start(&mut self) -> SinkResult<()> {
+        // The file stays open while paused, so that a session ends up in a single file.
+        if self.output.is_none() {
+            let file = self.file.as_deref().ok_or(WavError::MissingFile)?;
+
+            let mut output = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(file)
+                .map_err(|e| WavError::OpenFailure {
+                    file: file.to_string(),
+                    e,
+                })?;
+
+            output
+                .write_all(&header(self.format, UNKNOWN_SIZE))
+                .map_err(WavError::OnWrite)?;
+
+            self.output = Some(output);
+            self.data_len = 0;
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        let output = self.output.as_mut().ok_or(WavError::NoOutput)?;
+        finalize(output, self.data_len).map_err(WavError::FinalizeFailure)?;
+
+        Ok(())
+    }
+
+    sink_as_bytes!();
+}
+
+impl SinkAsBytes for WavSink {
+    fn write_bytes(&mut self, data: &[u8]) -> SinkResult<()> {
+        self.output
+            .as_mut()
+            .ok_or(WavError::NoOutput)?
+            .write_all(data)
+            .map_err(WavError::OnWrite)?;
+
+        self.data_len += data.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Some(ref mut output) = self.output {
+            if let Err(e) = finalize(output, self.data_len) {
+                error!("{}", WavError::FinalizeFailure(e));
+            }
+        }
+    }
+}
+
+impl WavSink {
+    pub const NAME: &'static str = "wav";
+}
+
+// A WAVE_FORMAT_EXTENSIBLE header, which can describe every format including S24,
+// where 24 bit samples are stored in 32 bits.
+fn header(format: AudioFormat, data_len: u32) -> Vec<u8> {
+    let (valid_bits, subformat) = match format {
+        AudioFormat::F64 => (64, KSDATAFORMAT_SUBTYPE_IEEE_FLOAT),
+        AudioFormat::F32 => (32, KSDATAFORMAT_SUBTYPE_IEEE_FLOAT),
+        AudioFormat::S32 => (32, KSDATAFORMAT_SUBTYPE_PCM),
+        AudioFormat::S24 | AudioFormat::S24_3 => (24, KSDATAFORMAT_SUBTYPE_PCM),
+        AudioFormat::S16 => (16, KSDATAFORMAT_SUBTYPE_PCM),
+    };
+
+    let block_align = format.size() as u16 * NUM_CHANNELS as u16;
+    let riff_len = data_len.saturating_add(HEADER_LEN as u32 - 8);
+
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&riff_len.to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&40u32.to_le_bytes());
+    header.extend_from_slice(&0xfffeu16.to_le_bytes());
+    header.extend_from_slice(&(NUM_CHANNELS as u16).to_le_bytes());
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(&(SAMPLE_RATE * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&(format.size() as u16 * 8).to_le_bytes());
+    header.extend_from_slice(&22u16.to_le_bytes());
+    header.extend_from_slice(&(valid_bits as u16).to_le_bytes());
+    // Front left and front right.
+    header.extend_from_slice(&0x3u32.to_le_bytes());
+    header.extend_from_slice(&subformat);
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+// Writes the actual sizes into the header and returns to the end of the file. Files over
+// 4 GiB keep the unknown sizes, there is no way to express them.
+fn finalize<W: Write + Seek>(output: &mut W, data_len: u64) -> io::Result<()> {
+    if data_len + HEADER_LEN - 8 <= u32::MAX as u64 {
+        let data_len = data_len as u32;
+
+        output.seek(SeekFrom::Start(4))?;
+        output.write_all(&(data_len + HEADER_LEN as u32 - 8).to_le_bytes())?;
+        output.seek(SeekFrom::Start(HEADER_LEN - 4))?;
+        output.write_all(&data_len.to_le_bytes())?;
+        output.seek(SeekFrom::End(0))?;
+    }
+
+    output.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn header_is_finalized() {
+        let mut output = Cursor::new(header(AudioFormat::S16, UNKNOWN_SIZE));
+        assert_eq!(output.get_ref().len() as u64, HEADER_LEN);
+
+        output.seek(SeekFrom::End(0)).unwrap();
+        output.write_all(&[0; 400]).unwrap();
+        finalize(&mut output, 400).unwrap();
+
+        let mut expected = header(AudioFormat::S16, 400);
+        expected.extend_from_slice(&[0; 400]);
+        assert_eq!(output.get_ref(), &expected);
+        assert_eq!(&expected[4..8], &(400u32 + 60).to_le_bytes());
+    }
+}
@@ -28,6 +28,16 @@ impl Default for Bitrate {
     }
 }
 
+impl Bitrate {
+    pub fn lower(self) -> Option<Self> {
+        match self {
+            Self::Bitrate96 => None,
+            Self::Bitrate160 => Some(Self::Bitrate96),
+            Self::Bitrate320 => Some(Self::Bitrate160),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub enum AudioFormat {
     F64,
@@ -121,12 +131,105 @@ impl Default for NormalisationMethod {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnavailableTrackPolicy {
+    // Moves on with `PlayerEvent::TrackSkipped`, up to `PlayerConfig::max_consecutive_skips`.
+    Skip,
+    // Ends the track with `PlayerEvent::EndOfTrack` like one that was played, and leaves it
+    // to Spirc what comes next.
+    Halt,
+}
+
+impl FromStr for UnavailableTrackPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "skip" => Ok(Self::Skip),
+            "halt" => Ok(Self::Halt),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for UnavailableTrackPolicy {
+    fn default() -> Self {
+        Self::Skip
+    }
+}
+
+pub const MAX_PRELOAD_DEPTH: usize = 5;
+
 #[derive(Clone)]
 pub struct PlayerConfig {
     pub bitrate: Bitrate,
     pub gapless: bool,
     pub passthrough: bool,
 
+    // how many upcoming tracks are fetched ahead, up to `MAX_PRELOAD_DEPTH`; the next track
+    // is preloaded and the ones after it are only downloaded into the audio cache, so more
+    // than one needs an audio cache. While downloading, each track is held in a temporary
+    // file, around 10 MB for four minutes at 320 kbps, which lives in memory where the
+    // temporary directory is a tmpfs. The downloads of the tracks after the next one take up
+    // at most `preload_budget_bytes` at the same time, tracks that don't fit are left out.
+    pub preload_depth: usize,
+    pub preload_budget_bytes: Option<u64>,
+
+    // switch to a lower bitrate for the rest of a track when it stalls more than
+    // `adaptive_bitrate_max_stalls` times or takes longer than `adaptive_bitrate_load_deadline` to load
+    pub adaptive_bitrate: bool,
+    pub adaptive_bitrate_max_stalls: u32,
+    pub adaptive_bitrate_load_deadline: Duration,
+
+    // what to do with a track that is unavailable or still fails to load after
+    // `track_load_retries` retries; skipping stops after `max_consecutive_skips` tracks in a row
+    pub unavailable_track_policy: UnavailableTrackPolicy,
+    pub track_load_retries: u32,
+    pub max_consecutive_skips: u32,
+
+    // fetch synced lyrics along with each track and emit an event as playback reaches each line
+    pub lyrics_events: bool,
+
+    // speed at which tracks are played, between `MIN_PLAYBACK_SPEED` and `MAX_PLAYBACK_SPEED`
+    pub playback_speed: f32,
+
+    // when the audio sink fails, playback pauses and the sink is reopened every
+    // `sink_retry_interval` for up to `sink_retry_timeout` before playback stops
+    pub sink_retry_interval: Duration,
+    pub sink_retry_timeout: Duration,
+
+    // whether to try the sink at startup, and to wait for it when it is busy or missing, then
+    // and on errors later, retrying less and less often without giving up
+    pub wait_for_sink: bool,
+
+    // how far before the requested position a seek may land; the decoder decodes and
+    // discards audio from the nearest page until it is within this tolerance
+    pub seek_tolerance_ms: u32,
+
+    // how long the volume ramps down when the sleep timer fires, before playback pauses
+    pub sleep_timer_fade: Duration,
+
+    // pause when the sink reports that its output moved away, such as when headphones are
+    // unplugged. Playback doesn't resume by itself afterwards.
+    pub pause_on_route_change: bool,
+
+    // how long the volume ramps up when playback starts or resumes, and down before it pauses
+    pub fade_in: Duration,
+    pub fade_out: Duration,
+
+    // how long the sink stays open during a pause, so that resuming doesn't need to open
+    // the device again; zero closes it right away and `None` keeps it open
+    pub sink_close_delay: Option<Duration>,
+
+    // send the position in a progress event this often while playing, if set
+    pub progress_interval: Option<Duration>,
+
+    // send the metrics of the session in an event this often while playing, if set
+    pub metrics_interval: Option<Duration>,
+
+    // send the peak and RMS levels of what is played in an event for every window of this
+    // much audio, if set. Nothing is measured otherwise.
+    pub levels_interval: Option<Duration>,
+
     pub normalisation: bool,
     pub normalisation_type: NormalisationType,
     pub normalisation_method: NormalisationMethod,
@@ -135,6 +238,15 @@ pub struct PlayerConfig {
     pub normalisation_attack_cf: f64,
     pub normalisation_release_cf: f64,
     pub normalisation_knee_db: f64,
+    // warn about tracks that may exceed dBFS with the dynamic method, at most every few
+    // minutes. They are logged at debug level otherwise.
+    pub normalisation_warnings: bool,
+    // with the dynamic method, lower the gain of tracks that would peak above dBFS to peak
+    // at it, instead of leaving it all to the limiter
+    pub normalisation_auto_pregain: bool,
+    // play at unity gain instead of normalising, for outputs into something that manages
+    // loudness itself. Volume is still applied. It can be changed while playing.
+    pub normalisation_bypass: bool,
 
     // pass function pointers so they can be lazily instantiated *after* spawning a thread
     // (thereby circumventing Send bounds that they might not satisfy)
@@ -146,6 +258,28 @@ impl Default for PlayerConfig {
         Self {
             bitrate: Bitrate::default(),
             gapless: true,
+            preload_depth: 1,
+            preload_budget_bytes: Some(64 * 1024 * 1024),
+            adaptive_bitrate: false,
+            adaptive_bitrate_max_stalls: 2,
+            adaptive_bitrate_load_deadline: Duration::from_secs(5),
+            unavailable_track_policy: UnavailableTrackPolicy::default(),
+            track_load_retries: 2,
+            max_consecutive_skips: 5,
+            lyrics_events: false,
+            playback_speed: 1.0,
+            sink_retry_interval: Duration::from_secs(2),
+            sink_retry_timeout: Duration::from_secs(60),
+            wait_for_sink: false,
+            seek_tolerance_ms: 0,
+            sleep_timer_fade: Duration::from_secs(5),
+            pause_on_route_change: false,
+            fade_in: Duration::ZERO,
+            fade_out: Duration::ZERO,
+            sink_close_delay: Some(Duration::ZERO),
+            progress_interval: None,
+            metrics_interval: None,
+            levels_interval: None,
             normalisation: false,
             normalisation_type: NormalisationType::default(),
             normalisation_method: NormalisationMethod::default(),
@@ -154,6 +288,9 @@ impl Default for PlayerConfig {
             normalisation_attack_cf: duration_to_coefficient(Duration::from_millis(5)),
             normalisation_release_cf: duration_to_coefficient(Duration::from_millis(100)),
             normalisation_knee_db: 5.0,
+            normalisation_warnings: true,
+            normalisation_auto_pregain: false,
+            normalisation_bypass: false,
             passthrough: false,
             ditherer: Some(mk_ditherer::<TriangularDitherer>),
         }
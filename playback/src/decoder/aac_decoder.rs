@@ -0,0 +1,165 @@
+use super::{AudioDecoder, AudioPacket, DecoderError, DecoderResult};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_AAC};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+// Symphonia reads from a `MediaSource`, which our `Read + Seek` files don't implement.
+struct Source<R>(R);
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for Source<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl<R: Read + Seek + Send + Sync> MediaSource for Source<R> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Decodes AAC in an MP4 container or as an ADTS stream.
+pub struct AacDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_buffer: Option<SampleBuffer<f64>>,
+    // After a seek, the timestamp up to which decoded audio is dropped.
+    skip_until: Option<u64>,
+}
+
+impl AacDecoder {
+    pub fn new<R>(input: R) -> DecoderResult<Self>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        let stream = MediaSourceStream::new(Box::new(Source(input)), Default::default());
+
+        let probed = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .map_err(|e| DecoderError::AacDecoder(e.to_string()))?;
+        let format = probed.format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec == CODEC_TYPE_AAC)
+            .ok_or_else(|| DecoderError::AacDecoder("No AAC track found".to_string()))?;
+        let track_id = track.id;
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|e| DecoderError::AacDecoder(e.to_string()))?;
+
+        Ok(AacDecoder {
+            format,
+            decoder,
+            track_id,
+            sample_buffer: None,
+            skip_until: None,
+        })
+    }
+}
+
+impl AudioDecoder for AacDecoder {
+    fn seek(&mut self, absgp: u64) -> DecoderResult<u64> {
+        let seeked_to = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(absgp as f64 / SAMPLE_RATE as f64),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| DecoderError::AacDecoder(e.to_string()))?;
+        self.decoder.reset();
+
+        // An accurate seek lands on the packet containing the requested timestamp, so the
+        // audio before it is decoded and dropped.
+        self.skip_until = Some(seeked_to.required_ts);
+        Ok(absgp)
+    }
+
+    fn next_packet(&mut self) -> DecoderResult<Option<AudioPacket>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(DecoderError::AacDecoder(e.to_string())),
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt frame is skipped, like lewton skips bad audio packets.
+                Err(Error::DecodeError(_)) => continue,
+                Err(e) => return Err(DecoderError::AacDecoder(e.to_string())),
+            };
+
+            let spec = *decoded.spec();
+            if spec.rate != SAMPLE_RATE || spec.channels.count() != NUM_CHANNELS as usize {
+                return Err(DecoderError::AacDecoder(format!(
+                    "Unsupported stream with {} channels at {} Hz",
+                    spec.channels.count(),
+                    spec.rate
+                )));
+            }
+
+            let frames = decoded.capacity();
+            let sample_buffer = match self.sample_buffer {
+                Some(ref mut buffer) if buffer.capacity() >= frames * spec.channels.count() => {
+                    buffer
+                }
+                _ => self
+                    .sample_buffer
+                    .insert(SampleBuffer::new(frames as u64, spec)),
+            };
+            sample_buffer.copy_interleaved_ref(decoded);
+
+            // The timestamps of AAC tracks count frames at the sample rate.
+            let skip_frames = match self.skip_until.take() {
+                Some(skip_until) if packet.ts() + packet.dur() <= skip_until => {
+                    self.skip_until = Some(skip_until);
+                    continue;
+                }
+                Some(skip_until) => skip_until.saturating_sub(packet.ts()) as usize,
+                None => 0,
+            };
+
+            let samples = sample_buffer.samples();
+            let skip = (skip_frames * spec.channels.count()).min(samples.len());
+            return Ok(Some(AudioPacket::Samples(samples[skip..].to_vec())));
+        }
+    }
+}
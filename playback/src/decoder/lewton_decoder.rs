@@ -8,7 +8,22 @@ use lewton::VorbisError::{BadAudio, OggError};
 
 use std::io::{Read, Seek};
 
-pub struct VorbisDecoder<R: Read + Seek>(OggStreamReader<R>);
+use crate::SAMPLE_RATE;
+
+// Seeking at most this far ahead decodes forward instead of seeking the file, which would
+// need random access to data that is likely already being downloaded.
+const DECODE_FORWARD_FRAMES: u64 = SAMPLE_RATE as u64;
+
+pub struct VorbisDecoder<R: Read + Seek> {
+    reader: OggStreamReader<R>,
+    channels: usize,
+    // The position of the next frame that is returned, if known.
+    position: Option<u64>,
+    // How many frames before the requested position a seek may land.
+    seek_tolerance: u64,
+    // Decoded audio that is returned before decoding the next packet.
+    pending: Option<Vec<f32>>,
+}
 
 impl<R> VorbisDecoder<R>
 where
@@ -17,25 +32,29 @@ where
     pub fn new(input: R) -> DecoderResult<VorbisDecoder<R>> {
         let reader =
             OggStreamReader::new(input).map_err(|e| DecoderError::LewtonDecoder(e.to_string()))?;
-        Ok(VorbisDecoder(reader))
+        let channels = reader.ident_hdr.audio_channels as usize;
+        Ok(VorbisDecoder {
+            reader,
+            channels,
+            position: Some(0),
+            seek_tolerance: 0,
+            pending: None,
+        })
     }
-}
 
-impl<R> AudioDecoder for VorbisDecoder<R>
-where
-    R: Read + Seek,
-{
-    fn seek(&mut self, absgp: u64) -> DecoderResult<()> {
-        self.0
-            .seek_absgp_pg(absgp)
-            .map_err(|e| DecoderError::LewtonDecoder(e.to_string()))?;
-        Ok(())
+    /// Lets a seek land up to `frames` before the requested position, instead of decoding
+    /// up to the exact frame.
+    pub fn set_seek_tolerance(&mut self, frames: u64) {
+        self.seek_tolerance = frames;
     }
 
-    fn next_packet(&mut self) -> DecoderResult<Option<AudioPacket>> {
+    fn decode_packet(&mut self) -> DecoderResult<Option<Vec<f32>>> {
         loop {
-            match self.0.read_dec_packet_generic::<InterleavedSamples<f32>>() {
-                Ok(Some(packet)) => return Ok(Some(AudioPacket::samples_from_f32(packet.samples))),
+            match self
+                .reader
+                .read_dec_packet_generic::<InterleavedSamples<f32>>()
+            {
+                Ok(Some(packet)) => return Ok(Some(packet.samples)),
                 Ok(None) => return Ok(None),
                 Err(BadAudio(AudioIsHeader)) => (),
                 Err(OggError(NoCapturePatternFound)) => (),
@@ -43,4 +62,140 @@ where
             }
         }
     }
+
+    fn next_samples(&mut self) -> DecoderResult<Option<Vec<f32>>> {
+        if let Some(samples) = self.pending.take() {
+            self.position = self
+                .position
+                .map(|position| position + (samples.len() / self.channels) as u64);
+            return Ok(Some(samples));
+        }
+
+        let samples = self.decode_packet()?;
+        if let Some(ref samples) = samples {
+            // Once the end of a page has been decoded, the reader knows the exact position.
+            self.position = self.reader.get_last_absgp().or_else(|| {
+                self.position
+                    .map(|position| position + (samples.len() / self.channels) as u64)
+            });
+        }
+        Ok(samples)
+    }
+
+    // Discards decoded audio up to `absgp`, or until it is within the seek tolerance.
+    fn skip_to(&mut self, absgp: u64) -> DecoderResult<()> {
+        while let Some(position) = self.position {
+            if position + self.seek_tolerance >= absgp {
+                break;
+            }
+
+            let samples = match self.next_samples()? {
+                Some(samples) => samples,
+                None => break,
+            };
+
+            let frames = (samples.len() / self.channels) as u64;
+            if position + frames > absgp {
+                let skip = (absgp - position) as usize * self.channels;
+                self.pending = Some(samples[skip..].to_vec());
+                self.position = Some(absgp);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R> AudioDecoder for VorbisDecoder<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, absgp: u64) -> DecoderResult<u64> {
+        match self.position {
+            Some(position) if absgp >= position && absgp - position <= DECODE_FORWARD_FRAMES => {}
+            _ => {
+                self.reader
+                    .seek_absgp_pg(absgp)
+                    .map_err(|e| DecoderError::LewtonDecoder(e.to_string()))?;
+
+                // The seek lands on a page boundary at or before `absgp`. Where exactly is
+                // only known once the end of a page has been decoded, so keep the audio up
+                // to there and count back from the position of its end.
+                let mut samples = Vec::new();
+                let end = loop {
+                    match self.decode_packet()? {
+                        Some(packet) => samples.extend(packet),
+                        None => break None,
+                    }
+                    if let Some(end) = self.reader.get_last_absgp() {
+                        break Some(end);
+                    }
+                };
+
+                let frames = (samples.len() / self.channels) as u64;
+                self.position = end.map(|end| end.saturating_sub(frames));
+                self.pending = Some(samples);
+            }
+        }
+
+        self.skip_to(absgp)?;
+        Ok(self.position.unwrap_or(absgp))
+    }
+
+    fn next_packet(&mut self) -> DecoderResult<Option<AudioPacket>> {
+        Ok(self.next_samples()?.map(AudioPacket::samples_from_f32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    // A short mono beep at 44.1 kHz.
+    const BEEP: &[u8] = include_bytes!("../../tests/data/beep.ogg");
+
+    fn decode_all(decoder: &mut VorbisDecoder<Cursor<&[u8]>>) -> Vec<f64> {
+        let mut samples = Vec::new();
+        while let Some(packet) = decoder.next_packet().unwrap() {
+            samples.extend_from_slice(packet.samples().unwrap());
+        }
+        samples
+    }
+
+    #[test]
+    fn seek_lands_on_requested_frame() {
+        let reference = decode_all(&mut VorbisDecoder::new(Cursor::new(BEEP)).unwrap());
+
+        // Seek back from the end of the stream to offsets on and between page boundaries.
+        for &target in &[20_000u64, 5_000, 30_000, 30_500, 1_000] {
+            let mut decoder = VorbisDecoder::new(Cursor::new(BEEP)).unwrap();
+            decode_all(&mut decoder);
+
+            assert_eq!(decoder.seek(target).unwrap(), target);
+            let samples = decode_all(&mut decoder);
+            assert_eq!(samples.len(), reference.len() - target as usize);
+            assert!((samples[0] - reference[target as usize]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn short_seek_decodes_forward() {
+        let reference = decode_all(&mut VorbisDecoder::new(Cursor::new(BEEP)).unwrap());
+
+        let mut decoder = VorbisDecoder::new(Cursor::new(BEEP)).unwrap();
+        assert_eq!(decoder.seek(10_000).unwrap(), 10_000);
+        assert_eq!(decoder.seek(12_345).unwrap(), 12_345);
+        let samples = decode_all(&mut decoder);
+        assert_eq!(samples[..], reference[12_345..]);
+    }
+
+    #[test]
+    fn seek_within_tolerance() {
+        let mut decoder = VorbisDecoder::new(Cursor::new(BEEP)).unwrap();
+        decode_all(&mut decoder);
+        decoder.set_seek_tolerance(4_410);
+
+        let landed = decoder.seek(25_000).unwrap();
+        assert!(landed <= 25_000 && landed + 4_410 >= 25_000);
+    }
 }
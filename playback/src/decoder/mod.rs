@@ -1,3 +1,6 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
 use thiserror::Error;
 
 mod lewton_decoder;
@@ -6,12 +9,24 @@ pub use lewton_decoder::VorbisDecoder;
 mod passthrough_decoder;
 pub use passthrough_decoder::PassthroughDecoder;
 
+#[cfg(feature = "aac-decoder")]
+mod aac_decoder;
+#[cfg(feature = "aac-decoder")]
+pub use aac_decoder::AacDecoder;
+
 #[derive(Error, Debug)]
 pub enum DecoderError {
     #[error("Lewton Decoder Error: {0}")]
     LewtonDecoder(String),
     #[error("Passthrough Decoder Error: {0}")]
     PassthroughDecoder(String),
+    #[cfg(feature = "aac-decoder")]
+    #[error("AAC Decoder Error: {0}")]
+    AacDecoder(String),
+    #[error("Unknown Audio Codec: {0}")]
+    UnknownCodec(String),
+    #[error("Unsupported Audio Codec: {0}")]
+    UnsupportedCodec(AudioCodec),
 }
 
 pub type DecoderResult<T> = Result<T, DecoderError>;
@@ -26,6 +41,7 @@ pub enum AudioPacketError {
 
 pub type AudioPacketResult<T> = Result<T, AudioPacketError>;
 
+#[derive(Clone)]
 pub enum AudioPacket {
     Samples(Vec<f64>),
     OggData(Vec<u8>),
@@ -60,6 +76,105 @@ impl AudioPacket {
 }
 
 pub trait AudioDecoder {
-    fn seek(&mut self, absgp: u64) -> DecoderResult<()>;
+    // Seeks to `absgp` and returns the position it actually landed on.
+    fn seek(&mut self, absgp: u64) -> DecoderResult<u64>;
     fn next_packet(&mut self) -> DecoderResult<Option<AudioPacket>>;
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Vorbis,
+    Aac,
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioCodec::Vorbis => f.write_str("vorbis"),
+            AudioCodec::Aac => f.write_str("aac"),
+        }
+    }
+}
+
+// Spotify prepends a header of its own to Ogg Vorbis files.
+pub const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+
+const CODEC_HEADER_LEN: usize = SPOTIFY_OGG_HEADER_END as usize + 4;
+
+/// Detects the codec of `file` from its first bytes and returns it with the offset at
+/// which the audio stream starts. The file is rewound afterwards.
+pub fn detect_codec<T: Read + Seek>(file: &mut T) -> DecoderResult<(AudioCodec, u64)> {
+    let mut header = [0u8; CODEC_HEADER_LEN];
+    let mut len = 0;
+
+    // While streaming, a read may only return the part of the header that has arrived yet.
+    while len < header.len() {
+        match file.read(&mut header[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(DecoderError::UnknownCodec(e.to_string())),
+        }
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| DecoderError::UnknownCodec(e.to_string()))?;
+
+    codec_from_header(&header[..len])
+        .ok_or_else(|| DecoderError::UnknownCodec(format!("{:02x?}", &header[..len.min(8)])))
+}
+
+fn codec_from_header(header: &[u8]) -> Option<(AudioCodec, u64)> {
+    let ogg_at = |offset: usize| header.get(offset..offset + 4) == Some(&b"OggS"[..]);
+
+    if ogg_at(SPOTIFY_OGG_HEADER_END as usize) {
+        Some((AudioCodec::Vorbis, SPOTIFY_OGG_HEADER_END))
+    } else if ogg_at(0) {
+        Some((AudioCodec::Vorbis, 0))
+    } else if header.get(4..8) == Some(&b"ftyp"[..]) {
+        // MP4 container
+        Some((AudioCodec::Aac, 0))
+    } else if header.len() >= 2 && header[0] == 0xff && header[1] & 0xf6 == 0xf0 {
+        // ADTS frame sync
+        Some((AudioCodec::Aac, 0))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn detect_spotify_ogg() {
+        let mut header = vec![0u8; SPOTIFY_OGG_HEADER_END as usize];
+        header.extend_from_slice(b"OggS");
+        assert_eq!(
+            codec_from_header(&header),
+            Some((AudioCodec::Vorbis, SPOTIFY_OGG_HEADER_END))
+        );
+    }
+
+    #[test]
+    fn detect_aac() {
+        assert_eq!(
+            codec_from_header(b"\0\0\0\x20ftypM4A "),
+            Some((AudioCodec::Aac, 0))
+        );
+        assert_eq!(
+            codec_from_header(&[0xff, 0xf1, 0x50, 0x80]),
+            Some((AudioCodec::Aac, 0))
+        );
+    }
+
+    #[test]
+    fn detect_short_file() {
+        let mut file = Cursor::new(b"OggS".to_vec());
+        assert_eq!(detect_codec(&mut file).unwrap(), (AudioCodec::Vorbis, 0));
+        assert_eq!(file.position(), 0);
+
+        assert!(detect_codec(&mut Cursor::new(vec![0u8; 2])).is_err());
+    }
+}
@@ -68,7 +68,7 @@ impl<R: Read + Seek> PassthroughDecoder<R> {
 }
 
 impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
-    fn seek(&mut self, absgp: u64) -> DecoderResult<()> {
+    fn seek(&mut self, absgp: u64) -> DecoderResult<u64> {
         // add an eos to previous stream if missing
         if self.bos && !self.eos {
             match self.rdr.read_packet() {
@@ -103,7 +103,9 @@ impl<R: Read + Seek> AudioDecoder for PassthroughDecoder<R> {
                     Some(pck) => {
                         self.ofsgp_page = pck.absgp_page();
                         debug!("Seek to offset page {}", self.ofsgp_page);
-                        Ok(())
+                        // The raw stream can only be cut at pages, and without decoding it
+                        // there is no telling how far before `absgp` this one starts.
+                        Ok(absgp)
                     }
                     None => Err(DecoderError::PassthroughDecoder(
                         "Packet is None".to_string(),
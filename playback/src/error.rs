@@ -0,0 +1,15 @@
+use thiserror::Error;
+
+use crate::audio_backend::SinkError;
+use crate::decoder::{AudioPacketError, DecoderError};
+
+/// Any of the errors of this crate, like `librespot_core::Error`.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Sink(#[from] SinkError),
+    #[error(transparent)]
+    Decoder(#[from] DecoderError),
+    #[error(transparent)]
+    AudioPacket(#[from] AudioPacketError),
+}
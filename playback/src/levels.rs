@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use crate::{NUM_CHANNELS, SAMPLE_RATE};
+
+/// The level of one channel over a window, as ratios of full scale.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChannelLevel {
+    pub peak: f64,
+    pub rms: f64,
+}
+
+/// The levels of the audio that was played over a window, for meters and visualisations.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Levels {
+    /// Left, then right.
+    pub channels: [ChannelLevel; NUM_CHANNELS as usize],
+    /// Some samples were at or beyond full scale, so they were clipped on output.
+    pub clipped: bool,
+}
+
+// Measures the samples that are written to the sink, window by window.
+pub(crate) struct LevelMeter {
+    window_frames: usize,
+    frames: usize,
+    peak: [f64; NUM_CHANNELS as usize],
+    sum_of_squares: [f64; NUM_CHANNELS as usize],
+    clipped: bool,
+}
+
+impl LevelMeter {
+    pub(crate) fn new(window: Duration) -> Self {
+        let window_frames = (window.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+        Self {
+            window_frames: window_frames.max(1),
+            frames: 0,
+            peak: Default::default(),
+            sum_of_squares: Default::default(),
+            clipped: false,
+        }
+    }
+
+    // Adds the interleaved samples, and returns the levels of the window they completed, if
+    // any. When they complete more than one, that is the last one.
+    pub(crate) fn measure(&mut self, samples: &[f64]) -> Option<Levels> {
+        let mut levels = None;
+
+        for frame in samples.chunks_exact(NUM_CHANNELS as usize) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let magnitude = sample.abs();
+                self.peak[channel] = self.peak[channel].max(magnitude);
+                self.sum_of_squares[channel] += sample * sample;
+                self.clipped |= magnitude >= 1.0;
+            }
+
+            self.frames += 1;
+            if self.frames == self.window_frames {
+                levels = Some(self.take_levels());
+            }
+        }
+
+        levels
+    }
+
+    fn take_levels(&mut self) -> Levels {
+        let mut levels = Levels {
+            clipped: self.clipped,
+            ..Levels::default()
+        };
+        for (channel, level) in levels.channels.iter_mut().enumerate() {
+            level.peak = self.peak[channel].min(1.0);
+            level.rms = (self.sum_of_squares[channel] / self.frames as f64)
+                .sqrt()
+                .min(1.0);
+        }
+
+        self.frames = 0;
+        self.peak = Default::default();
+        self.sum_of_squares = Default::default();
+        self.clipped = false;
+
+        levels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels_are_measured_per_window() {
+        let mut meter = LevelMeter::new(Duration::from_millis(100));
+        let window: Vec<f64> = (0..SAMPLE_RATE as usize / 10)
+            .flat_map(|frame| {
+                let left = if frame % 2 == 0 { 0.5 } else { -0.5 };
+                [left, 0.0]
+            })
+            .collect();
+
+        let (first, second) = window.split_at(window.len() / 2);
+        assert_eq!(meter.measure(first), None);
+        let levels = meter.measure(second).unwrap();
+        assert_eq!(
+            levels.channels[0],
+            ChannelLevel {
+                peak: 0.5,
+                rms: 0.5
+            }
+        );
+        assert_eq!(levels.channels[1], ChannelLevel::default());
+        assert!(!levels.clipped);
+
+        let mut clipping = window.clone();
+        clipping[1] = -1.2;
+        let levels = meter.measure(&clipping).unwrap();
+        assert_eq!(levels.channels[1].peak, 1.0);
+        assert!(levels.clipped);
+    }
+}
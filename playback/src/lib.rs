@@ -10,8 +10,14 @@ pub mod config;
 pub mod convert;
 pub mod decoder;
 pub mod dither;
+mod error;
+pub mod levels;
 pub mod mixer;
 pub mod player;
+pub mod prefetch;
+pub mod stretch;
+
+pub use crate::error::Error;
 
 pub const SAMPLE_RATE: u32 = 44100;
 pub const NUM_CHANNELS: u8 = 2;
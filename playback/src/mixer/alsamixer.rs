@@ -1,13 +1,16 @@
 use crate::player::{db_to_ratio, ratio_to_db};
 
 use super::mappings::{LogMapping, MappedCtrl, VolumeMapping};
-use super::{Mixer, MixerConfig, VolumeCtrl};
+use super::{Mixer, MixerConfig, VolumeChangeCallback, VolumeCtrl};
 
 use alsa::ctl::{ElemId, ElemIface};
 use alsa::mixer::{MilliBel, SelemChannelId, SelemId};
+use alsa::poll::Descriptors;
 use alsa::{Ctl, Round};
 
 use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[derive(Clone)]
 #[allow(dead_code)]
@@ -22,8 +25,26 @@ pub struct AlsaMixer {
     has_switch: bool,
     is_softvol: bool,
     use_linear_in_db: bool,
+    // Shared with the watcher, as it can be changed at runtime.
+    volume_ctrl: Arc<Mutex<VolumeCtrl>>,
+    // The volume from before muting, when the control has no playback switch to mute with.
+    muted_volume: Arc<Mutex<Option<u16>>>,
+    external: Arc<ExternalVolume>,
 }
 
+// Shared with the thread that watches the mixer control for changes made outside of
+// librespot, e.g. with alsamixer.
+#[derive(Default)]
+struct ExternalVolume {
+    // The volume as last set or seen, to tell external changes from our own. It is locked
+    // while the volume is set, until it is read back.
+    last_volume: Mutex<Option<u16>>,
+    callback: Mutex<Option<VolumeChangeCallback>>,
+}
+
+// How often the watcher checks whether the mixer is still in use.
+const WATCH_TIMEOUT_MS: i32 = 1000;
+
 // min_db cannot be depended on to be mute. Also note that contrary to
 // its name copied verbatim from Alsa, this is in millibel scale.
 const SND_CTL_TLV_DB_GAIN_MUTE: MilliBel = MilliBel(-9999999);
@@ -150,6 +171,8 @@ impl Mixer for AlsaMixer {
         );
         debug!("Alsa forcing linear dB mapping: {}", use_linear_in_db);
 
+        let volume_ctrl = config.volume_ctrl;
+
         Self {
             config,
             min,
@@ -161,6 +184,9 @@ impl Mixer for AlsaMixer {
             has_switch,
             is_softvol,
             use_linear_in_db,
+            volume_ctrl: Arc::new(Mutex::new(volume_ctrl)),
+            muted_volume: Arc::new(Mutex::new(None)),
+            external: Arc::new(ExternalVolume::default()),
         }
     }
 
@@ -196,15 +222,106 @@ impl Mixer for AlsaMixer {
             }
         };
 
-        // see comment in `set_volume` why we are handling an antilog volume
+        // see comment in `apply_volume` why we are handling an antilog volume
         if mapped_volume > 0.0 && self.is_some_linear() {
             mapped_volume = LogMapping::linear_to_mapped(mapped_volume, self.db_range);
         }
 
-        self.config.volume_ctrl.to_unmapped(mapped_volume)
+        self.volume_ctrl().to_unmapped(mapped_volume)
     }
 
     fn set_volume(&self, volume: u16) {
+        let mut last_volume = self.external.last_volume.lock().unwrap();
+        *self.muted_volume.lock().unwrap() = None;
+        self.apply_volume(volume);
+        *last_volume = Some(self.volume());
+    }
+
+    fn set_mute(&self, mute: bool) {
+        let mut last_volume = self.external.last_volume.lock().unwrap();
+
+        if self.has_switch {
+            // The volume stays where it is, only the playback switch changes.
+            let mixer = alsa::mixer::Mixer::new(&self.config.device, false)
+                .expect("Could not open Alsa mixer");
+            let simple_element = mixer
+                .find_selem(&SelemId::new(&self.config.control, self.config.index))
+                .expect("Could not find Alsa mixer control");
+
+            debug!("Setting Alsa playback switch to {}", !mute);
+            simple_element
+                .set_playback_switch_all(!mute as i32)
+                .expect("Could not set the playback switch on Alsa");
+        } else {
+            let mut muted_volume = self.muted_volume.lock().unwrap();
+            match (mute, *muted_volume) {
+                (true, None) => {
+                    *muted_volume = Some(self.volume());
+                    self.apply_volume(0);
+                }
+                (false, Some(volume)) => {
+                    *muted_volume = None;
+                    self.apply_volume(volume);
+                }
+                _ => (),
+            }
+        }
+
+        *last_volume = Some(self.volume());
+    }
+
+    fn is_muted(&self) -> bool {
+        if self.has_switch {
+            self.switched_off()
+        } else {
+            self.muted_volume.lock().unwrap().is_some()
+        }
+    }
+
+    fn volume_ctrl(&self) -> VolumeCtrl {
+        *self.volume_ctrl.lock().unwrap()
+    }
+
+    fn set_volume_ctrl(&self, volume_ctrl: VolumeCtrl) {
+        // See `open`, controls with a small range only work well with a linear mapping.
+        if self.use_linear_in_db {
+            warn!(
+                "Alsa mixer control has a dB range of {:.2}, keeping linear volume control",
+                self.db_range
+            );
+            return;
+        }
+
+        // Keep the loudness: the mixer control stays where it is and reads back as a
+        // different volume, which is not an external change.
+        let mut last_volume = self.external.last_volume.lock().unwrap();
+        info!("Alsa volume control is now {:?}", volume_ctrl);
+        *self.volume_ctrl.lock().unwrap() = volume_ctrl;
+        *last_volume = Some(self.volume());
+    }
+
+    fn set_volume_change_callback(&self, callback: VolumeChangeCallback) {
+        let watching = self
+            .external
+            .callback
+            .lock()
+            .unwrap()
+            .replace(callback)
+            .is_some();
+
+        if !watching {
+            *self.external.last_volume.lock().unwrap() = Some(self.volume());
+
+            let mixer = self.clone();
+            thread::spawn(move || mixer.watch());
+        }
+    }
+}
+
+impl AlsaMixer {
+    pub const NAME: &'static str = "alsa";
+
+    fn apply_volume(&self, volume: u16) {
         let mixer =
             alsa::mixer::Mixer::new(&self.config.device, false).expect("Could not open Alsa mixer");
         let simple_element = mixer
@@ -225,7 +342,7 @@ impl Mixer for AlsaMixer {
             }
         }
 
-        let mut mapped_volume = self.config.volume_ctrl.to_mapped(volume);
+        let mut mapped_volume = self.volume_ctrl().to_mapped(volume);
 
         // Alsa's linear algorithms map everything onto log. Alsa softvol does
         // this internally. In the case of `use_linear_in_db` this happens
@@ -260,10 +377,51 @@ impl Mixer for AlsaMixer {
             .set_playback_db_all(MilliBel::from_db(db_volume as f32), Round::Floor)
             .expect("Could not set Alsa dB volume");
     }
-}
 
-impl AlsaMixer {
-    pub const NAME: &'static str = "alsa";
+    // Waits for events on the mixer control, and reports the volume when it differs from
+    // what was last set or seen. Runs until no other copy of this mixer remains.
+    fn watch(self) {
+        let mixer = match alsa::mixer::Mixer::new(&self.config.device, false) {
+            Ok(mixer) => mixer,
+            Err(e) => {
+                warn!("Could not watch the Alsa mixer for volume changes: {}", e);
+                return;
+            }
+        };
+        let mut fds = match mixer.get() {
+            Ok(fds) => fds,
+            Err(e) => {
+                warn!("Could not watch the Alsa mixer for volume changes: {}", e);
+                return;
+            }
+        };
+
+        while Arc::strong_count(&self.external) > 1 {
+            match alsa::poll::poll(&mut fds, WATCH_TIMEOUT_MS) {
+                Ok(0) => continue,
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("Stopped watching the Alsa mixer for volume changes: {}", e);
+                    return;
+                }
+            }
+
+            if let Err(e) = mixer.handle_events() {
+                warn!("Stopped watching the Alsa mixer for volume changes: {}", e);
+                return;
+            }
+
+            let mut last_volume = self.external.last_volume.lock().unwrap();
+            let volume = self.volume();
+            if *last_volume != Some(volume) {
+                debug!("Alsa volume was changed externally to {}", volume);
+                *last_volume = Some(volume);
+                if let Some(ref callback) = *self.external.callback.lock().unwrap() {
+                    callback(volume);
+                }
+            }
+        }
+    }
 
     fn switched_off(&self) -> bool {
         if !self.has_switch {
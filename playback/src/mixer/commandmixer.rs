@@ -0,0 +1,235 @@
+use super::softmixer::SoftMixer;
+use super::{Mixer, MixerConfig, NoOpVolume, VolumeChangeCallback, VolumeCtrl, VolumeGetter};
+
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+enum CommandMixerError {
+    #[error("<CommandMixer> Could not run {command}, {e}")]
+    Spawn { command: String, e: std::io::Error },
+
+    #[error("<CommandMixer> {command} failed with {status}")]
+    Failed { command: String, status: ExitStatus },
+}
+
+// Runs a command to set the volume, e.g. of an amplifier that is controlled over a serial
+// port. The command runs on a thread of its own, so that a slow or hanging command does not
+// hold up playback.
+pub struct CommandMixer {
+    // The volume as last set or confirmed by the command. It is kept while muted, when the
+    // command is run with 0 instead.
+    volume: Arc<AtomicUsize>,
+    muted: AtomicBool,
+    volume_ctrl: Mutex<VolumeCtrl>,
+    // Used in addition to the command when enabled.
+    softvol: Option<SoftMixer>,
+    // The volume that is set at startup only updates the command when enabled.
+    skip_next: AtomicBool,
+    requests: mpsc::Sender<u16>,
+    callback: Arc<Mutex<Option<VolumeChangeCallback>>>,
+}
+
+impl Mixer for CommandMixer {
+    fn open(config: MixerConfig) -> Self {
+        let command = config
+            .command
+            .clone()
+            .expect("The command mixer needs a command to run");
+
+        info!(
+            "Mixing with command {} and volume control: {:?}, softvol: {}",
+            command, config.volume_ctrl, config.command_softvol
+        );
+
+        let volume = Arc::new(AtomicUsize::new(VolumeCtrl::MAX_VOLUME as usize / 2));
+        let callback = Arc::new(Mutex::new(None));
+        let (requests, requests_rx) = mpsc::channel();
+
+        {
+            let volume = volume.clone();
+            let callback = callback.clone();
+            thread::spawn(move || run(command, requests_rx, volume, callback));
+        }
+
+        Self {
+            volume,
+            muted: AtomicBool::new(false),
+            volume_ctrl: Mutex::new(config.volume_ctrl),
+            softvol: config
+                .command_softvol
+                .then(|| SoftMixer::open(config.clone())),
+            skip_next: AtomicBool::new(!config.command_initial),
+            requests,
+            callback,
+        }
+    }
+
+    fn volume(&self) -> u16 {
+        if self.is_muted() {
+            return 0;
+        }
+
+        self.volume.load(Ordering::Relaxed) as u16
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.volume.store(volume as usize, Ordering::Relaxed);
+        self.muted.store(false, Ordering::Relaxed);
+
+        if let Some(ref softvol) = self.softvol {
+            softvol.set_volume(volume);
+        }
+
+        if self.skip_next.swap(false, Ordering::Relaxed) {
+            debug!(
+                "Not running the mixer command for the initial volume {}",
+                volume
+            );
+            return;
+        }
+
+        // The thread only ends with the mixer.
+        let _ = self.requests.send(volume);
+    }
+
+    fn volume_ctrl(&self) -> VolumeCtrl {
+        match self.softvol {
+            Some(ref softvol) => softvol.volume_ctrl(),
+            None => *self.volume_ctrl.lock().unwrap(),
+        }
+    }
+
+    fn set_volume_ctrl(&self, volume_ctrl: VolumeCtrl) {
+        // The command is given the volume as is, so the curve only matters to softvol.
+        match self.softvol {
+            Some(ref softvol) => softvol.set_volume_ctrl(volume_ctrl),
+            None => *self.volume_ctrl.lock().unwrap() = volume_ctrl,
+        }
+    }
+
+    fn set_mute(&self, mute: bool) {
+        if self.muted.swap(mute, Ordering::Relaxed) == mute {
+            return;
+        }
+
+        if let Some(ref softvol) = self.softvol {
+            softvol.set_mute(mute);
+        }
+
+        let _ = self.requests.send(self.volume());
+    }
+
+    fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
+        match self.softvol {
+            Some(ref softvol) => softvol.get_soft_volume(),
+            None => Box::new(NoOpVolume),
+        }
+    }
+
+    fn set_volume_change_callback(&self, callback: VolumeChangeCallback) {
+        *self.callback.lock().unwrap() = Some(callback);
+    }
+}
+
+impl CommandMixer {
+    pub const NAME: &'static str = "command";
+}
+
+fn run(
+    command: String,
+    requests: mpsc::Receiver<u16>,
+    volume: Arc<AtomicUsize>,
+    callback: Arc<Mutex<Option<VolumeChangeCallback>>>,
+) {
+    while let Ok(mut target) = requests.recv() {
+        // Only the latest volume matters when changes come in faster than the command runs.
+        while let Ok(newer) = requests.try_recv() {
+            target = newer;
+        }
+
+        match run_command(&command, target) {
+            Ok(Some(confirmed)) if confirmed != target => {
+                debug!(
+                    "Mixer command set the volume to {} instead of {}",
+                    confirmed, target
+                );
+                volume.store(confirmed as usize, Ordering::Relaxed);
+                if let Some(ref callback) = *callback.lock().unwrap() {
+                    callback(confirmed);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+// Runs `<command> <volume> <percent>`, with the same values as `VOLUME` and `VOLUME_PERCENT`
+// in its environment. The command may print the volume it ended up setting.
+fn run_command(command: &str, volume: u16) -> Result<Option<u16>, CommandMixerError> {
+    let volume_percent = to_percent(volume).to_string();
+    let volume = volume.to_string();
+
+    let output = Command::new(command)
+        .arg(&volume)
+        .arg(&volume_percent)
+        .env("VOLUME", &volume)
+        .env("VOLUME_PERCENT", &volume_percent)
+        .output()
+        .map_err(|e| CommandMixerError::Spawn {
+            command: command.to_string(),
+            e,
+        })?;
+
+    if !output.status.success() {
+        return Err(CommandMixerError::Failed {
+            command: command.to_string(),
+            status: output.status,
+        });
+    }
+
+    Ok(confirmed_volume(&output.stdout))
+}
+
+fn to_percent(volume: u16) -> u8 {
+    (volume as f64 / VolumeCtrl::MAX_VOLUME as f64 * 100.0).round() as u8
+}
+
+// The last line of output, if it is a volume.
+fn confirmed_volume(stdout: &[u8]) -> Option<u16> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .and_then(|line| line.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn volume_is_read_back_from_the_last_line() {
+        assert_eq!(confirmed_volume(b"setting volume\n32768\n"), Some(32768));
+        assert_eq!(confirmed_volume(b"  1024  \r\n\n"), Some(1024));
+        assert_eq!(confirmed_volume(b"32768\ndone\n"), None);
+        assert_eq!(confirmed_volume(b"70000\n"), None);
+        assert_eq!(confirmed_volume(b""), None);
+    }
+
+    #[test]
+    fn percent_is_rounded() {
+        assert_eq!(to_percent(0), 0);
+        assert_eq!(to_percent(VolumeCtrl::MAX_VOLUME / 2), 50);
+        assert_eq!(to_percent(VolumeCtrl::MAX_VOLUME), 100);
+    }
+}
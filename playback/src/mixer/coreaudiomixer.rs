@@ -0,0 +1,505 @@
+use crate::player::{db_to_ratio, ratio_to_db};
+
+use super::mappings::MappedCtrl;
+use super::softmixer::SoftMixer;
+use super::{Mixer, MixerConfig, NoOpVolume, VolumeChangeCallback, VolumeCtrl, VolumeGetter};
+
+use coreaudio_sys::{
+    kAudioDevicePropertyMute, kAudioDevicePropertyScopeOutput,
+    kAudioDevicePropertyVolumeDecibelRange, kAudioDevicePropertyVolumeDecibelsToScalar,
+    kAudioDevicePropertyVolumeScalar, kAudioDevicePropertyVolumeScalarToDecibels,
+    kAudioHardwareNoError, kAudioHardwarePropertyDefaultOutputDevice,
+    kAudioObjectPropertyElementMaster, kAudioObjectPropertyScopeGlobal, kAudioObjectSystemObject,
+    AudioDeviceID, AudioObjectAddPropertyListener, AudioObjectGetPropertyData,
+    AudioObjectHasProperty, AudioObjectID, AudioObjectIsPropertySettable,
+    AudioObjectPropertyAddress, AudioObjectPropertySelector, AudioObjectRemovePropertyListener,
+    AudioObjectSetPropertyData, AudioValueRange, Boolean, OSStatus,
+};
+use thiserror::Error;
+
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr::null;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Devices round the volume scalar that is set, so a change smaller than this is our own.
+const SCALAR_TOLERANCE: f32 = 0.001;
+
+// The channels that are set when a device has no master volume or mute.
+const STEREO_ELEMENTS: [u32; 2] = [1, 2];
+
+#[derive(Debug, Error)]
+enum CoreAudioMixerError {
+    #[error("<CoreAudioMixer> Could not get the default output device, status {0}")]
+    DeviceNotAvailable(OSStatus),
+
+    #[error("<CoreAudioMixer> The output device has no volume that can be set")]
+    VolumeNotSettable,
+
+    #[error("<CoreAudioMixer> Could not get the dB range of the output device, status {0}")]
+    DecibelRangeNotAvailable(OSStatus),
+}
+
+pub struct CoreAudioMixer {
+    hardware: Option<Arc<HardwareVolume>>,
+    // Used instead when the device has no volume that can be set.
+    softvol: Option<SoftMixer>,
+}
+
+struct HardwareVolume {
+    device: AudioDeviceID,
+    // The master element, or the channels when there is no master volume.
+    elements: Vec<u32>,
+    // The same for the mute control, which may be missing.
+    mute_elements: Vec<u32>,
+    // The volume from before muting, when there is no mute control to mute with.
+    muted_volume: Mutex<Option<u16>>,
+    volume_ctrl: Mutex<VolumeCtrl>,
+    min_db: f32,
+    max_db: f32,
+    // The scalar that was last set or seen, to tell external changes from our own.
+    last_scalar: AtomicU32,
+    callback: Mutex<Option<VolumeChangeCallback>>,
+}
+
+impl Mixer for CoreAudioMixer {
+    fn open(config: MixerConfig) -> Self {
+        info!(
+            "Mixing with CoreAudio and volume control: {:?} for the default output device",
+            config.volume_ctrl
+        );
+
+        match unsafe { HardwareVolume::open(config.clone()) } {
+            Ok(hardware) => {
+                let hardware = Arc::new(hardware);
+                unsafe { hardware.add_listeners() };
+
+                Self {
+                    hardware: Some(hardware),
+                    softvol: None,
+                }
+            }
+            Err(e) => {
+                warn!("{}, falling back to softvol", e);
+
+                Self {
+                    hardware: None,
+                    softvol: Some(SoftMixer::open(config)),
+                }
+            }
+        }
+    }
+
+    fn volume(&self) -> u16 {
+        match (&self.hardware, &self.softvol) {
+            (Some(hardware), _) if hardware.is_muted() => 0,
+            (Some(hardware), _) => hardware.volume(hardware.scalar()),
+            (None, Some(softvol)) => softvol.volume(),
+            (None, None) => 0,
+        }
+    }
+
+    fn set_volume(&self, volume: u16) {
+        match (&self.hardware, &self.softvol) {
+            (Some(hardware), _) => {
+                if hardware.is_muted() {
+                    hardware.set_mute(false);
+                }
+                hardware.set_volume(volume);
+            }
+            (None, Some(softvol)) => softvol.set_volume(volume),
+            (None, None) => (),
+        }
+    }
+
+    fn set_mute(&self, mute: bool) {
+        match (&self.hardware, &self.softvol) {
+            (Some(hardware), _) => hardware.set_mute(mute),
+            (None, Some(softvol)) => softvol.set_mute(mute),
+            (None, None) => (),
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        match (&self.hardware, &self.softvol) {
+            (Some(hardware), _) => hardware.is_muted(),
+            (None, Some(softvol)) => softvol.is_muted(),
+            (None, None) => false,
+        }
+    }
+
+    fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
+        match self.softvol {
+            Some(ref softvol) => softvol.get_soft_volume(),
+            None => Box::new(NoOpVolume),
+        }
+    }
+
+    fn volume_ctrl(&self) -> VolumeCtrl {
+        match (&self.hardware, &self.softvol) {
+            (Some(hardware), _) => *hardware.volume_ctrl.lock().unwrap(),
+            (None, Some(softvol)) => softvol.volume_ctrl(),
+            (None, None) => VolumeCtrl::default(),
+        }
+    }
+
+    fn set_volume_ctrl(&self, volume_ctrl: VolumeCtrl) {
+        match (&self.hardware, &self.softvol) {
+            (Some(hardware), _) => {
+                // The device volume stays where it is and reads back as a different volume.
+                info!("CoreAudio volume control is now {:?}", volume_ctrl);
+                *hardware.volume_ctrl.lock().unwrap() = volume_ctrl;
+            }
+            (None, Some(softvol)) => softvol.set_volume_ctrl(volume_ctrl),
+            (None, None) => (),
+        }
+    }
+
+    fn set_volume_change_callback(&self, callback: VolumeChangeCallback) {
+        if let Some(ref hardware) = self.hardware {
+            *hardware.callback.lock().unwrap() = Some(callback);
+        }
+    }
+}
+
+impl Drop for CoreAudioMixer {
+    fn drop(&mut self) {
+        if let Some(ref hardware) = self.hardware {
+            unsafe { hardware.remove_listeners() };
+        }
+    }
+}
+
+impl CoreAudioMixer {
+    pub const NAME: &'static str = "coreaudio";
+}
+
+impl HardwareVolume {
+    unsafe fn open(mut config: MixerConfig) -> Result<Self, CoreAudioMixerError> {
+        let device = default_output_device().map_err(CoreAudioMixerError::DeviceNotAvailable)?;
+
+        let elements = settable_elements(device, kAudioDevicePropertyVolumeScalar);
+        if elements.is_empty() {
+            return Err(CoreAudioMixerError::VolumeNotSettable);
+        }
+
+        let mut range = AudioValueRange {
+            mMinimum: 0.0,
+            mMaximum: 0.0,
+        };
+        let mut size = mem::size_of::<AudioValueRange>() as u32;
+        let status = AudioObjectGetPropertyData(
+            device,
+            &volume_address(kAudioDevicePropertyVolumeDecibelRange, elements[0]),
+            0,
+            null(),
+            &mut size,
+            &mut range as *mut _ as *mut c_void,
+        );
+        if status != kAudioHardwareNoError as OSStatus {
+            return Err(CoreAudioMixerError::DecibelRangeNotAvailable(status));
+        }
+
+        let max_db = range.mMaximum as f32;
+        let reported_db_range = (range.mMaximum - range.mMinimum).abs();
+
+        // Like the Alsa mixer, use the range of the device unless it was set with a
+        // command line option, and then only down from its maximum.
+        let db_range = if config.volume_ctrl.range_ok() && config.volume_ctrl.db_range().is_normal()
+        {
+            config.volume_ctrl.db_range().min(reported_db_range)
+        } else {
+            config.volume_ctrl.set_db_range(reported_db_range);
+            reported_db_range
+        };
+        let min_db = max_db - db_range as f32;
+
+        let mute_elements = settable_elements(device, kAudioDevicePropertyMute);
+
+        debug!("CoreAudio volume elements: {:?}", elements);
+        debug!("CoreAudio mute elements: {:?}", mute_elements);
+        debug!(
+            "CoreAudio dB volume range: [{:.2}..{:.2}] ({:.2}), using [{:.2}..{:.2}]",
+            range.mMinimum, range.mMaximum, reported_db_range, min_db, max_db
+        );
+
+        let hardware = Self {
+            device,
+            elements,
+            mute_elements,
+            muted_volume: Mutex::new(None),
+            volume_ctrl: Mutex::new(config.volume_ctrl),
+            min_db,
+            max_db,
+            last_scalar: AtomicU32::new(0),
+            callback: Mutex::new(None),
+        };
+        hardware
+            .last_scalar
+            .store(hardware.scalar().to_bits(), Ordering::Relaxed);
+
+        Ok(hardware)
+    }
+
+    fn volume(&self, scalar: f32) -> u16 {
+        if scalar <= 0.0 {
+            return 0;
+        }
+
+        let db_volume = self.translate(kAudioDevicePropertyVolumeScalarToDecibels, scalar);
+        let mapped_volume = db_to_ratio((db_volume - self.max_db) as f64);
+        self.volume_ctrl.lock().unwrap().to_unmapped(mapped_volume)
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let scalar = if volume == 0 {
+            0.0
+        } else {
+            let mapped_volume = self.volume_ctrl.lock().unwrap().to_mapped(volume);
+            let db_volume = (ratio_to_db(mapped_volume) as f32 + self.max_db).max(self.min_db);
+            debug!("Setting CoreAudio volume to {:.2} dB", db_volume);
+            self.translate(kAudioDevicePropertyVolumeDecibelsToScalar, db_volume)
+        };
+
+        self.last_scalar.store(scalar.to_bits(), Ordering::Relaxed);
+
+        for &element in &self.elements {
+            let status = unsafe {
+                AudioObjectSetPropertyData(
+                    self.device,
+                    &volume_address(kAudioDevicePropertyVolumeScalar, element),
+                    0,
+                    null(),
+                    mem::size_of::<f32>() as u32,
+                    &scalar as *const _ as *const c_void,
+                )
+            };
+            if status != kAudioHardwareNoError as OSStatus {
+                error!("Could not set CoreAudio volume, status {}", status);
+            }
+        }
+    }
+
+    fn set_mute(&self, mute: bool) {
+        if self.mute_elements.is_empty() {
+            // Without a mute control, set the volume to 0 and back.
+            let mut muted_volume = self.muted_volume.lock().unwrap();
+            match (mute, *muted_volume) {
+                (true, None) => {
+                    *muted_volume = Some(self.volume(self.scalar()));
+                    self.set_volume(0);
+                }
+                (false, Some(volume)) => {
+                    *muted_volume = None;
+                    self.set_volume(volume);
+                }
+                _ => (),
+            }
+            return;
+        }
+
+        let value = mute as u32;
+        debug!("Setting CoreAudio mute to {}", mute);
+        for &element in &self.mute_elements {
+            let status = unsafe {
+                AudioObjectSetPropertyData(
+                    self.device,
+                    &volume_address(kAudioDevicePropertyMute, element),
+                    0,
+                    null(),
+                    mem::size_of::<u32>() as u32,
+                    &value as *const _ as *const c_void,
+                )
+            };
+            if status != kAudioHardwareNoError as OSStatus {
+                error!("Could not set CoreAudio mute, status {}", status);
+            }
+        }
+    }
+
+    fn is_muted(&self) -> bool {
+        let element = match self.mute_elements.first() {
+            Some(&element) => element,
+            None => return self.muted_volume.lock().unwrap().is_some(),
+        };
+
+        let mut value = 0u32;
+        let mut size = mem::size_of::<u32>() as u32;
+        let status = unsafe {
+            AudioObjectGetPropertyData(
+                self.device,
+                &volume_address(kAudioDevicePropertyMute, element),
+                0,
+                null(),
+                &mut size,
+                &mut value as *mut _ as *mut c_void,
+            )
+        };
+        if status != kAudioHardwareNoError as OSStatus {
+            error!("Could not get CoreAudio mute, status {}", status);
+        }
+        value != 0
+    }
+
+    fn scalar(&self) -> f32 {
+        let mut scalar = 0.0f32;
+        let mut size = mem::size_of::<f32>() as u32;
+        let status = unsafe {
+            AudioObjectGetPropertyData(
+                self.device,
+                &volume_address(kAudioDevicePropertyVolumeScalar, self.elements[0]),
+                0,
+                null(),
+                &mut size,
+                &mut scalar as *mut _ as *mut c_void,
+            )
+        };
+        if status != kAudioHardwareNoError as OSStatus {
+            error!("Could not get CoreAudio volume, status {}", status);
+        }
+        scalar
+    }
+
+    // Converts between the scalar and dB with the device's own curve.
+    fn translate(&self, selector: AudioObjectPropertySelector, value: f32) -> f32 {
+        let mut translated = value;
+        let mut size = mem::size_of::<f32>() as u32;
+        let status = unsafe {
+            AudioObjectGetPropertyData(
+                self.device,
+                &volume_address(selector, self.elements[0]),
+                0,
+                null(),
+                &mut size,
+                &mut translated as *mut _ as *mut c_void,
+            )
+        };
+        if status != kAudioHardwareNoError as OSStatus {
+            error!("Could not convert CoreAudio volume, status {}", status);
+        }
+        translated
+    }
+
+    fn handle_change(&self) {
+        let scalar = self.scalar();
+        let last_scalar =
+            f32::from_bits(self.last_scalar.swap(scalar.to_bits(), Ordering::Relaxed));
+        if (scalar - last_scalar).abs() < SCALAR_TOLERANCE {
+            return;
+        }
+
+        let volume = self.volume(scalar);
+        debug!("CoreAudio volume was changed externally to {}", volume);
+        if let Some(ref callback) = *self.callback.lock().unwrap() {
+            callback(volume);
+        }
+    }
+
+    // The listeners get a pointer to `self`, so they have to be removed before it is dropped.
+    unsafe fn add_listeners(self: &Arc<Self>) {
+        for &element in &self.elements {
+            let status = AudioObjectAddPropertyListener(
+                self.device,
+                &volume_address(kAudioDevicePropertyVolumeScalar, element),
+                Some(volume_listener),
+                Arc::as_ptr(self) as *mut c_void,
+            );
+            if status != kAudioHardwareNoError as OSStatus {
+                warn!(
+                    "Could not observe the CoreAudio volume, status {}, external changes are missed",
+                    status
+                );
+            }
+        }
+    }
+
+    unsafe fn remove_listeners(self: &Arc<Self>) {
+        for &element in &self.elements {
+            AudioObjectRemovePropertyListener(
+                self.device,
+                &volume_address(kAudioDevicePropertyVolumeScalar, element),
+                Some(volume_listener),
+                Arc::as_ptr(self) as *mut c_void,
+            );
+        }
+    }
+}
+
+unsafe extern "C" fn volume_listener(
+    _device: AudioObjectID,
+    _n_addresses: u32,
+    _addresses: *const AudioObjectPropertyAddress,
+    hardware: *mut c_void,
+) -> OSStatus {
+    let hardware = &*(hardware as *const HardwareVolume);
+    hardware.handle_change();
+    kAudioHardwareNoError as OSStatus
+}
+
+fn volume_address(
+    selector: AudioObjectPropertySelector,
+    element: u32,
+) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: kAudioDevicePropertyScopeOutput,
+        mElement: element,
+    }
+}
+
+// The master element if the property can be set on it, or else the channels it can be set on.
+unsafe fn settable_elements(
+    device: AudioDeviceID,
+    selector: AudioObjectPropertySelector,
+) -> Vec<u32> {
+    if is_settable(device, selector, kAudioObjectPropertyElementMaster) {
+        vec![kAudioObjectPropertyElementMaster]
+    } else {
+        STEREO_ELEMENTS
+            .iter()
+            .copied()
+            .filter(|&element| is_settable(device, selector, element))
+            .collect()
+    }
+}
+
+unsafe fn is_settable(
+    device: AudioDeviceID,
+    selector: AudioObjectPropertySelector,
+    element: u32,
+) -> bool {
+    let address = volume_address(selector, element);
+    if AudioObjectHasProperty(device, &address) == 0 {
+        return false;
+    }
+
+    let mut settable: Boolean = 0;
+    AudioObjectIsPropertySettable(device, &address, &mut settable)
+        == kAudioHardwareNoError as OSStatus
+        && settable != 0
+}
+
+unsafe fn default_output_device() -> Result<AudioDeviceID, OSStatus> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioHardwarePropertyDefaultOutputDevice,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+
+    let mut device: AudioDeviceID = 0;
+    let mut size = mem::size_of::<AudioDeviceID>() as u32;
+    let status = AudioObjectGetPropertyData(
+        kAudioObjectSystemObject,
+        &address,
+        0,
+        null(),
+        &mut size,
+        &mut device as *mut _ as *mut c_void,
+    );
+    if status != kAudioHardwareNoError as OSStatus {
+        return Err(status);
+    }
+
+    Ok(device)
+}
@@ -5,6 +5,8 @@ use self::mappings::MappedCtrl;
 
 pub struct NoOpVolume;
 
+pub type VolumeChangeCallback = Box<dyn Fn(u16) + Send>;
+
 pub trait Mixer: Send {
     fn open(config: MixerConfig) -> Self
     where
@@ -13,9 +15,40 @@ pub trait Mixer: Send {
     fn set_volume(&self, volume: u16);
     fn volume(&self) -> u16;
 
+    fn volume_ctrl(&self) -> VolumeCtrl;
+
+    // Switches to another volume control curve at the same loudness, so that `volume`
+    // afterwards returns where the current loudness is on the new curve.
+    fn set_volume_ctrl(&self, volume_ctrl: VolumeCtrl);
+
+    // Mutes without losing the volume: while muted `volume` returns 0, and unmuting goes
+    // back to the volume from before. Setting the volume unmutes. Mixers use the mute
+    // control of the hardware where there is one.
+    fn set_mute(&self, mute: bool);
+    fn is_muted(&self) -> bool;
+
+    fn mute(&self) {
+        self.set_mute(true);
+    }
+
+    fn unmute(&self) {
+        self.set_mute(false);
+    }
+
+    // Returns whether it is muted now.
+    fn toggle_mute(&self) -> bool {
+        let mute = !self.is_muted();
+        self.set_mute(mute);
+        mute
+    }
+
     fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
         Box::new(NoOpVolume)
     }
+
+    // Mixers that can observe the volume being changed outside of librespot call this
+    // with the new volume. Our own changes are not reported.
+    fn set_volume_change_callback(&self, _callback: VolumeChangeCallback) {}
 }
 
 pub trait VolumeGetter {
@@ -31,17 +64,31 @@ impl VolumeGetter for NoOpVolume {
 pub mod softmixer;
 use self::softmixer::SoftMixer;
 
+pub mod commandmixer;
+use self::commandmixer::CommandMixer;
+
 #[cfg(feature = "alsa-backend")]
 pub mod alsamixer;
 #[cfg(feature = "alsa-backend")]
 use self::alsamixer::AlsaMixer;
 
+#[cfg(all(target_os = "macos", feature = "coreaudio-mixer"))]
+pub mod coreaudiomixer;
+#[cfg(all(target_os = "macos", feature = "coreaudio-mixer"))]
+use self::coreaudiomixer::CoreAudioMixer;
+
 #[derive(Debug, Clone)]
 pub struct MixerConfig {
     pub device: String,
     pub control: String,
     pub index: u32,
     pub volume_ctrl: VolumeCtrl,
+    // The executable that the command mixer runs on every volume change.
+    pub command: Option<String>,
+    // Whether the command mixer runs the command for the volume that is set at startup too.
+    pub command_initial: bool,
+    // Whether the command mixer applies softvol in addition to running the command.
+    pub command_softvol: bool,
 }
 
 impl Default for MixerConfig {
@@ -51,6 +98,9 @@ impl Default for MixerConfig {
             control: String::from("PCM"),
             index: 0,
             volume_ctrl: VolumeCtrl::default(),
+            command: None,
+            command_initial: false,
+            command_softvol: false,
         }
     }
 }
@@ -65,6 +115,9 @@ pub const MIXERS: &[(&str, MixerFn)] = &[
     (SoftMixer::NAME, mk_sink::<SoftMixer>), // default goes first
     #[cfg(feature = "alsa-backend")]
     (AlsaMixer::NAME, mk_sink::<AlsaMixer>),
+    #[cfg(all(target_os = "macos", feature = "coreaudio-mixer"))]
+    (CoreAudioMixer::NAME, mk_sink::<CoreAudioMixer>),
+    (CommandMixer::NAME, mk_sink::<CommandMixer>),
 ];
 
 pub fn find(name: Option<&str>) -> Option<MixerFn> {
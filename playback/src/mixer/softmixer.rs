@@ -1,5 +1,5 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use super::VolumeGetter;
 use super::{MappedCtrl, VolumeCtrl};
@@ -10,7 +10,9 @@ pub struct SoftMixer {
     // There is no AtomicF64, so we store the f64 as bits in a u64 field.
     // It's much faster than a Mutex<f64>.
     volume: Arc<AtomicU64>,
-    volume_ctrl: VolumeCtrl,
+    // The volume is kept while muted, to go back to.
+    muted: Arc<AtomicBool>,
+    volume_ctrl: Arc<Mutex<VolumeCtrl>>,
 }
 
 impl Mixer for SoftMixer {
@@ -20,23 +22,50 @@ impl Mixer for SoftMixer {
 
         Self {
             volume: Arc::new(AtomicU64::new(f64::to_bits(0.5))),
-            volume_ctrl,
+            muted: Arc::new(AtomicBool::new(false)),
+            volume_ctrl: Arc::new(Mutex::new(volume_ctrl)),
         }
     }
 
     fn volume(&self) -> u16 {
+        if self.is_muted() {
+            return 0;
+        }
+
         let mapped_volume = f64::from_bits(self.volume.load(Ordering::Relaxed));
-        self.volume_ctrl.to_unmapped(mapped_volume)
+        self.volume_ctrl.lock().unwrap().to_unmapped(mapped_volume)
     }
 
     fn set_volume(&self, volume: u16) {
-        let mapped_volume = self.volume_ctrl.to_mapped(volume);
+        let mapped_volume = self.volume_ctrl.lock().unwrap().to_mapped(volume);
         self.volume
-            .store(mapped_volume.to_bits(), Ordering::Relaxed)
+            .store(mapped_volume.to_bits(), Ordering::Relaxed);
+        self.muted.store(false, Ordering::Relaxed);
+    }
+
+    fn volume_ctrl(&self) -> VolumeCtrl {
+        *self.volume_ctrl.lock().unwrap()
+    }
+
+    fn set_volume_ctrl(&self, volume_ctrl: VolumeCtrl) {
+        // The attenuation is kept as it is, only its mapping to the volume changes.
+        info!("Softvol volume control is now {:?}", volume_ctrl);
+        *self.volume_ctrl.lock().unwrap() = volume_ctrl;
+    }
+
+    fn set_mute(&self, mute: bool) {
+        self.muted.store(mute, Ordering::Relaxed);
+    }
+
+    fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
     }
 
     fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
-        Box::new(SoftVolume(self.volume.clone()))
+        Box::new(SoftVolume {
+            volume: self.volume.clone(),
+            muted: self.muted.clone(),
+        })
     }
 }
 
@@ -44,10 +73,63 @@ impl SoftMixer {
     pub const NAME: &'static str = "softvol";
 }
 
-struct SoftVolume(Arc<AtomicU64>);
+struct SoftVolume {
+    volume: Arc<AtomicU64>,
+    muted: Arc<AtomicBool>,
+}
 
 impl VolumeGetter for SoftVolume {
     fn attenuation_factor(&self) -> f64 {
-        f64::from_bits(self.0.load(Ordering::Relaxed))
+        if self.muted.load(Ordering::Relaxed) {
+            return 0.0;
+        }
+
+        f64::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changing_volume_ctrl_keeps_loudness() {
+        let mixer = SoftMixer::open(MixerConfig {
+            volume_ctrl: VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE),
+            ..MixerConfig::default()
+        });
+        let soft_volume = mixer.get_soft_volume();
+
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME / 2);
+        let attenuation = soft_volume.attenuation_factor();
+
+        mixer.set_volume_ctrl(VolumeCtrl::Linear);
+        assert_eq!(soft_volume.attenuation_factor(), attenuation);
+
+        // Half way on the log curve is much quieter than half way on a linear one.
+        let volume = mixer.volume();
+        assert!(volume < VolumeCtrl::MAX_VOLUME / 2);
+        assert!((volume as f64 / VolumeCtrl::MAX_VOLUME as f64 - attenuation).abs() < 0.001);
+    }
+
+    #[test]
+    fn mute_keeps_volume() {
+        let mixer = SoftMixer::open(MixerConfig::default());
+        let soft_volume = mixer.get_soft_volume();
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME / 2);
+        let volume = mixer.volume();
+        let attenuation = soft_volume.attenuation_factor();
+
+        assert!(mixer.toggle_mute());
+        assert_eq!(mixer.volume(), 0);
+        assert_eq!(soft_volume.attenuation_factor(), 0.0);
+
+        mixer.unmute();
+        assert_eq!(mixer.volume(), volume);
+        assert_eq!(soft_volume.attenuation_factor(), attenuation);
+
+        mixer.mute();
+        mixer.set_volume(VolumeCtrl::MAX_VOLUME);
+        assert!(!mixer.is_muted());
     }
 }
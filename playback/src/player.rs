@@ -3,6 +3,8 @@ use std::future::Future;
 use std::io::{self, Read, Seek, SeekFrom};
 use std::pin::Pin;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 use std::{mem, thread};
@@ -17,15 +19,29 @@ use crate::audio::{
     READ_AHEAD_BEFORE_PLAYBACK, READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS, READ_AHEAD_DURING_PLAYBACK,
     READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS,
 };
-use crate::audio_backend::Sink;
-use crate::config::{Bitrate, NormalisationMethod, NormalisationType, PlayerConfig};
+use crate::audio_backend::{RouteChange, Sink, SinkError, TrackMetadata};
+use crate::config::{
+    Bitrate, NormalisationMethod, NormalisationType, PlayerConfig, UnavailableTrackPolicy,
+    VolumeCtrl, MAX_PRELOAD_DEPTH,
+};
 use crate::convert::Converter;
+use crate::core::audio_key::AudioKeyError;
+use crate::core::config::{ContextKind, DeviceType, EndOfContextAction, RepeatMode};
+use crate::core::rate_limit::RequestPriority;
 use crate::core::session::Session;
-use crate::core::spotify_id::SpotifyId;
+use crate::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
 use crate::core::util::SeqGenerator;
-use crate::decoder::{AudioDecoder, AudioPacket, DecoderError, PassthroughDecoder, VorbisDecoder};
-use crate::metadata::{AudioItem, FileFormat};
+#[cfg(feature = "aac-decoder")]
+use crate::decoder::AacDecoder;
+use crate::decoder::{
+    detect_codec, AudioCodec, AudioDecoder, AudioPacket, DecoderError, DecoderResult,
+    PassthroughDecoder, VorbisDecoder, SPOTIFY_OGG_HEADER_END,
+};
+use crate::levels::{LevelMeter, Levels};
+use crate::metadata::{AudioItem, FileFormat, Lyrics, LyricsLine};
 use crate::mixer::VolumeGetter;
+use crate::prefetch::{PrefetchError, PrefetchReport};
+use crate::stretch::{TimeStretcher, MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED};
 
 use crate::{MS_PER_PAGE, NUM_CHANNELS, PAGES_PER_MS, SAMPLES_PER_SECOND};
 
@@ -33,10 +49,19 @@ const PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS: u32 = 30000;
 pub const DB_VOLTAGE_RATIO: f64 = 20.0;
 pub const PCM_AT_0DBFS: f64 = 1.0;
 
+// How often the latency of the sink is queried while playing, as it changes with how full
+// its buffer is.
+const SINK_LATENCY_INTERVAL: Duration = Duration::from_millis(500);
+
+// How far apart attempts to open the sink get while waiting for it with
+// `PlayerConfig::wait_for_sink`.
+const SINK_RETRY_MAX_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct Player {
     commands: Option<mpsc::UnboundedSender<PlayerCommand>>,
     thread_handle: Option<thread::JoinHandle<()>>,
     play_request_id_generator: SeqGenerator<u64>,
+    position: Arc<Mutex<Option<PositionAnchor>>>,
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -46,7 +71,39 @@ pub enum SinkStatus {
     TemporarilyClosed,
 }
 
-pub type SinkEventCallback = Box<dyn Fn(SinkStatus) + Send>;
+// Why the sink status changed.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SinkStatusReason {
+    // Playback started or resumed.
+    Play,
+    Pause,
+    // Playback stopped, or the sink is closed while the next track loads.
+    Stop,
+    Error,
+    // The track is loaded again in a different file format, see `BitrateChanged`.
+    FormatChange,
+    // The sink could not be opened at startup, see `PlayerConfig::wait_for_sink`, or it is
+    // running again because the device is free again, with nothing playing yet.
+    Unavailable,
+    // The output route of the sink changed, such as to a new default device or because
+    // headphones were unplugged, and it keeps running. See `PlayerEvent::RouteChanged`.
+    DeviceChange,
+}
+
+pub type SinkEventCallback = Box<dyn Fn(SinkStatus, SinkStatusReason) + Send>;
+
+// Where a volume change came from.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum VolumeSource {
+    // Connect, or the `Spirc` API.
+    Connect,
+    // The mixer observed a change made outside of librespot.
+    External,
+    // The volume control curve was changed, the same loudness is now at another volume.
+    Remapped,
+    // The mixer was muted or unmuted, see `Mixer::set_mute`.
+    Mute,
+}
 
 struct PlayerInternal {
     session: Session,
@@ -55,17 +112,92 @@ struct PlayerInternal {
 
     state: PlayerState,
     preload: PlayerPreload,
+    // Tracks after the preloaded one that are being downloaded into the cache.
+    prefetches: Vec<PlayerPrefetch>,
+    // Tracks that were downloaded into the cache ahead of time and haven't been played since.
+    prefetched: Vec<(SpotifyId, FileId)>,
+    preload_budget: PreloadBudget,
     sink: Box<dyn Sink>,
     sink_status: SinkStatus,
+    sink_hold: Option<SinkHold>,
+    sink_latency: Option<Duration>,
+    sink_latency_queried: Option<Instant>,
     sink_event_callback: Option<SinkEventCallback>,
     volume_getter: Box<dyn VolumeGetter + Send>,
     event_senders: Vec<mpsc::UnboundedSender<PlayerEvent>>,
+    position: Arc<Mutex<Option<PositionAnchor>>>,
+    next_progress: Option<Instant>,
+    next_metrics: Option<Instant>,
+    buffer_ms: u32,
+    // Measures what is played when `PlayerConfig::levels_interval` is set.
+    level_meter: Option<LevelMeter>,
     converter: Converter,
 
     normalisation_integrator: f64,
     normalisation_peak: f64,
+    normalisation_limiting: bool,
+    normalisation_bypass: bool,
+    limiting_warnings: LimitingWarnings,
 
     auto_normalise_as_album: bool,
+
+    session_counters: AtomicPlaybackCounters,
+    track_counters: AtomicPlaybackCounters,
+
+    bitrate: Bitrate,
+    bitrate_stalls: u32,
+
+    load_attempts: u32,
+    consecutive_skips: u32,
+
+    lyrics: Vec<LyricsLine>,
+    next_lyrics_line: usize,
+
+    time_stretcher: TimeStretcher,
+
+    sink_recovery: Option<SinkRecovery>,
+    // Whether to try the sink once the commands sent at startup are handled.
+    sink_probe_pending: bool,
+
+    stop_after_current: bool,
+
+    restrictions: Restrictions,
+    context_uri: String,
+    context_kind: ContextKind,
+
+    sleep_timer: Option<SleepTimer>,
+    fade: Option<Fade>,
+}
+
+// A gain ramp on the output, towards full volume or towards silence and a pause.
+struct Fade {
+    gain: f64,
+    // Added to the gain with every frame, negative while fading out.
+    step: f64,
+    // Fading out because the sleep timer went off, rather than for a pause.
+    sleep: bool,
+}
+
+struct SleepTimer {
+    deadline: Instant,
+    // The deadline that a thread was started to wake the player up for.
+    wake_scheduled_for: Option<Instant>,
+}
+
+// The sink stays open during a pause until `close_at`, or for as long as the pause lasts
+// if that is `None`, so that resuming doesn't need to open the device again.
+struct SinkHold {
+    close_at: Option<Instant>,
+    wake_scheduled: bool,
+}
+
+// Playback is paused after a sink error until the sink can be started again.
+struct SinkRecovery {
+    started: Instant,
+    next_attempt: Instant,
+    // Between the attempts, which only grows with `PlayerConfig::wait_for_sink`.
+    interval: Duration,
+    wake_scheduled: bool,
 }
 
 enum PlayerCommand {
@@ -78,14 +210,41 @@ enum PlayerCommand {
     Preload {
         track_id: SpotifyId,
     },
+    PreloadUpcoming {
+        track_ids: Vec<SpotifyId>,
+    },
     Play,
     Pause,
     Stop,
     Seek(u32),
     AddEventSender(mpsc::UnboundedSender<PlayerEvent>),
     SetSinkEventCallback(Option<SinkEventCallback>),
-    EmitVolumeSetEvent(u16),
+    EmitVolumeSetEvent(u16, VolumeSource, VolumeCtrl),
+    EmitContextEndedEvent(EndOfContextAction),
+    EmitContextChangedEvent(String, Option<String>, ContextKind),
+    EmitQueueChangedEvent(Vec<String>),
+    EmitShuffleChangedEvent(bool),
+    EmitRepeatChangedEvent(RepeatMode),
+    EmitAutoplayChangedEvent(bool),
+    EmitMuteChangedEvent(bool),
+    EmitPlaybackTakenOverEvent(Option<String>, Option<DeviceType>),
+    EmitBecameActiveEvent,
+    EmitBecameInactiveEvent,
+    EmitControlBlockedEvent(String, String, String),
+    EmitFilterExplicitContentChangedEvent(bool),
+    EmitDeviceInfoChangedEvent(String, DeviceType),
+    EmitTrackFilteredEvent(SpotifyId, TrackFilterReason),
+    EmitExplicitContentStoppedEvent,
+    EmitSkippedLocalFileEvent(String),
     SetAutoNormaliseAsAlbum(bool),
+    SetNormalisationBypass(bool),
+    SetPlaybackSpeed(f32),
+    SetStopAfterCurrent(bool),
+    SetRestrictions(Restrictions),
+    SetSleepTimer(Duration),
+    ExtendSleepTimer(Duration),
+    CancelSleepTimer,
+    GetStatistics(oneshot::Sender<PlaybackStatistics>),
 }
 
 #[derive(Debug, Clone)]
@@ -101,12 +260,20 @@ pub enum PlayerEvent {
         play_request_id: u64,
         track_id: SpotifyId,
         position_ms: u32,
+        context_uri: String,
+        context_kind: ContextKind,
+        restrictions: Restrictions,
     },
     // Same as started but in the case that the player already had a track loaded.
     // The player was either playing the loaded track or it was paused.
+    // It is also sent once a track is loaded when it is not playable in the user's country
+    // and one of its alternatives is played instead, from the requested track to that one.
     Changed {
         old_track_id: SpotifyId,
         new_track_id: SpotifyId,
+        context_uri: String,
+        context_kind: ContextKind,
+        restrictions: Restrictions,
     },
     // The player is delayed by loading a track.
     Loading {
@@ -117,6 +284,8 @@ pub enum PlayerEvent {
     // The player is preloading a track.
     Preloading {
         track_id: SpotifyId,
+        // Where the track is in the upcoming tracks, 1 being the next one.
+        queue_position: usize,
     },
     // The player is playing a track.
     // This event is issued at the start of playback of whenever the position must be communicated
@@ -150,16 +319,309 @@ pub enum PlayerEvent {
     EndOfTrack {
         play_request_id: u64,
         track_id: SpotifyId,
+        statistics: PlaybackStatistics,
     },
     // The player was unable to load the requested track.
     Unavailable {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    // The player gave up on a track that could not be loaded and moves on to the next one.
+    // This is sent after "Unavailable". Spirc will respond by loading the next track.
+    TrackSkipped {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        reason: TrackSkipReason,
+        retries: u32,
+    },
+    // The player seeked within the current track. `requested_position_ms` is set if the
+    // requested position was outside of the track and `position_ms` was clamped to it.
+    Seeked {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        position_ms: u32,
+        requested_position_ms: Option<u32>,
+        duration_ms: u32,
+    },
+    // The position while playing, sent every `PlayerConfig::progress_interval` unless another
+    // event reported the position in the meantime.
+    Progress {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        position_ms: u32,
+        duration_ms: u32,
+    },
+    // A seek landed on a different position than the one that was reported in "Seeked",
+    // for example because the decoder can only seek to the start of a page.
+    PositionCorrection {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        position_ms: u32,
+    },
+    // Playback reached a line of the track's synced lyrics.
+    // This is only sent if `PlayerConfig::lyrics_events` is enabled.
+    LyricsLine {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        start_time_ms: u32,
+        words: String,
+    },
     // The mixer volume was set to a new level.
     VolumeSet {
         volume: u16,
+        source: VolumeSource,
+        // The volume control curve in effect.
+        volume_ctrl: VolumeCtrl,
+    },
+    // The file of a track was opened, either when loading it or when a preloaded track is played.
+    Loaded {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        file_format: FileFormat,
+        codec: AudioCodec,
+    },
+    // The player switched to a different bitrate, either because playback of the current
+    // track was struggling or because a new track restored the configured bitrate.
+    BitrateChanged {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        old_bitrate: Bitrate,
+        new_bitrate: Bitrate,
+        reason: BitrateChangeReason,
+    },
+    // Writing to the audio sink failed and playback was paused at `position_ms`. It resumes
+    // once the sink can be started again, or stops after `PlayerConfig::sink_retry_timeout`.
+    SinkError {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        position_ms: u32,
+        error: String,
+    },
+    // Requests for parts of the track failed and are being retried. Playback continues
+    // unless the retries run out, in which case the track is skipped.
+    FetchDegraded {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        retries: usize,
     },
+    // The playback speed was changed. Positions remain in track time, so the playback
+    // position now advances `speed` times as fast as the wall clock.
+    PlaybackSpeedChanged {
+        speed: f32,
+    },
+    // Normalisation was bypassed or applied again, see `Player::set_normalisation_bypass`.
+    NormalisationBypassChanged {
+        bypass: bool,
+    },
+    // Stopping after the current track was armed or disarmed. It disarms itself once the
+    // current track ends, right after "EndOfTrack".
+    StopAfterCurrentChanged {
+        armed: bool,
+    },
+    // The last track of the context has played and spirc went on with `action`.
+    ContextEnded {
+        action: EndOfContextAction,
+    },
+    // What is played from changed, because a new context was loaded by a controller or through
+    // `Spirc::load_context`, or because a queued track or autoplay is next. `context_name` is
+    // the name of the context, if it is known.
+    ContextChanged {
+        context_uri: String,
+        context_name: Option<String>,
+        context_kind: ContextKind,
+    },
+    // Tracks were queued or unqueued through `Spirc`. `uris` is what is queued now, in order.
+    QueueChanged {
+        uris: Vec<String>,
+    },
+    // Shuffle was turned on or off, by a controller or through `Spirc::set_shuffle`.
+    ShuffleChanged {
+        shuffle: bool,
+    },
+    // The repeat mode changed, by a controller or through `Spirc::set_repeat`.
+    RepeatChanged {
+        repeat: RepeatMode,
+    },
+    // Autoplay at the end of a context was turned on or off through `Spirc::set_autoplay`.
+    AutoplayChanged {
+        autoplay: bool,
+    },
+    // The mixer was muted or unmuted through `Spirc::set_mute`. The volume it goes back to
+    // when unmuted is kept, while `VolumeSet` reports the volume that is heard.
+    MuteChanged {
+        muted: bool,
+    },
+    // Another device on the account took over playback, so this one stopped and let go of
+    // the sink. Its name and type are given as far as they are known.
+    PlaybackTakenOver {
+        device_name: Option<String>,
+        device_type: Option<DeviceType>,
+    },
+    // This device became the active one, and plays what is loaded.
+    BecameActive,
+    // This device is no longer the active one, because another one took over playback or
+    // because it shut down. It stays active while its session is reconnected.
+    BecameInactive,
+    // Skipping explicit tracks was turned on or off, through `Spirc::set_filter_explicit_content`
+    // or in the settings of the account.
+    FilterExplicitContentChanged {
+        filter: bool,
+    },
+    // The name or type of the device was changed through `Spirc::set_device_name` or
+    // `Spirc::set_device_type`.
+    DeviceInfoChanged {
+        name: String,
+        device_type: DeviceType,
+    },
+    // A track was skipped instead of played, because it is filtered.
+    TrackFiltered {
+        track_id: SpotifyId,
+        reason: TrackFilterReason,
+    },
+    // Playback stopped because every track that is left to play is explicit, and explicit
+    // content is filtered.
+    ExplicitContentStopped,
+    // A local file of the user, which is on their own device and can't be streamed, was
+    // skipped. They aren't played at all.
+    SkippedLocalFile {
+        uri: String,
+    },
+    // What the context allows changed while a track was loaded, see `Player::set_restrictions`.
+    RestrictionsChanged {
+        restrictions: Restrictions,
+    },
+    // Another device tried to control this one, and was turned away because it is not allowed
+    // to, see `ConnectConfig::allowed_controllers`.
+    ControlBlocked {
+        ident: String,
+        name: String,
+        command: String,
+    },
+    // How playback is doing, sent every `PlayerConfig::metrics_interval` while playing: how
+    // much of the playing track is downloaded ahead of where it is decoded, the counters since
+    // the player was created, and the reconnects of the session, see `Session::reconnects`.
+    MetricsUpdated {
+        buffer_ms: u32,
+        counters: PlaybackCounters,
+        reconnects: u64,
+    },
+    // The levels of what was played, sent every `PlayerConfig::levels_interval` of audio.
+    Levels {
+        levels: Levels,
+    },
+    // The sleep timer was set or extended and fires in `remaining_ms`.
+    SleepTimerArmed {
+        remaining_ms: u32,
+    },
+    // The sleep timer went off. If the player is playing, the volume ramps down over
+    // `PlayerConfig::sleep_timer_fade` and then the player pauses, which is sent as "Paused".
+    SleepTimerFired,
+    // The sleep timer was cancelled before it went off, or while the volume was ramping down.
+    SleepTimerCancelled,
+    // The sink reported that its output route changed. `paused` is set when playback paused
+    // because of it, see `PlayerConfig::pause_on_route_change`.
+    RouteChanged {
+        change: RouteChange,
+        paused: bool,
+    },
+}
+
+/// Where playback is, as returned by `Player::current_position`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlaybackPosition {
+    pub play_request_id: u64,
+    pub track_id: SpotifyId,
+    pub position_ms: u32,
+    /// The duration of the track, or 0 while it is loading.
+    pub duration_ms: u32,
+    pub state: PlaybackState,
+    /// How many times as fast as the wall clock the position advances while playing.
+    pub speed: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackState {
+    Loading,
+    Playing,
+    Paused,
+}
+
+// The position last reported in an event, which `Player::current_position` advances from.
+#[derive(Debug, Clone, Copy)]
+struct PositionAnchor {
+    position: PlaybackPosition,
+    at: Instant,
+}
+
+impl PositionAnchor {
+    fn new(position: PlaybackPosition) -> Self {
+        Self {
+            position,
+            at: Instant::now(),
+        }
+    }
+
+    fn current(&self) -> PlaybackPosition {
+        let mut position = self.position;
+        if position.state == PlaybackState::Playing {
+            let elapsed_ms = self.at.elapsed().as_secs_f64() * 1000.0 * position.speed as f64;
+            position.position_ms =
+                (position.position_ms as f64 + elapsed_ms).min(position.duration_ms as f64) as u32;
+        }
+        position
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackSkipReason {
+    // The track and its alternatives are not playable in the user's country, or its audio
+    // key was denied.
+    Unavailable,
+    // The track could not be loaded, e.g. because its audio key requests failed.
+    LoadFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackFilterReason {
+    // The track is explicit, and explicit content is filtered on this device.
+    Explicit,
+    // The track is explicit, and the account has explicit content filtered.
+    AccountExplicit,
+    // Autoplay picked the track, and `Spirc::set_autoplay_veto` rejected it.
+    AutoplayVeto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitrateChangeReason {
+    // Playback fell behind more often than `PlayerConfig::adaptive_bitrate_max_stalls`.
+    BufferingStalls,
+    // Loading the track took longer than `PlayerConfig::adaptive_bitrate_load_deadline`.
+    SlowInitialFetch,
+    // A new track was loaded at the configured bitrate.
+    Restored,
+}
+
+// What the context that is playing allows controllers to do. Stations, for one, can't be
+// skipped back in or repeated. Contexts that say nothing about it allow everything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Restrictions {
+    pub can_skip_next: bool,
+    pub can_skip_prev: bool,
+    pub can_seek: bool,
+    pub can_repeat: bool,
+    pub can_shuffle: bool,
+}
+
+impl Default for Restrictions {
+    fn default() -> Self {
+        Self {
+            can_skip_next: true,
+            can_skip_prev: true,
+            can_seek: true,
+            can_repeat: true,
+            can_shuffle: true,
+        }
+    }
 }
 
 impl PlayerEvent {
@@ -172,6 +634,9 @@ impl PlayerEvent {
             | Unavailable {
                 play_request_id, ..
             }
+            | TrackSkipped {
+                play_request_id, ..
+            }
             | Started {
                 play_request_id, ..
             }
@@ -189,14 +654,128 @@ impl PlayerEvent {
             }
             | Stopped {
                 play_request_id, ..
+            }
+            | BitrateChanged {
+                play_request_id, ..
+            }
+            | LyricsLine {
+                play_request_id, ..
+            }
+            | FetchDegraded {
+                play_request_id, ..
+            }
+            | Seeked {
+                play_request_id, ..
+            }
+            | PositionCorrection {
+                play_request_id, ..
+            }
+            | Progress {
+                play_request_id, ..
+            }
+            | SinkError {
+                play_request_id, ..
+            }
+            | Loaded {
+                play_request_id, ..
             } => Some(*play_request_id),
-            Changed { .. } | Preloading { .. } | VolumeSet { .. } => None,
+            Changed { .. }
+            | Preloading { .. }
+            | NormalisationBypassChanged { .. }
+            | VolumeSet { .. }
+            | PlaybackSpeedChanged { .. }
+            | StopAfterCurrentChanged { .. }
+            | ContextEnded { .. }
+            | ContextChanged { .. }
+            | QueueChanged { .. }
+            | ShuffleChanged { .. }
+            | RepeatChanged { .. }
+            | AutoplayChanged { .. }
+            | MuteChanged { .. }
+            | PlaybackTakenOver { .. }
+            | BecameActive
+            | BecameInactive
+            | MetricsUpdated { .. }
+            | Levels { .. }
+            | ControlBlocked { .. }
+            | FilterExplicitContentChanged { .. }
+            | DeviceInfoChanged { .. }
+            | TrackFiltered { .. }
+            | ExplicitContentStopped
+            | SkippedLocalFile { .. }
+            | RestrictionsChanged { .. }
+            | SleepTimerArmed { .. }
+            | SleepTimerFired
+            | SleepTimerCancelled
+            | RouteChanged { .. } => None,
         }
     }
 }
 
 pub type PlayerEventChannel = mpsc::UnboundedReceiver<PlayerEvent>;
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PlaybackCounters {
+    /// Number of PCM frames produced by the decoder.
+    pub decoded_frames: u64,
+    /// Number of packets or samples the decoder failed to produce.
+    pub decode_errors: u64,
+    /// Number of audio file bytes received from the network.
+    pub fetched_bytes: u64,
+    /// Number of audio file bytes served from the cache.
+    pub cache_hit_bytes: u64,
+    /// Number of times playback fell behind its nominal position by more than a second,
+    /// or the audio sink ran out of audio.
+    pub sink_underruns: u64,
+    /// Number of times the dynamic limiter started reducing gain.
+    pub limiter_engagements: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PlaybackStatistics {
+    /// Counters since the player was created.
+    pub session: PlaybackCounters,
+    /// Counters since the current track was loaded.
+    pub track: PlaybackCounters,
+    /// The latency last reported by the audio sink, if it reports one. Positions in
+    /// events are corrected by it.
+    pub sink_latency: Option<Duration>,
+}
+
+#[derive(Default)]
+struct AtomicPlaybackCounters {
+    decoded_frames: AtomicU64,
+    decode_errors: AtomicU64,
+    fetched_bytes: AtomicU64,
+    cache_hit_bytes: AtomicU64,
+    sink_underruns: AtomicU64,
+    limiter_engagements: AtomicU64,
+}
+
+impl AtomicPlaybackCounters {
+    fn load(&self) -> PlaybackCounters {
+        PlaybackCounters {
+            decoded_frames: self.decoded_frames.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            fetched_bytes: self.fetched_bytes.load(Ordering::Relaxed),
+            cache_hit_bytes: self.cache_hit_bytes.load(Ordering::Relaxed),
+            sink_underruns: self.sink_underruns.load(Ordering::Relaxed),
+            limiter_engagements: self.limiter_engagements.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.decoded_frames.store(0, Ordering::Relaxed);
+        self.decode_errors.store(0, Ordering::Relaxed);
+        self.fetched_bytes.store(0, Ordering::Relaxed);
+        self.cache_hit_bytes.store(0, Ordering::Relaxed);
+        self.sink_underruns.store(0, Ordering::Relaxed);
+        self.limiter_engagements.store(0, Ordering::Relaxed);
+    }
+}
+
+type PlaybackCounter = fn(&AtomicPlaybackCounters) -> &AtomicU64;
+
 pub fn db_to_ratio(db: f64) -> f64 {
     f64::powf(10.0, db / DB_VOLTAGE_RATIO)
 }
@@ -241,7 +820,11 @@ impl NormalisationData {
         Ok(r)
     }
 
-    fn get_factor(config: &PlayerConfig, data: NormalisationData) -> f64 {
+    fn get_factor(
+        config: &PlayerConfig,
+        data: NormalisationData,
+        warnings: &mut LimitingWarnings,
+    ) -> f64 {
         if !config.normalisation {
             return 1.0;
         }
@@ -279,23 +862,41 @@ impl NormalisationData {
             // For Dynamic Normalisation it's up to the player to decide,
             // factor = ratio of (ReplayGain + PreGain).
             // We then let the dynamic limiter handle gain reduction.
-            let factor = db_to_ratio(gain_db + config.normalisation_pregain_db);
+            let mut factor = db_to_ratio(gain_db + config.normalisation_pregain_db);
             let threshold_ratio = db_to_ratio(config.normalisation_threshold_dbfs);
 
-            if factor > PCM_AT_0DBFS {
-                let factor_db = gain_db + config.normalisation_pregain_db;
-                let limiting_db = factor_db + config.normalisation_threshold_dbfs.abs();
-
-                warn!(
-                    "This track may exceed dBFS by {:.2} dB and be subject to {:.2} dB of dynamic limiting at it's peak.",
-                    factor_db, limiting_db
+            // A track that would peak above dBFS is lowered to peak at it instead, leaving
+            // the limiter only what is between the threshold and dBFS.
+            let mut lowered = false;
+            if config.normalisation_auto_pregain && factor * gain_peak > PCM_AT_0DBFS {
+                let peak_factor = PCM_AT_0DBFS / gain_peak;
+                debug!(
+                    "Lowering pregain by {:.2} dB for the duration of this track to avoid exceeding dBFS.",
+                    ratio_to_db(factor / peak_factor)
                 );
-            } else if factor > threshold_ratio {
-                let limiting_db = gain_db
-                    + config.normalisation_pregain_db
-                    + config.normalisation_threshold_dbfs.abs();
+                factor = peak_factor;
+                lowered = true;
+            }
 
-                info!(
+            let factor_db = ratio_to_db(factor);
+            let limiting_db = factor_db + config.normalisation_threshold_dbfs.abs();
+            if factor > PCM_AT_0DBFS && !lowered {
+                match warnings.allow(config.normalisation_warnings, Instant::now()) {
+                    Some(0) => warn!(
+                        "This track may exceed dBFS by {:.2} dB and be subject to {:.2} dB of dynamic limiting at it's peak.",
+                        factor_db, limiting_db
+                    ),
+                    Some(suppressed) => warn!(
+                        "This track may exceed dBFS by {:.2} dB and be subject to {:.2} dB of dynamic limiting at it's peak, as may {} tracks since the last warning.",
+                        factor_db, limiting_db, suppressed
+                    ),
+                    None => debug!(
+                        "This track may exceed dBFS by {:.2} dB and be subject to {:.2} dB of dynamic limiting at it's peak.",
+                        factor_db, limiting_db
+                    ),
+                }
+            } else if factor > threshold_ratio {
+                debug!(
                     "This track may be subject to {:.2} dB of dynamic limiting at it's peak.",
                     limiting_db
                 );
@@ -315,6 +916,33 @@ impl NormalisationData {
     }
 }
 
+// How often the warning about a track that may exceed dBFS is logged at most. The ones in
+// between are counted, and the count is given in the next one.
+const LIMITING_WARNING_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Default)]
+struct LimitingWarnings {
+    last: Option<Instant>,
+    suppressed: u32,
+}
+
+impl LimitingWarnings {
+    // Whether to log the warning now, and if so how many were left out since the last one.
+    fn allow(&mut self, enabled: bool, now: Instant) -> Option<u32> {
+        match self.last {
+            _ if !enabled => None,
+            Some(last) if now.duration_since(last) < LIMITING_WARNING_INTERVAL => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last = Some(now);
+                Some(mem::take(&mut self.suppressed))
+            }
+        }
+    }
+}
+
 impl Player {
     pub fn new<F>(
         config: PlayerConfig,
@@ -327,6 +955,7 @@ impl Player {
     {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        let position = Arc::new(Mutex::new(None));
 
         if config.normalisation {
             debug!("Normalisation Type: {:?}", config.normalisation_type);
@@ -354,10 +983,51 @@ impl Player {
             }
         }
 
+        let internal_position = position.clone();
         let handle = thread::spawn(move || {
             debug!("new Player[{}]", session.session_id());
 
+            let sink = sink_builder();
+            let mut config = config;
+            if config.passthrough {
+                if !sink.supports_passthrough() {
+                    error!(
+                        "The audio backend doesn't support passthrough, decoding tracks instead"
+                    );
+                    config.passthrough = false;
+                } else {
+                    if config.normalisation {
+                        warn!("Normalisation is disabled in passthrough mode");
+                        config.normalisation = false;
+                    }
+                    if config.playback_speed != 1.0 {
+                        warn!("The playback speed can't be changed in passthrough mode");
+                        config.playback_speed = 1.0;
+                    }
+                    info!("Passthrough mode: tracks are written as Ogg Vorbis, volume control has no effect");
+                }
+            }
+            if config.preload_depth > MAX_PRELOAD_DEPTH {
+                warn!(
+                    "Preloading at most {} tracks instead of {}",
+                    MAX_PRELOAD_DEPTH, config.preload_depth
+                );
+                config.preload_depth = MAX_PRELOAD_DEPTH;
+            }
+            if config.preload_depth > 1
+                && !matches!(session.cache(), Some(cache) if cache.caches_audio())
+            {
+                warn!("Preloading more than the next track needs an audio cache");
+                config.preload_depth = 1;
+            }
+            let preload_budget = PreloadBudget::new(config.preload_budget_bytes);
+
             let converter = Converter::new(config.ditherer);
+            let bitrate = config.bitrate;
+            let normalisation_bypass = config.normalisation_bypass;
+            let sink_probe_pending = config.wait_for_sink;
+            let level_meter = config.levels_interval.map(LevelMeter::new);
+            let time_stretcher = TimeStretcher::new(config.playback_speed);
 
             let internal = PlayerInternal {
                 session,
@@ -366,17 +1036,57 @@ impl Player {
 
                 state: PlayerState::Stopped,
                 preload: PlayerPreload::None,
-                sink: sink_builder(),
+                prefetches: Vec::new(),
+                prefetched: Vec::new(),
+                preload_budget,
+                sink,
                 sink_status: SinkStatus::Closed,
+                sink_hold: None,
+                sink_latency: None,
+                sink_latency_queried: None,
                 sink_event_callback: None,
                 volume_getter,
                 event_senders: [event_sender].to_vec(),
+                position: internal_position,
+                next_progress: None,
+                next_metrics: None,
+                buffer_ms: 0,
+                level_meter,
                 converter,
 
                 normalisation_peak: 0.0,
                 normalisation_integrator: 0.0,
+                normalisation_limiting: false,
+                limiting_warnings: LimitingWarnings::default(),
+                normalisation_bypass,
 
                 auto_normalise_as_album: false,
+
+                session_counters: AtomicPlaybackCounters::default(),
+                track_counters: AtomicPlaybackCounters::default(),
+
+                bitrate,
+                bitrate_stalls: 0,
+
+                load_attempts: 0,
+                consecutive_skips: 0,
+
+                lyrics: Vec::new(),
+                next_lyrics_line: 0,
+
+                time_stretcher,
+
+                sink_recovery: None,
+                sink_probe_pending,
+
+                stop_after_current: false,
+
+                restrictions: Restrictions::default(),
+                context_uri: String::new(),
+                context_kind: ContextKind::default(),
+
+                sleep_timer: None,
+                fade: None,
             };
 
             // While PlayerInternal is written as a future, it still contains blocking code.
@@ -390,6 +1100,7 @@ impl Player {
                 commands: Some(cmd_tx),
                 thread_handle: Some(handle),
                 play_request_id_generator: SeqGenerator::new(0),
+                position,
             },
             event_receiver,
         )
@@ -419,6 +1130,13 @@ impl Player {
         self.command(PlayerCommand::Preload { track_id });
     }
 
+    /// Preloads the first of the upcoming tracks like `preload`, and downloads the ones
+    /// after it into the audio cache, up to the configured preload depth. Tracks that are
+    /// left out since the last call, and were not played, are removed from the cache again.
+    pub fn preload_upcoming(&self, track_ids: Vec<SpotifyId>) {
+        self.command(PlayerCommand::PreloadUpcoming { track_ids });
+    }
+
     pub fn play(&self) {
         self.command(PlayerCommand::Play)
     }
@@ -457,13 +1175,161 @@ impl Player {
         self.command(PlayerCommand::SetSinkEventCallback(callback));
     }
 
-    pub fn emit_volume_set_event(&self, volume: u16) {
-        self.command(PlayerCommand::EmitVolumeSetEvent(volume));
+    pub fn emit_volume_set_event(
+        &self,
+        volume: u16,
+        source: VolumeSource,
+        volume_ctrl: VolumeCtrl,
+    ) {
+        self.command(PlayerCommand::EmitVolumeSetEvent(
+            volume,
+            source,
+            volume_ctrl,
+        ));
+    }
+
+    pub fn emit_context_ended_event(&self, action: EndOfContextAction) {
+        self.command(PlayerCommand::EmitContextEndedEvent(action));
+    }
+
+    /// Reports what the tracks loaded from now on are played from. `PlayerEvent::Started` and
+    /// `PlayerEvent::Changed` carry it too.
+    pub fn emit_context_changed_event(
+        &self,
+        context_uri: String,
+        context_name: Option<String>,
+        context_kind: ContextKind,
+    ) {
+        self.command(PlayerCommand::EmitContextChangedEvent(
+            context_uri,
+            context_name,
+            context_kind,
+        ));
+    }
+
+    pub fn emit_queue_changed_event(&self, uris: Vec<String>) {
+        self.command(PlayerCommand::EmitQueueChangedEvent(uris));
+    }
+
+    pub fn emit_shuffle_changed_event(&self, shuffle: bool) {
+        self.command(PlayerCommand::EmitShuffleChangedEvent(shuffle));
+    }
+
+    pub fn emit_repeat_changed_event(&self, repeat: RepeatMode) {
+        self.command(PlayerCommand::EmitRepeatChangedEvent(repeat));
+    }
+
+    pub fn emit_autoplay_changed_event(&self, autoplay: bool) {
+        self.command(PlayerCommand::EmitAutoplayChangedEvent(autoplay));
+    }
+
+    pub fn emit_mute_changed_event(&self, muted: bool) {
+        self.command(PlayerCommand::EmitMuteChangedEvent(muted));
+    }
+
+    pub fn emit_playback_taken_over_event(
+        &self,
+        device_name: Option<String>,
+        device_type: Option<DeviceType>,
+    ) {
+        self.command(PlayerCommand::EmitPlaybackTakenOverEvent(
+            device_name,
+            device_type,
+        ));
+    }
+
+    pub fn emit_became_active_event(&self) {
+        self.command(PlayerCommand::EmitBecameActiveEvent);
+    }
+
+    pub fn emit_became_inactive_event(&self) {
+        self.command(PlayerCommand::EmitBecameInactiveEvent);
+    }
+
+    pub fn emit_control_blocked_event(&self, ident: String, name: String, command: String) {
+        self.command(PlayerCommand::EmitControlBlockedEvent(ident, name, command));
+    }
+
+    pub fn emit_filter_explicit_content_changed_event(&self, filter: bool) {
+        self.command(PlayerCommand::EmitFilterExplicitContentChangedEvent(filter));
+    }
+
+    pub fn emit_device_info_changed_event(&self, name: String, device_type: DeviceType) {
+        self.command(PlayerCommand::EmitDeviceInfoChangedEvent(name, device_type));
+    }
+
+    pub fn emit_track_filtered_event(&self, track_id: SpotifyId, reason: TrackFilterReason) {
+        self.command(PlayerCommand::EmitTrackFilteredEvent(track_id, reason));
+    }
+
+    pub fn emit_explicit_content_stopped_event(&self) {
+        self.command(PlayerCommand::EmitExplicitContentStoppedEvent);
+    }
+
+    pub fn emit_skipped_local_file_event(&self, uri: String) {
+        self.command(PlayerCommand::EmitSkippedLocalFileEvent(uri));
     }
 
     pub fn set_auto_normalise_as_album(&self, setting: bool) {
         self.command(PlayerCommand::SetAutoNormaliseAsAlbum(setting));
     }
+
+    /// Plays at unity gain instead of normalising, or normalises again, from the next packet
+    /// on. Volume is applied either way. It has no effect if normalisation is off.
+    pub fn set_normalisation_bypass(&self, bypass: bool) {
+        self.command(PlayerCommand::SetNormalisationBypass(bypass));
+    }
+
+    /// Sets the playback speed, between `MIN_PLAYBACK_SPEED` and `MAX_PLAYBACK_SPEED`.
+    /// The audio is time-stretched, so the pitch doesn't change.
+    pub fn set_playback_speed(&self, speed: f32) {
+        self.command(PlayerCommand::SetPlaybackSpeed(speed));
+    }
+
+    /// Arms or disarms stopping once the current track ends. The player only reports it with
+    /// `PlayerEvent::StopAfterCurrentChanged`, acting on it is up to whoever handles
+    /// `PlayerEvent::EndOfTrack`, like spirc.
+    pub fn set_stop_after_current(&self, armed: bool) {
+        self.command(PlayerCommand::SetStopAfterCurrent(armed));
+    }
+
+    /// Sets what the context that is playing allows, which `PlayerEvent::Started` and
+    /// `PlayerEvent::Changed` carry from then on. Changes while a track is loaded are reported
+    /// with `PlayerEvent::RestrictionsChanged`.
+    pub fn set_restrictions(&self, restrictions: Restrictions) {
+        self.command(PlayerCommand::SetRestrictions(restrictions));
+    }
+
+    /// Fades out and pauses playback once `duration` has passed, replacing a timer that
+    /// was set before.
+    pub fn set_sleep_timer(&self, duration: Duration) {
+        self.command(PlayerCommand::SetSleepTimer(duration));
+    }
+
+    /// Pushes the sleep timer back by `duration`, or sets it if there is none.
+    pub fn extend_sleep_timer(&self, duration: Duration) {
+        self.command(PlayerCommand::ExtendSleepTimer(duration));
+    }
+
+    pub fn cancel_sleep_timer(&self) {
+        self.command(PlayerCommand::CancelSleepTimer);
+    }
+
+    pub async fn get_statistics(&self) -> PlaybackStatistics {
+        let (tx, rx) = oneshot::channel();
+        self.command(PlayerCommand::GetStatistics(tx));
+        rx.await.unwrap_or_default()
+    }
+
+    /// The playback position now, advanced from the last position the player reported at
+    /// the playback speed. `None` when no track is loaded.
+    pub fn current_position(&self) -> Option<PlaybackPosition> {
+        self.position
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(PositionAnchor::current)
+    }
 }
 
 impl Drop for Player {
@@ -486,29 +1352,100 @@ struct PlayerLoadedTrackData {
     bytes_per_second: usize,
     duration_ms: u32,
     stream_position_pcm: u64,
+    // Set if the loader played an alternative of the requested track.
+    relinked_track_id: Option<SpotifyId>,
+    // None keeps the lyrics of the track that is already playing.
+    lyrics: Option<Vec<LyricsLine>>,
+    // Set if the file was opened for this load, None if a decoder was reused.
+    format: Option<(FileFormat, AudioCodec)>,
+    // Set if the track was loaded from scratch, None if a decoder was reused.
+    metadata: Option<TrackMetadata>,
 }
 
-enum PlayerPreload {
-    None,
-    Loading {
-        track_id: SpotifyId,
-        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
-    },
-    Ready {
-        track_id: SpotifyId,
-        loaded_track: Box<PlayerLoadedTrackData>,
-    },
-}
+// The file of a prefetched track if it is in the cache, and whether it was downloaded for
+// the prefetch.
+type PrefetchedFile = Option<(FileId, bool)>;
 
-type Decoder = Box<dyn AudioDecoder + Send>;
+struct PlayerPrefetch {
+    track_id: SpotifyId,
+    queue_position: usize,
+    cancelled: Arc<AtomicBool>,
+    loader: Pin<Box<dyn Future<Output = PrefetchedFile> + Send>>,
+}
 
-enum PlayerState {
+// A prefetch that is no longer waited for, like when the player shuts down, stops downloading.
+impl Drop for PlayerPrefetch {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+// The least time a prefetch gets to download its file.
+const PREFETCH_MIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a prefetch may take to download `size` bytes before it is given up on: as long
+// as playing them takes, so that it doesn't hold on to a thread and the budget for a download
+// that has stalled.
+fn prefetch_timeout(size: u64, bytes_per_second: usize) -> Duration {
+    let playing_time = Duration::from_secs(size / bytes_per_second.max(1) as u64);
+    playing_time.max(PREFETCH_MIN_TIMEOUT)
+}
+
+// What the downloads of prefetched tracks take up, see `PlayerConfig::preload_budget_bytes`.
+#[derive(Clone)]
+struct PreloadBudget {
+    limit: Option<u64>,
+    used: Arc<Mutex<u64>>,
+}
+
+impl PreloadBudget {
+    fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            used: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    fn reserve(&self, bytes: u64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        if matches!(self.limit, Some(limit) if *used + bytes > limit) {
+            return false;
+        }
+        *used += bytes;
+        true
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(bytes);
+    }
+}
+
+enum PlayerPreload {
+    None,
+    Loading {
+        track_id: SpotifyId,
+        loader:
+            Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, TrackSkipReason>> + Send>>,
+    },
+    Ready {
+        track_id: SpotifyId,
+        loaded_track: Box<PlayerLoadedTrackData>,
+    },
+}
+
+type Decoder = Box<dyn AudioDecoder + Send>;
+
+enum PlayerState {
     Stopped,
     Loading {
         track_id: SpotifyId,
         play_request_id: u64,
         start_playback: bool,
-        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        loader:
+            Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, TrackSkipReason>> + Send>>,
+        load_started: Instant,
+        position_ms: u32,
     },
     Paused {
         track_id: SpotifyId,
@@ -616,6 +1553,17 @@ impl PlayerState {
                 stream_loader_controller,
                 stream_position_pcm,
                 ..
+            }
+            | Paused {
+                track_id,
+                play_request_id,
+                decoder,
+                duration_ms,
+                bytes_per_second,
+                normalisation_data,
+                stream_loader_controller,
+                stream_position_pcm,
+                ..
             } => {
                 *self = EndOfTrack {
                     track_id,
@@ -627,11 +1575,17 @@ impl PlayerState {
                         bytes_per_second,
                         duration_ms,
                         stream_position_pcm,
+                        relinked_track_id: None,
+                        lyrics: None,
+                        format: None,
+                        metadata: None,
                     },
                 };
             }
             _ => {
-                error!("Called playing_to_end_of_track in non-playing state.");
+                error!(
+                    "Called playing_to_end_of_track in a state that is neither playing nor paused."
+                );
                 exit(1);
             }
         }
@@ -710,19 +1664,25 @@ impl PlayerState {
     }
 }
 
-struct PlayerTrackLoader {
-    session: Session,
-    config: PlayerConfig,
+pub(crate) struct PlayerTrackLoader {
+    pub(crate) session: Session,
+    pub(crate) config: PlayerConfig,
 }
 
 impl PlayerTrackLoader {
-    async fn find_available_alternative(&self, audio: AudioItem) -> Option<AudioItem> {
+    async fn find_available_alternative(
+        &self,
+        audio: AudioItem,
+        priority: RequestPriority,
+    ) -> Option<AudioItem> {
         if audio.available {
             Some(audio)
         } else if let Some(alternatives) = &audio.alternatives {
             let alternatives: FuturesUnordered<_> = alternatives
                 .iter()
-                .map(|alt_id| AudioItem::get_audio_item(&self.session, *alt_id))
+                .map(|alt_id| {
+                    AudioItem::get_audio_item_with_priority(&self.session, *alt_id, priority)
+                })
                 .collect();
 
             alternatives
@@ -754,25 +1714,253 @@ impl PlayerTrackLoader {
         }
     }
 
+    async fn load_lyrics(&self, spotify_id: SpotifyId) -> Vec<LyricsLine> {
+        if spotify_id.audio_type != SpotifyAudioType::Track {
+            return Vec::new();
+        }
+
+        match Lyrics::get(&self.session, spotify_id).await {
+            Ok(lyrics) if lyrics.synced => lyrics.lines,
+            Ok(_) => {
+                debug!("No synced lyrics for <{:?}>", spotify_id);
+                Vec::new()
+            }
+            Err(_) => {
+                warn!("Unable to load lyrics for <{:?}>", spotify_id);
+                Vec::new()
+            }
+        }
+    }
+
+    // Detects the codec of a file and opens a decoder for it, along with the normalisation
+    // data that Spotify stores in front of its Ogg Vorbis files.
+    fn open_decoder(
+        &self,
+        mut file: AudioDecrypt<AudioFile>,
+    ) -> DecoderResult<(Decoder, NormalisationData, AudioCodec)> {
+        let (codec, offset) = detect_codec(&mut file)?;
+        debug!("Detected {} audio", codec);
+
+        let normalisation_data = match offset {
+            SPOTIFY_OGG_HEADER_END => NormalisationData::parse_from_file(&mut file).ok(),
+            _ => None,
+        };
+        let normalisation_data = normalisation_data.unwrap_or_else(|| {
+            warn!("Unable to extract normalisation data, using default value.");
+            NormalisationData {
+                track_gain_db: 0.0,
+                track_peak: 1.0,
+                album_gain_db: 0.0,
+                album_peak: 1.0,
+            }
+        });
+
+        let audio_file = Subfile::new(file, offset);
+
+        let decoder = match codec {
+            AudioCodec::Vorbis if self.config.passthrough => {
+                Box::new(PassthroughDecoder::new(audio_file)?) as Decoder
+            }
+            AudioCodec::Vorbis => {
+                let mut decoder = VorbisDecoder::new(audio_file)?;
+                decoder.set_seek_tolerance(PlayerInternal::position_ms_to_pcm(
+                    self.config.seek_tolerance_ms,
+                ));
+                Box::new(decoder) as Decoder
+            }
+            #[cfg(feature = "aac-decoder")]
+            AudioCodec::Aac if !self.config.passthrough => {
+                Box::new(AacDecoder::new(audio_file)?) as Decoder
+            }
+            AudioCodec::Aac => return Err(DecoderError::UnsupportedCodec(codec)),
+        };
+
+        Ok((decoder, normalisation_data, codec))
+    }
+
+    fn select_file(&self, audio: &AudioItem) -> Option<(FileFormat, FileId)> {
+        // (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it
+        let vorbis_formats = match self.config.bitrate {
+            Bitrate::Bitrate96 => [
+                FileFormat::OGG_VORBIS_96,
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_320,
+            ],
+            Bitrate::Bitrate160 => [
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_96,
+                FileFormat::OGG_VORBIS_320,
+            ],
+            Bitrate::Bitrate320 => [
+                FileFormat::OGG_VORBIS_320,
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_96,
+            ],
+        };
+
+        // AAC is only played if a track isn't available as Vorbis, and can't be passed through.
+        #[cfg(feature = "aac-decoder")]
+        let aac_formats: &[FileFormat] = match self.config.bitrate {
+            _ if self.config.passthrough => &[],
+            Bitrate::Bitrate320 => &[FileFormat::AAC_320, FileFormat::AAC_160],
+            _ => &[FileFormat::AAC_160, FileFormat::AAC_320],
+        };
+        #[cfg(not(feature = "aac-decoder"))]
+        let aac_formats: &[FileFormat] = &[];
+
+        vorbis_formats
+            .iter()
+            .chain(aac_formats)
+            .find_map(|format| audio.files.get(format).map(|&file_id| (*format, file_id)))
+    }
+
+    // Downloads a track into the audio cache, without decrypting or decoding it. A download
+    // that doesn't fit into the budget isn't started.
+    async fn prefetch_track(
+        &self,
+        spotify_id: SpotifyId,
+        budget: PreloadBudget,
+        cancelled: Arc<AtomicBool>,
+    ) -> PrefetchedFile {
+        match self
+            .prefetch_within(spotify_id, Some(&budget), &cancelled, false)
+            .await
+        {
+            Ok(report) => Some((report.file_id, !report.already_cached)),
+            Err(e) => {
+                debug!("Unable to prefetch <{:?}>: {}", spotify_id, e);
+                None
+            }
+        }
+    }
+
+    // Like `prefetch_track`, but the download holds a permit of the rate limiter of the
+    // session. Waiting for the download blocks and needs the runtime of the session, see
+    // `prefetch::prefetch_track`.
+    pub(crate) async fn prefetch(
+        &self,
+        spotify_id: SpotifyId,
+    ) -> Result<PrefetchReport, PrefetchError> {
+        self.prefetch_within(spotify_id, None, &AtomicBool::new(false), true)
+            .await
+    }
+
+    async fn prefetch_within(
+        &self,
+        spotify_id: SpotifyId,
+        budget: Option<&PreloadBudget>,
+        cancelled: &AtomicBool,
+        rate_limited: bool,
+    ) -> Result<PrefetchReport, PrefetchError> {
+        let audio = AudioItem::get_audio_item(&self.session, spotify_id)
+            .await
+            .map_err(PrefetchError::Metadata)?;
+        let audio = self
+            .find_available_alternative(audio, RequestPriority::Normal)
+            .await
+            .ok_or(PrefetchError::Unavailable)?;
+
+        let (format, file_id) = self
+            .select_file(&audio)
+            .ok_or(PrefetchError::NoSupportedFormat)?;
+
+        // Like for playback, the key is fetched even though the file isn't decrypted, so that
+        // it is known to be playable and the key is at hand when it is played.
+        self.session
+            .audio_key()
+            .request(audio.id, file_id)
+            .await
+            .map_err(PrefetchError::AudioKey)?;
+
+        let bytes_per_second = self.stream_data_rate(format);
+        let encrypted_file = AudioFile::open(&self.session, file_id, bytes_per_second, true)
+            .await
+            .map_err(|_| PrefetchError::Download)?;
+
+        let already_cached = encrypted_file.is_cached();
+        let stream_loader_controller = encrypted_file.get_stream_loader_controller();
+        let bytes_written = if already_cached {
+            0
+        } else {
+            let size = stream_loader_controller.len() as u64;
+            if matches!(budget, Some(budget) if !budget.reserve(size)) {
+                stream_loader_controller.close();
+                return Err(PrefetchError::OverBudget);
+            }
+
+            let _permit = if rate_limited {
+                Some(
+                    self.session
+                        .rate_limiter()
+                        .acquire(RequestPriority::Normal)
+                        .await,
+                )
+            } else {
+                None
+            };
+
+            debug!("Prefetching <{}>", audio.uri);
+            // The file is written to the cache once it is complete, for which it has to stay
+            // open until then.
+            stream_loader_controller.set_stream_mode();
+            let complete = stream_loader_controller.fetch_next_blocking_unless(
+                stream_loader_controller.len(),
+                cancelled,
+                prefetch_timeout(size, bytes_per_second),
+            );
+            drop(encrypted_file);
+            if let Some(budget) = budget {
+                budget.release(size);
+            }
+            if !complete && cancelled.load(Ordering::Relaxed) {
+                return Err(PrefetchError::Cancelled);
+            }
+            if !complete {
+                return Err(PrefetchError::TimedOut);
+            }
+            if !stream_loader_controller.range_to_end_available() {
+                return Err(PrefetchError::Download);
+            }
+            size
+        };
+
+        Ok(PrefetchReport {
+            track_id: audio.id,
+            file_id,
+            format,
+            bytes_written,
+            already_cached,
+        })
+    }
+
     async fn load_track(
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> Option<PlayerLoadedTrackData> {
-        let audio = match AudioItem::get_audio_item(&self.session, spotify_id).await {
-            Ok(audio) => match self.find_available_alternative(audio).await {
+    ) -> Result<PlayerLoadedTrackData, TrackSkipReason> {
+        let audio = match AudioItem::get_audio_item_with_priority(
+            &self.session,
+            spotify_id,
+            RequestPriority::Essential,
+        )
+        .await
+        {
+            Ok(audio) => match self
+                .find_available_alternative(audio, RequestPriority::Essential)
+                .await
+            {
                 Some(audio) => audio,
                 None => {
                     warn!(
                         "<{}> is not available",
                         spotify_id.to_uri().unwrap_or_default()
                     );
-                    return None;
+                    return Err(TrackSkipReason::Unavailable);
                 }
             },
             Err(e) => {
                 error!("Unable to load audio item: {:?}", e);
-                return None;
+                return Err(TrackSkipReason::LoadFailed);
             }
         };
 
@@ -784,46 +1972,33 @@ impl PlayerTrackLoader {
                 spotify_id.to_uri().unwrap_or_default(),
                 audio.duration
             );
-            return None;
+            return Err(TrackSkipReason::LoadFailed);
         }
         let duration_ms = audio.duration as u32;
 
-        // (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it
-        let formats = match self.config.bitrate {
-            Bitrate::Bitrate96 => [
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate160 => [
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate320 => [
-                FileFormat::OGG_VORBIS_320,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-            ],
+        let (format, file_id) = match self.select_file(&audio) {
+            Some(t) => t,
+            None => {
+                warn!("<{}> is not available in any supported format", audio.name);
+                return Err(TrackSkipReason::Unavailable);
+            }
         };
 
-        let (format, file_id) =
-            match formats
-                .iter()
-                .find_map(|format| match audio.files.get(format) {
-                    Some(&file_id) => Some((*format, file_id)),
-                    _ => None,
-                }) {
-                Some(t) => t,
-                None => {
-                    warn!("<{}> is not available in any supported format", audio.name);
-                    return None;
-                }
-            };
-
         let bytes_per_second = self.stream_data_rate(format);
         let play_from_beginning = position_ms == 0;
 
+        // Lyrics are fetched alongside the file and its key instead of before them, so that
+        // they don't delay the start of the track.
+        let lyrics_id = audio.id;
+        let lyrics = future::maybe_done(async move {
+            if self.config.lyrics_events {
+                Some(self.load_lyrics(lyrics_id).await)
+            } else {
+                None
+            }
+        });
+        futures_util::pin_mut!(lyrics);
+
         // This is only a loop to be able to reload the file if an error occurred
         // while opening a cached file.
         loop {
@@ -834,11 +2009,12 @@ impl PlayerTrackLoader {
                 play_from_beginning,
             );
 
-            let encrypted_file = match encrypted_file.await {
+            let (_, encrypted_file) = future::join(lyrics.as_mut(), encrypted_file).await;
+            let encrypted_file = match encrypted_file {
                 Ok(encrypted_file) => encrypted_file,
                 Err(e) => {
                     error!("Unable to load encrypted file: {:?}", e);
-                    return None;
+                    return Err(TrackSkipReason::LoadFailed);
                 }
             };
             let is_cached = encrypted_file.is_cached();
@@ -853,45 +2029,28 @@ impl PlayerTrackLoader {
                 stream_loader_controller.set_random_access_mode();
             }
 
-            let key = match self.session.audio_key().request(spotify_id, file_id).await {
+            let key = self.session.audio_key().request(spotify_id, file_id);
+            let (_, key) = future::join(lyrics.as_mut(), key).await;
+            let key = match key {
                 Ok(key) => key,
+                Err(e @ AudioKeyError::Denied(_)) => {
+                    warn!(
+                        "<{}> is not available: {}",
+                        spotify_id.to_uri().unwrap_or_default(),
+                        e
+                    );
+                    return Err(TrackSkipReason::Unavailable);
+                }
                 Err(e) => {
                     error!("Unable to load decryption key: {:?}", e);
-                    return None;
+                    return Err(TrackSkipReason::LoadFailed);
                 }
             };
 
-            let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
-
-            let normalisation_data = match NormalisationData::parse_from_file(&mut decrypted_file) {
-                Ok(data) => data,
-                Err(_) => {
-                    warn!("Unable to extract normalisation data, using default value.");
-                    NormalisationData {
-                        track_gain_db: 0.0,
-                        track_peak: 1.0,
-                        album_gain_db: 0.0,
-                        album_peak: 1.0,
-                    }
-                }
-            };
-
-            let audio_file = Subfile::new(decrypted_file, 0xa7);
-
-            let result = if self.config.passthrough {
-                match PassthroughDecoder::new(audio_file) {
-                    Ok(result) => Ok(Box::new(result) as Decoder),
-                    Err(e) => Err(DecoderError::PassthroughDecoder(e.to_string())),
-                }
-            } else {
-                match VorbisDecoder::new(audio_file) {
-                    Ok(result) => Ok(Box::new(result) as Decoder),
-                    Err(e) => Err(DecoderError::LewtonDecoder(e.to_string())),
-                }
-            };
+            let decrypted_file = AudioDecrypt::new(key, encrypted_file);
 
-            let mut decoder = match result {
-                Ok(decoder) => decoder,
+            let (mut decoder, normalisation_data, codec) = match self.open_decoder(decrypted_file) {
+                Ok(opened) => opened,
                 Err(e) if is_cached => {
                     warn!(
                         "Unable to read cached audio file: {}. Trying to download it.",
@@ -902,12 +2061,12 @@ impl PlayerTrackLoader {
                         Some(cache) => {
                             if cache.remove_file(file_id).is_err() {
                                 error!("Error removing file from cache");
-                                return None;
+                                return Err(TrackSkipReason::LoadFailed);
                             }
                         }
                         None => {
                             error!("If the audio file is cached, a cache should exist");
-                            return None;
+                            return Err(TrackSkipReason::LoadFailed);
                         }
                     }
 
@@ -916,28 +2075,43 @@ impl PlayerTrackLoader {
                 }
                 Err(e) => {
                     error!("Unable to read audio file: {}", e);
-                    return None;
+                    return Err(TrackSkipReason::LoadFailed);
                 }
             };
 
-            let position_pcm = PlayerInternal::position_ms_to_pcm(position_ms);
+            let mut stream_position_pcm = PlayerInternal::position_ms_to_pcm(position_ms);
 
-            if position_pcm != 0 {
-                if let Err(e) = decoder.seek(position_pcm) {
-                    error!("PlayerTrackLoader load_track: {}", e);
+            if stream_position_pcm != 0 {
+                match decoder.seek(stream_position_pcm) {
+                    Ok(landed_pcm) => stream_position_pcm = landed_pcm,
+                    Err(e) => error!("PlayerTrackLoader load_track: {}", e),
                 }
                 stream_loader_controller.set_stream_mode();
             }
-            let stream_position_pcm = position_pcm;
+            lyrics.as_mut().await;
             info!("<{}> ({} ms) loaded", audio.name, audio.duration);
 
-            return Some(PlayerLoadedTrackData {
+            let relinked_track_id = if audio.id != spotify_id {
+                Some(audio.id)
+            } else {
+                None
+            };
+
+            return Ok(PlayerLoadedTrackData {
                 decoder,
                 normalisation_data,
                 stream_loader_controller,
                 bytes_per_second,
                 duration_ms,
                 stream_position_pcm,
+                relinked_track_id,
+                lyrics: lyrics.as_mut().take_output().flatten(),
+                format: Some((format, codec)),
+                metadata: Some(TrackMetadata {
+                    track_id: spotify_id,
+                    name: audio.name,
+                    duration_ms,
+                }),
             });
         }
     }
@@ -966,6 +2140,21 @@ impl Future for PlayerInternal {
 
             if let Some(cmd) = cmd {
                 self.handle_command(cmd);
+            } else if self.sink_probe_pending {
+                self.sink_probe_pending = false;
+                self.probe_sink();
+            }
+
+            if self.sink_recovery.is_some() {
+                self.poll_sink_recovery(cx);
+            }
+
+            if self.sink_hold.is_some() {
+                self.poll_sink_hold(cx);
+            }
+
+            if self.sleep_timer.is_some() {
+                self.poll_sleep_timer(cx);
             }
 
             // Handle loading of a new track to play
@@ -974,10 +2163,18 @@ impl Future for PlayerInternal {
                 track_id,
                 start_playback,
                 play_request_id,
+                load_started,
+                position_ms,
             } = self.state
             {
                 match loader.as_mut().poll(cx) {
                     Poll::Ready(Ok(loaded_track)) => {
+                        let slow_initial_fetch = self.config.adaptive_bitrate
+                            && !loaded_track.stream_loader_controller.is_cached()
+                            && load_started.elapsed() > self.config.adaptive_bitrate_load_deadline;
+
+                        self.consecutive_skips = 0;
+                        self.handle_track_loaded(track_id, play_request_id, &loaded_track);
                         self.start_playback(
                             track_id,
                             play_request_id,
@@ -988,22 +2185,48 @@ impl Future for PlayerInternal {
                             error!("The state wasn't changed by start_playback()");
                             exit(1);
                         }
+
+                        if slow_initial_fetch {
+                            self.handle_bitrate_fallback(BitrateChangeReason::SlowInitialFetch);
+                        }
                     }
-                    Poll::Ready(Err(e)) => {
-                        warn!(
-                            "Skipping to next track, unable to load track <{:?}>: {:?}",
-                            track_id, e
-                        );
+                    Poll::Ready(Err(reason)) => {
                         debug_assert!(self.state.is_loading());
-                        self.send_event(PlayerEvent::EndOfTrack {
+                        self.handle_track_load_failure(
                             track_id,
                             play_request_id,
-                        })
+                            position_ms,
+                            start_playback,
+                            reason,
+                        );
                     }
                     Poll::Pending => (),
                 }
             }
 
+            // handle finished prefetches.
+            let mut i = 0;
+            while i < self.prefetches.len() {
+                match self.prefetches[i].loader.as_mut().poll(cx) {
+                    Poll::Ready(result) => {
+                        let prefetch = self.prefetches.remove(i);
+                        match result {
+                            Some((file_id, downloaded)) => {
+                                if downloaded {
+                                    self.prefetched.push((prefetch.track_id, file_id));
+                                }
+                                self.send_event(PlayerEvent::Preloading {
+                                    track_id: prefetch.track_id,
+                                    queue_position: prefetch.queue_position,
+                                });
+                            }
+                            None => debug!("Unable to prefetch {:?}", prefetch.track_id),
+                        }
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+
             // handle pending preload requests.
             if let PlayerPreload::Loading {
                 ref mut loader,
@@ -1012,7 +2235,10 @@ impl Future for PlayerInternal {
             {
                 match loader.as_mut().poll(cx) {
                     Poll::Ready(Ok(loaded_track)) => {
-                        self.send_event(PlayerEvent::Preloading { track_id });
+                        self.send_event(PlayerEvent::Preloading {
+                            track_id,
+                            queue_position: 1,
+                        });
                         self.preload = PlayerPreload::Ready {
                             track_id,
                             loaded_track: Box::new(loaded_track),
@@ -1041,6 +2267,42 @@ impl Future for PlayerInternal {
 
             if self.state.is_playing() {
                 self.ensure_sink_running();
+            }
+
+            if self.state.is_playing() {
+                let mut stalled = false;
+                let speed = self.time_stretcher.speed();
+
+                let latency_due = match self.sink_latency_queried {
+                    Some(queried) => queried.elapsed() >= SINK_LATENCY_INTERVAL,
+                    None => true,
+                };
+                if latency_due {
+                    self.query_sink_latency();
+                }
+                let latency_ms = self.sink_latency_ms();
+
+                if matches!(self.next_progress, Some(next) if next <= Instant::now()) {
+                    self.send_progress();
+                }
+
+                if let Some(interval) = self.config.metrics_interval {
+                    let now = Instant::now();
+                    match self.next_metrics {
+                        Some(next) if next > now => (),
+                        Some(_) => {
+                            self.next_metrics = Some(now + interval);
+                            self.count_fetched_bytes();
+                            let event = PlayerEvent::MetricsUpdated {
+                                buffer_ms: self.buffer_ms,
+                                counters: self.session_counters.load(),
+                                reconnects: self.session.reconnects(),
+                            };
+                            self.send_event(event);
+                        }
+                        None => self.next_metrics = Some(now + interval),
+                    }
+                }
 
                 if let PlayerState::Playing {
                     track_id,
@@ -1059,32 +2321,36 @@ impl Future for PlayerInternal {
                                 if let Some(ref packet) = packet {
                                     match packet.samples() {
                                         Ok(samples) => {
-                                            *stream_position_pcm +=
+                                            let decoded_frames =
                                                 (samples.len() / NUM_CHANNELS as usize) as u64;
+                                            *stream_position_pcm += decoded_frames;
+                                            // Where playback is audibly, rather than how far it has been decoded.
                                             let stream_position_millis =
-                                                Self::position_pcm_to_ms(*stream_position_pcm);
+                                                Self::position_pcm_to_ms(*stream_position_pcm)
+                                                    .saturating_sub(latency_ms);
 
                                             let notify_about_position =
                                                 match *reported_nominal_start_time {
                                                     None => true,
                                                     Some(reported_nominal_start_time) => {
                                                         // only notify if we're behind. If we're ahead it's probably due to a buffer of the backend and we're actually in time.
-                                                        let lag = (Instant::now()
-                                                            - reported_nominal_start_time)
-                                                            .as_millis()
-                                                            as i64
-                                                            - stream_position_millis as i64;
+                                                        let lag = playback_lag_ms(
+                                                            reported_nominal_start_time,
+                                                            stream_position_millis,
+                                                            speed,
+                                                        );
                                                         lag > Duration::from_secs(1).as_millis()
                                                             as i64
                                                     }
                                                 };
+                                            let fell_behind = notify_about_position
+                                                && reported_nominal_start_time.is_some();
                                             if notify_about_position {
-                                                *reported_nominal_start_time = Some(
-                                                    Instant::now()
-                                                        - Duration::from_millis(
-                                                            stream_position_millis as u64,
-                                                        ),
-                                                );
+                                                *reported_nominal_start_time =
+                                                    Some(nominal_start_time(
+                                                        stream_position_millis,
+                                                        speed,
+                                                    ));
                                                 self.send_event(PlayerEvent::Playing {
                                                     track_id,
                                                     play_request_id,
@@ -1092,13 +2358,23 @@ impl Future for PlayerInternal {
                                                     duration_ms,
                                                 });
                                             }
+
+                                            self.handle_lyrics_position(
+                                                track_id,
+                                                play_request_id,
+                                                stream_position_millis,
+                                            );
+
+                                            self.count(|c| &c.decoded_frames, decoded_frames);
+                                            if fell_behind {
+                                                self.count(|c| &c.sink_underruns, 1);
+                                                stalled = true;
+                                            }
                                         }
                                         Err(e) => {
                                             warn!("Skipping to next track, unable to decode samples for track <{:?}>: {:?}", track_id, e);
-                                            self.send_event(PlayerEvent::EndOfTrack {
-                                                track_id,
-                                                play_request_id,
-                                            })
+                                            self.count(|c| &c.decode_errors, 1);
+                                            self.send_end_of_track(track_id, play_request_id)
                                         }
                                     }
                                 }
@@ -1111,18 +2387,27 @@ impl Future for PlayerInternal {
                         }
                         Err(e) => {
                             warn!("Skipping to next track, unable to get next packet for track <{:?}>: {:?}", track_id, e);
-                            self.send_event(PlayerEvent::EndOfTrack {
-                                track_id,
-                                play_request_id,
-                            })
+                            self.count(|c| &c.decode_errors, 1);
+                            self.send_end_of_track(track_id, play_request_id)
                         }
                     }
                 } else {
                     error!("PlayerInternal poll: Invalid PlayerState");
                     exit(1);
                 };
+
+                if stalled && self.config.adaptive_bitrate {
+                    self.bitrate_stalls += 1;
+                    if self.bitrate_stalls > self.config.adaptive_bitrate_max_stalls {
+                        self.handle_bitrate_fallback(BitrateChangeReason::BufferingStalls);
+                    }
+                }
             }
 
+            self.count_fetched_bytes();
+            self.update_buffer_metric();
+            self.check_fetch_degraded();
+
             if let PlayerState::Playing {
                 track_id,
                 play_request_id,
@@ -1166,6 +2451,34 @@ impl Future for PlayerInternal {
     }
 }
 
+// The instant playback would have started at to be at `position_ms` now, at `speed`.
+fn nominal_start_time(position_ms: u32, speed: f32) -> Instant {
+    Instant::now() - Duration::from_secs_f64(position_ms as f64 / 1000.0 / speed as f64)
+}
+
+// How far playback at `speed` is behind where it should be, had it started at `nominal_start_time`.
+fn playback_lag_ms(nominal_start_time: Instant, position_ms: u32, speed: f32) -> i64 {
+    let elapsed_ms = (Instant::now() - nominal_start_time).as_secs_f64() * 1000.0;
+    (elapsed_ms * speed as f64) as i64 - position_ms as i64
+}
+
+// Clamps a seek target to the duration of the track and returns whether it is at the end.
+fn clamp_seek_position(position_ms: u32, duration_ms: u32) -> (u32, bool) {
+    let position_ms = position_ms.min(duration_ms);
+    (position_ms, position_ms == duration_ms)
+}
+
+// Whether playback is paused for a route change, see `PlayerConfig::pause_on_route_change`.
+// After a sink error, this keeps playback from resuming when the sink is back.
+fn pauses_on_route_change(
+    pause_on_route_change: bool,
+    change: RouteChange,
+    playing: bool,
+    recovering_sink: bool,
+) -> bool {
+    pause_on_route_change && change.is_away() && (playing || recovering_sink)
+}
+
 impl PlayerInternal {
     fn position_pcm_to_ms(position_pcm: u64) -> u32 {
         (position_pcm as f64 * MS_PER_PAGE) as u32
@@ -1175,39 +2488,535 @@ impl PlayerInternal {
         (position_ms as f64 * PAGES_PER_MS) as u64
     }
 
-    fn ensure_sink_running(&mut self) {
-        if self.sink_status != SinkStatus::Running {
-            trace!("== Starting sink ==");
-            if let Some(callback) = &mut self.sink_event_callback {
-                callback(SinkStatus::Running);
-            }
-            match self.sink.start() {
-                Ok(()) => self.sink_status = SinkStatus::Running,
-                Err(e) => {
-                    error!("{}", e);
-                    exit(1);
-                }
-            }
-        }
+    fn query_sink_latency(&mut self) {
+        self.sink_latency = self.sink.latency();
+        self.sink_latency_queried = Some(Instant::now());
     }
 
-    fn ensure_sink_stopped(&mut self, temporarily: bool) {
-        match self.sink_status {
-            SinkStatus::Running => {
-                trace!("== Stopping sink ==");
-                match self.sink.stop() {
-                    Ok(()) => {
-                        self.sink_status = if temporarily {
-                            SinkStatus::TemporarilyClosed
-                        } else {
-                            SinkStatus::Closed
-                        };
-                        if let Some(callback) = &mut self.sink_event_callback {
-                            callback(self.sink_status);
-                        }
-                    }
-                    Err(e) => {
-                        error!("{}", e);
+    // How far the audible position is behind what was written to the sink, in track time.
+    fn sink_latency_ms(&self) -> u32 {
+        self.sink_latency.map_or(0, |latency| {
+            (latency.as_secs_f64() * 1000.0 * self.time_stretcher.speed() as f64) as u32
+        })
+    }
+
+    fn count(&self, counter: PlaybackCounter, value: u64) {
+        counter(&self.session_counters).fetch_add(value, Ordering::Relaxed);
+        counter(&self.track_counters).fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn handle_track_loaded(
+        &mut self,
+        track_id: SpotifyId,
+        play_request_id: u64,
+        loaded_track: &PlayerLoadedTrackData,
+    ) {
+        let stream_loader_controller = &loaded_track.stream_loader_controller;
+        if stream_loader_controller.is_cached() {
+            self.count(
+                |c| &c.cache_hit_bytes,
+                stream_loader_controller.len() as u64,
+            );
+        }
+
+        if let Some((file_format, codec)) = loaded_track.format {
+            self.send_event(PlayerEvent::Loaded {
+                play_request_id,
+                track_id,
+                file_format,
+                codec,
+            });
+        }
+
+        if let Some(relinked_track_id) = loaded_track.relinked_track_id {
+            self.send_event(PlayerEvent::Changed {
+                old_track_id: track_id,
+                new_track_id: relinked_track_id,
+                context_uri: self.context_uri.clone(),
+                context_kind: self.context_kind,
+                restrictions: self.restrictions,
+            });
+        }
+    }
+
+    fn count_fetched_bytes(&self) {
+        let stream_loader_controller = match self.state {
+            PlayerState::Playing {
+                ref stream_loader_controller,
+                ..
+            }
+            | PlayerState::Paused {
+                ref stream_loader_controller,
+                ..
+            } => stream_loader_controller,
+            PlayerState::EndOfTrack {
+                ref loaded_track, ..
+            } => &loaded_track.stream_loader_controller,
+            _ => return,
+        };
+
+        let bytes_fetched = stream_loader_controller.take_bytes_fetched() as u64;
+        if bytes_fetched > 0 {
+            self.count(|c| &c.fetched_bytes, bytes_fetched);
+        }
+    }
+
+    fn check_fetch_degraded(&mut self) {
+        let (track_id, play_request_id, stream_loader_controller) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                ref stream_loader_controller,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id,
+                ref stream_loader_controller,
+                ..
+            } => (track_id, play_request_id, stream_loader_controller),
+            _ => return,
+        };
+
+        let retries = stream_loader_controller.take_chunk_retries();
+        if retries > 0 {
+            self.send_event(PlayerEvent::FetchDegraded {
+                play_request_id,
+                track_id,
+                retries,
+            });
+        }
+    }
+
+    fn update_buffer_metric(&mut self) {
+        let buffer_ms = match self.state {
+            PlayerState::Playing {
+                ref stream_loader_controller,
+                bytes_per_second,
+                duration_ms,
+                stream_position_pcm,
+                ..
+            }
+            | PlayerState::Paused {
+                ref stream_loader_controller,
+                bytes_per_second,
+                duration_ms,
+                stream_position_pcm,
+                ..
+            } => match stream_loader_controller.buffered_bytes() {
+                Some(bytes) if bytes_per_second > 0 => {
+                    (bytes as u64 * 1000 / bytes_per_second as u64).min(u32::MAX as u64) as u32
+                }
+                // All of what is left of a cached track is there.
+                _ => duration_ms.saturating_sub(Self::position_pcm_to_ms(stream_position_pcm)),
+            },
+            _ => 0,
+        };
+        self.buffer_ms = buffer_ms;
+    }
+
+    fn statistics(&self) -> PlaybackStatistics {
+        self.count_fetched_bytes();
+        PlaybackStatistics {
+            session: self.session_counters.load(),
+            track: self.track_counters.load(),
+            sink_latency: self.sink_latency,
+        }
+    }
+
+    fn reset_track_statistics(&self) {
+        // Attribute what was downloaded so far to the outgoing track.
+        self.count_fetched_bytes();
+        let limiter_engagements = self
+            .track_counters
+            .limiter_engagements
+            .load(Ordering::Relaxed);
+        if limiter_engagements > 0 {
+            debug!(
+                "The dynamic limiter engaged {} times during the last track",
+                limiter_engagements
+            );
+        }
+        self.track_counters.reset();
+    }
+
+    fn ensure_sink_running(&mut self) {
+        if self.sink_status != SinkStatus::Running {
+            let held_open = self.sink_hold.take().is_some();
+
+            // Playback starts or resumes, rather than moving on to another track.
+            if self.sink_status == SinkStatus::Closed || held_open {
+                self.fade = None;
+                self.start_fade_in(true);
+            }
+
+            if let Some(callback) = &mut self.sink_event_callback {
+                callback(SinkStatus::Running, SinkStatusReason::Play);
+            }
+
+            if held_open {
+                trace!("== Resuming held open sink ==");
+                self.sink_status = SinkStatus::Running;
+                return;
+            }
+
+            trace!("== Starting sink ==");
+            match self.sink.start() {
+                Ok(()) => self.sink_status = SinkStatus::Running,
+                Err(e) => self.handle_sink_error(e),
+            }
+        }
+    }
+
+    fn handle_sink_error(&mut self, e: SinkError) {
+        error!("{}", e);
+
+        // The sink may be half broken, make sure it is closed before it is opened again.
+        self.sink_hold = None;
+        if let Err(e) = self.sink.stop() {
+            debug!("Unable to stop the failed audio sink: {}", e);
+        }
+        if self.sink_status != SinkStatus::TemporarilyClosed {
+            self.sink_status = SinkStatus::TemporarilyClosed;
+            if let Some(callback) = &mut self.sink_event_callback {
+                callback(SinkStatus::TemporarilyClosed, SinkStatusReason::Error);
+            }
+        }
+
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            stream_position_pcm,
+            duration_ms,
+            ..
+        } = self.state
+        {
+            self.state.playing_to_paused();
+
+            let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+            self.send_event(PlayerEvent::SinkError {
+                play_request_id,
+                track_id,
+                position_ms,
+                error: e.to_string(),
+            });
+            self.send_event(PlayerEvent::Paused {
+                track_id,
+                play_request_id,
+                position_ms,
+                duration_ms,
+            });
+        }
+
+        self.wait_for_sink();
+    }
+
+    // Tries to open the sink, and if it can't be, waits for it like after an error while
+    // nothing plays. That way the device isn't found busy only once something should play.
+    fn probe_sink(&mut self) {
+        if self.sink_status != SinkStatus::Closed {
+            return;
+        }
+
+        match self.sink.start() {
+            Ok(()) => {
+                if let Err(e) = self.sink.stop() {
+                    debug!("Unable to stop the audio sink after trying it: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("The audio sink is unavailable, waiting for it: {}", e);
+                self.sink_status = SinkStatus::TemporarilyClosed;
+                if let Some(callback) = &mut self.sink_event_callback {
+                    callback(SinkStatus::TemporarilyClosed, SinkStatusReason::Unavailable);
+                }
+                self.wait_for_sink();
+            }
+        }
+    }
+
+    fn wait_for_sink(&mut self) {
+        let now = Instant::now();
+        let (started, interval) = self
+            .sink_recovery
+            .as_ref()
+            .map_or((now, self.config.sink_retry_interval), |recovery| {
+                (recovery.started, recovery.interval)
+            });
+        self.sink_recovery = Some(SinkRecovery {
+            started,
+            next_attempt: now + interval,
+            interval,
+            wake_scheduled: false,
+        });
+    }
+
+    fn poll_sink_recovery(&mut self, cx: &mut Context<'_>) {
+        let recovery = match self.sink_recovery.as_mut() {
+            Some(recovery) => recovery,
+            None => return,
+        };
+
+        let now = Instant::now();
+        if now >= recovery.next_attempt {
+            if !self.config.wait_for_sink
+                && recovery.started.elapsed() > self.config.sink_retry_timeout
+            {
+                error!("The audio sink didn't come back, stopping playback");
+                self.handle_player_stop();
+                return;
+            }
+
+            match self.sink.start() {
+                Ok(()) => {
+                    info!("The audio sink is available again");
+                    self.sink_recovery = None;
+                    self.sink_status = SinkStatus::Running;
+                    // For playback that the failure paused, which resumes, or else because the
+                    // device it waited for is free again. The sink is kept running either way,
+                    // so that the device isn't taken away again.
+                    let paused = matches!(self.state, PlayerState::Paused { .. });
+                    let reason = if paused {
+                        SinkStatusReason::Play
+                    } else {
+                        SinkStatusReason::Unavailable
+                    };
+                    if let Some(callback) = &mut self.sink_event_callback {
+                        callback(SinkStatus::Running, reason);
+                    }
+                    if paused {
+                        self.handle_play();
+                    }
+                    return;
+                }
+                Err(e) => {
+                    debug!("The audio sink is still unavailable: {}", e);
+                    if self.config.wait_for_sink {
+                        recovery.interval = (recovery.interval * 2).min(SINK_RETRY_MAX_INTERVAL);
+                    }
+                    recovery.next_attempt = now + recovery.interval;
+                    recovery.wake_scheduled = false;
+                }
+            }
+        }
+
+        // Nothing else wakes the player while it is paused, so wake it up for the next attempt.
+        if !recovery.wake_scheduled {
+            recovery.wake_scheduled = true;
+            let delay = recovery.next_attempt.saturating_duration_since(now);
+            self.wake_after(delay, cx);
+        }
+    }
+
+    // Wakes the player after `delay`, with a timer on the runtime of the session rather than
+    // the thread of the player, which is blocked on the player itself.
+    fn wake_after(&self, delay: Duration, cx: &Context<'_>) {
+        let waker = cx.waker().clone();
+        self.session.spawn(async move {
+            tokio::time::sleep(delay).await;
+            waker.wake();
+        });
+    }
+
+    fn poll_sink_hold(&mut self, cx: &mut Context<'_>) {
+        let hold = match self.sink_hold.as_mut() {
+            Some(hold) => hold,
+            None => return,
+        };
+
+        let close_at = match hold.close_at {
+            Some(close_at) => close_at,
+            None => return,
+        };
+
+        let now = Instant::now();
+        if now >= close_at {
+            debug!("Closing the sink after the pause outlasted the close delay");
+            self.ensure_sink_stopped(false, SinkStatusReason::Pause);
+            return;
+        }
+
+        // Nothing else wakes the player while it is paused.
+        if !hold.wake_scheduled {
+            hold.wake_scheduled = true;
+            let delay = close_at.saturating_duration_since(now);
+            self.wake_after(delay, cx);
+        }
+    }
+
+    // Keeps the sink open during a pause for `PlayerConfig::sink_close_delay`.
+    fn hold_sink(&mut self) {
+        let close_at = match self.config.sink_close_delay {
+            Some(delay) if delay.is_zero() => {
+                self.ensure_sink_stopped(false, SinkStatusReason::Pause);
+                return;
+            }
+            Some(delay) => Some(Instant::now() + delay),
+            None => None,
+        };
+
+        if self.sink_status == SinkStatus::Running {
+            trace!("== Holding sink open ==");
+            self.sink_status = SinkStatus::TemporarilyClosed;
+            self.sink_hold = Some(SinkHold {
+                close_at,
+                wake_scheduled: false,
+            });
+            if let Some(callback) = &mut self.sink_event_callback {
+                callback(SinkStatus::TemporarilyClosed, SinkStatusReason::Pause);
+            }
+        }
+    }
+
+    fn arm_sleep_timer(&mut self, deadline: Instant) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        debug!("Setting sleep timer to {:?}", remaining);
+
+        if matches!(self.fade, Some(Fade { sleep: true, .. })) {
+            self.start_fade_in(false);
+        }
+        self.sleep_timer = Some(SleepTimer {
+            deadline,
+            wake_scheduled_for: None,
+        });
+
+        let remaining_ms = remaining.as_millis().min(u32::MAX as u128) as u32;
+        self.send_event(PlayerEvent::SleepTimerArmed { remaining_ms });
+    }
+
+    fn cancel_sleep_timer(&mut self) {
+        if self.sleep_timer.take().is_some() {
+            debug!("Cancelling sleep timer");
+            self.send_event(PlayerEvent::SleepTimerCancelled);
+        } else if matches!(self.fade, Some(Fade { sleep: true, .. })) {
+            self.cancel_fade_out();
+        }
+    }
+
+    fn poll_sleep_timer(&mut self, cx: &mut Context<'_>) {
+        let timer = match self.sleep_timer.as_mut() {
+            Some(timer) => timer,
+            None => return,
+        };
+
+        let now = Instant::now();
+        if now < timer.deadline {
+            // Nothing else wakes the player while it is paused, so wake it up when it's time.
+            if timer.wake_scheduled_for != Some(timer.deadline) {
+                timer.wake_scheduled_for = Some(timer.deadline);
+                let delay = timer.deadline - now;
+                self.wake_after(delay, cx);
+            }
+            return;
+        }
+
+        info!("Sleep timer went off");
+        self.sleep_timer = None;
+        self.send_event(PlayerEvent::SleepTimerFired);
+
+        if let PlayerState::Playing { .. } = self.state {
+            if !self.start_fade_out(self.config.sleep_timer_fade, true) {
+                self.handle_pause();
+            }
+        }
+    }
+
+    fn fade_frames(duration: Duration) -> u64 {
+        (duration.as_millis() as f64 * PAGES_PER_MS) as u64
+    }
+
+    // Starts ramping down the volume from where it is, to pause once it is silent.
+    // Returns false if there is nothing to fade, so playback should pause right away.
+    fn start_fade_out(&mut self, duration: Duration, sleep: bool) -> bool {
+        let frames = Self::fade_frames(duration);
+
+        // The raw stream can't be faded.
+        if frames == 0 || self.config.passthrough {
+            return false;
+        }
+
+        let gain = self.fade.as_ref().map_or(1.0, |fade| fade.gain);
+        self.fade = Some(Fade {
+            gain,
+            step: -1.0 / frames as f64,
+            sleep,
+        });
+        true
+    }
+
+    // Starts ramping up the volume from where it is, or from silence if `from_silence`.
+    fn start_fade_in(&mut self, from_silence: bool) {
+        let gain = match self.fade {
+            Some(ref fade) => fade.gain,
+            None if from_silence => 0.0,
+            None => return,
+        };
+
+        let frames = Self::fade_frames(self.config.fade_in);
+        self.fade = if frames == 0 || self.config.passthrough {
+            None
+        } else {
+            Some(Fade {
+                gain,
+                step: 1.0 / frames as f64,
+                sleep: false,
+            })
+        };
+    }
+
+    // Goes back to full volume if a fade out hasn't finished yet.
+    fn cancel_fade_out(&mut self) {
+        if let Some(Fade { step, sleep, .. }) = self.fade {
+            if step < 0.0 {
+                self.start_fade_in(false);
+                if sleep {
+                    debug!("Cancelling sleep timer");
+                    self.send_event(PlayerEvent::SleepTimerCancelled);
+                }
+            }
+        }
+    }
+
+    fn apply_fade(&mut self, data: &mut [f64]) {
+        let fade = match self.fade.as_mut() {
+            Some(fade) => fade,
+            None => return,
+        };
+
+        for frame in data.chunks_mut(NUM_CHANNELS as usize) {
+            for sample in frame.iter_mut() {
+                *sample *= fade.gain;
+            }
+            fade.gain = (fade.gain + fade.step).clamp(0.0, 1.0);
+        }
+
+        if fade.step > 0.0 && fade.gain >= 1.0 {
+            self.fade = None;
+        }
+    }
+
+    fn ensure_sink_stopped(&mut self, temporarily: bool, reason: SinkStatusReason) {
+        // A sink that is held open during a pause is still running as far as the device goes.
+        if self.sink_hold.take().is_some() {
+            self.sink_status = SinkStatus::Running;
+        }
+
+        match self.sink_status {
+            SinkStatus::Running => {
+                trace!("== Stopping sink ==");
+                match self.sink.stop() {
+                    Ok(()) => {
+                        self.sink_status = if temporarily {
+                            SinkStatus::TemporarilyClosed
+                        } else {
+                            SinkStatus::Closed
+                        };
+                        if let Some(callback) = &mut self.sink_event_callback {
+                            callback(self.sink_status, reason);
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}", e);
                         exit(1);
                     }
                 }
@@ -1216,7 +3025,7 @@ impl PlayerInternal {
                 if !temporarily {
                     self.sink_status = SinkStatus::Closed;
                     if let Some(callback) = &mut self.sink_event_callback {
-                        callback(SinkStatus::Closed);
+                        callback(SinkStatus::Closed, reason);
                     }
                 }
             }
@@ -1225,6 +3034,8 @@ impl PlayerInternal {
     }
 
     fn handle_player_stop(&mut self) {
+        self.sink_recovery = None;
+        self.fade = None;
         match self.state {
             PlayerState::Playing {
                 track_id,
@@ -1246,7 +3057,7 @@ impl PlayerInternal {
                 play_request_id,
                 ..
             } => {
-                self.ensure_sink_stopped(false);
+                self.ensure_sink_stopped(false, SinkStatusReason::Stop);
                 self.send_event(PlayerEvent::Stopped {
                     track_id,
                     play_request_id,
@@ -1262,7 +3073,10 @@ impl PlayerInternal {
     }
 
     fn handle_play(&mut self) {
-        if let PlayerState::Paused {
+        if self.state.is_playing() {
+            // Still fading out for a pause.
+            self.cancel_fade_out();
+        } else if let PlayerState::Paused {
             track_id,
             play_request_id,
             stream_position_pcm,
@@ -1272,7 +3086,9 @@ impl PlayerInternal {
         {
             self.state.paused_to_playing();
 
-            let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+            self.query_sink_latency();
+            let position_ms = Self::position_pcm_to_ms(stream_position_pcm)
+                .saturating_sub(self.sink_latency_ms());
             self.send_event(PlayerEvent::Playing {
                 track_id,
                 play_request_id,
@@ -1285,7 +3101,41 @@ impl PlayerInternal {
         }
     }
 
+    fn handle_command_pause(&mut self) {
+        if self.state.is_playing()
+            && self.sink_recovery.is_none()
+            && self.start_fade_out(self.config.fade_out, false)
+        {
+            return;
+        }
+
+        self.handle_pause();
+    }
+
     fn handle_pause(&mut self) {
+        self.fade = None;
+
+        // Paused by a sink error already, just don't resume when the sink is back.
+        if self.sink_recovery.take().is_some() {
+            self.ensure_sink_stopped(false, SinkStatusReason::Pause);
+            if let PlayerState::Paused {
+                track_id,
+                play_request_id,
+                stream_position_pcm,
+                duration_ms,
+                ..
+            } = self.state
+            {
+                self.send_event(PlayerEvent::Paused {
+                    track_id,
+                    play_request_id,
+                    position_ms: Self::position_pcm_to_ms(stream_position_pcm),
+                    duration_ms,
+                });
+            }
+            return;
+        }
+
         if let PlayerState::Playing {
             track_id,
             play_request_id,
@@ -1296,7 +3146,7 @@ impl PlayerInternal {
         {
             self.state.playing_to_paused();
 
-            self.ensure_sink_stopped(false);
+            self.hold_sink();
             let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
             self.send_event(PlayerEvent::Paused {
                 track_id,
@@ -1309,6 +3159,174 @@ impl PlayerInternal {
         }
     }
 
+    // Pauses right away, without fading out, when the output moved somewhere the listener
+    // may not expect. Playback only resumes when asked to.
+    fn handle_route_change(&mut self, change: RouteChange) {
+        info!("The audio output route changed: {:?}", change);
+        if self.sink_status == SinkStatus::Running {
+            if let Some(callback) = &mut self.sink_event_callback {
+                callback(SinkStatus::Running, SinkStatusReason::DeviceChange);
+            }
+        }
+
+        let paused = pauses_on_route_change(
+            self.config.pause_on_route_change,
+            change,
+            matches!(self.state, PlayerState::Playing { .. }),
+            self.sink_recovery.is_some(),
+        );
+        if paused {
+            self.handle_pause();
+        }
+
+        self.send_event(PlayerEvent::RouteChanged { change, paused });
+    }
+
+    fn handle_bitrate_fallback(&mut self, reason: BitrateChangeReason) {
+        let new_bitrate = match self.bitrate.lower() {
+            Some(bitrate) => bitrate,
+            None => return,
+        };
+
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            stream_position_pcm,
+            ..
+        } = self.state
+        {
+            let old_bitrate = self.bitrate;
+            let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+
+            warn!(
+                "Falling back from {:?} to {:?} for <{:?}>: {:?}",
+                old_bitrate, new_bitrate, track_id, reason
+            );
+
+            self.bitrate = new_bitrate;
+            self.bitrate_stalls = 0;
+            self.count_fetched_bytes();
+            self.ensure_sink_stopped(true, SinkStatusReason::FormatChange);
+
+            self.send_event(PlayerEvent::BitrateChanged {
+                play_request_id,
+                track_id,
+                old_bitrate,
+                new_bitrate,
+                reason,
+            });
+            self.send_event(PlayerEvent::Loading {
+                track_id,
+                play_request_id,
+                position_ms,
+            });
+
+            // Reload the remainder of the track at the lower bitrate and resume where we left off.
+            let loader = Box::pin(self.load_track(track_id, position_ms, new_bitrate));
+            self.state = PlayerState::Loading {
+                track_id,
+                play_request_id,
+                start_playback: true,
+                loader,
+                load_started: Instant::now(),
+                position_ms,
+            };
+        }
+    }
+
+    fn handle_lyrics_position(
+        &mut self,
+        track_id: SpotifyId,
+        play_request_id: u64,
+        position_ms: u32,
+    ) {
+        while let Some(line) = self.lyrics.get(self.next_lyrics_line) {
+            if line.start_time_ms > position_ms {
+                break;
+            }
+
+            self.send_event(PlayerEvent::LyricsLine {
+                play_request_id,
+                track_id,
+                start_time_ms: line.start_time_ms,
+                words: line.words.clone(),
+            });
+            self.next_lyrics_line += 1;
+        }
+    }
+
+    // Skip the lines before `position_ms` so that seeking doesn't emit them all at once.
+    fn seek_lyrics(&mut self, position_ms: u32) {
+        self.next_lyrics_line = self
+            .lyrics
+            .partition_point(|line| line.start_time_ms < position_ms);
+    }
+
+    fn handle_track_load_failure(
+        &mut self,
+        track_id: SpotifyId,
+        play_request_id: u64,
+        position_ms: u32,
+        start_playback: bool,
+        reason: TrackSkipReason,
+    ) {
+        if reason == TrackSkipReason::LoadFailed
+            && self.load_attempts < self.config.track_load_retries
+        {
+            self.load_attempts += 1;
+            warn!(
+                "Unable to load track <{:?}>, retrying ({}/{})",
+                track_id, self.load_attempts, self.config.track_load_retries
+            );
+
+            let loader = Box::pin(self.load_track(track_id, position_ms, self.bitrate));
+            self.state = PlayerState::Loading {
+                track_id,
+                play_request_id,
+                start_playback,
+                loader,
+                load_started: Instant::now(),
+                position_ms,
+            };
+            return;
+        }
+
+        self.send_event(PlayerEvent::Unavailable {
+            track_id,
+            play_request_id,
+        });
+
+        match self.config.unavailable_track_policy {
+            UnavailableTrackPolicy::Skip
+                if self.consecutive_skips < self.config.max_consecutive_skips =>
+            {
+                warn!(
+                    "Skipping to next track, unable to load track <{:?}>: {:?}",
+                    track_id, reason
+                );
+                self.consecutive_skips += 1;
+                self.send_event(PlayerEvent::TrackSkipped {
+                    play_request_id,
+                    track_id,
+                    reason,
+                    retries: self.load_attempts,
+                });
+            }
+            UnavailableTrackPolicy::Skip => {
+                error!(
+                    "Stopping playback, {} tracks in a row could not be loaded",
+                    self.consecutive_skips + 1
+                );
+                self.consecutive_skips = 0;
+                self.handle_player_stop();
+            }
+            UnavailableTrackPolicy::Halt => {
+                warn!("Unable to load track <{:?}>: {:?}", track_id, reason);
+                self.send_end_of_track(track_id, play_request_id);
+            }
+        }
+    }
+
     fn handle_packet(&mut self, packet: Option<AudioPacket>, normalisation_factor: f64) {
         match packet {
             Some(mut packet) => {
@@ -1323,18 +3341,14 @@ impl PlayerInternal {
                         // there is nothing to normalise (all samples should pass unaltered). For the
                         // dynamic method, there may still be peaks that we want to shave off.
                         // No matter the case we apply volume attenuation last if there is any.
-                        if !self.config.normalisation {
+                        if !self.config.normalisation || self.normalisation_bypass {
                             if volume < 1.0 {
-                                for sample in data.iter_mut() {
-                                    *sample *= volume;
-                                }
+                                apply_gain(data, volume);
                             }
                         } else if self.config.normalisation_method == NormalisationMethod::Basic
                             && (normalisation_factor < 1.0 || volume < 1.0)
                         {
-                            for sample in data.iter_mut() {
-                                *sample *= normalisation_factor * volume;
-                            }
+                            apply_gain(data, normalisation_factor * volume);
                         } else if self.config.normalisation_method == NormalisationMethod::Dynamic {
                             // zero-cost shorthands
                             let threshold_db = self.config.normalisation_threshold_dbfs;
@@ -1342,6 +3356,8 @@ impl PlayerInternal {
                             let attack_cf = self.config.normalisation_attack_cf;
                             let release_cf = self.config.normalisation_release_cf;
 
+                            let mut limiting = false;
+
                             for sample in data.iter_mut() {
                                 *sample *= normalisation_factor;
 
@@ -1383,6 +3399,8 @@ impl PlayerInternal {
                                     0.0
                                 };
 
+                                limiting |= limiter_db > 0.0;
+
                                 // Spare the CPU unless (1) the limiter is engaged, (2) we
                                 // were in attack or (3) we were in release, and that attack/
                                 // release wasn't finished yet.
@@ -1420,12 +3438,49 @@ impl PlayerInternal {
 
                                 *sample *= volume;
                             }
+
+                            if limiting && !self.normalisation_limiting {
+                                self.count(|c| &c.limiter_engagements, 1);
+                            }
+                            self.normalisation_limiting = limiting;
                         }
                     }
 
-                    if let Err(e) = self.sink.write(packet, &mut self.converter) {
-                        error!("{}", e);
-                        exit(1);
+                    if let AudioPacket::Samples(ref mut data) = packet {
+                        if self.time_stretcher.is_active() {
+                            *data = self.time_stretcher.process(data);
+                        }
+
+                        self.apply_fade(data);
+                    }
+
+                    let levels = match (self.level_meter.as_mut(), &packet) {
+                        (Some(meter), AudioPacket::Samples(data)) => meter.measure(data),
+                        _ => None,
+                    };
+                    if let Some(levels) = levels {
+                        self.send_event(PlayerEvent::Levels { levels });
+                    }
+
+                    if !packet.is_empty() {
+                        if let Err(e) = self.sink.write(packet, &mut self.converter) {
+                            self.handle_sink_error(e);
+                        }
+
+                        let underruns = self.sink.take_underruns();
+                        if underruns > 0 {
+                            self.count(|c| &c.sink_underruns, underruns);
+                        }
+
+                        if let Some(change) = self.sink.take_route_change() {
+                            self.handle_route_change(change);
+                        }
+                    }
+
+                    if matches!(self.fade, Some(Fade { gain, step, .. }) if step < 0.0 && gain <= 0.0)
+                    {
+                        debug!("Pausing after fading out");
+                        self.handle_pause();
                     }
                 }
             }
@@ -1438,10 +3493,7 @@ impl PlayerInternal {
                     ..
                 } = self.state
                 {
-                    self.send_event(PlayerEvent::EndOfTrack {
-                        track_id,
-                        play_request_id,
-                    })
+                    self.send_end_of_track(track_id, play_request_id)
                 } else {
                     error!("PlayerInternal handle_packet: Invalid PlayerState");
                     exit(1);
@@ -1467,19 +3519,32 @@ impl PlayerInternal {
                 config.normalisation_type = NormalisationType::Track;
             }
         };
-        let normalisation_factor =
-            NormalisationData::get_factor(&config, loaded_track.normalisation_data);
+        let normalisation_factor = NormalisationData::get_factor(
+            &config,
+            loaded_track.normalisation_data,
+            &mut self.limiting_warnings,
+        );
+
+        if let Some(lyrics) = loaded_track.lyrics {
+            self.lyrics = lyrics;
+        }
+        if let Some(ref metadata) = loaded_track.metadata {
+            self.sink.track_changed(metadata);
+        }
+        self.seek_lyrics(position_ms);
+        self.time_stretcher.reset();
 
         if start_playback {
-            self.ensure_sink_running();
+            // Playing something else right after a pause, but the sleep timer stays in effect.
+            if !matches!(self.fade, Some(Fade { sleep: true, .. })) {
+                self.cancel_fade_out();
+            }
 
-            self.send_event(PlayerEvent::Playing {
-                track_id,
-                play_request_id,
-                position_ms,
-                duration_ms: loaded_track.duration_ms,
-            });
+            // Audio of the previous track may still be queued in the sink.
+            self.query_sink_latency();
+            let audible_position_ms = position_ms.saturating_sub(self.sink_latency_ms());
 
+            let duration_ms = loaded_track.duration_ms;
             self.state = PlayerState::Playing {
                 track_id,
                 play_request_id,
@@ -1490,13 +3555,26 @@ impl PlayerInternal {
                 duration_ms: loaded_track.duration_ms,
                 bytes_per_second: loaded_track.bytes_per_second,
                 stream_position_pcm: loaded_track.stream_position_pcm,
-                reported_nominal_start_time: Some(
-                    Instant::now() - Duration::from_millis(position_ms as u64),
-                ),
+                reported_nominal_start_time: Some(nominal_start_time(
+                    audible_position_ms,
+                    self.time_stretcher.speed(),
+                )),
                 suggested_to_preload_next_track: false,
             };
+
+            // This pauses playback again if the sink can't be started.
+            self.ensure_sink_running();
+
+            if self.state.is_playing() {
+                self.send_event(PlayerEvent::Playing {
+                    track_id,
+                    play_request_id,
+                    position_ms: audible_position_ms,
+                    duration_ms,
+                });
+            }
         } else {
-            self.ensure_sink_stopped(false);
+            self.ensure_sink_stopped(false, SinkStatusReason::Pause);
 
             self.state = PlayerState::Paused {
                 track_id,
@@ -1528,8 +3606,32 @@ impl PlayerInternal {
         position_ms: u32,
     ) {
         if !self.config.gapless {
-            self.ensure_sink_stopped(play);
+            self.ensure_sink_stopped(play, SinkStatusReason::Stop);
         }
+
+        self.reset_track_statistics();
+        self.bitrate_stalls = 0;
+        self.load_attempts = 0;
+        self.prefetched
+            .retain(|(prefetched, _)| *prefetched != track_id);
+
+        let restore_bitrate = self.bitrate != self.config.bitrate
+            && match self.state {
+                PlayerState::Playing {
+                    track_id: current_track_id,
+                    ..
+                }
+                | PlayerState::Paused {
+                    track_id: current_track_id,
+                    ..
+                }
+                | PlayerState::EndOfTrack {
+                    track_id: current_track_id,
+                    ..
+                } => current_track_id != track_id,
+                _ => true,
+            };
+
         // emit the correct player event
         match self.state {
             PlayerState::Playing {
@@ -1550,11 +3652,17 @@ impl PlayerInternal {
             } => self.send_event(PlayerEvent::Changed {
                 old_track_id,
                 new_track_id: track_id,
+                context_uri: self.context_uri.clone(),
+                context_kind: self.context_kind,
+                restrictions: self.restrictions,
             }),
             PlayerState::Stopped => self.send_event(PlayerEvent::Started {
                 track_id,
                 play_request_id,
                 position_ms,
+                context_uri: self.context_uri.clone(),
+                context_kind: self.context_kind,
+                restrictions: self.restrictions,
             }),
             PlayerState::Invalid { .. } => {
                 error!("PlayerInternal handle_command_load: invalid state");
@@ -1562,6 +3670,17 @@ impl PlayerInternal {
             }
         }
 
+        if restore_bitrate {
+            let old_bitrate = mem::replace(&mut self.bitrate, self.config.bitrate);
+            self.send_event(PlayerEvent::BitrateChanged {
+                play_request_id,
+                track_id,
+                old_bitrate,
+                new_bitrate: self.bitrate,
+                reason: BitrateChangeReason::Restored,
+            });
+        }
+
         // Now we check at different positions whether we already have a pre-loaded version
         // of this track somewhere. If so, use it and return.
 
@@ -1587,12 +3706,16 @@ impl PlayerInternal {
                     loaded_track
                         .stream_loader_controller
                         .set_random_access_mode();
-                    if let Err(e) = loaded_track.decoder.seek(position_pcm) {
-                        // This may be blocking.
-                        error!("PlayerInternal handle_command_load: {}", e);
-                    }
+                    // This may be blocking.
+                    loaded_track.stream_position_pcm = match loaded_track.decoder.seek(position_pcm)
+                    {
+                        Ok(landed_pcm) => landed_pcm,
+                        Err(e) => {
+                            error!("PlayerInternal handle_command_load: {}", e);
+                            position_pcm
+                        }
+                    };
                     loaded_track.stream_loader_controller.set_stream_mode();
-                    loaded_track.stream_position_pcm = position_pcm;
                 }
                 self.preload = PlayerPreload::None;
                 self.start_playback(track_id, play_request_id, loaded_track, play);
@@ -1626,12 +3749,15 @@ impl PlayerInternal {
 
                 if position_pcm != *stream_position_pcm {
                     stream_loader_controller.set_random_access_mode();
-                    if let Err(e) = decoder.seek(position_pcm) {
-                        // This may be blocking.
-                        error!("PlayerInternal handle_command_load: {}", e);
-                    }
+                    // This may be blocking.
+                    *stream_position_pcm = match decoder.seek(position_pcm) {
+                        Ok(landed_pcm) => landed_pcm,
+                        Err(e) => {
+                            error!("PlayerInternal handle_command_load: {}", e);
+                            position_pcm
+                        }
+                    };
                     stream_loader_controller.set_stream_mode();
-                    *stream_position_pcm = position_pcm;
                 }
 
                 // Move the info from the current state into a PlayerLoadedTrackData so we can use
@@ -1664,6 +3790,10 @@ impl PlayerInternal {
                         bytes_per_second,
                         duration_ms,
                         stream_position_pcm,
+                        relinked_track_id: None,
+                        lyrics: None,
+                        format: None,
+                        metadata: None,
                     };
 
                     self.preload = PlayerPreload::None;
@@ -1695,16 +3825,23 @@ impl PlayerInternal {
                     mut loaded_track,
                 } = preload
                 {
+                    self.handle_track_loaded(track_id, play_request_id, &loaded_track);
+
                     let position_pcm = Self::position_ms_to_pcm(position_ms);
 
                     if position_pcm != loaded_track.stream_position_pcm {
                         loaded_track
                             .stream_loader_controller
                             .set_random_access_mode();
-                        if let Err(e) = loaded_track.decoder.seek(position_pcm) {
-                            // This may be blocking
-                            error!("PlayerInternal handle_command_load: {}", e);
-                        }
+                        // This may be blocking
+                        loaded_track.stream_position_pcm =
+                            match loaded_track.decoder.seek(position_pcm) {
+                                Ok(landed_pcm) => landed_pcm,
+                                Err(e) => {
+                                    error!("PlayerInternal handle_command_load: {}", e);
+                                    position_pcm
+                                }
+                            };
                         loaded_track.stream_loader_controller.set_stream_mode();
                     }
                     self.start_playback(track_id, play_request_id, *loaded_track, play);
@@ -1718,7 +3855,7 @@ impl PlayerInternal {
 
         // We need to load the track - either from scratch or by completing a preload.
         // In any case we go into a Loading state to load the track.
-        self.ensure_sink_stopped(play);
+        self.ensure_sink_stopped(play, SinkStatusReason::Stop);
 
         self.send_event(PlayerEvent::Loading {
             track_id,
@@ -1750,7 +3887,8 @@ impl PlayerInternal {
         self.preload = PlayerPreload::None;
 
         // If we don't have a loader yet, create one from scratch.
-        let loader = loader.unwrap_or_else(|| Box::pin(self.load_track(track_id, position_ms)));
+        let loader = loader
+            .unwrap_or_else(|| Box::pin(self.load_track(track_id, position_ms, self.bitrate)));
 
         // Set ourselves to a loading state.
         self.state = PlayerState::Loading {
@@ -1758,6 +3896,8 @@ impl PlayerInternal {
             play_request_id,
             start_playback: play,
             loader,
+            load_started: Instant::now(),
+            position_ms,
         };
     }
 
@@ -1804,7 +3944,7 @@ impl PlayerInternal {
 
         // schedule the preload of the current track if desired.
         if preload_track {
-            let loader = self.load_track(track_id, 0);
+            let loader = self.load_track(track_id, 0, self.config.bitrate);
             self.preload = PlayerPreload::Loading {
                 track_id,
                 loader: Box::pin(loader),
@@ -1812,7 +3952,139 @@ impl PlayerInternal {
         }
     }
 
-    fn handle_command_seek(&mut self, position_ms: u32) {
+    fn handle_command_preload_upcoming(&mut self, track_ids: Vec<SpotifyId>) {
+        let upcoming: Vec<SpotifyId> = track_ids
+            .into_iter()
+            .take(self.config.preload_depth)
+            .collect();
+
+        // What was fetched for tracks that are no longer coming up is dropped, and was never
+        // played.
+        self.prefetches.retain(|prefetch| {
+            let keep = upcoming.iter().skip(1).any(|id| *id == prefetch.track_id);
+            if !keep {
+                debug!("Cancelling the prefetch of {:?}", prefetch.track_id);
+            }
+            keep
+        });
+        let cache = self.session.cache().cloned();
+        self.prefetched.retain(|(track_id, file_id)| {
+            let keep = upcoming.contains(track_id);
+            if !keep {
+                debug!("Removing the prefetched {:?} from the cache", track_id);
+                if let Some(cache) = cache.as_ref() {
+                    let _ = cache.remove_file(*file_id);
+                }
+            }
+            keep
+        });
+
+        let mut track_ids = upcoming.into_iter().enumerate();
+        if let Some((_, track_id)) = track_ids.next() {
+            self.handle_command_preload(track_id);
+        }
+
+        for (index, track_id) in track_ids {
+            let queue_position = index + 1;
+            if let Some(prefetch) = self
+                .prefetches
+                .iter_mut()
+                .find(|prefetch| prefetch.track_id == track_id)
+            {
+                prefetch.queue_position = queue_position;
+                continue;
+            }
+            if self
+                .prefetched
+                .iter()
+                .any(|(prefetched, _)| *prefetched == track_id)
+            {
+                continue;
+            }
+
+            let loader = PlayerTrackLoader {
+                session: self.session.clone(),
+                config: self.config.clone(),
+            };
+            let budget = self.preload_budget.clone();
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let thread_cancelled = cancelled.clone();
+
+            let (result_tx, result_rx) = oneshot::channel();
+
+            // Waiting for the download blocks, like loading a track does.
+            std::thread::spawn(move || {
+                let result = futures_executor::block_on(loader.prefetch_track(
+                    track_id,
+                    budget,
+                    thread_cancelled,
+                ));
+                let _ = result_tx.send(result);
+            });
+
+            self.prefetches.push(PlayerPrefetch {
+                track_id,
+                queue_position,
+                cancelled,
+                loader: Box::pin(result_rx.unwrap_or_else(|_| None)),
+            });
+        }
+    }
+
+    fn handle_command_seek(&mut self, requested_position_ms: u32) {
+        let (track_id, play_request_id, duration_ms) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                duration_ms,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id,
+                duration_ms,
+                ..
+            } => (track_id, play_request_id, duration_ms),
+            _ => {
+                warn!("Player::seek called from invalid state");
+                return;
+            }
+        };
+
+        let (position_ms, at_end) = clamp_seek_position(requested_position_ms, duration_ms);
+
+        // While playing, the new position is heard once the audio queued in the sink is.
+        let latency_ms = if self.state.is_playing() {
+            self.query_sink_latency();
+            self.sink_latency_ms()
+        } else {
+            0
+        };
+
+        let seeked = PlayerEvent::Seeked {
+            play_request_id,
+            track_id,
+            position_ms: position_ms.saturating_sub(latency_ms),
+            requested_position_ms: if position_ms != requested_position_ms {
+                debug!(
+                    "Clamping seek to {} ms to the duration of {} ms",
+                    requested_position_ms, duration_ms
+                );
+                Some(requested_position_ms)
+            } else {
+                None
+            },
+            duration_ms,
+        };
+
+        if at_end {
+            // There is nothing left to play, so move on right away instead of decoding past the end.
+            self.send_event(seeked);
+            self.state.playing_to_end_of_track();
+            self.send_end_of_track(track_id, play_request_id);
+            return;
+        }
+
         if let Some(stream_loader_controller) = self.state.stream_loader_controller() {
             stream_loader_controller.set_random_access_mode();
         }
@@ -1820,7 +4092,7 @@ impl PlayerInternal {
             let position_pcm = Self::position_ms_to_pcm(position_ms);
 
             match decoder.seek(position_pcm) {
-                Ok(_) => {
+                Ok(landed_pcm) => {
                     if let PlayerState::Playing {
                         ref mut stream_position_pcm,
                         ..
@@ -1830,13 +4102,24 @@ impl PlayerInternal {
                         ..
                     } = self.state
                     {
-                        *stream_position_pcm = position_pcm;
+                        *stream_position_pcm = landed_pcm;
+                    }
+                    let landed_ms = Self::position_pcm_to_ms(landed_pcm);
+                    self.seek_lyrics(landed_ms);
+                    self.time_stretcher.reset();
+                    self.send_event(seeked);
+
+                    if landed_pcm != position_pcm {
+                        debug!("Seek to {} ms landed at {} ms", position_ms, landed_ms);
+                        self.send_event(PlayerEvent::PositionCorrection {
+                            play_request_id,
+                            track_id,
+                            position_ms: landed_ms.saturating_sub(latency_ms),
+                        });
                     }
                 }
                 Err(e) => error!("PlayerInternal handle_command_seek: {}", e),
             }
-        } else {
-            warn!("Player::seek called from invalid state");
         }
 
         // If we're playing, ensure, that we have enough data leaded to avoid a buffer underrun.
@@ -1847,6 +4130,7 @@ impl PlayerInternal {
         // ensure we have a bit of a buffer of downloaded data
         self.preload_data_before_playback();
 
+        let speed = self.time_stretcher.speed();
         if let PlayerState::Playing {
             track_id,
             play_request_id,
@@ -1855,8 +4139,8 @@ impl PlayerInternal {
             ..
         } = self.state
         {
-            *reported_nominal_start_time =
-                Some(Instant::now() - Duration::from_millis(position_ms as u64));
+            let position_ms = position_ms.saturating_sub(latency_ms);
+            *reported_nominal_start_time = Some(nominal_start_time(position_ms, speed));
             self.send_event(PlayerEvent::Playing {
                 track_id,
                 play_request_id,
@@ -1880,6 +4164,39 @@ impl PlayerInternal {
         }
     }
 
+    fn handle_command_set_playback_speed(&mut self, speed: f32) {
+        if !(MIN_PLAYBACK_SPEED..=MAX_PLAYBACK_SPEED).contains(&speed) {
+            warn!(
+                "Ignoring playback speed {}, it must be between {} and {}",
+                speed, MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED
+            );
+            return;
+        }
+
+        if self.config.passthrough {
+            warn!("The playback speed can't be changed in passthrough mode");
+            return;
+        }
+
+        if speed == self.time_stretcher.speed() {
+            return;
+        }
+
+        debug!("Setting playback speed to {}", speed);
+        self.time_stretcher.set_speed(speed);
+
+        // Report the position again, so that listeners can follow it at the new speed.
+        if let PlayerState::Playing {
+            ref mut reported_nominal_start_time,
+            ..
+        } = self.state
+        {
+            *reported_nominal_start_time = None;
+        }
+
+        self.send_event(PlayerEvent::PlaybackSpeedChanged { speed });
+    }
+
     fn handle_command(&mut self, cmd: PlayerCommand) {
         debug!("command={:?}", cmd);
         match cmd {
@@ -1892,59 +4209,352 @@ impl PlayerInternal {
 
             PlayerCommand::Preload { track_id } => self.handle_command_preload(track_id),
 
+            PlayerCommand::PreloadUpcoming { track_ids } => {
+                self.handle_command_preload_upcoming(track_ids)
+            }
+
             PlayerCommand::Seek(position_ms) => self.handle_command_seek(position_ms),
 
             PlayerCommand::Play => self.handle_play(),
 
-            PlayerCommand::Pause => self.handle_pause(),
+            PlayerCommand::Pause => self.handle_command_pause(),
 
             PlayerCommand::Stop => self.handle_player_stop(),
 
-            PlayerCommand::AddEventSender(sender) => self.event_senders.push(sender),
+            PlayerCommand::AddEventSender(sender) => {
+                // Let the new listener know if it doesn't play at normal speed.
+                let speed = self.time_stretcher.speed();
+                if speed != 1.0 {
+                    let _ = sender.send(PlayerEvent::PlaybackSpeedChanged { speed });
+                }
+                if self.stop_after_current {
+                    let _ = sender.send(PlayerEvent::StopAfterCurrentChanged { armed: true });
+                }
+                self.event_senders.push(sender)
+            }
 
             PlayerCommand::SetSinkEventCallback(callback) => self.sink_event_callback = callback,
 
-            PlayerCommand::EmitVolumeSetEvent(volume) => {
-                self.send_event(PlayerEvent::VolumeSet { volume })
+            PlayerCommand::EmitVolumeSetEvent(volume, source, volume_ctrl) => {
+                self.send_event(PlayerEvent::VolumeSet {
+                    volume,
+                    source,
+                    volume_ctrl,
+                })
+            }
+
+            PlayerCommand::EmitContextEndedEvent(action) => {
+                self.send_event(PlayerEvent::ContextEnded { action })
+            }
+
+            PlayerCommand::EmitContextChangedEvent(context_uri, context_name, context_kind) => {
+                self.context_uri = context_uri.clone();
+                self.context_kind = context_kind;
+                self.send_event(PlayerEvent::ContextChanged {
+                    context_uri,
+                    context_name,
+                    context_kind,
+                })
+            }
+
+            PlayerCommand::EmitQueueChangedEvent(uris) => {
+                self.send_event(PlayerEvent::QueueChanged { uris })
+            }
+
+            PlayerCommand::EmitShuffleChangedEvent(shuffle) => {
+                self.send_event(PlayerEvent::ShuffleChanged { shuffle })
+            }
+
+            PlayerCommand::EmitRepeatChangedEvent(repeat) => {
+                self.send_event(PlayerEvent::RepeatChanged { repeat })
+            }
+
+            PlayerCommand::EmitAutoplayChangedEvent(autoplay) => {
+                self.send_event(PlayerEvent::AutoplayChanged { autoplay })
+            }
+            PlayerCommand::EmitMuteChangedEvent(muted) => {
+                self.send_event(PlayerEvent::MuteChanged { muted })
+            }
+            PlayerCommand::EmitPlaybackTakenOverEvent(device_name, device_type) => {
+                self.send_event(PlayerEvent::PlaybackTakenOver {
+                    device_name,
+                    device_type,
+                })
+            }
+            PlayerCommand::EmitBecameActiveEvent => self.send_event(PlayerEvent::BecameActive),
+            PlayerCommand::EmitBecameInactiveEvent => self.send_event(PlayerEvent::BecameInactive),
+
+            PlayerCommand::EmitControlBlockedEvent(ident, name, command) => {
+                self.send_event(PlayerEvent::ControlBlocked {
+                    ident,
+                    name,
+                    command,
+                })
+            }
+
+            PlayerCommand::EmitFilterExplicitContentChangedEvent(filter) => {
+                self.send_event(PlayerEvent::FilterExplicitContentChanged { filter })
+            }
+
+            PlayerCommand::EmitDeviceInfoChangedEvent(name, device_type) => {
+                self.send_event(PlayerEvent::DeviceInfoChanged { name, device_type })
+            }
+
+            PlayerCommand::EmitTrackFilteredEvent(track_id, reason) => {
+                self.send_event(PlayerEvent::TrackFiltered { track_id, reason })
+            }
+
+            PlayerCommand::EmitExplicitContentStoppedEvent => {
+                self.send_event(PlayerEvent::ExplicitContentStopped)
+            }
+            PlayerCommand::EmitSkippedLocalFileEvent(uri) => {
+                self.send_event(PlayerEvent::SkippedLocalFile { uri })
             }
 
             PlayerCommand::SetAutoNormaliseAsAlbum(setting) => {
                 self.auto_normalise_as_album = setting
             }
+
+            PlayerCommand::SetNormalisationBypass(bypass) => {
+                if bypass != self.normalisation_bypass {
+                    info!(
+                        "Normalisation is {}",
+                        if bypass { "bypassed" } else { "applied" }
+                    );
+                    self.normalisation_bypass = bypass;
+                    // The limiter starts over instead of releasing a peak from before.
+                    self.normalisation_integrator = 0.0;
+                    self.normalisation_peak = 0.0;
+                    self.normalisation_limiting = false;
+                    self.send_event(PlayerEvent::NormalisationBypassChanged { bypass });
+                }
+            }
+
+            PlayerCommand::SetPlaybackSpeed(speed) => self.handle_command_set_playback_speed(speed),
+
+            PlayerCommand::SetSleepTimer(duration) => {
+                self.arm_sleep_timer(Instant::now() + duration)
+            }
+
+            PlayerCommand::ExtendSleepTimer(duration) => {
+                let deadline = self
+                    .sleep_timer
+                    .as_ref()
+                    .map_or_else(Instant::now, |timer| timer.deadline);
+                self.arm_sleep_timer(deadline + duration)
+            }
+
+            PlayerCommand::CancelSleepTimer => self.cancel_sleep_timer(),
+
+            PlayerCommand::SetStopAfterCurrent(armed) => {
+                if armed != self.stop_after_current {
+                    self.stop_after_current = armed;
+                    self.send_event(PlayerEvent::StopAfterCurrentChanged { armed });
+                }
+            }
+
+            PlayerCommand::SetRestrictions(restrictions) => {
+                if restrictions != self.restrictions {
+                    self.restrictions = restrictions;
+                    if !self.state.is_stopped() {
+                        self.send_event(PlayerEvent::RestrictionsChanged { restrictions });
+                    }
+                }
+            }
+
+            PlayerCommand::GetStatistics(tx) => {
+                let _ = tx.send(self.statistics());
+            }
         }
     }
 
     fn send_event(&mut self, event: PlayerEvent) {
+        self.update_position(&event);
+        if let PlayerEvent::Playing { .. }
+        | PlayerEvent::Seeked { .. }
+        | PlayerEvent::PositionCorrection { .. }
+        | PlayerEvent::Progress { .. } = event
+        {
+            // A progress event right after the position was reported would be redundant.
+            self.next_progress = self
+                .config
+                .progress_interval
+                .map(|interval| Instant::now() + interval);
+        }
         self.event_senders
             .retain(|sender| sender.send(event.clone()).is_ok());
     }
 
+    fn send_progress(&mut self) {
+        let position = self
+            .position
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(PositionAnchor::current);
+
+        match position {
+            Some(position) if position.state == PlaybackState::Playing => {
+                self.send_event(PlayerEvent::Progress {
+                    play_request_id: position.play_request_id,
+                    track_id: position.track_id,
+                    position_ms: position.position_ms,
+                    duration_ms: position.duration_ms,
+                })
+            }
+            _ => self.next_progress = None,
+        }
+    }
+
+    // Keeps the position for `Player::current_position` up to date with the events that
+    // report it.
+    fn update_position(&self, event: &PlayerEvent) {
+        let speed = self.time_stretcher.speed();
+        let mut anchor = self.position.lock().unwrap();
+        let current = anchor.as_ref().map(PositionAnchor::current);
+
+        let at = |play_request_id, track_id, position_ms, duration_ms, state| PlaybackPosition {
+            play_request_id,
+            track_id,
+            position_ms,
+            duration_ms,
+            state,
+            speed,
+        };
+
+        let position = match *event {
+            PlayerEvent::Loading {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Some(at(
+                play_request_id,
+                track_id,
+                position_ms,
+                0,
+                PlaybackState::Loading,
+            )),
+            PlayerEvent::Playing {
+                play_request_id,
+                track_id,
+                position_ms,
+                duration_ms,
+            } => Some(at(
+                play_request_id,
+                track_id,
+                position_ms,
+                duration_ms,
+                PlaybackState::Playing,
+            )),
+            PlayerEvent::Paused {
+                play_request_id,
+                track_id,
+                position_ms,
+                duration_ms,
+            } => Some(at(
+                play_request_id,
+                track_id,
+                position_ms,
+                duration_ms,
+                PlaybackState::Paused,
+            )),
+            PlayerEvent::Seeked {
+                play_request_id,
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                let state = current
+                    .filter(|current| current.play_request_id == play_request_id)
+                    .map_or(PlaybackState::Paused, |current| current.state);
+                Some(at(
+                    play_request_id,
+                    track_id,
+                    position_ms,
+                    duration_ms,
+                    state,
+                ))
+            }
+            PlayerEvent::PositionCorrection {
+                play_request_id,
+                position_ms,
+                ..
+            } => match current {
+                Some(current) if current.play_request_id == play_request_id => {
+                    Some(PlaybackPosition {
+                        position_ms,
+                        ..current
+                    })
+                }
+                _ => return,
+            },
+            PlayerEvent::SinkError {
+                play_request_id,
+                position_ms,
+                ..
+            } => match current {
+                Some(current) if current.play_request_id == play_request_id => {
+                    Some(PlaybackPosition {
+                        position_ms,
+                        state: PlaybackState::Paused,
+                        ..current
+                    })
+                }
+                _ => return,
+            },
+            PlayerEvent::PlaybackSpeedChanged { speed } => {
+                current.map(|current| PlaybackPosition { speed, ..current })
+            }
+            PlayerEvent::Stopped { .. } => None,
+            _ => return,
+        };
+
+        *anchor = position.map(PositionAnchor::new);
+    }
+
+    fn send_end_of_track(&mut self, track_id: SpotifyId, play_request_id: u64) {
+        let statistics = self.statistics();
+        self.send_event(PlayerEvent::EndOfTrack {
+            track_id,
+            play_request_id,
+            statistics,
+        });
+
+        if self.stop_after_current {
+            self.stop_after_current = false;
+            self.send_event(PlayerEvent::StopAfterCurrentChanged { armed: false });
+        }
+    }
+
     fn load_track(
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> impl Future<Output = Result<PlayerLoadedTrackData, ()>> + Send + 'static {
+        bitrate: Bitrate,
+    ) -> impl Future<Output = Result<PlayerLoadedTrackData, TrackSkipReason>> + Send + 'static {
         // This method creates a future that returns the loaded stream and associated info.
         // Ideally all work should be done using asynchronous code. However, seek() on the
         // audio stream is implemented in a blocking fashion. Thus, we can't turn it into future
         // easily. Instead we spawn a thread to do the work and return a one-shot channel as the
         // future to work with.
 
+        let mut config = self.config.clone();
+        config.bitrate = bitrate;
+
         let loader = PlayerTrackLoader {
             session: self.session.clone(),
-            config: self.config.clone(),
+            config,
         };
 
         let (result_tx, result_rx) = oneshot::channel();
 
         std::thread::spawn(move || {
             let data = futures_executor::block_on(loader.load_track(spotify_id, position_ms));
-            if let Some(data) = data {
-                let _ = result_tx.send(data);
-            }
+            let _ = result_tx.send(data);
         });
 
-        result_rx.map_err(|_| ())
+        result_rx.unwrap_or_else(|_| Err(TrackSkipReason::LoadFailed))
     }
 
     fn preload_data_before_playback(&mut self) {
@@ -1998,6 +4608,9 @@ impl ::std::fmt::Debug for PlayerCommand {
             PlayerCommand::Preload { track_id } => {
                 f.debug_tuple("Preload").field(&track_id).finish()
             }
+            PlayerCommand::PreloadUpcoming { ref track_ids } => {
+                f.debug_tuple("PreloadUpcoming").field(track_ids).finish()
+            }
             PlayerCommand::Play => f.debug_tuple("Play").finish(),
             PlayerCommand::Pause => f.debug_tuple("Pause").finish(),
             PlayerCommand::Stop => f.debug_tuple("Stop").finish(),
@@ -2006,13 +4619,99 @@ impl ::std::fmt::Debug for PlayerCommand {
             PlayerCommand::SetSinkEventCallback(_) => {
                 f.debug_tuple("SetSinkEventCallback").finish()
             }
-            PlayerCommand::EmitVolumeSetEvent(volume) => {
-                f.debug_tuple("VolumeSet").field(&volume).finish()
+            PlayerCommand::EmitVolumeSetEvent(volume, source, volume_ctrl) => f
+                .debug_tuple("VolumeSet")
+                .field(&volume)
+                .field(&source)
+                .field(&volume_ctrl)
+                .finish(),
+            PlayerCommand::EmitContextEndedEvent(action) => {
+                f.debug_tuple("ContextEnded").field(&action).finish()
+            }
+            PlayerCommand::EmitContextChangedEvent(
+                ref context_uri,
+                ref context_name,
+                context_kind,
+            ) => f
+                .debug_tuple("ContextChanged")
+                .field(context_uri)
+                .field(context_name)
+                .field(&context_kind)
+                .finish(),
+            PlayerCommand::EmitQueueChangedEvent(ref uris) => {
+                f.debug_tuple("QueueChanged").field(uris).finish()
+            }
+            PlayerCommand::EmitShuffleChangedEvent(shuffle) => {
+                f.debug_tuple("ShuffleChanged").field(&shuffle).finish()
+            }
+            PlayerCommand::EmitRepeatChangedEvent(repeat) => {
+                f.debug_tuple("RepeatChanged").field(&repeat).finish()
+            }
+            PlayerCommand::EmitAutoplayChangedEvent(autoplay) => {
+                f.debug_tuple("AutoplayChanged").field(&autoplay).finish()
+            }
+            PlayerCommand::EmitMuteChangedEvent(muted) => {
+                f.debug_tuple("MuteChanged").field(&muted).finish()
+            }
+            PlayerCommand::EmitPlaybackTakenOverEvent(ref device_name, device_type) => f
+                .debug_tuple("PlaybackTakenOver")
+                .field(device_name)
+                .field(&device_type)
+                .finish(),
+            PlayerCommand::EmitBecameActiveEvent => f.debug_tuple("BecameActive").finish(),
+            PlayerCommand::EmitBecameInactiveEvent => f.debug_tuple("BecameInactive").finish(),
+            PlayerCommand::EmitControlBlockedEvent(ref ident, ref name, ref command) => f
+                .debug_tuple("ControlBlocked")
+                .field(ident)
+                .field(name)
+                .field(command)
+                .finish(),
+            PlayerCommand::EmitFilterExplicitContentChangedEvent(filter) => f
+                .debug_tuple("FilterExplicitContentChanged")
+                .field(&filter)
+                .finish(),
+            PlayerCommand::EmitDeviceInfoChangedEvent(ref name, device_type) => f
+                .debug_tuple("DeviceInfoChanged")
+                .field(name)
+                .field(&device_type)
+                .finish(),
+            PlayerCommand::EmitTrackFilteredEvent(track_id, reason) => f
+                .debug_tuple("TrackFiltered")
+                .field(&track_id)
+                .field(&reason)
+                .finish(),
+            PlayerCommand::EmitExplicitContentStoppedEvent => {
+                f.debug_tuple("ExplicitContentStopped").finish()
+            }
+            PlayerCommand::EmitSkippedLocalFileEvent(ref uri) => {
+                f.debug_tuple("SkippedLocalFile").field(uri).finish()
             }
             PlayerCommand::SetAutoNormaliseAsAlbum(setting) => f
                 .debug_tuple("SetAutoNormaliseAsAlbum")
                 .field(&setting)
                 .finish(),
+            PlayerCommand::SetNormalisationBypass(bypass) => f
+                .debug_tuple("SetNormalisationBypass")
+                .field(&bypass)
+                .finish(),
+            PlayerCommand::SetPlaybackSpeed(speed) => {
+                f.debug_tuple("SetPlaybackSpeed").field(&speed).finish()
+            }
+            PlayerCommand::SetStopAfterCurrent(armed) => {
+                f.debug_tuple("SetStopAfterCurrent").field(&armed).finish()
+            }
+            PlayerCommand::SetRestrictions(restrictions) => f
+                .debug_tuple("SetRestrictions")
+                .field(&restrictions)
+                .finish(),
+            PlayerCommand::SetSleepTimer(duration) => {
+                f.debug_tuple("SetSleepTimer").field(&duration).finish()
+            }
+            PlayerCommand::ExtendSleepTimer(duration) => {
+                f.debug_tuple("ExtendSleepTimer").field(&duration).finish()
+            }
+            PlayerCommand::CancelSleepTimer => f.debug_tuple("CancelSleepTimer").finish(),
+            PlayerCommand::GetStatistics(_) => f.debug_tuple("GetStatistics").finish(),
         }
     }
 }
@@ -2094,3 +4793,174 @@ impl<T: Read + Seek> Seek for Subfile<T> {
         Ok(newpos.saturating_sub(self.offset))
     }
 }
+
+// Applies the volume, and the factor of basic normalisation, to the float samples. That is
+// before the sink dithers them and converts them to its format, so that a low volume costs
+// no resolution.
+fn apply_gain(samples: &mut [f64], gain: f64) {
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        apply_gain, clamp_seek_position, pauses_on_route_change, prefetch_timeout,
+        LimitingWarnings, PlaybackPosition, PlaybackState, PositionAnchor, PreloadBudget,
+        LIMITING_WARNING_INTERVAL, PREFETCH_MIN_TIMEOUT,
+    };
+    use crate::audio_backend::{self, RouteChange};
+    use crate::config::{AudioFormat, VolumeCtrl};
+    use crate::convert::Converter;
+    use crate::core::spotify_id::SpotifyId;
+    use crate::decoder::AudioPacket;
+    use crate::mixer::mappings::MappedCtrl;
+    use crate::mixer::softmixer::SoftMixer;
+    use crate::mixer::{Mixer, MixerConfig};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn softvol_keeps_resolution_at_low_volume() {
+        let volume_ctrl = VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE);
+        let mixer = SoftMixer::open(MixerConfig {
+            volume_ctrl,
+            ..MixerConfig::default()
+        });
+        // About -40 dB.
+        mixer.set_volume(volume_ctrl.to_unmapped(0.01));
+        let gain = mixer.get_soft_volume().attenuation_factor();
+        assert!((gain - 0.01).abs() < 0.001, "gain {}", gain);
+
+        let sine: Vec<f64> = (0..4410)
+            .map(|i| 0.9 * (i as f64 * 2.0 * std::f64::consts::PI / 441.0).sin())
+            .collect();
+        let mut samples = sine.clone();
+        apply_gain(&mut samples, gain);
+
+        // Through the pipe sink to a file, as S24 without dither.
+        let path = std::env::temp_dir().join(format!("librespot-softvol-{}", std::process::id()));
+        let sink_builder = audio_backend::find(Some("pipe".to_owned())).unwrap();
+        let mut sink = sink_builder(
+            Some(path.to_string_lossy().into_owned()),
+            AudioFormat::S24,
+            &Default::default(),
+        );
+        sink.start().unwrap();
+        sink.write(AudioPacket::Samples(samples), &mut Converter::new(None))
+            .unwrap();
+        sink.stop().unwrap();
+        let written: Vec<i32> = std::fs::read(&path)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(written.len(), sine.len());
+
+        // Within half a step of 24 bit, and using the bits that 16 bit doesn't have.
+        let sum: f64 = written
+            .iter()
+            .zip(&sine)
+            .map(|(sample, sine)| (*sample as f64 - sine * gain * 8388608.0).powi(2))
+            .sum();
+        let rms_error = (sum / written.len() as f64).sqrt();
+        assert!(rms_error <= 0.5, "{} LSB", rms_error);
+        let below_s16 = written.iter().filter(|sample| *sample % 256 != 0).count();
+        assert!(below_s16 > written.len() * 9 / 10);
+    }
+
+    #[test]
+    fn limiting_warnings_are_rate_limited() {
+        let mut warnings = LimitingWarnings::default();
+        let start = Instant::now();
+        assert_eq!(warnings.allow(true, start), Some(0));
+        assert_eq!(warnings.allow(true, start + Duration::from_secs(1)), None);
+        assert_eq!(warnings.allow(true, start + Duration::from_secs(2)), None);
+        assert_eq!(
+            warnings.allow(true, start + LIMITING_WARNING_INTERVAL),
+            Some(2)
+        );
+        assert_eq!(
+            warnings.allow(false, start + 2 * LIMITING_WARNING_INTERVAL),
+            None
+        );
+    }
+
+    #[test]
+    fn prefetches_get_as_long_as_their_tracks_play() {
+        // Four minutes at 160 kbit/s.
+        let size = 240 * 20 * 1024;
+        assert_eq!(prefetch_timeout(size, 20 * 1024), Duration::from_secs(240));
+        assert_eq!(prefetch_timeout(1024, 20 * 1024), PREFETCH_MIN_TIMEOUT);
+        assert_eq!(prefetch_timeout(size, 0), Duration::from_secs(size));
+    }
+
+    #[test]
+    fn preload_budget_is_not_exceeded() {
+        let budget = PreloadBudget::new(Some(10));
+        assert!(budget.reserve(6));
+        assert!(!budget.reserve(5));
+        assert!(budget.reserve(4));
+        budget.release(6);
+        assert!(budget.reserve(5));
+
+        assert!(PreloadBudget::new(None).reserve(u64::MAX));
+    }
+
+    #[test]
+    fn seek_within_track() {
+        assert_eq!(clamp_seek_position(0, 180_000), (0, false));
+        assert_eq!(clamp_seek_position(90_000, 180_000), (90_000, false));
+    }
+
+    #[test]
+    fn seek_to_duration() {
+        assert_eq!(clamp_seek_position(180_000, 180_000), (180_000, true));
+    }
+
+    #[test]
+    fn seek_beyond_duration() {
+        assert_eq!(clamp_seek_position(180_001, 180_000), (180_000, true));
+        assert_eq!(clamp_seek_position(u32::MAX, 180_000), (180_000, true));
+    }
+
+    #[test]
+    fn only_routes_away_from_the_listener_pause() {
+        for change in [RouteChange::DeviceRemoved, RouteChange::JackUnplugged] {
+            assert!(pauses_on_route_change(true, change, true, false));
+            // The sink is recovering from an error, and shouldn't resume.
+            assert!(pauses_on_route_change(true, change, false, true));
+            assert!(!pauses_on_route_change(true, change, false, false));
+            assert!(!pauses_on_route_change(false, change, true, false));
+        }
+        for change in [RouteChange::DefaultDevice, RouteChange::JackPlugged] {
+            assert!(!pauses_on_route_change(true, change, true, false));
+            assert!(!pauses_on_route_change(true, change, false, true));
+        }
+    }
+
+    #[test]
+    fn position_advances_while_playing() {
+        let position = |position_ms, state| PositionAnchor {
+            position: PlaybackPosition {
+                play_request_id: 0,
+                track_id: SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap(),
+                position_ms,
+                duration_ms: 180_000,
+                state,
+                speed: 2.0,
+            },
+            at: Instant::now() - Duration::from_secs(1),
+        };
+
+        let playing = position(10_000, PlaybackState::Playing).current();
+        assert!((12_000..12_500).contains(&playing.position_ms));
+
+        let paused = position(10_000, PlaybackState::Paused).current();
+        assert_eq!(paused.position_ms, 10_000);
+
+        let at_end = position(179_000, PlaybackState::Playing).current();
+        assert_eq!(at_end.position_ms, 180_000);
+    }
+}
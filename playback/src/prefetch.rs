@@ -0,0 +1,232 @@
+//! Downloads tracks into the audio cache without playing them, such as to have a playlist at
+//! hand before going offline. A track is fetched just like for playback: its metadata, then
+//! its audio key and its file in the format of the bitrate. Nothing is decrypted or decoded,
+//! and the player isn't involved.
+
+use std::future::Future;
+
+use futures_util::stream::{self, Stream, StreamExt};
+use thiserror::Error;
+
+use crate::config::{Bitrate, PlayerConfig};
+use crate::core::audio_key::AudioKeyError;
+use crate::core::cache::Cache;
+use crate::core::session::Session;
+use crate::core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
+use crate::metadata::{Album, FileFormat, Metadata, MetadataError, Playlist};
+use crate::player::PlayerTrackLoader;
+
+/// How many tracks `prefetch_tracks` is usually asked to fetch at the same time.
+pub const DEFAULT_PREFETCH_CONCURRENCY: usize = 2;
+
+#[derive(Debug, Error)]
+pub enum PrefetchError {
+    #[error("The session has no audio cache to prefetch into")]
+    NoCache,
+    #[error("Cannot get the metadata: {0}")]
+    Metadata(MetadataError),
+    #[error("The track is not available")]
+    Unavailable,
+    #[error("The track is not available in any supported format")]
+    NoSupportedFormat,
+    #[error("Cannot get the audio key: {0}")]
+    AudioKey(AudioKeyError),
+    #[error("The download failed")]
+    Download,
+    // Only when the player fetches upcoming tracks ahead, see `PlayerConfig::preload_depth`.
+    #[error("The download doesn't fit into the preload budget")]
+    OverBudget,
+    #[error("The download was cancelled")]
+    Cancelled,
+    #[error("The download took too long")]
+    TimedOut,
+}
+
+/// What was fetched for a track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchReport {
+    /// The track that was fetched, which is an alternative to the one that was asked for if
+    /// that isn't available.
+    pub track_id: SpotifyId,
+    pub file_id: FileId,
+    pub format: FileFormat,
+    /// What was downloaded into the cache, none if the file was in it already.
+    pub bytes_written: u64,
+    pub already_cached: bool,
+}
+
+/// Where `source_tracks` gets the tracks to prefetch from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefetchSource {
+    Album(SpotifyId),
+    Playlist(SpotifyId),
+}
+
+/// Downloads the track into the audio cache of the session, in the format that playback with
+/// `bitrate` would use. The download waits while the session is rate limited, and takes up
+/// one of its concurrent requests until it is done.
+pub async fn prefetch_track(
+    session: &Session,
+    track_id: SpotifyId,
+    bitrate: Bitrate,
+) -> Result<PrefetchReport, PrefetchError> {
+    check_audio_cache(session.cache().map(|cache| &**cache))?;
+
+    let loader = PlayerTrackLoader {
+        session: session.clone(),
+        config: PlayerConfig {
+            bitrate,
+            ..Default::default()
+        },
+    };
+
+    // Waiting for the download blocks, like loading a track does. The requests on the way
+    // need the timers of the runtime, which is entered for them.
+    let handle = session.runtime_handle().clone();
+    session
+        .runtime_handle()
+        .spawn_blocking(move || {
+            let _runtime = handle.enter();
+            futures_executor::block_on(loader.prefetch(track_id))
+        })
+        .await
+        .unwrap_or(Err(PrefetchError::Download))
+}
+
+fn check_audio_cache(cache: Option<&Cache>) -> Result<(), PrefetchError> {
+    match cache {
+        Some(cache) if cache.caches_audio() => Ok(()),
+        _ => Err(PrefetchError::NoCache),
+    }
+}
+
+/// Prefetches the tracks, up to `concurrency` at the same time. Each track is yielded with
+/// its result as soon as it is done, in whatever order they finish.
+pub fn prefetch_tracks(
+    session: &Session,
+    track_ids: Vec<SpotifyId>,
+    bitrate: Bitrate,
+    concurrency: usize,
+) -> impl Stream<Item = (SpotifyId, Result<PrefetchReport, PrefetchError>)> {
+    let session = session.clone();
+    concurrently(track_ids, concurrency, move |track_id| {
+        let session = session.clone();
+        async move {
+            let result = prefetch_track(&session, track_id, bitrate).await;
+            (track_id, result)
+        }
+    })
+}
+
+// Runs `f` for the items, up to `concurrency` (at least one) of them at the same time.
+fn concurrently<T, F, Fut>(
+    items: Vec<T>,
+    concurrency: usize,
+    f: F,
+) -> impl Stream<Item = Fut::Output>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future,
+{
+    stream::iter(items)
+        .map(f)
+        .buffer_unordered(concurrency.max(1))
+}
+
+/// The tracks of the album or playlist, without those that can't be played, such as local
+/// files.
+pub async fn source_tracks(
+    session: &Session,
+    source: PrefetchSource,
+) -> Result<Vec<SpotifyId>, PrefetchError> {
+    let tracks = match source {
+        PrefetchSource::Album(id) => Album::get(session, id).await.map(|album| album.tracks),
+        PrefetchSource::Playlist(id) => Playlist::get(session, id)
+            .await
+            .map(|playlist| playlist.tracks),
+    }
+    .map_err(PrefetchError::Metadata)?;
+
+    Ok(tracks
+        .into_iter()
+        .filter(|track| track.audio_type != SpotifyAudioType::NonPlayable)
+        .collect())
+}
+
+/// Prefetches the tracks of the album or playlist, see `prefetch_tracks`.
+pub async fn prefetch_source(
+    session: &Session,
+    source: PrefetchSource,
+    bitrate: Bitrate,
+    concurrency: usize,
+) -> Result<impl Stream<Item = (SpotifyId, Result<PrefetchReport, PrefetchError>)>, PrefetchError> {
+    let tracks = source_tracks(session, source).await?;
+    Ok(prefetch_tracks(session, tracks, bitrate, concurrency))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_audio_cache, concurrently, PrefetchError};
+    use crate::core::cache::Cache;
+    use futures_util::future;
+    use futures_util::StreamExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Poll;
+
+    #[test]
+    fn prefetching_needs_an_audio_cache() {
+        assert!(matches!(
+            check_audio_cache(None),
+            Err(PrefetchError::NoCache)
+        ));
+
+        let cache = Cache::new(None::<PathBuf>, None, None, None).unwrap();
+        assert!(matches!(
+            check_audio_cache(Some(&cache)),
+            Err(PrefetchError::NoCache)
+        ));
+    }
+
+    #[test]
+    fn no_more_than_concurrency_tracks_are_fetched_at_once() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+
+        let fetches = concurrently((0..10).collect(), 3, |item: usize| {
+            let running = running.clone();
+            let most_running = most_running.clone();
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running.fetch_max(now, Ordering::SeqCst);
+                // Stays pending for a while, so that the others get started meanwhile.
+                let mut polls = 0;
+                future::poll_fn(|cx| {
+                    polls += 1;
+                    if polls < 3 {
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    } else {
+                        Poll::Ready(())
+                    }
+                })
+                .await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                item
+            }
+        });
+
+        let mut done: Vec<usize> = futures_executor::block_on(fetches.collect());
+        done.sort_unstable();
+        assert_eq!(done, (0..10).collect::<Vec<_>>());
+        assert_eq!(most_running.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn at_least_one_track_is_fetched_at_once() {
+        let done: Vec<usize> =
+            futures_executor::block_on(concurrently(vec![1, 2], 0, future::ready).collect());
+        assert_eq!(done, vec![1, 2]);
+    }
+}
@@ -0,0 +1,226 @@
+use std::f64::consts::PI;
+
+use crate::NUM_CHANNELS;
+
+pub const MIN_PLAYBACK_SPEED: f32 = 0.5;
+pub const MAX_PLAYBACK_SPEED: f32 = 3.0;
+
+// Frames per analysis window (about 23 ms). Consecutive windows overlap by half.
+const WINDOW_FRAMES: usize = 1024;
+const HOP_FRAMES: usize = WINDOW_FRAMES / 2;
+
+// How far a window may be moved away from its nominal position to line up with the
+// previously written audio.
+const SEEK_FRAMES: usize = 256;
+
+// Only every n-th frame is correlated, which is plenty to find the best alignment.
+const CORRELATION_STEP: usize = 4;
+
+/// Changes the playback speed of decoded audio without changing its pitch.
+///
+/// This is an implementation of WSOLA (waveform similarity overlap-add): the input is cut
+/// into overlapping windows that are taken further apart (faster) or closer together
+/// (slower) than they are written to the output. Each window is moved slightly to where
+/// it best continues the audio written before it, which avoids phasing artifacts.
+pub struct TimeStretcher {
+    speed: f64,
+    window: Vec<f64>,
+    input: Vec<f64>,
+    // The nominal start frame of the next window in `input`.
+    input_position: f64,
+    // The start frame in `input` of the audio that naturally follows the last window.
+    continuation: Option<usize>,
+    // The windowed second half of the last window, to be added to the next one.
+    overlap: Vec<f64>,
+}
+
+impl TimeStretcher {
+    pub fn new(speed: f32) -> Self {
+        // A periodic Hann window: two of them overlapping by half sum up to exactly one.
+        let window = (0..WINDOW_FRAMES)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f64 / WINDOW_FRAMES as f64).cos())
+            .collect();
+
+        Self {
+            speed: speed as f64,
+            window,
+            input: Vec::new(),
+            input_position: 0.0,
+            continuation: None,
+            overlap: vec![0.0; HOP_FRAMES * NUM_CHANNELS as usize],
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed as f32
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        let was_active = self.is_active();
+        self.speed = speed as f64;
+        if was_active != self.is_active() {
+            self.reset();
+        }
+    }
+
+    /// Returns whether the audio has to be stretched at all.
+    pub fn is_active(&self) -> bool {
+        (self.speed - 1.0).abs() > f64::EPSILON
+    }
+
+    /// Drops all buffered audio, for example after a seek or when a new track starts.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.input_position = 0.0;
+        self.continuation = None;
+        self.overlap.iter_mut().for_each(|sample| *sample = 0.0);
+    }
+
+    /// Stretches interleaved `samples`. Some audio is buffered between calls, so the
+    /// length of the output only matches the speed over time.
+    pub fn process(&mut self, samples: &[f64]) -> Vec<f64> {
+        let channels = NUM_CHANNELS as usize;
+        self.input.extend_from_slice(samples);
+
+        let mut output =
+            Vec::with_capacity((samples.len() as f64 / self.speed) as usize + self.overlap.len());
+
+        loop {
+            let nominal = self.input_position.round() as usize;
+            let latest = nominal + SEEK_FRAMES;
+            if (latest + WINDOW_FRAMES) * channels > self.input.len() {
+                break;
+            }
+
+            let start = match self.continuation {
+                Some(continuation) => {
+                    self.best_alignment(continuation, nominal.saturating_sub(SEEK_FRAMES), latest)
+                }
+                None => nominal,
+            };
+
+            for frame in 0..HOP_FRAMES {
+                let gain = self.window[frame];
+                for channel in 0..channels {
+                    let i = frame * channels + channel;
+                    output.push(self.overlap[i] + self.input[start * channels + i] * gain);
+                }
+            }
+
+            for frame in 0..HOP_FRAMES {
+                let gain = self.window[HOP_FRAMES + frame];
+                for channel in 0..channels {
+                    let i = frame * channels + channel;
+                    self.overlap[i] = self.input[(start + HOP_FRAMES) * channels + i] * gain;
+                }
+            }
+
+            self.continuation = Some(start + HOP_FRAMES);
+            self.input_position += HOP_FRAMES as f64 * self.speed;
+        }
+
+        // Drop the input that is neither needed for the next window nor to align it.
+        if let Some(continuation) = self.continuation {
+            let keep_from = continuation
+                .min((self.input_position as usize).saturating_sub(SEEK_FRAMES))
+                .min(self.input.len() / channels);
+            self.input.drain(..keep_from * channels);
+            self.input_position -= keep_from as f64;
+            self.continuation = Some(continuation - keep_from);
+        }
+
+        output
+    }
+
+    // Finds the window start between `earliest` and `latest` that best matches the audio
+    // at `continuation`, by normalised cross-correlation of the channel sums.
+    fn best_alignment(&self, continuation: usize, earliest: usize, latest: usize) -> usize {
+        let channels = NUM_CHANNELS as usize;
+        let frame_sum = |frame: usize| -> f64 {
+            self.input[frame * channels..(frame + 1) * channels]
+                .iter()
+                .sum()
+        };
+
+        let reference: Vec<f64> = (0..HOP_FRAMES)
+            .step_by(CORRELATION_STEP)
+            .map(|frame| frame_sum(continuation + frame))
+            .collect();
+
+        let mut best_start = earliest;
+        let mut best_score = f64::MIN;
+
+        for start in earliest..=latest {
+            let mut correlation = 0.0;
+            let mut energy = 0.0;
+            for (i, frame) in (0..HOP_FRAMES).step_by(CORRELATION_STEP).enumerate() {
+                let sample = frame_sum(start + frame);
+                correlation += sample * reference[i];
+                energy += sample * sample;
+            }
+
+            let score = if energy > 0.0 {
+                correlation / energy.sqrt()
+            } else {
+                0.0
+            };
+
+            if score > best_score {
+                best_score = score;
+                best_start = start;
+            }
+        }
+
+        best_start
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SAMPLE_RATE;
+
+    fn sine(frames: usize) -> Vec<f64> {
+        (0..frames)
+            .flat_map(|frame| {
+                let sample = (2.0 * PI * 440.0 * frame as f64 / SAMPLE_RATE as f64).sin();
+                [sample; NUM_CHANNELS as usize]
+            })
+            .collect()
+    }
+
+    fn stretched_frames(speed: f32, input_frames: usize) -> usize {
+        let mut stretcher = TimeStretcher::new(speed);
+        let input = sine(input_frames);
+        let output: usize = input
+            .chunks(2048)
+            .map(|chunk| stretcher.process(chunk).len())
+            .sum();
+        output / NUM_CHANNELS as usize
+    }
+
+    #[test]
+    fn faster_shortens() {
+        let frames = stretched_frames(2.0, SAMPLE_RATE as usize * 4);
+        let expected = SAMPLE_RATE as usize * 2;
+        assert!(frames <= expected && frames > expected - 2 * WINDOW_FRAMES);
+    }
+
+    #[test]
+    fn slower_lengthens() {
+        let frames = stretched_frames(0.5, SAMPLE_RATE as usize * 4);
+        let expected = SAMPLE_RATE as usize * 8;
+        assert!(frames <= expected && frames > expected - 4 * WINDOW_FRAMES);
+    }
+
+    #[test]
+    fn keeps_level() {
+        let mut stretcher = TimeStretcher::new(1.5);
+        let output = stretcher.process(&sine(SAMPLE_RATE as usize));
+        // Skip the fade in of the first window.
+        let peak = output[WINDOW_FRAMES * NUM_CHANNELS as usize..]
+            .iter()
+            .fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.9 && peak < 1.1);
+    }
+}
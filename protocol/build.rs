@@ -17,6 +17,7 @@ fn compile() {
 
     let files = &[
         proto_dir.join("authentication.proto"),
+        proto_dir.join("context_player_state.proto"),
         proto_dir.join("keyexchange.proto"),
         proto_dir.join("mercury.proto"),
         proto_dir.join("metadata.proto"),
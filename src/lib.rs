@@ -7,3 +7,53 @@ pub use librespot_discovery as discovery;
 pub use librespot_metadata as metadata;
 pub use librespot_playback as playback;
 pub use librespot_protocol as protocol;
+
+use thiserror::Error;
+
+/// The errors of all of the crates, which their own errors convert into. Each variant
+/// keeps the error it came from, so that callers can still match on it.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Core(#[from] core::Error),
+    #[error(transparent)]
+    Metadata(#[from] metadata::MetadataError),
+    #[error(transparent)]
+    Playback(#[from] playback::Error),
+    #[error(transparent)]
+    Connect(#[from] connect::spirc::SpircError),
+    #[error(transparent)]
+    Discovery(#[from] discovery::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::session::{AuthenticationError, SessionError};
+    use std::error::Error as _;
+    use std::io;
+
+    #[test]
+    fn errors_can_be_matched_after_conversion() {
+        let error: Error =
+            core::Error::from(SessionError::from(AuthenticationError::BadCredentials)).into();
+        assert!(matches!(
+            error,
+            Error::Core(core::Error::Session(SessionError::AuthenticationError(
+                AuthenticationError::BadCredentials
+            )))
+        ));
+        assert_eq!(
+            error.to_string(),
+            AuthenticationError::BadCredentials.to_string()
+        );
+    }
+
+    #[test]
+    fn sources_are_kept() {
+        let io_error = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let error: Error = core::Error::from(SessionError::from(io_error)).into();
+        let source = error.source().expect("no source");
+        assert_eq!(source.to_string(), "reset");
+    }
+}
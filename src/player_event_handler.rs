@@ -1,5 +1,12 @@
+use librespot::core::session::SessionEvent;
+use librespot::playback::audio_backend::RouteChange;
+use librespot::playback::config::VolumeCtrl;
 use librespot::playback::player::PlayerEvent;
-use librespot::playback::player::SinkStatus;
+use librespot::playback::player::Restrictions;
+use librespot::playback::player::TrackFilterReason;
+use librespot::playback::player::TrackSkipReason;
+use librespot::playback::player::VolumeSource;
+use librespot::playback::player::{SinkStatus, SinkStatusReason};
 use log::info;
 use tokio::process::{Child as AsyncChild, Command as AsyncCommand};
 
@@ -14,6 +21,9 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
         PlayerEvent::Changed {
             old_track_id,
             new_track_id,
+            context_uri,
+            context_kind,
+            restrictions,
         } => match old_track_id.to_base62() {
             Err(e) => {
                 return Some(Err(Error::new(
@@ -38,10 +48,19 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
                     env_vars.insert("PLAYER_EVENT", "changed".to_string());
                     env_vars.insert("OLD_TRACK_ID", old_id);
                     env_vars.insert("TRACK_ID", new_id);
+                    env_vars.insert("CONTEXT_URI", context_uri);
+                    env_vars.insert("CONTEXT_KIND", context_kind.to_string());
+                    insert_restrictions(&mut env_vars, &restrictions);
                 }
             },
         },
-        PlayerEvent::Started { track_id, .. } => match track_id.to_base62() {
+        PlayerEvent::Started {
+            track_id,
+            context_uri,
+            context_kind,
+            restrictions,
+            ..
+        } => match track_id.to_base62() {
             Err(e) => {
                 return Some(Err(Error::new(
                     ErrorKind::InvalidData,
@@ -51,6 +70,9 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
             Ok(id) => {
                 env_vars.insert("PLAYER_EVENT", "started".to_string());
                 env_vars.insert("TRACK_ID", id);
+                env_vars.insert("CONTEXT_URI", context_uri);
+                env_vars.insert("CONTEXT_KIND", context_kind.to_string());
+                insert_restrictions(&mut env_vars, &restrictions);
             }
         },
         PlayerEvent::Stopped { track_id, .. } => match track_id.to_base62() {
@@ -103,7 +125,10 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
                 env_vars.insert("POSITION_MS", position_ms.to_string());
             }
         },
-        PlayerEvent::Preloading { track_id, .. } => match track_id.to_base62() {
+        PlayerEvent::Preloading {
+            track_id,
+            queue_position,
+        } => match track_id.to_base62() {
             Err(e) => {
                 return Some(Err(Error::new(
                     ErrorKind::InvalidData,
@@ -116,26 +141,442 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
             Ok(id) => {
                 env_vars.insert("PLAYER_EVENT", "preloading".to_string());
                 env_vars.insert("TRACK_ID", id);
+                env_vars.insert("QUEUE_POSITION", queue_position.to_string());
+            }
+        },
+        PlayerEvent::TrackSkipped {
+            track_id,
+            reason,
+            retries,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::TrackSkipped: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                let reason = match reason {
+                    TrackSkipReason::Unavailable => "unavailable",
+                    TrackSkipReason::LoadFailed => "load_failed",
+                };
+                env_vars.insert("PLAYER_EVENT", "track_skipped".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("SKIP_REASON", reason.to_string());
+                env_vars.insert("RETRIES", retries.to_string());
+            }
+        },
+        PlayerEvent::Seeked {
+            track_id,
+            position_ms,
+            requested_position_ms,
+            duration_ms,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("PlayerEvent::Seeked: Invalid track id: {}", e.utf8_error()),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "seeked".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("DURATION_MS", duration_ms.to_string());
+                env_vars.insert("POSITION_MS", position_ms.to_string());
+                if let Some(requested_position_ms) = requested_position_ms {
+                    env_vars.insert("REQUESTED_POSITION_MS", requested_position_ms.to_string());
+                }
+            }
+        },
+        PlayerEvent::PositionCorrection {
+            track_id,
+            position_ms,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::PositionCorrection: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "position_correction".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("POSITION_MS", position_ms.to_string());
+            }
+        },
+        PlayerEvent::Progress {
+            track_id,
+            position_ms,
+            duration_ms,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::Progress: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "progress".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("POSITION_MS", position_ms.to_string());
+                env_vars.insert("DURATION_MS", duration_ms.to_string());
+            }
+        },
+        PlayerEvent::LyricsLine {
+            track_id,
+            start_time_ms,
+            words,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::LyricsLine: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "lyrics_line".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("START_TIME_MS", start_time_ms.to_string());
+                env_vars.insert("WORDS", words);
+            }
+        },
+        PlayerEvent::Loaded {
+            track_id,
+            file_format,
+            codec,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("PlayerEvent::Loaded: Invalid track id: {}", e.utf8_error()),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "loaded".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("FILE_FORMAT", format!("{:?}", file_format));
+                env_vars.insert("CODEC", codec.to_string());
+            }
+        },
+        PlayerEvent::SinkError {
+            track_id,
+            position_ms,
+            error,
+            ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::SinkError: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "sink_error".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("POSITION_MS", position_ms.to_string());
+                env_vars.insert("ERROR", error);
+            }
+        },
+        PlayerEvent::FetchDegraded {
+            track_id, retries, ..
+        } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::FetchDegraded: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                env_vars.insert("PLAYER_EVENT", "fetch_degraded".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("RETRIES", retries.to_string());
             }
         },
-        PlayerEvent::VolumeSet { volume } => {
+        PlayerEvent::VolumeSet {
+            volume,
+            source,
+            volume_ctrl,
+        } => {
             env_vars.insert("PLAYER_EVENT", "volume_set".to_string());
             env_vars.insert("VOLUME", volume.to_string());
+            let source = match source {
+                VolumeSource::Connect => "connect",
+                VolumeSource::External => "external",
+                VolumeSource::Remapped => "remapped",
+                VolumeSource::Mute => "mute",
+            };
+            env_vars.insert("SOURCE", source.to_string());
+            let (volume_ctrl, db_range) = match volume_ctrl {
+                VolumeCtrl::Cubic(db_range) => ("cubic", Some(db_range)),
+                VolumeCtrl::Fixed => ("fixed", None),
+                VolumeCtrl::Linear => ("linear", None),
+                VolumeCtrl::Log(db_range) => ("log", Some(db_range)),
+            };
+            env_vars.insert("VOLUME_CTRL", volume_ctrl.to_string());
+            if let Some(db_range) = db_range {
+                env_vars.insert("VOLUME_RANGE", db_range.to_string());
+            }
+        }
+        PlayerEvent::NormalisationBypassChanged { bypass } => {
+            env_vars.insert("PLAYER_EVENT", "normalisation_bypass_changed".to_string());
+            env_vars.insert("BYPASS", bypass.to_string());
+        }
+        PlayerEvent::PlaybackSpeedChanged { speed } => {
+            env_vars.insert("PLAYER_EVENT", "playback_speed_changed".to_string());
+            env_vars.insert("SPEED", speed.to_string());
+        }
+        PlayerEvent::StopAfterCurrentChanged { armed } => {
+            env_vars.insert("PLAYER_EVENT", "stop_after_current_changed".to_string());
+            env_vars.insert("ARMED", armed.to_string());
+        }
+        PlayerEvent::ContextEnded { action } => {
+            env_vars.insert("PLAYER_EVENT", "context_ended".to_string());
+            env_vars.insert("ACTION", action.to_string());
+        }
+        PlayerEvent::ContextChanged {
+            context_uri,
+            context_name,
+            context_kind,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "context_changed".to_string());
+            env_vars.insert("CONTEXT_URI", context_uri);
+            env_vars.insert("CONTEXT_NAME", context_name.unwrap_or_default());
+            env_vars.insert("CONTEXT_KIND", context_kind.to_string());
+        }
+        PlayerEvent::QueueChanged { uris } => {
+            env_vars.insert("PLAYER_EVENT", "queue_changed".to_string());
+            env_vars.insert("QUEUE", uris.join("\n"));
+        }
+        PlayerEvent::MetricsUpdated {
+            buffer_ms,
+            counters,
+            reconnects,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "metrics".to_string());
+            env_vars.insert("BUFFER_MS", buffer_ms.to_string());
+            env_vars.insert("FETCHED_BYTES", counters.fetched_bytes.to_string());
+            env_vars.insert("DECODE_ERRORS", counters.decode_errors.to_string());
+            env_vars.insert("UNDERRUNS", counters.sink_underruns.to_string());
+            env_vars.insert("RECONNECTS", reconnects.to_string());
+        }
+        PlayerEvent::Levels { levels } => {
+            env_vars.insert("PLAYER_EVENT", "levels".to_string());
+            let [left, right] = levels.channels;
+            env_vars.insert("PEAK_LEFT", left.peak.to_string());
+            env_vars.insert("PEAK_RIGHT", right.peak.to_string());
+            env_vars.insert("RMS_LEFT", left.rms.to_string());
+            env_vars.insert("RMS_RIGHT", right.rms.to_string());
+            env_vars.insert("CLIPPED", levels.clipped.to_string());
+        }
+        PlayerEvent::ShuffleChanged { shuffle } => {
+            env_vars.insert("PLAYER_EVENT", "shuffle_changed".to_string());
+            env_vars.insert("SHUFFLE", shuffle.to_string());
+        }
+        PlayerEvent::RepeatChanged { repeat } => {
+            env_vars.insert("PLAYER_EVENT", "repeat_changed".to_string());
+            env_vars.insert("REPEAT", repeat.to_string());
+        }
+        PlayerEvent::AutoplayChanged { autoplay } => {
+            env_vars.insert("PLAYER_EVENT", "autoplay_changed".to_string());
+            env_vars.insert("AUTOPLAY", autoplay.to_string());
+        }
+        PlayerEvent::MuteChanged { muted } => {
+            env_vars.insert("PLAYER_EVENT", "mute_changed".to_string());
+            env_vars.insert("MUTED", muted.to_string());
+        }
+        PlayerEvent::PlaybackTakenOver {
+            device_name,
+            device_type,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "playback_taken_over".to_string());
+            if let Some(device_name) = device_name {
+                env_vars.insert("DEVICE_NAME", device_name);
+            }
+            if let Some(device_type) = device_type {
+                env_vars.insert("DEVICE_TYPE", device_type.to_string());
+            }
+        }
+        PlayerEvent::BecameActive => {
+            env_vars.insert("PLAYER_EVENT", "became_active".to_string());
+        }
+        PlayerEvent::BecameInactive => {
+            env_vars.insert("PLAYER_EVENT", "became_inactive".to_string());
+        }
+        PlayerEvent::FilterExplicitContentChanged { filter } => {
+            env_vars.insert(
+                "PLAYER_EVENT",
+                "filter_explicit_content_changed".to_string(),
+            );
+            env_vars.insert("FILTER", filter.to_string());
+        }
+        PlayerEvent::DeviceInfoChanged { name, device_type } => {
+            env_vars.insert("PLAYER_EVENT", "device_info_changed".to_string());
+            env_vars.insert("NAME", name);
+            env_vars.insert("DEVICE_TYPE", device_type.to_string());
+        }
+        PlayerEvent::TrackFiltered { track_id, reason } => match track_id.to_base62() {
+            Err(e) => {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "PlayerEvent::TrackFiltered: Invalid track id: {}",
+                        e.utf8_error()
+                    ),
+                )))
+            }
+            Ok(id) => {
+                let reason = match reason {
+                    TrackFilterReason::Explicit => "explicit",
+                    TrackFilterReason::AccountExplicit => "account_explicit",
+                    TrackFilterReason::AutoplayVeto => "autoplay_veto",
+                };
+                env_vars.insert("PLAYER_EVENT", "track_filtered".to_string());
+                env_vars.insert("TRACK_ID", id);
+                env_vars.insert("FILTER_REASON", reason.to_string());
+            }
+        },
+        PlayerEvent::ExplicitContentStopped => {
+            env_vars.insert("PLAYER_EVENT", "explicit_content_stopped".to_string());
+        }
+        PlayerEvent::SkippedLocalFile { uri } => {
+            env_vars.insert("PLAYER_EVENT", "skipped_local_file".to_string());
+            env_vars.insert("URI", uri);
+        }
+        PlayerEvent::RestrictionsChanged { restrictions } => {
+            env_vars.insert("PLAYER_EVENT", "restrictions_changed".to_string());
+            insert_restrictions(&mut env_vars, &restrictions);
+        }
+        PlayerEvent::ControlBlocked {
+            ident,
+            name,
+            command,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "control_blocked".to_string());
+            env_vars.insert("DEVICE_IDENT", ident);
+            env_vars.insert("DEVICE_NAME", name);
+            env_vars.insert("COMMAND", command);
+        }
+        PlayerEvent::SleepTimerArmed { remaining_ms } => {
+            env_vars.insert("PLAYER_EVENT", "sleep_timer_armed".to_string());
+            env_vars.insert("REMAINING_MS", remaining_ms.to_string());
+        }
+        PlayerEvent::SleepTimerFired => {
+            env_vars.insert("PLAYER_EVENT", "sleep_timer_fired".to_string());
+        }
+        PlayerEvent::SleepTimerCancelled => {
+            env_vars.insert("PLAYER_EVENT", "sleep_timer_cancelled".to_string());
+        }
+        PlayerEvent::RouteChanged { change, paused } => {
+            env_vars.insert("PLAYER_EVENT", "route_changed".to_string());
+            env_vars.insert(
+                "ROUTE_CHANGE",
+                match change {
+                    RouteChange::DefaultDevice => "default_device",
+                    RouteChange::DeviceRemoved => "device_removed",
+                    RouteChange::JackUnplugged => "jack_unplugged",
+                    RouteChange::JackPlugged => "jack_plugged",
+                }
+                .to_string(),
+            );
+            env_vars.insert("PAUSED", paused.to_string());
         }
         _ => return None,
     }
 
+    Some(run_program(env_vars, onevent))
+}
+
+pub fn run_program_on_session_events(event: SessionEvent, onevent: &str) -> io::Result<AsyncChild> {
+    let mut env_vars = HashMap::new();
+    match event {
+        SessionEvent::RateLimited { wait_ms } => {
+            env_vars.insert("PLAYER_EVENT", "rate_limited".to_string());
+            env_vars.insert("WAIT_MS", wait_ms.to_string());
+        }
+        SessionEvent::CountryChanged { country } => {
+            env_vars.insert("PLAYER_EVENT", "country_changed".to_string());
+            env_vars.insert("COUNTRY", country.to_string());
+        }
+        SessionEvent::Connected {
+            session_id,
+            user_name,
+            access_point,
+            connect_attempts,
+            country,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "session_connected".to_string());
+            env_vars.insert("SESSION_ID", session_id.to_string());
+            env_vars.insert("USER_NAME", user_name);
+            env_vars.insert("ACCESS_POINT", access_point);
+            env_vars.insert("CONNECT_ATTEMPTS", connect_attempts.to_string());
+            if let Some(country) = country {
+                env_vars.insert("COUNTRY", country.to_string());
+            }
+        }
+        SessionEvent::Disconnected {
+            session_id,
+            user_name,
+        } => {
+            env_vars.insert("PLAYER_EVENT", "session_disconnected".to_string());
+            env_vars.insert("SESSION_ID", session_id.to_string());
+            env_vars.insert("USER_NAME", user_name);
+        }
+        SessionEvent::Reconnecting { attempt, delay_ms } => {
+            env_vars.insert("PLAYER_EVENT", "reconnecting".to_string());
+            env_vars.insert("ATTEMPT", attempt.to_string());
+            env_vars.insert("DELAY_MS", delay_ms.to_string());
+        }
+    }
+
+    run_program(env_vars, onevent)
+}
+
+fn run_program(env_vars: HashMap<&str, String>, onevent: &str) -> io::Result<AsyncChild> {
     let mut v: Vec<&str> = onevent.split_whitespace().collect();
     info!("Running {:?} with environment variables {:?}", v, env_vars);
-    Some(
-        AsyncCommand::new(&v.remove(0))
-            .args(&v)
-            .envs(env_vars.iter())
-            .spawn(),
-    )
+    AsyncCommand::new(&v.remove(0))
+        .args(&v)
+        .envs(env_vars.iter())
+        .spawn()
 }
 
-pub fn emit_sink_event(sink_status: SinkStatus, onevent: &str) -> io::Result<ExitStatus> {
+fn insert_restrictions(env_vars: &mut HashMap<&str, String>, restrictions: &Restrictions) {
+    env_vars.insert("CAN_SKIP_NEXT", restrictions.can_skip_next.to_string());
+    env_vars.insert("CAN_SKIP_PREV", restrictions.can_skip_prev.to_string());
+    env_vars.insert("CAN_SEEK", restrictions.can_seek.to_string());
+    env_vars.insert("CAN_REPEAT", restrictions.can_repeat.to_string());
+    env_vars.insert("CAN_SHUFFLE", restrictions.can_shuffle.to_string());
+}
+
+pub fn emit_sink_event(
+    sink_status: SinkStatus,
+    reason: SinkStatusReason,
+    onevent: &str,
+) -> io::Result<ExitStatus> {
     let mut env_vars = HashMap::new();
     env_vars.insert("PLAYER_EVENT", "sink".to_string());
     let sink_status = match sink_status {
@@ -144,6 +585,16 @@ pub fn emit_sink_event(sink_status: SinkStatus, onevent: &str) -> io::Result<Exi
         SinkStatus::Closed => "closed",
     };
     env_vars.insert("SINK_STATUS", sink_status.to_string());
+    let reason = match reason {
+        SinkStatusReason::Play => "play",
+        SinkStatusReason::Pause => "pause",
+        SinkStatusReason::Stop => "stop",
+        SinkStatusReason::Error => "error",
+        SinkStatusReason::FormatChange => "format_change",
+        SinkStatusReason::Unavailable => "unavailable",
+        SinkStatusReason::DeviceChange => "device_change",
+    };
+    env_vars.insert("SINK_REASON", reason.to_string());
     let mut v: Vec<&str> = onevent.split_whitespace().collect();
     info!("Running {:?} with environment variables {:?}", v, env_vars);
 